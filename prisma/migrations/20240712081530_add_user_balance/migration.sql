@@ -0,0 +1,36 @@
+-- CreateTable
+CREATE TABLE `UserBalance` (
+    `id` INTEGER NOT NULL AUTO_INCREMENT,
+    `appId` VARCHAR(191) NOT NULL,
+    `uid` VARCHAR(191) NOT NULL,
+    `balance` INTEGER NOT NULL,
+    `createdAt` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `updatedAt` DATETIME(3) NOT NULL,
+
+    UNIQUE INDEX `UserBalance_appId_uid_key`(`appId`, `uid`),
+    PRIMARY KEY (`id`)
+) DEFAULT CHARACTER SET utf8mb4 COLLATE utf8mb4_unicode_ci;
+
+-- CreateTable
+CREATE TABLE `BalanceTransaction` (
+    `id` VARCHAR(191) NOT NULL,
+    `appId` VARCHAR(191) NOT NULL,
+    `uid` VARCHAR(191) NOT NULL,
+    `kind` VARCHAR(191) NOT NULL,
+    `amount` INTEGER NOT NULL,
+    `balance` INTEGER NOT NULL,
+    `chargeId` VARCHAR(191) NULL,
+    `refundId` VARCHAR(191) NULL,
+    `description` VARCHAR(191) NOT NULL,
+    `createdAt` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `updatedAt` DATETIME(3) NOT NULL,
+
+    INDEX `BalanceTransaction_appId_uid_idx`(`appId`, `uid`),
+    PRIMARY KEY (`id`)
+) DEFAULT CHARACTER SET utf8mb4 COLLATE utf8mb4_unicode_ci;
+
+-- AddForeignKey
+ALTER TABLE `UserBalance` ADD CONSTRAINT `UserBalance_appId_fkey` FOREIGN KEY (`appId`) REFERENCES `App`(`id`) ON DELETE CASCADE ON UPDATE CASCADE;
+
+-- AddForeignKey
+ALTER TABLE `BalanceTransaction` ADD CONSTRAINT `BalanceTransaction_appId_fkey` FOREIGN KEY (`appId`) REFERENCES `App`(`id`) ON DELETE CASCADE ON UPDATE CASCADE;
@@ -0,0 +1,14 @@
+-- CreateTable
+CREATE TABLE `WxPlatformCert` (
+    `id` INTEGER NOT NULL AUTO_INCREMENT,
+    `mchId` VARCHAR(191) NOT NULL,
+    `serialNo` VARCHAR(191) NOT NULL,
+    `certificate` TEXT NOT NULL,
+    `effectiveAt` INTEGER NOT NULL,
+    `expireAt` INTEGER NOT NULL,
+    `createdAt` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `updatedAt` DATETIME(3) NOT NULL,
+
+    UNIQUE INDEX `WxPlatformCert_mchId_serialNo_key`(`mchId`, `serialNo`),
+    PRIMARY KEY (`id`)
+) DEFAULT CHARACTER SET utf8mb4 COLLATE utf8mb4_unicode_ci;
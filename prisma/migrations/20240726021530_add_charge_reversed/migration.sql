@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE `Charge` ADD COLUMN `reversed` BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN `timeReversed` INTEGER NULL;
@@ -0,0 +1,144 @@
+use super::{
+    load_alipay_config,
+    openapi::{trade_cancel_result, trade_close_result, trade_query_result, OpenApiRequestPayload},
+    openapi_trade::{refund_trade, send_trade_request, sign_client_request, verify_trade_notify},
+    AlipayError, AlipayOpenApiConfig,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelQueryRequest, ChannelRefundRequest, ChargeError,
+    ChargeResult, ChargeStatus, PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+use serde_json::json;
+
+pub struct AlipayApp {
+    config: AlipayOpenApiConfig,
+    app_auth_token: Option<String>, // 服务商模式下商户授权的令牌
+}
+
+impl AlipayApp {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+    ) -> Result<Self, AlipayError> {
+        let (config, app_auth_token) = load_alipay_config::<AlipayOpenApiConfig>(
+            prisma_client,
+            app_id,
+            sub_app_id,
+            PaymentChannel::Alipay,
+        )
+        .await?;
+        config.check_public_key()?;
+        Ok(Self {
+            config,
+            app_auth_token,
+        })
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for AlipayApp {
+    /**
+     * alipay.trade.app.pay 不需要服务端请求支付宝, 返回签名好的 orderInfo 给客户端 SDK
+     * https://opendocs.alipay.com/open/204/105465
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            currency,
+            merchant_order_no,
+            time_expire,
+            subject,
+            body,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let mut openapi_request_payload = OpenApiRequestPayload::new(
+            charge_id,
+            "alipay.trade.app.pay",
+            &config.alipay_app_id,
+            &config.alipay_pid,
+            self.app_auth_token.as_deref(),
+            "", // App 支付没有 return_url
+            merchant_order_no,
+            charge_amount,
+            currency,
+            time_expire,
+            subject,
+            body,
+        )?;
+        sign_client_request(config, &mut openapi_request_payload)?;
+        let order_info = openapi_request_payload.build_order_info();
+        Ok(ChargeResult {
+            credential: json!({ "orderInfo": order_info }),
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        Ok(verify_trade_notify(&self.config, payload)?)
+    }
+
+    async fn create_refund(
+        &self,
+        request: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        Ok(refund_trade(&self.config, self.app_auth_token.as_deref(), request).await?)
+    }
+
+    fn process_refund_notify(&self, _payload: &str) -> Result<RefundStatus, RefundError> {
+        Err(RefundError::Unexpected("not implemented".to_string()))
+    }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let query_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.query",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_query_result(query_response)?)
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let close_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.close",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_close_result(close_response)?)
+    }
+
+    async fn reverse_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let cancel_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.cancel",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_cancel_result(cancel_response)?)
+    }
+}
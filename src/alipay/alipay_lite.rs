@@ -0,0 +1,164 @@
+use super::{
+    load_alipay_config,
+    openapi::{trade_cancel_result, trade_close_result, trade_query_result, OpenApiRequestPayload},
+    openapi_trade::{refund_trade, send_charge_request, send_trade_request, verify_trade_notify},
+    AlipayError, AlipayOpenApiConfig,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelQueryRequest, ChannelRefundRequest, ChargeError,
+    ChargeResult, ChargeStatus, PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+use serde_json::json;
+
+pub struct AlipayLite {
+    config: AlipayOpenApiConfig,
+    app_auth_token: Option<String>, // 服务商模式下商户授权的令牌
+}
+
+impl AlipayLite {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+    ) -> Result<Self, AlipayError> {
+        let (config, app_auth_token) = load_alipay_config::<AlipayOpenApiConfig>(
+            prisma_client,
+            app_id,
+            sub_app_id,
+            PaymentChannel::AlipayLite,
+        )
+        .await?;
+        config.check_public_key()?;
+        Ok(Self {
+            config,
+            app_auth_token,
+        })
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for AlipayLite {
+    /**
+     * alipay.trade.create 创建交易, credential 里返回 tradeNO 给小程序的 my.tradePay 调起支付
+     * https://opendocs.alipay.com/mini/05xhsr
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            currency,
+            merchant_order_no,
+            time_expire,
+            subject,
+            body,
+            extra,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let buyer_id = match extra.buyer_id.as_ref() {
+            Some(buyer_id) => buyer_id.to_string(),
+            None => {
+                return Err(ChargeError::MalformedRequest(
+                    "missing buyer_id in charge extra".to_string(),
+                ))
+            }
+        };
+        let mut openapi_request_payload = OpenApiRequestPayload::new(
+            charge_id,
+            "alipay.trade.create",
+            &config.alipay_app_id,
+            &config.alipay_pid,
+            self.app_auth_token.as_deref(),
+            "", // 小程序支付没有 return_url
+            merchant_order_no,
+            charge_amount,
+            currency,
+            time_expire,
+            subject,
+            body,
+        )?;
+        openapi_request_payload.extend_biz_content("buyer_id", json!(buyer_id));
+        let create_response = send_charge_request(config, openapi_request_payload).await?;
+        if create_response["code"].as_str() != Some("10000") {
+            return Err(AlipayError::ApiError(format!(
+                "alipay.trade.create code != 10000: {:?} {:?}",
+                create_response["sub_code"].as_str(),
+                create_response["sub_msg"].as_str(),
+            ))
+            .into());
+        }
+        let trade_no = create_response["trade_no"].as_str().ok_or_else(|| {
+            AlipayError::ApiError("missing trade_no in alipay.trade.create response".into())
+        })?;
+        Ok(ChargeResult {
+            credential: json!({ "tradeNO": trade_no }),
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        Ok(verify_trade_notify(&self.config, payload)?)
+    }
+
+    async fn create_refund(
+        &self,
+        request: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        Ok(refund_trade(&self.config, self.app_auth_token.as_deref(), request).await?)
+    }
+
+    fn process_refund_notify(&self, _payload: &str) -> Result<RefundStatus, RefundError> {
+        Err(RefundError::Unexpected("not implemented".to_string()))
+    }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let query_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.query",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_query_result(query_response)?)
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let close_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.close",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_close_result(close_response)?)
+    }
+
+    async fn reverse_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let cancel_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.cancel",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_cancel_result(cancel_response)?)
+    }
+}
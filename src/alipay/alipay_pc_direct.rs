@@ -1,16 +1,23 @@
 use super::{
-    mapi::{MapiNotifyPayload, MapiRefundPayload, MapiRequestPayload},
-    openapi::{OpenApiNotifyPayload, OpenApiRefundPayload, OpenApiRequestPayload},
+    load_alipay_config,
+    mapi::{
+        self, is_forex_currency, MapiForexRefundPayload, MapiForexRequestPayload,
+        MapiNotifyPayload, MapiRefundPayload, MapiRequestPayload, MapiTradeClosePayload,
+        MapiTradeQueryPayload,
+    },
+    openapi::{self, OpenApiRequestPayload},
+    openapi_trade::{refund_trade, send_trade_request, sign_client_request, verify_trade_notify},
     AlipayApiType, AlipayError, AlipayPcDirectConfig,
 };
 use crate::core::{
-    ChannelChargeRequest, ChannelHandler, ChannelRefundRequest, ChargeError, ChargeStatus,
-    PaymentChannel, RefundError, RefundResult, RefundStatus,
+    ChannelChargeRequest, ChannelHandler, ChannelQueryRequest, ChannelRefundRequest, ChargeError,
+    ChargeResult, ChargeStatus, PaymentChannel, RefundError, RefundResult, RefundStatus,
 };
 use async_trait::async_trait;
 
 pub struct AlipayPcDirect {
     config: AlipayPcDirectConfig,
+    app_auth_token: Option<String>, // 服务商模式下商户授权的令牌
 }
 
 impl AlipayPcDirect {
@@ -19,21 +26,23 @@ impl AlipayPcDirect {
         app_id: Option<&str>,
         sub_app_id: Option<&str>,
     ) -> Result<Self, AlipayError> {
-        let channel_params = crate::utils::load_channel_params_from_db(
-            &prisma_client,
+        let (config, app_auth_token) = load_alipay_config::<AlipayPcDirectConfig>(
+            prisma_client,
             app_id,
             sub_app_id,
-            &PaymentChannel::AlipayPcDirect.to_string(),
+            PaymentChannel::AlipayPcDirect,
         )
-        .await
-        .map_err(|e| AlipayError::InvalidConfig(format!("{:?}", e)))?;
-        let config: AlipayPcDirectConfig =
-            serde_json::from_value(channel_params.params).map_err(|e| {
-                AlipayError::InvalidConfig(
-                    format!("error deserializing alipay_pc_direct config: {:?}", e).into(),
-                )
-            })?;
-        Ok(Self { config })
+        .await?;
+        // mapi 接口没有服务商代调用
+        if app_auth_token.is_some() && matches!(config.alipay_version, AlipayApiType::MAPI) {
+            return Err(AlipayError::InvalidConfig(
+                "alipay isv mode requires alipay_version 2 (openapi)".into(),
+            ));
+        }
+        Ok(Self {
+            config,
+            app_auth_token,
+        })
     }
 }
 
@@ -44,6 +53,7 @@ impl ChannelHandler for AlipayPcDirect {
         &ChannelChargeRequest {
             charge_id,
             charge_amount,
+            currency,
             merchant_order_no,
             time_expire,
             subject,
@@ -51,7 +61,7 @@ impl ChannelHandler for AlipayPcDirect {
             extra,
             ..
         }: &ChannelChargeRequest,
-    ) -> Result<serde_json::Value, ChargeError> {
+    ) -> Result<ChargeResult, ChargeError> {
         let config = &self.config;
         let return_url = match extra.success_url.as_ref() {
             Some(url) => url.to_string(),
@@ -62,6 +72,25 @@ impl ChannelHandler for AlipayPcDirect {
             }
         };
         let res_json = match config.alipay_version {
+            // 人民币之外的币种走境外支付宝
+            AlipayApiType::MAPI if is_forex_currency(currency) => {
+                let mut forex_request_payload = MapiForexRequestPayload::new(
+                    charge_id,
+                    "create_forex_trade",
+                    "NEW_OVERSEAS_SELLER",
+                    &config.alipay_pid,
+                    return_url.as_str(),
+                    merchant_order_no,
+                    charge_amount,
+                    currency,
+                    time_expire,
+                    subject,
+                    body,
+                )?;
+                let private_key = config.mapi_private_key()?;
+                forex_request_payload.sign_rsa(private_key)?;
+                serde_json::to_value(forex_request_payload)
+            }
             AlipayApiType::MAPI => {
                 let mut mapi_request_payload = MapiRequestPayload::new(
                     charge_id,
@@ -74,66 +103,51 @@ impl ChannelHandler for AlipayPcDirect {
                     subject,
                     body,
                 )?;
-                let private_key = config
-                    .alipay_private_key
-                    .as_deref()
-                    .ok_or(AlipayError::InvalidConfig("missing alipay_private_key".to_string()))?;
+                let private_key = config.mapi_private_key()?;
                 mapi_request_payload.sign_rsa(private_key)?;
                 serde_json::to_value(mapi_request_payload)
             }
             AlipayApiType::OPENAPI => {
-                let alipay_app_id = config
-                    .alipay_app_id
-                    .as_deref()
-                    .ok_or(AlipayError::InvalidConfig("missing alipay_app_id".to_string()))?;
+                let openapi_config = config.openapi_config()?;
                 let mut openapi_request_payload = OpenApiRequestPayload::new(
                     charge_id,
                     "alipay.trade.page.pay",
-                    alipay_app_id,
+                    &openapi_config.alipay_app_id,
                     &config.alipay_pid,
+                    self.app_auth_token.as_deref(),
                     &return_url,
                     merchant_order_no,
                     charge_amount,
+                    currency,
                     time_expire,
                     subject,
                     body,
                 )?;
-                let private_key = config
-                    .alipay_private_key_rsa2
-                    .as_deref()
-                    .ok_or(AlipayError::InvalidConfig("missing alipay_private_key_rsa2".to_string()))?;
-                openapi_request_payload.sign_rsa2(private_key)?;
+                sign_client_request(&openapi_config, &mut openapi_request_payload)?;
                 serde_json::to_value(openapi_request_payload)
             }
         };
         let res_json = res_json.map_err(|e| {
             AlipayError::Unexpected(format!("error serializing MapiRequestPayload: {:?}", e))
         })?;
-        Ok(res_json)
+        Ok(ChargeResult {
+            credential: res_json,
+            ..Default::default()
+        })
     }
 
-    fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
         let config = &self.config;
         let success = match config.alipay_version {
             AlipayApiType::MAPI => {
                 let notify_payload = MapiNotifyPayload::new(payload)?;
-                let public_key = config
-                    .alipay_public_key
-                    .as_deref()
-                    .ok_or(AlipayError::InvalidConfig("missing alipay_public_key".to_string()))?;
+                let public_key = config.mapi_public_key()?;
                 notify_payload.verify_rsa_sign(public_key)?;
                 let trade_status = notify_payload.trade_status;
                 trade_status == "TRADE_SUCCESS" || trade_status == "TRADE_FINISHED"
             }
             AlipayApiType::OPENAPI => {
-                let notify_payload = OpenApiNotifyPayload::new(payload)?;
-                let public_key = config
-                    .alipay_public_key_rsa2
-                    .as_deref()
-                    .ok_or(AlipayError::InvalidConfig("missing alipay_public_key_rsa2".to_string()))?;
-                notify_payload.verify_rsa2_sign(public_key)?;
-                let trade_status = notify_payload.trade_status;
-                trade_status == "TRADE_SUCCESS" || trade_status == "TRADE_FINISHED"
+                return Ok(verify_trade_notify(&config.openapi_config()?, payload)?);
             }
         };
         // TODO! 需要验证 OpenApiNotifyPayload 上的 out_trade_no 和 total_amount
@@ -146,8 +160,11 @@ impl ChannelHandler for AlipayPcDirect {
 
     async fn create_refund(
         &self,
-        &ChannelRefundRequest {
+        request: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        let &ChannelRefundRequest {
             charge_id,
+            charge_currency,
             charge_merchant_order_no,
             refund_id,
             refund_amount,
@@ -155,10 +172,36 @@ impl ChannelHandler for AlipayPcDirect {
             description,
             // extra,
             ..
-        }: &ChannelRefundRequest,
-    ) -> Result<RefundResult, RefundError> {
+        } = request;
         let config = &self.config;
         let result = match config.alipay_version {
+            AlipayApiType::MAPI if is_forex_currency(charge_currency) => {
+                let mut refund_payload = MapiForexRefundPayload::new(
+                    &config.alipay_pid,
+                    "NEW_OVERSEAS_SELLER",
+                    charge_merchant_order_no,
+                    refund_merchant_order_no,
+                    refund_amount,
+                    charge_currency,
+                    description,
+                )?;
+                let private_key = config.mapi_private_key()?;
+                refund_payload.sign_rsa(private_key)?;
+                let mut result = RefundResult {
+                    amount: refund_amount,
+                    description: description.to_string(),
+                    ..Default::default()
+                };
+                // forex_refund 同步返回退款结果
+                match refund_payload.send_request().await? {
+                    None => result.status = RefundStatus::Success,
+                    Some(error) => {
+                        result.status = RefundStatus::Fail(error.clone());
+                        result.failure_code = Some(error);
+                    }
+                }
+                result
+            }
             AlipayApiType::MAPI => {
                 let mut refund_payload = MapiRefundPayload::new(
                     refund_id,
@@ -168,10 +211,7 @@ impl ChannelHandler for AlipayPcDirect {
                     refund_amount,
                     description,
                 )?;
-                let private_key = config
-                    .alipay_private_key
-                    .as_deref()
-                    .ok_or(AlipayError::InvalidConfig("missing alipay_private_key".to_string()))?;
+                let private_key = config.mapi_private_key()?;
                 refund_payload.sign_rsa(private_key)?;
                 // refund_payload.sign_md5(&config.alipay_security_key)?;
                 let refund_url = refund_payload.build_refund_url().await?;
@@ -184,44 +224,12 @@ impl ChannelHandler for AlipayPcDirect {
                 }
             }
             AlipayApiType::OPENAPI => {
-                let alipay_app_id = config
-                    .alipay_app_id
-                    .as_deref()
-                    .ok_or(AlipayError::InvalidConfig("missing alipay_app_id".to_string()))?;
-                let mut refund_payload = OpenApiRefundPayload::new(
-                    alipay_app_id,
-                    charge_merchant_order_no,
-                    refund_merchant_order_no,
-                    refund_amount,
-                    description,
-                )?;
-                let private_key = config
-                    .alipay_private_key_rsa2
-                    .as_deref()
-                    .ok_or(AlipayError::InvalidConfig("missing alipay_private_key_rsa2".to_string()))?;
-                refund_payload.sign_rsa2(private_key)?;
-                let refund_response = refund_payload.send_request().await?;
-                let mut result = RefundResult {
-                    amount: refund_amount,
-                    description: description.to_string(),
-                    extra: refund_response.clone(),
-                    ..Default::default()
-                };
-                let code = refund_response["code"].as_str();
-                if code == Some("10000") {
-                    if refund_response["fund_change"].as_str() == Some("Y") {
-                        result.status = RefundStatus::Success;
-                    } else {
-                        result.status = RefundStatus::Fail(format!("fund_change != Y"));
-                    }
-                } else {
-                    result.status = RefundStatus::Fail(format!("code = {:?}", code));
-                    result.failure_msg = match refund_response["msg"].as_str() {
-                        Some(msg) => Some(msg.to_string()),
-                        None => None,
-                    };
-                }
-                result
+                refund_trade(
+                    &config.openapi_config()?,
+                    self.app_auth_token.as_deref(),
+                    request,
+                )
+                .await?
             }
         };
         Ok(result)
@@ -230,4 +238,103 @@ impl ChannelHandler for AlipayPcDirect {
     fn process_refund_notify(&self, _payload: &str) -> Result<RefundStatus, RefundError> {
         Err(RefundError::Unexpected("not implemented".to_string()))
     }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let result = match config.alipay_version {
+            AlipayApiType::MAPI => {
+                let mut query_payload =
+                    MapiTradeQueryPayload::new(&config.alipay_pid, merchant_order_no)?;
+                let private_key = config.mapi_private_key()?;
+                query_payload.sign_rsa(private_key)?;
+                let public_key = config.mapi_public_key()?;
+                let query_response = query_payload.send_request(public_key).await?;
+                mapi::trade_query_result(query_response)?
+            }
+            AlipayApiType::OPENAPI => {
+                let query_response = send_trade_request(
+                    &config.openapi_config()?,
+                    self.app_auth_token.as_deref(),
+                    "alipay.trade.query",
+                    merchant_order_no,
+                )
+                .await?;
+                openapi::trade_query_result(query_response)?
+            }
+        };
+        Ok(result)
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let config = &self.config;
+        match config.alipay_version {
+            AlipayApiType::MAPI => {
+                let mut close_payload =
+                    MapiTradeClosePayload::new(&config.alipay_pid, merchant_order_no)?;
+                let private_key = config.mapi_private_key()?;
+                close_payload.sign_rsa(private_key)?;
+                close_payload.send_request().await?
+            }
+            AlipayApiType::OPENAPI => {
+                let close_response = send_trade_request(
+                    &config.openapi_config()?,
+                    self.app_auth_token.as_deref(),
+                    "alipay.trade.close",
+                    merchant_order_no,
+                )
+                .await?;
+                openapi::trade_close_result(close_response)?
+            }
+        }
+        Ok(())
+    }
+
+    async fn reverse_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let config = &self.config;
+        match config.alipay_version {
+            AlipayApiType::MAPI => {
+                // mapi 没有撤销接口
+                return Err(AlipayError::MalformedRequest(
+                    "reverse is not supported by alipay mapi".to_string(),
+                )
+                .into());
+            }
+            AlipayApiType::OPENAPI => {
+                let cancel_response = send_trade_request(
+                    &config.openapi_config()?,
+                    self.app_auth_token.as_deref(),
+                    "alipay.trade.cancel",
+                    merchant_order_no,
+                )
+                .await?;
+                openapi::trade_cancel_result(cancel_response)?
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * 境外支付宝 (mapi create_forex_trade) 的通知里有外币币种和折算成人民币的金额, 记录到 charge extra 上
+     */
+    fn charge_notify_extra(&self, payload: &str) -> Option<serde_json::Value> {
+        match self.config.alipay_version {
+            AlipayApiType::MAPI => MapiNotifyPayload::new(payload).ok()?.forex_extra(),
+            AlipayApiType::OPENAPI => None,
+        }
+    }
 }
@@ -0,0 +1,153 @@
+use super::{
+    load_alipay_config,
+    openapi::{trade_cancel_result, trade_close_result, trade_query_result, OpenApiRequestPayload},
+    openapi_trade::{refund_trade, send_charge_request, send_trade_request, verify_trade_notify},
+    AlipayError, AlipayOpenApiConfig,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelQueryRequest, ChannelRefundRequest, ChargeError,
+    ChargeResult, ChargeStatus, PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+
+pub struct AlipayQr {
+    config: AlipayOpenApiConfig,
+    app_auth_token: Option<String>, // 服务商模式下商户授权的令牌
+}
+
+impl AlipayQr {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+    ) -> Result<Self, AlipayError> {
+        let (config, app_auth_token) = load_alipay_config::<AlipayOpenApiConfig>(
+            prisma_client,
+            app_id,
+            sub_app_id,
+            PaymentChannel::AlipayQr,
+        )
+        .await?;
+        config.check_public_key()?;
+        Ok(Self {
+            config,
+            app_auth_token,
+        })
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for AlipayQr {
+    /**
+     * alipay.trade.precreate 生成二维码, credential 里直接返回 qr_code 字符串
+     * https://opendocs.alipay.com/open/02ekfg
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            currency,
+            merchant_order_no,
+            time_expire,
+            subject,
+            body,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let openapi_request_payload = OpenApiRequestPayload::new(
+            charge_id,
+            "alipay.trade.precreate",
+            &config.alipay_app_id,
+            &config.alipay_pid,
+            self.app_auth_token.as_deref(),
+            "", // 当面付没有 return_url
+            merchant_order_no,
+            charge_amount,
+            currency,
+            time_expire,
+            subject,
+            body,
+        )?;
+        let precreate_response = send_charge_request(config, openapi_request_payload).await?;
+        if precreate_response["code"].as_str() != Some("10000") {
+            return Err(AlipayError::ApiError(format!(
+                "alipay.trade.precreate code != 10000: {:?} {:?}",
+                precreate_response["sub_code"].as_str(),
+                precreate_response["sub_msg"].as_str(),
+            ))
+            .into());
+        }
+        let qr_code = precreate_response["qr_code"].as_str().ok_or_else(|| {
+            AlipayError::ApiError("missing qr_code in alipay.trade.precreate response".into())
+        })?;
+        Ok(ChargeResult {
+            credential: serde_json::Value::String(qr_code.to_string()),
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        Ok(verify_trade_notify(&self.config, payload)?)
+    }
+
+    async fn create_refund(
+        &self,
+        request: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        Ok(refund_trade(&self.config, self.app_auth_token.as_deref(), request).await?)
+    }
+
+    fn process_refund_notify(&self, _payload: &str) -> Result<RefundStatus, RefundError> {
+        Err(RefundError::Unexpected("not implemented".to_string()))
+    }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let query_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.query",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_query_result(query_response)?)
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let close_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.close",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_close_result(close_response)?)
+    }
+
+    async fn reverse_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let cancel_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.cancel",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_cancel_result(cancel_response)?)
+    }
+}
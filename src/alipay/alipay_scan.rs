@@ -0,0 +1,171 @@
+use super::{
+    load_alipay_config,
+    openapi::{trade_cancel_result, trade_close_result, trade_query_result, OpenApiRequestPayload},
+    openapi_trade::{refund_trade, send_charge_request, send_trade_request, verify_trade_notify},
+    AlipayError, AlipayOpenApiConfig,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelQueryRequest, ChannelRefundRequest, ChargeError,
+    ChargeResult, ChargeStatus, PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+use serde_json::json;
+
+pub struct AlipayScan {
+    config: AlipayOpenApiConfig,
+    app_auth_token: Option<String>, // 服务商模式下商户授权的令牌
+}
+
+impl AlipayScan {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+    ) -> Result<Self, AlipayError> {
+        let (config, app_auth_token) = load_alipay_config::<AlipayOpenApiConfig>(
+            prisma_client,
+            app_id,
+            sub_app_id,
+            PaymentChannel::AlipayScan,
+        )
+        .await?;
+        config.check_public_key()?;
+        Ok(Self {
+            config,
+            app_auth_token,
+        })
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for AlipayScan {
+    /**
+     * alipay.trade.pay 付款码支付, 商户扫用户的付款码, 同步返回支付结果
+     * 返回 10003 表示等待用户付款, 返回 Pending, charge 保存以后再轮询 query_charge, 超时以后 reverse_charge 撤销
+     * https://opendocs.alipay.com/open/02ekfp
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            currency,
+            merchant_order_no,
+            time_expire,
+            subject,
+            body,
+            extra,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let auth_code = match extra.auth_code.as_ref() {
+            Some(auth_code) => auth_code.to_string(),
+            None => {
+                return Err(ChargeError::MalformedRequest(
+                    "missing auth_code in charge extra".to_string(),
+                ))
+            }
+        };
+        let mut openapi_request_payload = OpenApiRequestPayload::new(
+            charge_id,
+            "alipay.trade.pay",
+            &config.alipay_app_id,
+            &config.alipay_pid,
+            self.app_auth_token.as_deref(),
+            "", // 付款码支付没有 return_url
+            merchant_order_no,
+            charge_amount,
+            currency,
+            time_expire,
+            subject,
+            body,
+        )?;
+        openapi_request_payload.extend_biz_content("scene", json!("bar_code"));
+        openapi_request_payload.extend_biz_content("auth_code", json!(auth_code));
+        let pay_response = send_charge_request(config, openapi_request_payload).await?;
+        match pay_response["code"].as_str() {
+            Some("10000") => Ok(ChargeResult {
+                status: ChargeStatus::Success,
+                credential: pay_response,
+                ..Default::default()
+            }),
+            // 10003 等待用户付款, 20000 服务不可用, 都需要查询订单确认结果
+            Some("10003") | Some("20000") => Ok(ChargeResult {
+                status: ChargeStatus::Pending,
+                credential: pay_response,
+                ..Default::default()
+            }),
+            _ => Ok(ChargeResult {
+                status: ChargeStatus::Fail,
+                failure_code: pay_response["sub_code"].as_str().map(|s| s.to_string()),
+                failure_msg: pay_response["sub_msg"].as_str().map(|s| s.to_string()),
+                credential: pay_response,
+                ..Default::default()
+            }),
+        }
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        Ok(verify_trade_notify(&self.config, payload)?)
+    }
+
+    async fn create_refund(
+        &self,
+        request: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        Ok(refund_trade(&self.config, self.app_auth_token.as_deref(), request).await?)
+    }
+
+    fn process_refund_notify(&self, _payload: &str) -> Result<RefundStatus, RefundError> {
+        Err(RefundError::Unexpected("not implemented".to_string()))
+    }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let query_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.query",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_query_result(query_response)?)
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let close_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.close",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_close_result(close_response)?)
+    }
+
+    async fn reverse_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no, ..
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let cancel_response = send_trade_request(
+            &self.config,
+            self.app_auth_token.as_deref(),
+            "alipay.trade.cancel",
+            merchant_order_no,
+        )
+        .await?;
+        Ok(trade_cancel_result(cancel_response)?)
+    }
+}
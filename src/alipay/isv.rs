@@ -0,0 +1,341 @@
+use super::{
+    openapi::{OpenApiAuthTokenPayload, OpenApiPayload},
+    AlipayError, AlipayIsvAuthConfig,
+};
+use crate::core::PaymentChannel;
+use crate::prisma::channel_params;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/**
+ * 刷新授权令牌需要服务商应用的 app_id 和私钥, 各个渠道的私钥字段名不一样
+ */
+#[derive(Debug, Deserialize)]
+struct AlipayIsvAppConfig {
+    alipay_app_id: Option<String>,
+    alipay_private_key_rsa2: Option<String>,
+    alipay_mer_wap_private_key_rsa2: Option<String>, // alipay_wap 渠道
+    alipay_public_key_rsa2: Option<String>,
+    alipay_wap_public_key_rsa2: Option<String>, // alipay_wap 渠道
+    alipay_app_cert: Option<String>,            // 公钥证书模式
+    alipay_public_cert: Option<String>,
+    alipay_root_cert: Option<String>,
+}
+
+/**
+ * 加载支付宝渠道参数, 返回 (签名用的渠道参数, 服务商模式下的 app_auth_token)
+ * sub_app 上的渠道参数里有 alipay_app_auth_token 的就是服务商模式,
+ * 这时候签名用的渠道参数从 parent App 上加载, 不是服务商模式就直接用 sub_app 上的
+ */
+pub async fn load_alipay_config<T: DeserializeOwned>(
+    prisma_client: &crate::prisma::PrismaClient,
+    app_id: Option<&str>,
+    sub_app_id: Option<&str>,
+    channel: PaymentChannel,
+) -> Result<(T, Option<String>), AlipayError> {
+    let channel = channel.to_string();
+    let channel_params =
+        crate::utils::load_channel_params_from_db(prisma_client, app_id, sub_app_id, &channel)
+            .await
+            .map_err(|e| AlipayError::InvalidConfig(format!("{:?}", e)))?;
+    if channel_params.params.get("alipay_app_auth_token").is_none() {
+        let config: T = serde_json::from_value(channel_params.params).map_err(|e| {
+            AlipayError::InvalidConfig(format!("error deserializing {} config: {:?}", channel, e))
+        })?;
+        return Ok((config, None));
+    }
+
+    let auth_config: AlipayIsvAuthConfig = serde_json::from_value(channel_params.params.clone())
+        .map_err(|e| {
+            AlipayError::InvalidConfig(format!(
+                "error deserializing {} isv config: {:?}",
+                channel, e
+            ))
+        })?;
+    let app_id = app_id.ok_or_else(|| {
+        AlipayError::InvalidConfig(format!("app_id is required to load {} isv config", channel))
+    })?;
+    let app_channel_params =
+        crate::utils::load_app_channel_params_from_db(prisma_client, app_id, &channel)
+            .await
+            .map_err(|e| AlipayError::InvalidConfig(format!("{:?}", e)))?;
+
+    let now = chrono::Utc::now().timestamp() as i32;
+    // 刷新失败的话先继续用旧的令牌
+    let app_auth_token = if needs_refresh(&auth_config, now) {
+        refresh_app_auth_token_single_flight(
+            prisma_client,
+            channel_params.id,
+            &app_channel_params.params,
+        )
+        .await?
+    } else {
+        auth_config.alipay_app_auth_token
+    };
+
+    let config: T = serde_json::from_value(app_channel_params.params).map_err(|e| {
+        AlipayError::InvalidConfig(format!("error deserializing {} config: {:?}", channel, e))
+    })?;
+    Ok((config, Some(app_auth_token)))
+}
+
+/**
+ * 一天内就要过期的时候刷新, 没有过期时间的不刷新
+ */
+fn needs_refresh(auth_config: &AlipayIsvAuthConfig, now: i32) -> bool {
+    matches!(
+        auth_config.alipay_app_auth_token_expires_at,
+        Some(expires_at) if expires_at - now < 24 * 3600
+    )
+}
+
+/**
+ * 同一个 sub_app 渠道参数上的授权令牌同时只刷新一次, key 是 channel_params.id
+ * 支付宝换新令牌以后旧的 app_refresh_token 就失效了, 并发刷新的话后面的请求会失败, 还可能把新令牌覆盖成旧的
+ */
+fn refresh_lock(channel_params_id: i32) -> Arc<tokio::sync::Mutex<()>> {
+    static REFRESH_LOCKS: OnceLock<Mutex<HashMap<i32, Arc<tokio::sync::Mutex<()>>>>> =
+        OnceLock::new();
+    let mut locks = REFRESH_LOCKS.get_or_init(Default::default).lock().unwrap();
+    locks.entry(channel_params_id).or_default().clone()
+}
+
+/**
+ * 拿到 sub_app 的刷新锁以后重新加载渠道参数, 别的请求已经刷新过的话直接用新令牌
+ * 还是快要过期的才请求支付宝刷新, 刷新失败的话先继续用旧的令牌
+ */
+async fn refresh_app_auth_token_single_flight(
+    prisma_client: &crate::prisma::PrismaClient,
+    channel_params_id: i32,
+    app_params: &serde_json::Value,
+) -> Result<String, AlipayError> {
+    let lock = refresh_lock(channel_params_id);
+    let _guard = lock.lock().await;
+
+    let channel_params = prisma_client
+        .channel_params()
+        .find_unique(channel_params::id::equals(channel_params_id))
+        .exec()
+        .await
+        .map_err(|e| AlipayError::Unexpected(format!("sql error: {:?}", e)))?
+        .ok_or_else(|| {
+            AlipayError::InvalidConfig(format!("channel params {} not found", channel_params_id))
+        })?;
+    let auth_config: AlipayIsvAuthConfig = serde_json::from_value(channel_params.params.clone())
+        .map_err(|e| {
+            AlipayError::InvalidConfig(format!("error deserializing isv config: {:?}", e))
+        })?;
+    let now = chrono::Utc::now().timestamp() as i32;
+    if !needs_refresh(&auth_config, now) {
+        return Ok(auth_config.alipay_app_auth_token);
+    }
+    let token = auth_config.alipay_app_auth_token.clone();
+    Ok(
+        refresh_app_auth_token(prisma_client, &channel_params, auth_config, app_params)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!("error refreshing alipay app_auth_token: {:?}", e);
+                token
+            }),
+    )
+}
+
+/**
+ * 用 app_refresh_token 换新的 app_auth_token, 并且存回 sub_app 的渠道参数
+ */
+async fn refresh_app_auth_token(
+    prisma_client: &crate::prisma::PrismaClient,
+    channel_params: &channel_params::Data,
+    mut auth_config: AlipayIsvAuthConfig,
+    app_params: &serde_json::Value,
+) -> Result<String, AlipayError> {
+    let refresh_token = auth_config
+        .alipay_app_refresh_token
+        .clone()
+        .ok_or_else(|| AlipayError::InvalidConfig("missing alipay_app_refresh_token".into()))?;
+    let app_config: AlipayIsvAppConfig =
+        serde_json::from_value(app_params.clone()).map_err(|e| {
+            AlipayError::InvalidConfig(format!("error deserializing isv app config: {:?}", e))
+        })?;
+    let alipay_app_id = app_config
+        .alipay_app_id
+        .ok_or_else(|| AlipayError::InvalidConfig("missing alipay_app_id".into()))?;
+    let private_key = app_config
+        .alipay_private_key_rsa2
+        .or(app_config.alipay_mer_wap_private_key_rsa2)
+        .ok_or_else(|| AlipayError::InvalidConfig("missing alipay_private_key_rsa2".into()))?;
+    // 验证应答签名, 公钥证书模式用支付宝公钥证书
+    let public_key = app_config
+        .alipay_public_cert
+        .or(app_config.alipay_public_key_rsa2)
+        .or(app_config.alipay_wap_public_key_rsa2)
+        .ok_or_else(|| AlipayError::InvalidConfig("missing alipay_public_key_rsa2".into()))?;
+
+    let mut auth_token_payload = OpenApiAuthTokenPayload::new(&alipay_app_id, &refresh_token)?;
+    auth_token_payload.set_cert_sn(
+        app_config.alipay_app_cert.as_deref(),
+        app_config.alipay_root_cert.as_deref(),
+    )?;
+    auth_token_payload.sign_rsa2(&private_key)?;
+    let auth_token_response = auth_token_payload.send_request(&public_key).await?;
+    if auth_token_response["code"].as_str() != Some("10000") {
+        return Err(AlipayError::ApiError(format!(
+            "error refreshing app_auth_token: {:?}",
+            auth_token_response["sub_msg"]
+        )));
+    }
+    let now = chrono::Utc::now().timestamp() as i32;
+    apply_auth_token_response(&mut auth_config, &auth_token_response, now)?;
+    let params = merge_auth_params(&channel_params.params, &auth_config);
+    prisma_client
+        .channel_params()
+        .update(
+            channel_params::id::equals(channel_params.id),
+            vec![channel_params::params::set(params)],
+        )
+        .exec()
+        .await
+        .map_err(|e| AlipayError::Unexpected(format!("sql error: {:?}", e)))?;
+    Ok(auth_config.alipay_app_auth_token)
+}
+
+/**
+ * 把 alipay.open.auth.token.app 应答里的新令牌写到 auth_config 上
+ * 应答里没有新的 app_refresh_token 的话继续用原来的
+ */
+fn apply_auth_token_response(
+    auth_config: &mut AlipayIsvAuthConfig,
+    auth_token_response: &serde_json::Value,
+    now: i32,
+) -> Result<(), AlipayError> {
+    let app_auth_token = auth_token_response["app_auth_token"]
+        .as_str()
+        .ok_or_else(|| AlipayError::ApiError("missing app_auth_token in response".into()))?;
+    // expires_in 文档上是数字, 有的时候返回的是字符串
+    let expires_in = match &auth_token_response["expires_in"] {
+        serde_json::Value::Number(n) => n.as_i64(),
+        serde_json::Value::String(s) => s.parse::<i64>().ok(),
+        _ => None,
+    };
+
+    auth_config.alipay_app_auth_token = app_auth_token.to_string();
+    if let Some(app_refresh_token) = auth_token_response["app_refresh_token"].as_str() {
+        auth_config.alipay_app_refresh_token = Some(app_refresh_token.to_string());
+    }
+    auth_config.alipay_app_auth_token_expires_at =
+        expires_in.map(|expires_in| now + expires_in as i32);
+    if let Some(user_id) = auth_token_response["user_id"].as_str() {
+        auth_config.alipay_auth_user_id = Some(user_id.to_string());
+    }
+    Ok(())
+}
+
+/**
+ * 保留 sub_app 渠道参数上的其他字段
+ */
+fn merge_auth_params(
+    params: &serde_json::Value,
+    auth_config: &AlipayIsvAuthConfig,
+) -> serde_json::Value {
+    let mut params = params.clone();
+    if let (Some(params), Ok(serde_json::Value::Object(auth_params))) =
+        (params.as_object_mut(), serde_json::to_value(auth_config))
+    {
+        params.extend(auth_params);
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_config(expires_at: Option<i32>) -> AlipayIsvAuthConfig {
+        AlipayIsvAuthConfig {
+            alipay_app_auth_token: "old_token".to_string(),
+            alipay_app_refresh_token: Some("old_refresh_token".to_string()),
+            alipay_app_auth_token_expires_at: expires_at,
+            alipay_auth_user_id: None,
+        }
+    }
+
+    #[test]
+    fn test_needs_refresh() {
+        let now = 1_700_000_000;
+        assert!(!needs_refresh(&auth_config(None), now));
+        assert!(!needs_refresh(&auth_config(Some(now + 24 * 3600)), now));
+        assert!(needs_refresh(&auth_config(Some(now + 24 * 3600 - 1)), now));
+        // 已经过期的也要刷新
+        assert!(needs_refresh(&auth_config(Some(now - 1)), now));
+    }
+
+    #[test]
+    fn test_apply_auth_token_response() {
+        let now = 1_700_000_000;
+        let mut config = auth_config(Some(now));
+        let response = serde_json::json!({
+            "code": "10000",
+            "app_auth_token": "new_token",
+            "app_refresh_token": "new_refresh_token",
+            "expires_in": "31536000",
+            "user_id": "2088000000000000",
+        });
+        apply_auth_token_response(&mut config, &response, now).unwrap();
+        assert_eq!(config.alipay_app_auth_token, "new_token");
+        assert_eq!(
+            config.alipay_app_refresh_token.as_deref(),
+            Some("new_refresh_token")
+        );
+        assert_eq!(
+            config.alipay_app_auth_token_expires_at,
+            Some(now + 31536000)
+        );
+        assert_eq!(
+            config.alipay_auth_user_id.as_deref(),
+            Some("2088000000000000")
+        );
+
+        // expires_in 是数字, 没有新的 app_refresh_token 的继续用原来的
+        let mut config = auth_config(Some(now));
+        let response = serde_json::json!({ "app_auth_token": "new_token", "expires_in": 3600 });
+        apply_auth_token_response(&mut config, &response, now).unwrap();
+        assert_eq!(
+            config.alipay_app_refresh_token.as_deref(),
+            Some("old_refresh_token")
+        );
+        assert_eq!(config.alipay_app_auth_token_expires_at, Some(now + 3600));
+
+        let mut config = auth_config(Some(now));
+        let response = serde_json::json!({ "expires_in": 3600 });
+        assert!(matches!(
+            apply_auth_token_response(&mut config, &response, now),
+            Err(AlipayError::ApiError(_))
+        ));
+        assert_eq!(config.alipay_app_auth_token, "old_token");
+    }
+
+    #[test]
+    fn test_merge_auth_params() {
+        let params = serde_json::json!({
+            "alipay_app_auth_token": "old_token",
+            "alipay_app_refresh_token": "old_refresh_token",
+            "note": "keep me",
+        });
+        let mut config = auth_config(Some(1_700_000_000));
+        config.alipay_app_auth_token = "new_token".to_string();
+        let merged = merge_auth_params(&params, &config);
+        assert_eq!(merged["alipay_app_auth_token"], "new_token");
+        assert_eq!(merged["alipay_app_refresh_token"], "old_refresh_token");
+        assert_eq!(merged["alipay_app_auth_token_expires_at"], 1_700_000_000);
+        assert_eq!(merged["note"], "keep me");
+    }
+
+    #[test]
+    fn test_refresh_lock() {
+        assert!(Arc::ptr_eq(&refresh_lock(1), &refresh_lock(1)));
+        assert!(!Arc::ptr_eq(&refresh_lock(1), &refresh_lock(2)));
+    }
+}
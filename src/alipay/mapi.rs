@@ -1,13 +1,16 @@
-use super::AlipayError;
+use super::{
+    keys::{load_private_key, load_public_key},
+    AlipayError,
+};
+use crate::core::{ChargeResult, ChargeStatus};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::HashMap;
 
 mod mapi_rsa {
     use super::*;
     use openssl::{
         hash::MessageDigest,
-        pkey::PKey,
-        rsa::Rsa,
         sign::{Signer, Verifier},
     };
 
@@ -36,8 +39,7 @@ mod mapi_rsa {
         });
         query_list.sort();
         let sign_sorted_source = query_list.join("&");
-        let keypair = Rsa::private_key_from_pem(private_key.as_bytes())?;
-        let keypair = PKey::from_rsa(keypair)?;
+        let keypair = load_private_key(private_key)?;
         let mut signer = Signer::new(MessageDigest::sha1(), &keypair)?;
         signer.update(sign_sorted_source.as_bytes())?;
         let signature_bytes = signer.sign_to_vec()?;
@@ -59,8 +61,7 @@ mod mapi_rsa {
         });
         query_list.sort();
         let sorted_payload = query_list.join("&");
-        let keypair = Rsa::public_key_from_pem(public_key.as_bytes())?;
-        let keypair = PKey::from_rsa(keypair)?;
+        let keypair = load_public_key(public_key)?;
         let mut verifier = Verifier::new(MessageDigest::sha1(), &keypair)?;
         verifier.update(sorted_payload.as_bytes())?;
         let signature_bytes = data_encoding::BASE64.decode(signature.as_bytes())?;
@@ -69,6 +70,63 @@ mod mapi_rsa {
     }
 }
 
+/**
+ * 境外支付宝 (create_forex_trade) 的金额是外币金额, charge 上的 amount 是货币的最小单位
+ * 按 utils::currency_decimals 的小数位换算
+ */
+pub fn format_amount(amount: i32, currency: &str) -> String {
+    let decimals = crate::utils::currency_decimals(currency);
+    format!(
+        "{:.*}",
+        decimals as usize,
+        amount as f64 / 10f64.powi(decimals)
+    )
+}
+
+pub fn parse_amount(fee: &str, currency: &str) -> Result<i32, AlipayError> {
+    let fee = fee
+        .parse::<f64>()
+        .map_err(|_| AlipayError::ApiError(format!("invalid fee {}", fee)))?;
+    Ok((fee * 10f64.powi(crate::utils::currency_decimals(currency))).round() as i32)
+}
+
+/**
+ * 人民币之外的币种都走境外支付宝的 forex 接口
+ */
+pub fn is_forex_currency(currency: &str) -> bool {
+    !currency.eq_ignore_ascii_case("cny")
+}
+
+/**
+ * create_forex_trade 只支持固定的几个超时时间, 取不超过 time_expire 的最大值
+ */
+fn forex_timeout_rule(time_expire: i32) -> Result<String, AlipayError> {
+    const TIMEOUT_RULES: [(i32, &str); 10] = [
+        (12 * 60, "12h"),
+        (10 * 60, "10h"),
+        (5 * 60, "5h"),
+        (3 * 60, "3h"),
+        (2 * 60, "2h"),
+        (60, "1h"),
+        (30, "30m"),
+        (15, "15m"),
+        (10, "10m"),
+        (5, "5m"),
+    ];
+    let now = chrono::Utc::now().timestamp() as i32;
+    if time_expire <= now {
+        return Err(AlipayError::MalformedRequest(
+            "expire_in_seconds < now".into(),
+        ));
+    }
+    let minutes = (time_expire - now) / 60;
+    let rule = TIMEOUT_RULES
+        .iter()
+        .find(|(rule_minutes, _)| minutes >= *rule_minutes)
+        .map_or("5m", |(_, rule)| rule);
+    Ok(rule.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MapiRequestPayload {
     pub channel_url: String,
@@ -144,10 +202,81 @@ impl MapiRequestPayload {
     }
 }
 
+/**
+ * 境外支付宝, https://global.alipay.com/docs/ac/website_hk/ux
+ * service 是 create_forex_trade (PC) 或者 create_forex_trade_wap (WAP)
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MapiForexRequestPayload {
+    pub channel_url: String,
+    pub service: String,
+    pub _input_charset: String,
+    pub return_url: String,
+    pub notify_url: String,
+    pub partner: String,
+    pub out_trade_no: String,
+    pub subject: String,
+    pub body: String,
+    pub total_fee: String, // 外币金额
+    pub currency: String,  // 外币币种, USD | HKD | JPY ...
+    pub timeout_rule: String,
+    pub product_code: String,
+    pub sign: String,
+    pub sign_type: String,
+}
+
+impl MapiForexRequestPayload {
+    pub fn new(
+        charge_id: &str,         //
+        service: &str,           // create_forex_trade | create_forex_trade_wap
+        product_code: &str,      // NEW_OVERSEAS_SELLER | NEW_WAP_OVERSEAS_SELLER
+        alipay_pid: &str,        // 合作者身份 ID, 商家唯一 ID
+        return_url: &str,        // 支付成功跳转
+        merchant_order_no: &str, // 商户订单号
+        charge_amount: i32,      // 支付金额, 货币的最小单位
+        currency: &str,          // 币种
+        time_expire: i32,        // 过期时间 timestamp 精确到秒
+        subject: &str,           // 标题
+        body: &str,              // 详情
+    ) -> Result<Self, AlipayError> {
+        let payload = Self {
+            channel_url: String::from("https://mapi.alipay.com/gateway.do"),
+            service: String::from(service),
+            _input_charset: String::from("utf-8"),
+            return_url: return_url.to_string(),
+            notify_url: crate::utils::charge_notify_url(charge_id),
+            partner: alipay_pid.to_string(),
+            out_trade_no: merchant_order_no.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            total_fee: format_amount(charge_amount, currency),
+            currency: currency.to_uppercase(),
+            timeout_rule: forex_timeout_rule(time_expire)?,
+            product_code: String::from(product_code),
+            sign: String::from(""),
+            sign_type: String::from("RSA"),
+        };
+        Ok(payload)
+    }
+
+    pub fn sign_rsa(&mut self, private_key: &str) -> Result<String, AlipayError> {
+        let v = serde_json::to_value(&self).unwrap();
+        let mut m: HashMap<String, String> = serde_json::from_value(v).unwrap();
+        m.remove("sign");
+        m.remove("sign_type");
+        m.remove("channel_url");
+        let signature = mapi_rsa::sign(&m, private_key)?;
+        self.sign = signature.clone();
+        Ok(signature)
+    }
+}
+
 pub struct MapiNotifyPayload {
     pub trade_status: String,
     pub merchant_order_no: String,
     pub amount: i32,
+    pub currency: Option<String>, // 境外支付宝才有, 外币币种
+    pub rmb_fee: Option<String>,  // 境外支付宝才有, 折算成人民币的金额
     signature: String,
     m: HashMap<String, String>,
 }
@@ -188,20 +317,32 @@ impl MapiNotifyPayload {
             return Err(AlipayError::ApiError("sign_type not RSA".into()));
         }
 
-        let amount = (total_fee
-            .parse::<f64>()
-            .map_err(|_| AlipayError::ApiError("invalid total_fee".into()))?
-            * 100.0) as i32;
+        // 境外支付宝的 total_fee 是外币金额, 按币种的小数位换算
+        let currency = m.get("currency").cloned();
+        let amount = parse_amount(total_fee, currency.as_deref().unwrap_or("cny"))?;
 
         Ok(Self {
             trade_status: trade_status.to_owned(),
             merchant_order_no: out_trade_no.to_owned(),
             amount,
+            currency,
+            rmb_fee: m.get("rmb_fee").cloned(),
             signature: signature.to_owned(),
             m,
         })
     }
 
+    /**
+     * 境外支付宝的通知里有外币币种和折算成人民币的金额, 记录到 charge extra 上
+     */
+    pub fn forex_extra(&self) -> Option<serde_json::Value> {
+        let currency = self.currency.as_ref()?;
+        Some(json!({
+            "currency": currency,
+            "rmb_fee": self.rmb_fee,
+        }))
+    }
+
     pub fn verify_rsa_sign(&self, public_key: &str) -> Result<(), AlipayError> {
         let mut m = self.m.clone();
         // k != "sign" && k != "sign_type";
@@ -303,3 +444,349 @@ impl MapiRefundPayload {
         Ok(url)
     }
 }
+
+/**
+ * 境外支付宝退款, 同步返回结果, 没有异步通知
+ * https://global.alipay.com/docs/ac/website_hk/forex_refund
+ */
+#[derive(Debug, Serialize)]
+pub struct MapiForexRefundPayload {
+    pub service: String,
+    pub partner: String,
+    pub _input_charset: String,
+    pub sign_type: String,
+    pub sign: String,
+    pub out_return_no: String,
+    pub out_trade_no: String,
+    pub return_amount: String, // 外币金额
+    pub currency: String,
+    pub gmt_return: String, // 北京时间 YYYYMMDDHHMMSS
+    pub reason: String,
+    pub product_code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MapiXmlResponse {
+    is_success: String,
+    error: Option<String>,
+}
+
+impl MapiForexRefundPayload {
+    pub fn new(
+        alipay_pid: &str,               // 合作者身份 ID, 商家唯一 ID
+        product_code: &str,             // NEW_OVERSEAS_SELLER | NEW_WAP_OVERSEAS_SELLER
+        charge_merchant_order_no: &str, // 商户订单号
+        refund_merchant_order_no: &str, // 商户退款单号
+        refund_amount: i32,             // 退款金额, 货币的最小单位
+        currency: &str,                 // 币种, 和支付的时候一致
+        description: &str,              // 退款说明
+    ) -> Result<Self, AlipayError> {
+        let beijing = chrono::FixedOffset::east_opt(8 * 3600).unwrap();
+        let gmt_return = chrono::Utc::now()
+            .with_timezone(&beijing)
+            .format("%Y%m%d%H%M%S")
+            .to_string();
+        Ok(Self {
+            service: String::from("forex_refund"),
+            partner: alipay_pid.to_string(),
+            _input_charset: String::from("utf-8"),
+            sign_type: String::from("RSA"),
+            sign: String::from(""),
+            out_return_no: refund_merchant_order_no.to_string(),
+            out_trade_no: charge_merchant_order_no.to_string(),
+            return_amount: format_amount(refund_amount, currency),
+            currency: currency.to_uppercase(),
+            gmt_return,
+            reason: description.to_string(),
+            product_code: product_code.to_string(),
+        })
+    }
+
+    pub fn sign_rsa(&mut self, private_key: &str) -> Result<String, AlipayError> {
+        // 这里 deserialize 不会出问题
+        let v = serde_json::to_value(&self).unwrap();
+        let mut m: HashMap<String, String> = serde_json::from_value(v).unwrap();
+        m.remove("sign");
+        m.remove("sign_type");
+        let signature = mapi_rsa::sign(&m, private_key)?;
+        self.sign = signature.clone();
+        Ok(signature)
+    }
+
+    /**
+     * 返回 Ok(None) 表示退款成功, Ok(Some(error)) 是支付宝返回的错误码
+     */
+    pub async fn send_request(&self) -> Result<Option<String>, AlipayError> {
+        let res = reqwest::Client::new()
+            .post("https://mapi.alipay.com/gateway.do")
+            .form(&self)
+            .send()
+            .await
+            .map_err(|e| AlipayError::ApiError(format!("error request forex_refund: {:?}", e)))?;
+        let res_text = res.text().await.map_err(|e| {
+            AlipayError::ApiError(format!("error read forex_refund response: {:?}", e))
+        })?;
+        tracing::debug!("alipay forex_refund response: {:?}", res_text);
+        let res_obj: MapiXmlResponse = quick_xml::de::from_str(&res_text).map_err(|e| {
+            AlipayError::ApiError(format!("error deserialize forex_refund response: {:?}", e))
+        })?;
+        if res_obj.is_success == "T" {
+            Ok(None)
+        } else {
+            Ok(Some(res_obj.error.unwrap_or_default()))
+        }
+    }
+}
+
+/**
+ * 单笔交易查询, 同步返回 xml
+ * 国内和境外 (create_forex_trade) 的交易都用这个接口
+ */
+#[derive(Debug, Serialize)]
+pub struct MapiTradeQueryPayload {
+    pub service: String,
+    pub partner: String,
+    pub _input_charset: String,
+    pub sign_type: String,
+    pub sign: String,
+    pub out_trade_no: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MapiTradeQueryXmlResponseBody {
+    trade: HashMap<String, String>, // trade_no | trade_status | total_fee | ...
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MapiTradeQueryXmlResponse {
+    is_success: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<MapiTradeQueryXmlResponseBody>,
+    #[serde(skip_serializing)]
+    sign: Option<String>, // 对 trade 节点下的参数签名
+}
+
+impl MapiTradeQueryPayload {
+    pub fn new(
+        alipay_pid: &str,        // 合作者身份 ID, 商家唯一 ID
+        merchant_order_no: &str, // 商户订单号
+    ) -> Result<Self, AlipayError> {
+        Ok(Self {
+            service: String::from("single_trade_query"),
+            partner: alipay_pid.to_string(),
+            _input_charset: String::from("utf-8"),
+            sign_type: String::from("RSA"),
+            sign: String::from(""),
+            out_trade_no: merchant_order_no.to_string(),
+        })
+    }
+
+    pub fn sign_rsa(&mut self, private_key: &str) -> Result<String, AlipayError> {
+        // 这里 deserialize 不会出问题
+        let v = serde_json::to_value(&self).unwrap();
+        let mut m: HashMap<String, String> = serde_json::from_value(v).unwrap();
+        m.remove("sign");
+        m.remove("sign_type");
+        let signature = mapi_rsa::sign(&m, private_key)?;
+        self.sign = signature.clone();
+        Ok(signature)
+    }
+
+    /**
+     * 返回 xml 转成的 json, 比如 { "is_success": "T", "response": { "trade": { "trade_status": "TRADE_SUCCESS", ... } } }
+     * 查询成功的应答用支付宝公钥验证 trade 节点的签名, 没有签名或者验签失败的返回错误, 防止伪造的应答把 charge 标记成已支付
+     * 查询失败 (is_success != T) 的应答没有签名, 由 trade_query_result 按错误码处理
+     */
+    pub async fn send_request(
+        &self,
+        alipay_public_key: &str,
+    ) -> Result<serde_json::Value, AlipayError> {
+        let res = reqwest::Client::new()
+            .post("https://mapi.alipay.com/gateway.do")
+            .form(&self)
+            .send()
+            .await
+            .map_err(|e| {
+                AlipayError::ApiError(format!("error request single_trade_query: {:?}", e))
+            })?;
+        let res_text = res.text().await.map_err(|e| {
+            AlipayError::ApiError(format!("error read single_trade_query response: {:?}", e))
+        })?;
+        tracing::debug!("alipay single_trade_query response: {:?}", res_text);
+        let res_obj: MapiTradeQueryXmlResponse =
+            quick_xml::de::from_str(&res_text).map_err(|e| {
+                AlipayError::ApiError(format!(
+                    "error deserialize single_trade_query response: {:?}",
+                    e
+                ))
+            })?;
+        if let Some(body) = res_obj.response.as_ref() {
+            let signature = res_obj.sign.as_deref().ok_or_else(|| {
+                AlipayError::ApiError("missing sign in single_trade_query response".into())
+            })?;
+            if !mapi_rsa::verify(&body.trade, signature, alipay_public_key)? {
+                return Err(AlipayError::ApiError(
+                    "wrong rsa signature in single_trade_query response".into(),
+                ));
+            }
+        }
+        serde_json::to_value(res_obj).map_err(|e| {
+            AlipayError::Unexpected(format!(
+                "error serializing single_trade_query response: {:?}",
+                e
+            ))
+        })
+    }
+}
+
+/**
+ * single_trade_query 的结果转成 ChargeResult, trade_status 和异步通知里的一样
+ * 交易不存在 (用户还没有打开收银台) 是 Pending, 其他错误码返回错误
+ */
+pub fn trade_query_result(query_response: serde_json::Value) -> Result<ChargeResult, AlipayError> {
+    if query_response["is_success"].as_str() != Some("T") {
+        let error = query_response["error"].as_str().unwrap_or_default();
+        if error == "TRADE_NOT_EXIST" {
+            return Ok(ChargeResult {
+                credential: query_response,
+                ..Default::default()
+            });
+        }
+        return Err(AlipayError::ApiError(format!(
+            "single_trade_query is_success != T: {}",
+            error
+        )));
+    }
+    let status = match query_response["response"]["trade"]["trade_status"].as_str() {
+        Some("TRADE_SUCCESS") | Some("TRADE_FINISHED") => ChargeStatus::Success,
+        Some("TRADE_CLOSED") => {
+            return Ok(ChargeResult {
+                status: ChargeStatus::Fail,
+                credential: query_response,
+                failure_code: Some("TRADE_CLOSED".to_string()),
+                failure_msg: Some("交易已关闭".to_string()),
+                ..Default::default()
+            });
+        }
+        _ => ChargeStatus::Pending,
+    };
+    Ok(ChargeResult {
+        status,
+        credential: query_response,
+        ..Default::default()
+    })
+}
+
+/**
+ * 关闭交易, 同步返回 xml, 只有 is_success 和 error
+ * 注意参数是 out_order_no, 不是 out_trade_no
+ */
+#[derive(Debug, Serialize)]
+pub struct MapiTradeClosePayload {
+    pub service: String,
+    pub partner: String,
+    pub _input_charset: String,
+    pub sign_type: String,
+    pub sign: String,
+    pub out_order_no: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MapiTradeCloseXmlResponse {
+    is_success: String,
+    error: Option<String>,
+}
+
+impl MapiTradeClosePayload {
+    pub fn new(
+        alipay_pid: &str,        // 合作者身份 ID, 商家唯一 ID
+        merchant_order_no: &str, // 商户订单号
+    ) -> Result<Self, AlipayError> {
+        Ok(Self {
+            service: String::from("close_trade"),
+            partner: alipay_pid.to_string(),
+            _input_charset: String::from("utf-8"),
+            sign_type: String::from("RSA"),
+            sign: String::from(""),
+            out_order_no: merchant_order_no.to_string(),
+        })
+    }
+
+    pub fn sign_rsa(&mut self, private_key: &str) -> Result<String, AlipayError> {
+        // 这里 deserialize 不会出问题
+        let v = serde_json::to_value(&self).unwrap();
+        let mut m: HashMap<String, String> = serde_json::from_value(v).unwrap();
+        m.remove("sign");
+        m.remove("sign_type");
+        let signature = mapi_rsa::sign(&m, private_key)?;
+        self.sign = signature.clone();
+        Ok(signature)
+    }
+
+    /**
+     * 交易不存在 (用户还没有打开收银台) 也当作关闭成功, 已经支付的交易返回 TRADE_STATUS_NOT_AVAILD 错误
+     */
+    pub async fn send_request(&self) -> Result<(), AlipayError> {
+        let res = reqwest::Client::new()
+            .post("https://mapi.alipay.com/gateway.do")
+            .form(&self)
+            .send()
+            .await
+            .map_err(|e| AlipayError::ApiError(format!("error request close_trade: {:?}", e)))?;
+        let res_text = res.text().await.map_err(|e| {
+            AlipayError::ApiError(format!("error read close_trade response: {:?}", e))
+        })?;
+        tracing::debug!("alipay close_trade response: {:?}", res_text);
+        let res_obj: MapiTradeCloseXmlResponse =
+            quick_xml::de::from_str(&res_text).map_err(|e| {
+                AlipayError::ApiError(format!("error deserialize close_trade response: {:?}", e))
+            })?;
+        match (res_obj.is_success.as_str(), res_obj.error.as_deref()) {
+            ("T", _) | (_, Some("TRADE_NOT_EXIST")) => Ok(()),
+            (_, error) => Err(AlipayError::ApiError(format!(
+                "close_trade is_success != T: {}",
+                error.unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(1, "cny"), "0.01");
+        assert_eq!(format_amount(12345, "USD"), "123.45");
+        assert_eq!(format_amount(100, "hkd"), "1.00");
+        assert_eq!(format_amount(0, "eur"), "0.00");
+        // 没有小数位的币种金额就是最小单位
+        assert_eq!(format_amount(100, "jpy"), "100");
+        assert_eq!(format_amount(5000, "KRW"), "5000");
+        // 按 ISO 4217 的小数位, 不受 PayPal 的限制影响
+        assert_eq!(format_amount(1000, "twd"), "10.00");
+        assert_eq!(format_amount(1234, "KWD"), "1.234");
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("0.01", "cny").unwrap(), 1);
+        assert_eq!(parse_amount("123.45", "usd").unwrap(), 12345);
+        // 浮点数误差按四舍五入处理
+        assert_eq!(parse_amount("0.29", "cny").unwrap(), 29);
+        assert_eq!(parse_amount("19.99", "hkd").unwrap(), 1999);
+        assert_eq!(parse_amount("100", "JPY").unwrap(), 100);
+        assert_eq!(parse_amount("5000", "krw").unwrap(), 5000);
+        assert_eq!(parse_amount("10.00", "TWD").unwrap(), 1000);
+        assert!(parse_amount("", "cny").is_err());
+        assert!(parse_amount("1.0a", "cny").is_err());
+
+        for (amount, currency) in [(1, "cny"), (999999, "usd"), (12345, "jpy")] {
+            let fee = format_amount(amount, currency);
+            assert_eq!(parse_amount(&fee, currency).unwrap(), amount);
+        }
+    }
+}
@@ -1,10 +1,17 @@
+mod alipay_app;
+mod alipay_lite;
 mod alipay_pc_direct;
+mod alipay_qr;
+mod alipay_scan;
 mod alipay_wap;
+mod isv;
+mod keys;
 mod mapi;
 mod openapi;
+mod openapi_trade;
 
 mod config {
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
 
     #[derive(Debug)]
     pub enum AlipayApiType {
@@ -54,6 +61,10 @@ mod config {
         pub alipay_public_key: Option<String>,
         pub alipay_private_key_rsa2: Option<String>,
         pub alipay_public_key_rsa2: Option<String>,
+        pub alipay_app_cert: Option<String>, // 公钥证书模式: 应用公钥证书 appCertPublicKey.crt
+        pub alipay_public_cert: Option<String>, // 公钥证书模式: 支付宝公钥证书 alipayCertPublicKey_RSA2.crt
+        pub alipay_root_cert: Option<String>, // 公钥证书模式: 支付宝根证书 alipayRootCert.crt
+        pub alipay_aes_key: Option<String>, // 内容加密: 开放平台上设置的 AES 密钥, 配置了就加密 biz_content
     }
 
     #[derive(Debug, Deserialize)]
@@ -68,6 +79,153 @@ mod config {
         pub alipay_wap_public_key: Option<String>,
         pub alipay_mer_wap_private_key_rsa2: Option<String>,
         pub alipay_wap_public_key_rsa2: Option<String>,
+        pub alipay_app_cert: Option<String>, // 公钥证书模式: 应用公钥证书 appCertPublicKey.crt
+        pub alipay_public_cert: Option<String>, // 公钥证书模式: 支付宝公钥证书 alipayCertPublicKey_RSA2.crt
+        pub alipay_root_cert: Option<String>, // 公钥证书模式: 支付宝根证书 alipayRootCert.crt
+        pub alipay_aes_key: Option<String>, // 内容加密: 开放平台上设置的 AES 密钥, 配置了就加密 biz_content
+    }
+
+    impl AlipayPcDirectConfig {
+        /**
+         * alipay_version == 1 的时候 mapi 接口用的 RSA 密钥
+         */
+        pub fn mapi_private_key(&self) -> Result<&str, super::AlipayError> {
+            self.alipay_private_key.as_deref().ok_or_else(|| {
+                super::AlipayError::InvalidConfig("missing alipay_private_key".to_string())
+            })
+        }
+
+        pub fn mapi_public_key(&self) -> Result<&str, super::AlipayError> {
+            self.alipay_public_key.as_deref().ok_or_else(|| {
+                super::AlipayError::InvalidConfig("missing alipay_public_key".to_string())
+            })
+        }
+
+        /**
+         * alipay_version == 2 的时候转成 AlipayOpenApiConfig, 和只有 openapi 接口的渠道共用请求
+         */
+        pub fn openapi_config(&self) -> Result<AlipayOpenApiConfig, super::AlipayError> {
+            fn missing(field: &str) -> super::AlipayError {
+                super::AlipayError::InvalidConfig(format!("missing {}", field))
+            }
+            if self.alipay_public_cert.is_none() && self.alipay_public_key_rsa2.is_none() {
+                return Err(missing("alipay_public_key_rsa2 or alipay_public_cert"));
+            }
+            Ok(AlipayOpenApiConfig {
+                alipay_pid: self.alipay_pid.clone(),
+                alipay_app_id: self
+                    .alipay_app_id
+                    .clone()
+                    .ok_or_else(|| missing("alipay_app_id"))?,
+                alipay_private_key_rsa2: self
+                    .alipay_private_key_rsa2
+                    .clone()
+                    .ok_or_else(|| missing("alipay_private_key_rsa2"))?,
+                alipay_public_key_rsa2: self.alipay_public_key_rsa2.clone().unwrap_or_default(),
+                alipay_app_cert: self.alipay_app_cert.clone(),
+                alipay_public_cert: self.alipay_public_cert.clone(),
+                alipay_root_cert: self.alipay_root_cert.clone(),
+                alipay_aes_key: self.alipay_aes_key.clone(),
+            })
+        }
+    }
+
+    impl AlipayWapConfig {
+        /**
+         * alipay_version == 1 的时候 mapi 接口用的 RSA 密钥
+         */
+        pub fn mapi_private_key(&self) -> Result<&str, super::AlipayError> {
+            self.alipay_mer_wap_private_key.as_deref().ok_or_else(|| {
+                super::AlipayError::InvalidConfig("missing alipay_mer_wap_private_key".to_string())
+            })
+        }
+
+        pub fn mapi_public_key(&self) -> Result<&str, super::AlipayError> {
+            self.alipay_wap_public_key.as_deref().ok_or_else(|| {
+                super::AlipayError::InvalidConfig("missing alipay_wap_public_key".to_string())
+            })
+        }
+
+        /**
+         * alipay_version == 2 的时候转成 AlipayOpenApiConfig, 和只有 openapi 接口的渠道共用请求
+         */
+        pub fn openapi_config(&self) -> Result<AlipayOpenApiConfig, super::AlipayError> {
+            fn missing(field: &str) -> super::AlipayError {
+                super::AlipayError::InvalidConfig(format!("missing {}", field))
+            }
+            if self.alipay_public_cert.is_none() && self.alipay_wap_public_key_rsa2.is_none() {
+                return Err(missing("alipay_wap_public_key_rsa2 or alipay_public_cert"));
+            }
+            Ok(AlipayOpenApiConfig {
+                alipay_pid: self.alipay_pid.clone(),
+                alipay_app_id: self
+                    .alipay_app_id
+                    .clone()
+                    .ok_or_else(|| missing("alipay_app_id"))?,
+                alipay_private_key_rsa2: self
+                    .alipay_mer_wap_private_key_rsa2
+                    .clone()
+                    .ok_or_else(|| missing("alipay_mer_wap_private_key_rsa2"))?,
+                alipay_public_key_rsa2: self.alipay_wap_public_key_rsa2.clone().unwrap_or_default(),
+                alipay_app_cert: self.alipay_app_cert.clone(),
+                alipay_public_cert: self.alipay_public_cert.clone(),
+                alipay_root_cert: self.alipay_root_cert.clone(),
+                alipay_aes_key: self.alipay_aes_key.clone(),
+            })
+        }
+    }
+
+    /**
+     * 当面付, 付款码, 小程序和 App 支付只有 openapi 接口, 所以不需要 alipay_version 和 rsa 的密钥
+     * 这几个渠道的参数完全一样, 共用一个配置
+     */
+    #[derive(Debug, Deserialize)]
+    pub struct AlipayOpenApiConfig {
+        pub alipay_pid: String,    // 合作者身份, 账号 ID
+        pub alipay_app_id: String, // 支付宝商户 AppID, 小程序支付是小程序 AppID
+        pub alipay_private_key_rsa2: String,
+        #[serde(default)]
+        pub alipay_public_key_rsa2: String, // 公钥证书模式下可以不填, 用 alipay_public_cert 验签
+        pub alipay_app_cert: Option<String>, // 公钥证书模式: 应用公钥证书 appCertPublicKey.crt
+        pub alipay_public_cert: Option<String>, // 公钥证书模式: 支付宝公钥证书 alipayCertPublicKey_RSA2.crt
+        pub alipay_root_cert: Option<String>, // 公钥证书模式: 支付宝根证书 alipayRootCert.crt
+        pub alipay_aes_key: Option<String>, // 内容加密: 开放平台上设置的 AES 密钥, 配置了就加密 biz_content
+    }
+
+    impl AlipayOpenApiConfig {
+        /**
+         * 验签用的支付宝公钥, 公钥证书模式用支付宝公钥证书验签
+         */
+        pub fn alipay_public_key(&self) -> &str {
+            self.alipay_public_cert
+                .as_deref()
+                .unwrap_or(&self.alipay_public_key_rsa2)
+        }
+
+        /**
+         * alipay_public_key_rsa2 和 alipay_public_cert 反序列化的时候都是可选的, 但是验签至少要有一个
+         */
+        pub fn check_public_key(&self) -> Result<(), super::AlipayError> {
+            if self.alipay_public_cert.is_none() && self.alipay_public_key_rsa2.trim().is_empty() {
+                return Err(super::AlipayError::InvalidConfig(
+                    "missing alipay_public_key_rsa2 or alipay_public_cert".to_string(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    /**
+     * 服务商 (ISV) 模式, sub_app 是授权给服务商应用的商户, 渠道参数里只有授权令牌
+     * alipay_app_id 和签名密钥都用 parent App 上同一个渠道的参数
+     * 各个支付宝渠道都一样
+     */
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct AlipayIsvAuthConfig {
+        pub alipay_app_auth_token: String,
+        pub alipay_app_refresh_token: Option<String>, // 没有的话不会自动刷新授权令牌
+        pub alipay_app_auth_token_expires_at: Option<i32>, // app_auth_token 过期时间 timestamp 精确到秒
+        pub alipay_auth_user_id: Option<String>, // 授权商户的 PID, 只做记录
     }
 }
 
@@ -124,7 +282,13 @@ mod error {
     }
 }
 
+pub use alipay_app::AlipayApp;
+pub use alipay_lite::AlipayLite;
 pub use alipay_pc_direct::AlipayPcDirect;
+pub use alipay_qr::AlipayQr;
+pub use alipay_scan::AlipayScan;
 pub use alipay_wap::AlipayWap;
 pub use config::*;
+pub use keys::normalize_alipay_keys;
 use error::*;
+use isv::load_alipay_config;
@@ -1,4 +1,10 @@
-use super::AlipayError;
+use super::{
+    keys::{load_private_key, load_public_key},
+    mapi::is_forex_currency,
+    AlipayError,
+};
+use crate::core::{ChargeResult, ChargeStatus};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
@@ -7,8 +13,6 @@ mod openapi_rsa2 {
     use super::*;
     use openssl::{
         hash::MessageDigest,
-        pkey::PKey,
-        rsa::Rsa,
         sign::{Signer, Verifier},
     };
 
@@ -22,8 +26,7 @@ mod openapi_rsa2 {
         });
         query_list.sort();
         let sign_sorted_source = query_list.join("&");
-        let keypair = Rsa::private_key_from_pem(private_key.as_bytes())?;
-        let keypair = PKey::from_rsa(keypair)?;
+        let keypair = load_private_key(private_key)?;
         let mut signer = Signer::new(MessageDigest::sha256(), &keypair)?;
         signer.update(sign_sorted_source.as_bytes())?;
         let signature_bytes = signer.sign_to_vec()?;
@@ -45,18 +48,143 @@ mod openapi_rsa2 {
         });
         query_list.sort();
         let sorted_payload = query_list.join("&");
-        let keypair = Rsa::public_key_from_pem(public_key.as_bytes())?;
-        let keypair = PKey::from_rsa(keypair)?;
+        let keypair = load_public_key(public_key)?;
         let mut verifier = Verifier::new(MessageDigest::sha256(), &keypair)?;
         verifier.update(sorted_payload.as_bytes())?;
         let signature_bytes = data_encoding::BASE64.decode(signature.as_bytes())?;
         let result = verifier.verify(&signature_bytes)?;
         Ok(result)
     }
+
+    /**
+     * 同步应答的签名是对 {method}_response 的原始 json 字符串签名, 不排序
+     */
+    pub fn verify_response(
+        content: &str,
+        signature: &str,
+        public_key: &str,
+    ) -> Result<bool, AlipayError> {
+        let keypair = load_public_key(public_key)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &keypair)?;
+        verifier.update(content.as_bytes())?;
+        let signature_bytes = data_encoding::BASE64.decode(signature.as_bytes())?;
+        let result = verifier.verify(&signature_bytes)?;
+        Ok(result)
+    }
+}
+
+/**
+ * 公钥证书模式, 请求里需要带上应用公钥证书和支付宝根证书的 SN
+ * 证书 SN 是 md5(issuer + 十进制的 serial number), issuer 按 RFC2253 格式, 比如 CN=Ant Financial Certification Authority Class 2 R1,OU=Certification Authority,O=Ant Financial,C=CN
+ * https://opendocs.alipay.com/common/02kf5q
+ */
+mod openapi_cert {
+    use super::*;
+    use openssl::{hash::MessageDigest, nid::Nid, x509::X509};
+
+    fn cert_sn(cert: &X509) -> Result<String, AlipayError> {
+        let issuer = cert
+            .issuer_name()
+            .entries()
+            .map(|entry| {
+                let name = entry.object().nid().short_name()?;
+                let value = entry.data().as_utf8()?;
+                Ok(format!("{}={}", name, value))
+            })
+            .collect::<Result<Vec<String>, openssl::error::ErrorStack>>()?;
+        // RFC2253 格式的 issuer 和证书里的顺序是反的
+        let issuer = issuer.into_iter().rev().collect::<Vec<String>>().join(",");
+        let serial_no = cert.serial_number().to_bn()?.to_dec_str()?.to_string();
+        let digest = openssl::hash::hash(MessageDigest::md5(), (issuer + &serial_no).as_bytes())?;
+        Ok(data_encoding::HEXLOWER.encode(&digest))
+    }
+
+    pub fn app_cert_sn(app_cert: &str) -> Result<String, AlipayError> {
+        let cert = X509::from_pem(app_cert.as_bytes())?;
+        cert_sn(&cert)
+    }
+
+    /**
+     * 根证书文件里有多个证书, 只取 RSA 签名算法的, 各个证书的 SN 用 _ 连接
+     */
+    pub fn alipay_root_cert_sn(alipay_root_cert: &str) -> Result<String, AlipayError> {
+        let certs = X509::stack_from_pem(alipay_root_cert.as_bytes())?;
+        let mut sn_list = Vec::<String>::new();
+        for cert in certs.iter() {
+            let nid = cert.signature_algorithm().object().nid();
+            if nid == Nid::SHA1WITHRSAENCRYPTION || nid == Nid::SHA256WITHRSAENCRYPTION {
+                sn_list.push(cert_sn(cert)?);
+            }
+        }
+        Ok(sn_list.join("_"))
+    }
+
+    /**
+     * 没有配置应用公钥证书就是普通公钥模式, 返回 None
+     */
+    pub fn cert_sn_pair(
+        app_cert: Option<&str>,
+        alipay_root_cert: Option<&str>,
+    ) -> Result<Option<(String, String)>, AlipayError> {
+        let Some(app_cert) = app_cert else {
+            return Ok(None);
+        };
+        let alipay_root_cert = alipay_root_cert.ok_or_else(|| {
+            AlipayError::InvalidConfig("missing alipay_root_cert for cert mode".into())
+        })?;
+        Ok(Some((
+            app_cert_sn(app_cert)?,
+            alipay_root_cert_sn(alipay_root_cert)?,
+        )))
+    }
 }
 
+/**
+ * 内容加密, biz_content 和应答用 AES-128-CBC 加密, IV 全是 0, PKCS#5 填充, 密文 base64
+ * alipay_aes_key 是支付宝开放平台上设置的 base64 密钥
+ * https://opendocs.alipay.com/common/02mse3
+ */
+mod openapi_aes {
+    use super::*;
+    use openssl::symm::{decrypt as aes_decrypt, encrypt as aes_encrypt, Cipher};
+
+    const IV: [u8; 16] = [0; 16];
+
+    fn decode_key(aes_key: &str) -> Result<Vec<u8>, AlipayError> {
+        let key = data_encoding::BASE64
+            .decode(aes_key.trim().as_bytes())
+            .map_err(|e| AlipayError::InvalidConfig(format!("invalid alipay_aes_key: {}", e)))?;
+        if key.len() != 16 {
+            return Err(AlipayError::InvalidConfig(format!(
+                "invalid alipay_aes_key: expected 16 bytes, got {}",
+                key.len()
+            )));
+        }
+        Ok(key)
+    }
+
+    pub fn encrypt(content: &str, aes_key: &str) -> Result<String, AlipayError> {
+        let key = decode_key(aes_key)?;
+        let encrypted = aes_encrypt(Cipher::aes_128_cbc(), &key, Some(&IV), content.as_bytes())?;
+        Ok(data_encoding::BASE64.encode(&encrypted))
+    }
+
+    pub fn decrypt(content: &str, aes_key: &str) -> Result<String, AlipayError> {
+        let key = decode_key(aes_key)?;
+        let encrypted = data_encoding::BASE64.decode(content.as_bytes())?;
+        let decrypted = aes_decrypt(Cipher::aes_128_cbc(), &key, Some(&IV), &encrypted)?;
+        String::from_utf8(decrypted).map_err(|e| {
+            AlipayError::ApiError(format!("error decoding decrypted alipay response: {}", e))
+        })
+    }
+}
+
+/**
+ * openapi 所有接口共用的公共请求参数, 各个接口的 payload 用 #[serde(flatten)] 带上
+ * biz_content 也放在这里, 加密的时候要用
+ */
 #[derive(Debug, Serialize, Deserialize)]
-pub struct OpenApiRequestPayload {
+pub struct OpenApiCommonParams {
     pub app_id: String,
     pub method: String,
     pub format: String,
@@ -65,25 +193,171 @@ pub struct OpenApiRequestPayload {
     pub sign: String,
     pub timestamp: String,
     pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_auth_token: Option<String>, // 服务商模式下代商户调用接口的授权令牌
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_cert_sn: Option<String>, // 公钥证书模式下应用公钥证书的 SN
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alipay_root_cert_sn: Option<String>, // 公钥证书模式下支付宝根证书的 SN
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encrypt_type: Option<String>, // 内容加密的时候是 AES
+    #[serde(skip)]
+    aes_key: Option<String>, // 内容加密的密钥, 不参与签名, 解密应答的时候要用
     pub biz_content: String,
+}
+
+impl OpenApiCommonParams {
+    fn new(
+        alipay_app_id: &str,          // 开放平台 ID, 应用 ID
+        method: &str,                 // alipay.trade.page.pay | alipay.trade.refund | ...
+        app_auth_token: Option<&str>, // 服务商模式下商户授权的 app_auth_token
+        biz_content: serde_json::Value,
+    ) -> Self {
+        Self {
+            app_id: alipay_app_id.to_string(),
+            method: method.to_string(),
+            format: String::from("JSON"),
+            charset: String::from("utf-8"),
+            sign_type: String::from("RSA2"),
+            sign: String::from(""),
+            timestamp: chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            version: String::from("1.0"),
+            app_auth_token: app_auth_token.map(|token| token.to_string()),
+            app_cert_sn: None,
+            alipay_root_cert_sn: None,
+            encrypt_type: None,
+            aes_key: None,
+            biz_content: biz_content.to_string(),
+        }
+    }
+}
+
+/**
+ * 证书 SN, 内容加密, 签名和发送请求对所有接口都是一样的, 只需要 payload 提供公共参数
+ * 调用顺序: set_cert_sn -> encrypt_biz_content -> sign_rsa2 -> send_request
+ */
+#[async_trait]
+pub trait OpenApiPayload: Serialize + Sync {
+    fn common_params(&self) -> &OpenApiCommonParams;
+
+    fn common_params_mut(&mut self) -> &mut OpenApiCommonParams;
+
+    /**
+     * 公钥证书模式下带上证书 SN, 普通公钥模式 app_cert 是 None, 不做处理
+     * 需要在 sign_rsa2 之前调用
+     */
+    fn set_cert_sn(
+        &mut self,
+        app_cert: Option<&str>,
+        alipay_root_cert: Option<&str>,
+    ) -> Result<(), AlipayError> {
+        if let Some((app_cert_sn, alipay_root_cert_sn)) =
+            openapi_cert::cert_sn_pair(app_cert, alipay_root_cert)?
+        {
+            let params = self.common_params_mut();
+            params.app_cert_sn = Some(app_cert_sn);
+            params.alipay_root_cert_sn = Some(alipay_root_cert_sn);
+        }
+        Ok(())
+    }
+
+    /**
+     * 配置了 alipay_aes_key 的话把 biz_content 加密, 没配置的话不做处理
+     * 需要在 sign_rsa2 之前调用
+     * 加密以后 biz_content 就不能再改了, extend_biz_content 要在这之前调用
+     */
+    fn encrypt_biz_content(&mut self, aes_key: Option<&str>) -> Result<(), AlipayError> {
+        if let Some(aes_key) = aes_key {
+            let params = self.common_params_mut();
+            params.biz_content = openapi_aes::encrypt(&params.biz_content, aes_key)?;
+            params.encrypt_type = Some(String::from("AES"));
+            params.aes_key = Some(aes_key.to_string());
+        }
+        Ok(())
+    }
+
+    /**
+     * 除了 sign 以外的参数都参与签名, channel_url 不是请求参数
+     */
+    fn sign_rsa2(&mut self, private_key: &str) -> Result<String, AlipayError> {
+        let mut m = self.to_params();
+        m.remove("sign");
+        let signature = openapi_rsa2::sign(&m, private_key)?;
+        self.common_params_mut().sign = signature.clone();
+        Ok(signature)
+    }
+
+    /**
+     * 请求参数拼成 map, 去掉 channel_url 和空的参数 (比如当面付不需要 return_url)
+     */
+    fn to_params(&self) -> HashMap<String, String> {
+        // 这里 deserialize 不会出问题
+        let v = serde_json::to_value(self).unwrap();
+        let mut m: HashMap<String, String> = serde_json::from_value(v).unwrap();
+        m.remove("channel_url");
+        m.retain(|_, v| !v.is_empty());
+        m
+    }
+
+    /**
+     * 服务端直接请求 openapi 网关, 返回 {method}_response 里的内容, 需要调用方自己判断 code
+     */
+    async fn send_request(
+        &self,
+        alipay_public_key: &str, // 支付宝公钥, 公钥证书模式下是支付宝公钥证书
+    ) -> Result<serde_json::Value, AlipayError> {
+        let params = self.common_params();
+        send_openapi_request(
+            &self.to_params(),
+            &params.method,
+            alipay_public_key,
+            params.aes_key.as_deref(),
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenApiRequestPayload {
+    #[serde(flatten)]
+    pub common_params: OpenApiCommonParams,
     pub notify_url: String,
     pub return_url: String,
     pub channel_url: String,
 }
 
+impl OpenApiPayload for OpenApiRequestPayload {
+    fn common_params(&self) -> &OpenApiCommonParams {
+        &self.common_params
+    }
+
+    fn common_params_mut(&mut self) -> &mut OpenApiCommonParams {
+        &mut self.common_params
+    }
+}
+
 impl OpenApiRequestPayload {
     pub fn new(
-        charge_id: &str,         //
-        method: &str,            // alipay.trade.page.pay | alipay.trade.wap.pay
-        alipay_app_id: &str,     // 开放平台 ID, 应用 ID
-        alipay_pid: &str,        // 合作者身份 ID, 商家唯一 ID
-        return_url: &str,        // 支付成功跳转
-        merchant_order_no: &str, // 商户订单号
-        charge_amount: i32,      // 支付金额, 精确到分
-        time_expire: i32,        // 过期时间 timestamp 精确到秒
-        subject: &str,           // 标题
-        body: &str,              // 详情
+        charge_id: &str,              //
+        method: &str,                 // alipay.trade.page.pay | alipay.trade.wap.pay | ...
+        alipay_app_id: &str,          // 开放平台 ID, 应用 ID
+        alipay_pid: &str,             // 合作者身份 ID, 商家唯一 ID, 服务商模式下是服务商的 PID
+        app_auth_token: Option<&str>, // 服务商模式下商户授权的 app_auth_token
+        return_url: &str,             // 支付成功跳转
+        merchant_order_no: &str,      // 商户订单号
+        charge_amount: i32,           // 支付金额, 精确到分
+        currency: &str,               // 币种
+        time_expire: i32,             // 过期时间 timestamp 精确到秒
+        subject: &str,                // 标题
+        body: &str,                   // 详情
     ) -> Result<Self, AlipayError> {
+        // 境外支付宝只有 mapi 的 create_forex_trade 接口
+        if is_forex_currency(currency) {
+            return Err(AlipayError::MalformedRequest(format!(
+                "currency {} is not supported by alipay openapi, forex trade requires alipay_version 1 (mapi)",
+                currency
+            )));
+        }
         let total_amount = format!("{:.2}", charge_amount as f64 / 100.0);
         let timeout_express = {
             let now = chrono::Utc::now().timestamp() as i32;
@@ -96,26 +370,33 @@ impl OpenApiRequestPayload {
                 ));
             }
         };
-        let biz_content = json!({
+        let product_code = match method {
+            "alipay.trade.precreate" => "FACE_TO_FACE_PAYMENT", // 当面付扫码
+            "alipay.trade.app.pay" => "QUICK_MSECURITY_PAY",    // App 支付
+            "alipay.trade.create" => "JSAPI_PAY",               // 小程序支付
+            "alipay.trade.pay" => "FACE_TO_FACE_PAYMENT",       // 当面付付款码
+            _ => "FAST_INSTANT_TRADE_PAY",
+        };
+        let mut biz_content = json!({
             "body": body,
             "subject": subject,
             "out_trade_no": merchant_order_no,
             "total_amount": total_amount,
-            "product_code": "FAST_INSTANT_TRADE_PAY",
-            "extend_params": { "sys_service_provider_id": alipay_pid },
+            "product_code": product_code,
             "timeout_express": timeout_express,
             "passback_params": charge_id,
         });
+        // 系统商返佣, 只有服务商代商户下单的时候才需要, 值是服务商的 PID
+        if app_auth_token.is_some() {
+            biz_content["extend_params"] = json!({ "sys_service_provider_id": alipay_pid });
+        }
         let payload = Self {
-            app_id: alipay_app_id.to_string(),
-            method: method.to_string(),
-            format: String::from("JSON"),
-            charset: String::from("utf-8"),
-            sign_type: String::from("RSA2"),
-            sign: String::from(""),
-            timestamp: chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-            version: String::from("1.0"),
-            biz_content: biz_content.to_string(),
+            common_params: OpenApiCommonParams::new(
+                alipay_app_id,
+                method,
+                app_auth_token,
+                biz_content,
+            ),
             return_url: return_url.to_string(),
             notify_url: crate::utils::charge_notify_url(charge_id),
             channel_url: String::from("https://openapi.alipay.com/gateway.do"),
@@ -123,15 +404,31 @@ impl OpenApiRequestPayload {
         Ok(payload)
     }
 
-    pub fn sign_rsa2(&mut self, private_key: &str) -> Result<String, AlipayError> {
-        // 这里 deserialize 不会出问题
-        let v = serde_json::to_value(&self).unwrap();
-        let mut m: HashMap<String, String> = serde_json::from_value(v).unwrap();
-        m.remove("sign");
-        m.remove("channel_url");
-        let signature = openapi_rsa2::sign(&m, private_key)?;
-        self.sign = signature.clone();
-        Ok(signature)
+    /**
+     * 有些接口的 biz_content 需要额外的参数, 比如 alipay.trade.create 需要 buyer_id
+     * 需要在 sign_rsa2 之前调用
+     */
+    pub fn extend_biz_content(&mut self, key: &str, value: serde_json::Value) {
+        // biz_content 是 new 里面用 json! 生成的, 这里 deserialize 不会出问题
+        let params = &mut self.common_params;
+        let mut biz_content: serde_json::Value = serde_json::from_str(&params.biz_content).unwrap();
+        biz_content[key] = value;
+        params.biz_content = biz_content.to_string();
+    }
+
+    /**
+     * App 支付不需要请求支付宝, 把签名后的参数拼成 orderInfo 交给客户端 SDK 调起支付
+     * 和签名时不同, 这里的 value 需要 urlencode
+     * https://opendocs.alipay.com/open/204/105465
+     */
+    pub fn build_order_info(&self) -> String {
+        let mut query_list = Vec::<String>::new();
+        self.to_params().iter().for_each(|(k, v)| {
+            let v = percent_encoding::utf8_percent_encode(v, percent_encoding::NON_ALPHANUMERIC);
+            query_list.push(format!("{}={}", k, v));
+        });
+        query_list.sort();
+        query_list.join("&")
     }
 }
 
@@ -207,17 +504,35 @@ impl OpenApiNotifyPayload {
     }
 }
 
+/**
+ * alipay.trade.refund
+ * 退款成功判断说明：接口返回fund_change=Y为退款成功，fund_change=N或无此字段值返回时需通过退款查询接口进一步确认退款状态。
+ * 注意，接口中code=10000，仅代表本次退款请求成功，不代表退款成功。
+ * code
+ * msg
+ * buyer_logon_id
+ * buyer_user_id
+ * fund_change
+ * gmt_refund_pay
+ * out_trade_no
+ * refund_fee      // 对应支付累计已退款的总金额
+ * send_back_fee   // 本次商户实际退回金额
+ * trade_no        // 支付宝交易号
+ */
 #[derive(Debug, Serialize)]
 pub struct OpenApiRefundPayload {
-    pub app_id: String,
-    pub method: String,
-    pub format: String,
-    pub charset: String,
-    pub sign_type: String,
-    pub sign: String,
-    pub timestamp: String,
-    pub version: String,
-    pub biz_content: String,
+    #[serde(flatten)]
+    pub common_params: OpenApiCommonParams,
+}
+
+impl OpenApiPayload for OpenApiRefundPayload {
+    fn common_params(&self) -> &OpenApiCommonParams {
+        &self.common_params
+    }
+
+    fn common_params_mut(&mut self) -> &mut OpenApiCommonParams {
+        &mut self.common_params
+    }
 }
 
 // #[derive(Debug, Deserialize)]
@@ -237,6 +552,7 @@ pub struct OpenApiRefundPayload {
 impl OpenApiRefundPayload {
     pub fn new(
         alipay_app_id: &str,            // 开放平台 ID, 应用 ID
+        app_auth_token: Option<&str>,   // 服务商模式下商户授权的 app_auth_token
         charge_merchant_order_no: &str, // 商户订单号
         refund_merchant_order_no: &str, // 退款订单号
         refund_amount: i32,             // 退款金额, 精确到分
@@ -250,64 +566,476 @@ impl OpenApiRefundPayload {
             "refund_reason": description,              // 退款说明
         });
         Ok(Self {
-            app_id: alipay_app_id.to_string(),
-            method: String::from("alipay.trade.refund"),
-            format: String::from("JSON"),
-            charset: String::from("utf-8"),
-            sign_type: String::from("RSA2"),
-            sign: String::from(""),
-            timestamp: chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-            version: String::from("1.0"),
-            biz_content: biz_content.to_string(),
+            common_params: OpenApiCommonParams::new(
+                alipay_app_id,
+                "alipay.trade.refund",
+                app_auth_token,
+                biz_content,
+            ),
         })
     }
+}
 
-    pub fn sign_rsa2(&mut self, private_key: &str) -> Result<String, AlipayError> {
-        // 这里 deserialize 不会出问题
-        let v = serde_json::to_value(&self).unwrap();
-        let mut m: HashMap<String, String> = serde_json::from_value(v).unwrap();
-        m.remove("sign");
-        let signature = openapi_rsa2::sign(&m, private_key)?;
-        self.sign = signature.clone();
-        Ok(signature)
+/**
+ * alipay.trade.query | alipay.trade.cancel | ... 这些接口的 biz_content 只需要 out_trade_no
+ */
+#[derive(Debug, Serialize)]
+pub struct OpenApiTradePayload {
+    #[serde(flatten)]
+    pub common_params: OpenApiCommonParams,
+}
+
+impl OpenApiPayload for OpenApiTradePayload {
+    fn common_params(&self) -> &OpenApiCommonParams {
+        &self.common_params
     }
 
-    /**
-     * 退款成功判断说明：接口返回fund_change=Y为退款成功，fund_change=N或无此字段值返回时需通过退款查询接口进一步确认退款状态。
-     * 注意，接口中code=10000，仅代表本次退款请求成功，不代表退款成功。
-     * code
-     * msg
-     * buyer_logon_id
-     * buyer_user_id
-     * fund_change
-     * gmt_refund_pay
-     * out_trade_no
-     * refund_fee      // 对应支付累计已退款的总金额
-     * send_back_fee   // 本次商户实际退回金额
-     * trade_no        // 支付宝交易号
-     */
-    pub async fn send_request(&self) -> Result<serde_json::Value, AlipayError> {
-        let res = reqwest::Client::new()
-            .post("https://openapi.alipay.com/gateway.do")
-            // .form(&self)  // 使用 x-www-form-urlencoded
-            .query(&self) // 参数放在 url 中
-            .send()
-            .await
-            .map_err(|e| AlipayError::ApiError(format!("error request alipay openapi: {}", e)))?;
-        let res_text = res.text().await.map_err(|e| {
-            AlipayError::ApiError(format!("error read alipay openapi response: {}", e))
-        })?;
-        tracing::debug!("alipay openapi response: {:?}", res_text);
-        // 这里不能用 to_value (str 会变成 serde_json::Value::String), 要用 from_str (把 str 转化成 json)
-        let res_json: serde_json::Value = serde_json::from_str(&res_text).map_err(|e| {
-            AlipayError::ApiError(format!("error deserialize alipay openapi response: {}", e))
-        })?;
-        let alipay_trade_refund_response = res_json["alipay_trade_refund_response"].clone();
-        // let refund_response: OpenApiRefundResponse =
-        //     serde_json::from_value(alipay_trade_refund_response).map_err(|e| {
-        //         AlipayError::ApiError(format!("error deserialize OpenApiRefundResponse: {}", e))
-        //     })?;
-        // Ok(refund_response)
-        Ok(alipay_trade_refund_response)
+    fn common_params_mut(&mut self) -> &mut OpenApiCommonParams {
+        &mut self.common_params
+    }
+}
+
+impl OpenApiTradePayload {
+    pub fn new(
+        alipay_app_id: &str,          // 开放平台 ID, 应用 ID
+        app_auth_token: Option<&str>, // 服务商模式下商户授权的 app_auth_token
+        method: &str,                 // alipay.trade.query | alipay.trade.cancel | ...
+        merchant_order_no: &str,      // 商户订单号
+    ) -> Result<Self, AlipayError> {
+        let biz_content = json!({
+            "out_trade_no": merchant_order_no,
+        });
+        Ok(Self {
+            common_params: OpenApiCommonParams::new(
+                alipay_app_id,
+                method,
+                app_auth_token,
+                biz_content,
+            ),
+        })
+    }
+}
+
+/**
+ * alipay.trade.query 的结果转成 ChargeResult
+ * TRADE_SUCCESS | TRADE_FINISHED 是支付成功, TRADE_CLOSED 是交易关闭 (超时未付款或者已全额退款)
+ * WAIT_BUYER_PAY 和交易不存在 (用户还没扫码) 都是 Pending, 其他错误码返回错误
+ */
+pub fn trade_query_result(query_response: serde_json::Value) -> Result<ChargeResult, AlipayError> {
+    match query_response["code"].as_str() {
+        Some("10000") => {}
+        Some("40004") if query_response["sub_code"].as_str() == Some("ACQ.TRADE_NOT_EXIST") => {
+            return Ok(ChargeResult {
+                credential: query_response,
+                ..Default::default()
+            });
+        }
+        code => {
+            return Err(AlipayError::ApiError(format!(
+                "alipay.trade.query code != 10000: {:?} {:?}",
+                code,
+                query_response["sub_msg"].as_str(),
+            )));
+        }
+    }
+    let status = match query_response["trade_status"].as_str() {
+        Some("TRADE_SUCCESS") | Some("TRADE_FINISHED") => ChargeStatus::Success,
+        Some("TRADE_CLOSED") => {
+            return Ok(ChargeResult {
+                status: ChargeStatus::Fail,
+                credential: query_response,
+                failure_code: Some("TRADE_CLOSED".to_string()),
+                failure_msg: Some("交易已关闭".to_string()),
+                ..Default::default()
+            });
+        }
+        _ => ChargeStatus::Pending,
+    };
+    Ok(ChargeResult {
+        status,
+        credential: query_response,
+        ..Default::default()
+    })
+}
+
+/**
+ * alipay.trade.close 的结果, 交易不存在 (用户还没扫码或者还没打开收银台) 也当作关闭成功
+ * 已经支付的交易返回 ACQ.TRADE_STATUS_ERROR, 和其他错误码一样返回错误
+ */
+pub fn trade_close_result(close_response: serde_json::Value) -> Result<(), AlipayError> {
+    match close_response["code"].as_str() {
+        Some("10000") => Ok(()),
+        Some("40004") if close_response["sub_code"].as_str() == Some("ACQ.TRADE_NOT_EXIST") => {
+            Ok(())
+        }
+        code => Err(AlipayError::ApiError(format!(
+            "alipay.trade.close code != 10000: {:?} {:?}",
+            code,
+            close_response["sub_msg"].as_str(),
+        ))),
+    }
+}
+
+/**
+ * alipay.trade.cancel 的结果, action 是 close (关闭交易) 或者 refund (已付款, 原路退款)
+ * retry_flag = Y 表示撤销没有完成需要重试, 返回错误
+ */
+pub fn trade_cancel_result(cancel_response: serde_json::Value) -> Result<(), AlipayError> {
+    if cancel_response["code"].as_str() != Some("10000") {
+        return Err(AlipayError::ApiError(format!(
+            "alipay.trade.cancel code != 10000: {:?} {:?}",
+            cancel_response["code"].as_str(),
+            cancel_response["sub_msg"].as_str(),
+        )));
+    }
+    if cancel_response["retry_flag"].as_str() == Some("Y") {
+        return Err(AlipayError::ApiError(
+            "alipay.trade.cancel retry_flag = Y, need to retry".to_string(),
+        ));
+    }
+    tracing::info!(
+        "alipay.trade.cancel action: {:?}",
+        cancel_response["action"].as_str()
+    );
+    Ok(())
+}
+
+/**
+ * alipay.open.auth.token.app 用 app_refresh_token 换新的 app_auth_token
+ * 用服务商自己的应用签名, 不需要 app_auth_token, 也不加密
+ * code
+ * msg
+ * app_auth_token    // 新的授权令牌
+ * app_refresh_token // 新的刷新令牌
+ * expires_in        // 授权令牌有效期, 秒
+ * re_expires_in     // 刷新令牌有效期, 秒
+ * user_id           // 授权商户的 PID
+ * auth_app_id       // 授权商户的 AppID
+ */
+#[derive(Debug, Serialize)]
+pub struct OpenApiAuthTokenPayload {
+    #[serde(flatten)]
+    pub common_params: OpenApiCommonParams,
+}
+
+impl OpenApiPayload for OpenApiAuthTokenPayload {
+    fn common_params(&self) -> &OpenApiCommonParams {
+        &self.common_params
+    }
+
+    fn common_params_mut(&mut self) -> &mut OpenApiCommonParams {
+        &mut self.common_params
+    }
+}
+
+impl OpenApiAuthTokenPayload {
+    pub fn new(
+        alipay_app_id: &str, // 服务商的开放平台应用 ID
+        refresh_token: &str, // 上次换取令牌时拿到的 app_refresh_token
+    ) -> Result<Self, AlipayError> {
+        let biz_content = json!({
+            "grant_type": "refresh_token",
+            "refresh_token": refresh_token,
+        });
+        Ok(Self {
+            common_params: OpenApiCommonParams::new(
+                alipay_app_id,
+                "alipay.open.auth.token.app",
+                None,
+                biz_content,
+            ),
+        })
+    }
+}
+
+/**
+ * 从应答原文里取出 "{response_key}": 后面的 json 对象原文, 验签要用原文, 不能用反序列化以后再序列化的结果
+ * 内容加密的时候是密文字符串
+ */
+fn extract_response_content<'a>(res_text: &'a str, response_key: &str) -> Option<&'a str> {
+    let key = format!("\"{}\"", response_key);
+    let key_end = res_text.find(&key)? + key.len();
+    let rest = res_text[key_end..]
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start();
+    if !rest.starts_with('{') && !rest.starts_with('"') {
+        return None;
+    }
+    // 按括号配对找到对象结尾, 字符串里的括号和转义字符跳过
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                // 内容加密的应答是一个 json 字符串, 带引号一起验签
+                '"' if depth == 0 => return Some(&rest[..=i]),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&rest[..=i]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/**
+ * 服务端直接请求 openapi 网关, 返回 {method}_response 里的内容
+ * 比如 alipay.trade.refund 的结果在 alipay_trade_refund_response 里
+ * 应答的 sign 必须用支付宝公钥验证通过, 否则返回错误, 防止伪造或者篡改的应答
+ * 只有网关直接拒绝的请求 (code 不是 10000) 支付宝可能不签名, 这种情况不验签, 由调用方判断 code
+ * 内容加密的时候验签用的是密文原文, 返回的是解密后的内容
+ */
+async fn send_openapi_request(
+    m: &HashMap<String, String>,
+    method: &str,
+    alipay_public_key: &str,
+    aes_key: Option<&str>, // 内容加密的密钥, 请求没有加密的话是 None
+) -> Result<serde_json::Value, AlipayError> {
+    let res = reqwest::Client::new()
+        .post("https://openapi.alipay.com/gateway.do")
+        // .form(&m)  // 使用 x-www-form-urlencoded
+        .query(m) // 参数放在 url 中
+        .send()
+        .await
+        .map_err(|e| AlipayError::ApiError(format!("error request alipay openapi: {}", e)))?;
+    let res_text = res
+        .text()
+        .await
+        .map_err(|e| AlipayError::ApiError(format!("error read alipay openapi response: {}", e)))?;
+    tracing::debug!("alipay openapi response: {:?}", res_text);
+    // 这里不能用 to_value (str 会变成 serde_json::Value::String), 要用 from_str (把 str 转化成 json)
+    let res_json: serde_json::Value = serde_json::from_str(&res_text).map_err(|e| {
+        AlipayError::ApiError(format!("error deserialize alipay openapi response: {}", e))
+    })?;
+    let response_key = format!("{}_response", method.replace(".", "_"));
+    // 内容加密的应答是密文字符串, 解密以后才能判断 code, 支付宝拒绝请求的时候返回的可能还是明文
+    let response = match (&res_json[response_key.as_str()], aes_key) {
+        (serde_json::Value::String(encrypted), Some(aes_key)) => {
+            let decrypted = openapi_aes::decrypt(encrypted, aes_key)?;
+            serde_json::from_str(&decrypted).map_err(|e| {
+                AlipayError::ApiError(format!(
+                    "error deserialize decrypted alipay openapi response: {}",
+                    e
+                ))
+            })?
+        }
+        (response, _) => response.clone(),
+    };
+    match res_json["sign"].as_str() {
+        Some(signature) => {
+            let content = extract_response_content(&res_text, &response_key).ok_or_else(|| {
+                AlipayError::ApiError(format!(
+                    "missing {} in alipay openapi response",
+                    response_key
+                ))
+            })?;
+            if !openapi_rsa2::verify_response(content, signature, alipay_public_key)? {
+                return Err(AlipayError::ApiError(format!(
+                    "wrong rsa2 signature in {}",
+                    response_key
+                )));
+            }
+        }
+        None if response_requires_sign(&response) => {
+            return Err(AlipayError::ApiError(format!(
+                "missing sign in {}",
+                response_key
+            )));
+        }
+        None => {}
+    }
+    Ok(response)
+}
+
+/**
+ * 网关拒绝请求 (比如验签失败) 的时候应答可能没有 sign, 这时候只是返回错误, 不会改变 charge 状态
+ * 成功的应答和交易不存在都会被当成结果处理 (交易不存在的关单算成功), 没有 sign 的不能相信
+ */
+fn response_requires_sign(response: &serde_json::Value) -> bool {
+    response["code"].as_str() == Some("10000")
+        || response["sub_code"].as_str() == Some("ACQ.TRADE_NOT_EXIST")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        x509::{X509Builder, X509NameBuilder},
+    };
+
+    fn build_cert(issuer: &[(&str, &str)], serial: u32, keypair: &PKey<Private>) -> String {
+        let mut name = X509NameBuilder::new().unwrap();
+        for (field, value) in issuer {
+            name.append_entry_by_text(field, value).unwrap();
+        }
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(keypair).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(keypair, MessageDigest::sha256()).unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    fn rsa_keypair() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_cert_sn() {
+        let keypair = rsa_keypair();
+        // 证书里的顺序是 C, O, OU, CN, 计算 SN 的时候反过来
+        let app_cert = build_cert(
+            &[
+                ("C", "CN"),
+                ("O", "Ant Financial"),
+                ("OU", "Certification Authority"),
+                ("CN", "Ant Financial Certification Authority Class 2 R1"),
+            ],
+            12345,
+            &keypair,
+        );
+        // md5("CN=Ant Financial Certification Authority Class 2 R1,OU=Certification Authority,O=Ant Financial,C=CN12345")
+        assert_eq!(
+            openapi_cert::app_cert_sn(&app_cert).unwrap(),
+            "a7a338406cfeffa7e3eae3d7e35de065"
+        );
+
+        // 根证书里 ECC 签名的证书跳过, RSA 的用 _ 连接
+        let ec_group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec_keypair = PKey::from_ec_key(EcKey::generate(&ec_group).unwrap()).unwrap();
+        let root_cert = [
+            build_cert(
+                &[("C", "CN"), ("O", "Ant Financial"), ("CN", "Root")],
+                1,
+                &keypair,
+            ),
+            build_cert(&[("C", "CN"), ("CN", "EC Root")], 2, &ec_keypair),
+            app_cert.clone(),
+        ]
+        .concat();
+        // md5("CN=Root,O=Ant Financial,C=CN1")
+        assert_eq!(
+            openapi_cert::alipay_root_cert_sn(&root_cert).unwrap(),
+            "36f3230ab51542af23d36723b975826b_a7a338406cfeffa7e3eae3d7e35de065"
+        );
+
+        assert!(openapi_cert::cert_sn_pair(None, None).unwrap().is_none());
+        assert!(openapi_cert::cert_sn_pair(Some(&app_cert), None).is_err());
+        let (app_cert_sn, alipay_root_cert_sn) =
+            openapi_cert::cert_sn_pair(Some(&app_cert), Some(&root_cert))
+                .unwrap()
+                .unwrap();
+        assert_eq!(app_cert_sn, "a7a338406cfeffa7e3eae3d7e35de065");
+        assert!(alipay_root_cert_sn.starts_with("36f3230ab51542af23d36723b975826b_"));
+    }
+
+    #[test]
+    fn test_extract_response_content() {
+        let res_text = r#"{"alipay_trade_query_response":{"code":"10000","msg":"Success","body":"a \"}\" {b}","extra":{"x":1}},"sign":"abc"}"#;
+        assert_eq!(
+            extract_response_content(res_text, "alipay_trade_query_response"),
+            Some(r#"{"code":"10000","msg":"Success","body":"a \"}\" {b}","extra":{"x":1}}"#)
+        );
+
+        // sign 在前面, 冒号前后有空白
+        let res_text =
+            "{\"sign\":\"abc\", \"alipay_trade_refund_response\" : {\"code\":\"40004\"}}";
+        assert_eq!(
+            extract_response_content(res_text, "alipay_trade_refund_response"),
+            Some(r#"{"code":"40004"}"#)
+        );
+
+        // 内容加密的应答是密文字符串, 带引号返回
+        let res_text = r#"{"alipay_trade_query_response":"hM7TQ18Q+/=","sign":"abc"}"#;
+        assert_eq!(
+            extract_response_content(res_text, "alipay_trade_query_response"),
+            Some(r#""hM7TQ18Q+/=""#)
+        );
+
+        assert_eq!(
+            extract_response_content(res_text, "alipay_trade_close_response"),
+            None
+        );
+        assert_eq!(
+            extract_response_content(
+                r#"{"alipay_trade_query_response":{"code":"#,
+                "alipay_trade_query_response"
+            ),
+            None
+        );
+        assert_eq!(
+            extract_response_content(
+                r#"{"alipay_trade_query_response":null}"#,
+                "alipay_trade_query_response"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_response_requires_sign() {
+        assert!(response_requires_sign(
+            &serde_json::json!({"code": "10000"})
+        ));
+        assert!(response_requires_sign(
+            &serde_json::json!({"code": "40004", "sub_code": "ACQ.TRADE_NOT_EXIST"})
+        ));
+        assert!(!response_requires_sign(
+            &serde_json::json!({"code": "40002", "sub_code": "isv.invalid-signature"})
+        ));
+        assert!(!response_requires_sign(&serde_json::Value::Null));
+    }
+
+    #[test]
+    fn test_openapi_aes() {
+        let aes_key = "MDEyMzQ1Njc4OWFiY2RlZg=="; // 0123456789abcdef
+        let content = r#"{"out_trade_no":"123"}"#;
+        // openssl enc -aes-128-cbc -K 30313233343536373839616263646566 -iv 00000000000000000000000000000000
+        let encrypted = "hM7TQ18QF5bhZ+Favk3byda24OaITkclVDgz4SHEe0g=";
+        assert_eq!(openapi_aes::encrypt(content, aes_key).unwrap(), encrypted);
+        assert_eq!(openapi_aes::decrypt(encrypted, aes_key).unwrap(), content);
+
+        let content = "中文内容";
+        let encrypted = openapi_aes::encrypt(content, aes_key).unwrap();
+        assert_eq!(openapi_aes::decrypt(&encrypted, aes_key).unwrap(), content);
+
+        // 密钥必须是 base64 的 16 字节
+        assert!(matches!(
+            openapi_aes::encrypt(content, "MDEyMzQ1Njc4OWFiY2Rl"),
+            Err(AlipayError::InvalidConfig(_))
+        ));
+        assert!(openapi_aes::decrypt(&encrypted, "ZmVkY2JhOTg3NjU0MzIxMA==").is_err());
     }
 }
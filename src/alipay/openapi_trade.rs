@@ -0,0 +1,138 @@
+use super::{
+    openapi::{
+        OpenApiNotifyPayload, OpenApiPayload, OpenApiRefundPayload, OpenApiRequestPayload,
+        OpenApiTradePayload,
+    },
+    AlipayError, AlipayOpenApiConfig,
+};
+use crate::core::{ChannelRefundRequest, ChargeStatus, RefundResult, RefundStatus};
+
+// 当面付, 付款码, 小程序和 App 支付这些只有 openapi 接口的渠道共用的请求
+// alipay_version == 2 的电脑网站和手机网站支付也用这些
+// app_auth_token 是服务商模式下商户授权的令牌
+
+/**
+ * 带上证书 SN, 配置了 alipay_aes_key 的话加密 biz_content, 最后签名
+ */
+fn sign_payload(
+    config: &AlipayOpenApiConfig,
+    payload: &mut impl OpenApiPayload,
+) -> Result<(), AlipayError> {
+    payload.set_cert_sn(
+        config.alipay_app_cert.as_deref(),
+        config.alipay_root_cert.as_deref(),
+    )?;
+    payload.encrypt_biz_content(config.alipay_aes_key.as_deref())?;
+    payload.sign_rsa2(&config.alipay_private_key_rsa2)?;
+    Ok(())
+}
+
+/**
+ * App 支付的 orderInfo 和电脑网站, 手机网站支付的表单由客户端提交给支付宝, 只带上证书 SN 签名, 不加密
+ */
+pub fn sign_client_request(
+    config: &AlipayOpenApiConfig,
+    payload: &mut OpenApiRequestPayload,
+) -> Result<(), AlipayError> {
+    payload.set_cert_sn(
+        config.alipay_app_cert.as_deref(),
+        config.alipay_root_cert.as_deref(),
+    )?;
+    payload.sign_rsa2(&config.alipay_private_key_rsa2)?;
+    Ok(())
+}
+
+/**
+ * 下单请求 (alipay.trade.precreate | alipay.trade.create | alipay.trade.pay) 带上证书 SN, 加密, 签名以后发送
+ * extend_biz_content 要在这之前调用
+ */
+pub async fn send_charge_request(
+    config: &AlipayOpenApiConfig,
+    mut payload: OpenApiRequestPayload,
+) -> Result<serde_json::Value, AlipayError> {
+    sign_payload(config, &mut payload)?;
+    payload.send_request(config.alipay_public_key()).await
+}
+
+/**
+ * alipay.trade.query | alipay.trade.close | alipay.trade.cancel 这些只需要 out_trade_no 的接口
+ */
+pub async fn send_trade_request(
+    config: &AlipayOpenApiConfig,
+    app_auth_token: Option<&str>,
+    method: &str,
+    merchant_order_no: &str,
+) -> Result<serde_json::Value, AlipayError> {
+    let mut payload = OpenApiTradePayload::new(
+        &config.alipay_app_id,
+        app_auth_token,
+        method,
+        merchant_order_no,
+    )?;
+    sign_payload(config, &mut payload)?;
+    payload.send_request(config.alipay_public_key()).await
+}
+
+/**
+ * alipay.trade.refund, fund_change = Y 才算退款成功
+ */
+pub async fn refund_trade(
+    config: &AlipayOpenApiConfig,
+    app_auth_token: Option<&str>,
+    &ChannelRefundRequest {
+        charge_merchant_order_no,
+        refund_amount,
+        refund_merchant_order_no,
+        description,
+        ..
+    }: &ChannelRefundRequest<'_>,
+) -> Result<RefundResult, AlipayError> {
+    let mut refund_payload = OpenApiRefundPayload::new(
+        &config.alipay_app_id,
+        app_auth_token,
+        charge_merchant_order_no,
+        refund_merchant_order_no,
+        refund_amount,
+        description,
+    )?;
+    sign_payload(config, &mut refund_payload)?;
+    let refund_response = refund_payload
+        .send_request(config.alipay_public_key())
+        .await?;
+    let mut result = RefundResult {
+        amount: refund_amount,
+        description: description.to_string(),
+        extra: refund_response.clone(),
+        ..Default::default()
+    };
+    let code = refund_response["code"].as_str();
+    if code == Some("10000") {
+        if refund_response["fund_change"].as_str() == Some("Y") {
+            result.status = RefundStatus::Success;
+        } else {
+            result.status = RefundStatus::Fail("fund_change != Y".to_string());
+        }
+    } else {
+        result.status = RefundStatus::Fail(format!("code = {:?}", code));
+        result.failure_msg = refund_response["msg"].as_str().map(|msg| msg.to_string());
+    }
+    Ok(result)
+}
+
+/**
+ * 验证异步通知的签名, TRADE_SUCCESS | TRADE_FINISHED 是支付成功
+ */
+pub fn verify_trade_notify(
+    config: &AlipayOpenApiConfig,
+    payload: &str,
+) -> Result<ChargeStatus, AlipayError> {
+    let notify_payload = OpenApiNotifyPayload::new(payload)?;
+    notify_payload.verify_rsa2_sign(config.alipay_public_key())?;
+    let trade_status = notify_payload.trade_status;
+    // TODO! 需要验证 OpenApiNotifyPayload 上的 out_trade_no 和 total_amount
+    if trade_status == "TRADE_SUCCESS" || trade_status == "TRADE_FINISHED" {
+        Ok(ChargeStatus::Success)
+    } else {
+        Ok(ChargeStatus::Fail)
+    }
+}
@@ -0,0 +1,114 @@
+use super::{refund_charge_to_balance, BalanceError};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelQueryRequest, ChannelRefundRequest, ChargeError,
+    ChargeResult, ChargeStatus, PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use crate::prisma::PrismaClient;
+use async_trait::async_trait;
+use serde_json::json;
+
+/**
+ * 余额支付, 不经过第三方渠道, 直接扣 app 下 uid 对应的余额
+ * 不需要渠道参数, 也没有异步通知
+ */
+pub struct Balance<'a> {
+    prisma_client: &'a PrismaClient,
+    app_id: String,
+}
+
+impl<'a> Balance<'a> {
+    pub fn new(prisma_client: &'a PrismaClient, app_id: &str) -> Self {
+        Self {
+            prisma_client,
+            app_id: app_id.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for Balance<'_> {
+    /**
+     * 这里只检查 uid, 返回 Pending, 扣款要等 charge 保存以后在 pay_charge 的事务里做
+     * 这样扣了余额的 charge 一定已经在数据库里
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_amount, uid, ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let uid = uid.ok_or_else(|| {
+            BalanceError::MalformedRequest("balance channel requires uid on order".to_string())
+        })?;
+        if charge_amount <= 0 {
+            return Err(BalanceError::MalformedRequest(format!(
+                "invalid charge amount {}",
+                charge_amount
+            ))
+            .into());
+        }
+        Ok(ChargeResult {
+            status: ChargeStatus::Pending,
+            credential: json!({ "uid": uid }),
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, _payload: &str) -> Result<ChargeStatus, ChargeError> {
+        Err(
+            BalanceError::MalformedRequest("balance channel has no charge notify".to_string())
+                .into(),
+        )
+    }
+
+    async fn create_refund(
+        &self,
+        &ChannelRefundRequest {
+            charge_id,
+            charge_amount,
+            charge_credential,
+            refund_id,
+            refund_amount,
+            description,
+            ..
+        }: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        let uid = charge_credential[PaymentChannel::Balance.to_string()]["uid"]
+            .as_str()
+            .ok_or_else(|| {
+                BalanceError::MalformedRequest("missing uid in charge credential".to_string())
+            })?;
+        let balance_transaction = refund_charge_to_balance(
+            self.prisma_client,
+            &self.app_id,
+            uid,
+            charge_id,
+            charge_amount,
+            refund_id,
+            refund_amount,
+            description,
+        )
+        .await?;
+        Ok(RefundResult {
+            status: RefundStatus::Success,
+            amount: refund_amount,
+            description: description.to_string(),
+            extra: json!({ "balance_transaction": balance_transaction.id }),
+            ..Default::default()
+        })
+    }
+
+    fn process_refund_notify(&self, _payload: &str) -> Result<RefundStatus, RefundError> {
+        Err(
+            BalanceError::MalformedRequest("balance channel has no refund notify".to_string())
+                .into(),
+        )
+    }
+
+    /**
+     * 余额支付的 charge 保存以后马上在 pay_charge 里扣款, 扣款失败的 charge 也不能再付款, 没有需要关闭的交易
+     */
+    async fn close_charge(&self, _request: &ChannelQueryRequest) -> Result<(), ChargeError> {
+        Ok(())
+    }
+}
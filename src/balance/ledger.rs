@@ -0,0 +1,373 @@
+use super::BalanceError;
+use crate::core::PaymentChannel;
+use crate::prisma::{balance_transaction, charge, order, user_balance, PrismaClient};
+use serde_json::json;
+
+/**
+ * 余额账本, 每个 app 下的每个 uid 一条 UserBalance 记录, 每次变动记一条 BalanceTransaction
+ * 金额都是货币的最小单位, 扣款的 BalanceTransaction.amount 是负数
+ */
+pub enum BalanceTransactionKind {
+    Recharge,
+    Payment,
+    Refund,
+}
+
+impl ToString for BalanceTransactionKind {
+    fn to_string(&self) -> String {
+        match self {
+            BalanceTransactionKind::Recharge => "recharge".to_string(),
+            BalanceTransactionKind::Payment => "payment".to_string(),
+            BalanceTransactionKind::Refund => "refund".to_string(),
+        }
+    }
+}
+
+/**
+ * 加减余额的金额都必须是正数, 扣款在流水里再记成负数
+ */
+fn check_amount(amount: i32, action: &str) -> Result<(), BalanceError> {
+    if amount <= 0 {
+        return Err(BalanceError::MalformedRequest(format!(
+            "invalid {} amount {}",
+            action, amount
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * 已退金额加上这次退款不能超过 charge 金额
+ */
+fn check_refundable_amount(
+    charge_amount: i32,
+    refunded_amount: i32,
+    refund_amount: i32,
+) -> Result<(), BalanceError> {
+    if refunded_amount + refund_amount > charge_amount {
+        return Err(BalanceError::MalformedRequest(format!(
+            "refund amount {} exceeds refundable amount {}",
+            refund_amount,
+            charge_amount - refunded_amount
+        )));
+    }
+    Ok(())
+}
+
+pub async fn load_user_balance(
+    prisma_client: &PrismaClient,
+    app_id: &str,
+    uid: &str,
+) -> Result<Option<user_balance::Data>, BalanceError> {
+    let user_balance = prisma_client
+        .user_balance()
+        .find_unique(user_balance::app_id_uid(
+            app_id.to_string(),
+            uid.to_string(),
+        ))
+        .exec()
+        .await?;
+    Ok(user_balance)
+}
+
+/**
+ * 扣余额, update 的条件里带上 balance >= amount, 余额不足的时候一行都不会更新
+ * 这样并发扣款也不会把余额扣成负数
+ * 需要在事务里调用, 扣款以后读到的余额才是这次扣款后的余额
+ */
+async fn debit_user_balance(
+    prisma_client: &PrismaClient,
+    app_id: &str,
+    uid: &str,
+    amount: i32,
+    description: &str,
+    params: Vec<balance_transaction::SetParam>,
+) -> Result<balance_transaction::Data, BalanceError> {
+    check_amount(amount, "debit")?;
+    let count = prisma_client
+        .user_balance()
+        .update_many(
+            vec![
+                user_balance::app_id::equals(app_id.to_string()),
+                user_balance::uid::equals(uid.to_string()),
+                user_balance::balance::gte(amount),
+            ],
+            vec![user_balance::balance::decrement(amount)],
+        )
+        .exec()
+        .await?;
+    if count == 0 {
+        return Err(BalanceError::InsufficientBalance(format!(
+            "balance of user {} is less than {}",
+            uid, amount
+        )));
+    }
+    let user_balance = load_user_balance(prisma_client, app_id, uid)
+        .await?
+        .ok_or_else(|| BalanceError::Unexpected(format!("balance of user {} not found", uid)))?;
+    create_balance_transaction(
+        prisma_client,
+        app_id,
+        uid,
+        BalanceTransactionKind::Payment,
+        -amount,
+        user_balance.balance,
+        description,
+        params,
+    )
+    .await
+}
+
+/**
+ * 用余额支付已经保存的 charge, 扣款, 记账和把 charge/order 标记为已支付在同一个事务里
+ * 任何一步失败整个事务回滚, 不会出现扣了钱 charge 却没支付的情况
+ * charge 已经支付或者 order 已经不是 created 状态 (比如被取消了) 的时候不扣款
+ */
+pub async fn pay_charge(
+    prisma_client: &PrismaClient,
+    charge: &charge::Data,
+    order_id: Option<&str>,
+    uid: &str,
+) -> Result<balance_transaction::Data, BalanceError> {
+    let time_paid = chrono::Utc::now().timestamp() as i32;
+    prisma_client
+        ._transaction()
+        .run(|tx| async move {
+            let balance_transaction = debit_user_balance(
+                &tx,
+                &charge.app_id,
+                uid,
+                charge.amount,
+                &charge.subject,
+                vec![balance_transaction::charge_id::set(Some(charge.id.clone()))],
+            )
+            .await?;
+            // credential 里记下 uid 和这次扣款的流水, 退款的时候退回到同一个用户
+            let mut credential = charge.credential.clone();
+            credential[PaymentChannel::Balance.to_string()] = json!({
+                "uid": uid,
+                "balance_transaction": balance_transaction.id,
+            });
+            let count = tx
+                .charge()
+                .update_many(
+                    vec![
+                        charge::id::equals(charge.id.clone()),
+                        charge::paid::equals(false),
+                    ],
+                    vec![
+                        charge::paid::set(true),
+                        charge::time_paid::set(Some(time_paid)),
+                        charge::credential::set(credential),
+                    ],
+                )
+                .exec()
+                .await?;
+            if count != 1 {
+                return Err(BalanceError::MalformedRequest(format!(
+                    "charge {} is already paid",
+                    charge.id
+                )));
+            }
+            if let Some(order_id) = order_id {
+                let count = tx
+                    .order()
+                    .update_many(
+                        vec![
+                            order::id::equals(order_id.to_string()),
+                            order::status::equals("created".to_string()),
+                        ],
+                        vec![
+                            order::paid::set(true),
+                            order::time_paid::set(Some(time_paid)),
+                            order::amount_paid::set(charge.amount),
+                            order::status::set("paid".to_string()),
+                        ],
+                    )
+                    .exec()
+                    .await?;
+                if count != 1 {
+                    return Err(BalanceError::MalformedRequest(format!(
+                        "order {} is not waiting for payment",
+                        order_id
+                    )));
+                }
+            }
+            Ok(balance_transaction)
+        })
+        .await
+}
+
+/**
+ * 加余额, 用户第一次充值的时候创建 UserBalance
+ */
+pub async fn credit_user_balance(
+    prisma_client: &PrismaClient,
+    app_id: &str,
+    uid: &str,
+    kind: BalanceTransactionKind,
+    amount: i32,
+    description: &str,
+    params: Vec<balance_transaction::SetParam>,
+) -> Result<balance_transaction::Data, BalanceError> {
+    check_amount(amount, "credit")?;
+    let user_balance = prisma_client
+        .user_balance()
+        .upsert(
+            user_balance::app_id_uid(app_id.to_string(), uid.to_string()),
+            user_balance::create(
+                crate::prisma::app::id::equals(app_id.to_string()),
+                uid.to_string(),
+                amount,
+                vec![],
+            ),
+            vec![user_balance::balance::increment(amount)],
+        )
+        .exec()
+        .await?;
+    create_balance_transaction(
+        prisma_client,
+        app_id,
+        uid,
+        kind,
+        amount,
+        user_balance.balance,
+        description,
+        params,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn create_balance_transaction(
+    prisma_client: &PrismaClient,
+    app_id: &str,
+    uid: &str,
+    kind: BalanceTransactionKind,
+    amount: i32,
+    balance: i32,
+    description: &str,
+    params: Vec<balance_transaction::SetParam>,
+) -> Result<balance_transaction::Data, BalanceError> {
+    let balance_transaction = prisma_client
+        .balance_transaction()
+        .create(
+            crate::utils::generate_id("txn_"),
+            crate::prisma::app::id::equals(app_id.to_string()),
+            uid.to_string(),
+            kind.to_string(),
+            amount,
+            balance,
+            description.to_string(),
+            params,
+        )
+        .exec()
+        .await?;
+    Ok(balance_transaction)
+}
+
+/**
+ * charge 上已经退回余额的总额, 用来防止超额退款
+ */
+async fn sum_refunded_amount(
+    prisma_client: &PrismaClient,
+    charge_id: &str,
+) -> Result<i32, BalanceError> {
+    let refunds = prisma_client
+        .balance_transaction()
+        .find_many(vec![
+            balance_transaction::charge_id::equals(Some(charge_id.to_string())),
+            balance_transaction::kind::equals(BalanceTransactionKind::Refund.to_string()),
+        ])
+        .exec()
+        .await?;
+    Ok(refunds.iter().map(|txn| txn.amount).sum())
+}
+
+/**
+ * 把 charge 的钱退回余额, 检查可退金额和加余额在同一个事务里
+ * 事务里先 update 一下 charge 拿到行锁, 同一个 charge 的并发退款会在这里排队
+ * 后面一个事务读到的已退金额包含前一个事务的退款, 不会超额退款
+ */
+#[allow(clippy::too_many_arguments)]
+pub async fn refund_charge_to_balance(
+    prisma_client: &PrismaClient,
+    app_id: &str,
+    uid: &str,
+    charge_id: &str,
+    charge_amount: i32,
+    refund_id: &str,
+    refund_amount: i32,
+    description: &str,
+) -> Result<balance_transaction::Data, BalanceError> {
+    prisma_client
+        ._transaction()
+        .run(|tx| async move {
+            tx.charge()
+                .update(
+                    charge::id::equals(charge_id.to_string()),
+                    vec![charge::updated_at::set(chrono::Utc::now().fixed_offset())],
+                )
+                .exec()
+                .await?;
+            let refunded_amount = sum_refunded_amount(&tx, charge_id).await?;
+            check_refundable_amount(charge_amount, refunded_amount, refund_amount)?;
+            credit_user_balance(
+                &tx,
+                app_id,
+                uid,
+                BalanceTransactionKind::Refund,
+                refund_amount,
+                description,
+                vec![
+                    balance_transaction::charge_id::set(Some(charge_id.to_string())),
+                    balance_transaction::refund_id::set(Some(refund_id.to_string())),
+                ],
+            )
+            .await
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{ChargeError, UserError};
+
+    #[test]
+    fn test_check_amount() {
+        assert!(check_amount(1, "debit").is_ok());
+        assert!(matches!(
+            check_amount(0, "debit"),
+            Err(BalanceError::MalformedRequest(_))
+        ));
+        assert!(matches!(
+            check_amount(-100, "credit"),
+            Err(BalanceError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_refundable_amount() {
+        assert!(check_refundable_amount(100, 0, 100).is_ok());
+        assert!(check_refundable_amount(100, 40, 60).is_ok());
+        assert!(check_refundable_amount(100, 40, 61).is_err());
+        assert!(check_refundable_amount(100, 100, 1).is_err());
+    }
+
+    #[test]
+    fn test_balance_transaction_kind() {
+        assert_eq!(BalanceTransactionKind::Recharge.to_string(), "recharge");
+        assert_eq!(BalanceTransactionKind::Payment.to_string(), "payment");
+        assert_eq!(BalanceTransactionKind::Refund.to_string(), "refund");
+    }
+
+    #[test]
+    fn test_balance_error_status() {
+        // 余额不足是请求的问题, 数据库错误是服务端的问题
+        let e: ChargeError = BalanceError::InsufficientBalance("".into()).into();
+        assert!(matches!(e, ChargeError::MalformedRequest(_)));
+        let e: UserError = BalanceError::Unexpected("sql error".into()).into();
+        assert!(matches!(e, UserError::Unexpected(_)));
+    }
+}
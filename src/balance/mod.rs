@@ -0,0 +1,60 @@
+mod balance_pay;
+mod ledger;
+
+mod error {
+    use crate::core::{ChargeError, RefundError, UserError};
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    pub enum BalanceError {
+        #[error("[Malformed Balance Request] {0}")]
+        MalformedRequest(String),
+        #[error("[Insufficient Balance] {0}")]
+        InsufficientBalance(String),
+        #[error("[Unexpected Balance Error] {0}")]
+        Unexpected(String),
+    }
+
+    impl From<prisma_client_rust::QueryError> for BalanceError {
+        fn from(e: prisma_client_rust::QueryError) -> Self {
+            BalanceError::Unexpected(format!("sql error: {:?}", e))
+        }
+    }
+
+    impl From<BalanceError> for ChargeError {
+        fn from(e: BalanceError) -> ChargeError {
+            tracing::error!("{:?}", e);
+            match e {
+                BalanceError::MalformedRequest(e) => ChargeError::MalformedRequest(e),
+                BalanceError::InsufficientBalance(e) => ChargeError::MalformedRequest(e),
+                BalanceError::Unexpected(e) => ChargeError::InternalError(e),
+            }
+        }
+    }
+
+    impl From<BalanceError> for UserError {
+        fn from(e: BalanceError) -> UserError {
+            tracing::error!("{:?}", e);
+            match e {
+                BalanceError::MalformedRequest(e) => UserError::BadRequest(e),
+                BalanceError::InsufficientBalance(e) => UserError::BadRequest(e),
+                BalanceError::Unexpected(e) => UserError::Unexpected(e),
+            }
+        }
+    }
+
+    impl From<BalanceError> for RefundError {
+        fn from(e: BalanceError) -> RefundError {
+            tracing::error!("{:?}", e);
+            match e {
+                BalanceError::MalformedRequest(e) => RefundError::BadRequest(e),
+                BalanceError::InsufficientBalance(e) => RefundError::BadRequest(e),
+                BalanceError::Unexpected(e) => RefundError::Unexpected(e),
+            }
+        }
+    }
+}
+
+pub use balance_pay::Balance;
+pub use error::*;
+pub use ledger::*;
@@ -3,14 +3,38 @@ use std::str::FromStr;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub enum PaymentChannel {
+    #[serde(rename = "alipay")]
+    Alipay,
     #[serde(rename = "alipay_pc_direct")]
     AlipayPcDirect,
     #[serde(rename = "alipay_wap")]
     AlipayWap,
+    #[serde(rename = "alipay_qr")]
+    AlipayQr,
+    #[serde(rename = "alipay_lite")]
+    AlipayLite,
+    #[serde(rename = "alipay_scan")]
+    AlipayScan,
+    #[serde(rename = "balance")]
+    Balance,
+    #[serde(rename = "paypal")]
+    Paypal,
+    #[serde(rename = "upacp_pc")]
+    UpacpPc,
+    #[serde(rename = "upacp_wap")]
+    UpacpWap,
+    #[serde(rename = "wx")]
+    Wx,
     #[serde(rename = "wx_pub")]
     WxPub,
+    #[serde(rename = "wx_pub_qr")]
+    WxPubQr,
+    #[serde(rename = "wx_pub_scan")]
+    WxPubScan,
     #[serde(rename = "wx_lite")]
     WxLite,
+    #[serde(rename = "wx_wap")]
+    WxWap,
 }
 
 impl FromStr for PaymentChannel {
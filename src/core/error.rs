@@ -60,3 +60,22 @@ impl IntoResponse for RefundError {
         (status_code, err_msg).into_response()
     }
 }
+
+#[derive(Error, Debug)]
+pub enum UserError {
+    #[error("[Bad User Request Payload] {0}")]
+    BadRequest(String),
+    #[error("[Unexpected User Request Error] {0}")]
+    Unexpected(String),
+}
+
+impl IntoResponse for UserError {
+    fn into_response(self) -> Response {
+        tracing::error!("{:?}", self);
+        let (status_code, err_msg) = match self {
+            UserError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            UserError::Unexpected(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+        };
+        (status_code, err_msg).into_response()
+    }
+}
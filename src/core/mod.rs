@@ -5,4 +5,4 @@ mod response;
 pub use channel::*;
 pub use error::*;
 pub use request::*;
-pub use response::{charge::*, order::*, refund::*};
+pub use response::{balance::*, charge::*, order::*, refund::*};
@@ -2,15 +2,16 @@ use super::error::{ChargeError, RefundError};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
 
 #[async_trait]
-pub trait ChannelHandler {
+pub trait ChannelHandler: Sync {
     async fn create_credential(
         &self,
         request: &ChannelChargeRequest,
-    ) -> Result<serde_json::Value, ChargeError>;
+    ) -> Result<ChargeResult, ChargeError>;
 
-    fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError>;
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError>;
 
     async fn create_refund(
         &self,
@@ -18,17 +19,114 @@ pub trait ChannelHandler {
     ) -> Result<RefundResult, RefundError>;
 
     fn process_refund_notify(&self, payload: &str) -> Result<RefundStatus, RefundError>;
+
+    /**
+     * 主动向渠道查询支付结果, 异步通知丢了的时候用来补单
+     * 返回 Pending 表示用户还没付款或者渠道上还没有这笔交易
+     * 不支持查询的渠道默认返回错误
+     */
+    async fn query_charge(
+        &self,
+        _request: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        Err(ChargeError::MalformedRequest(
+            "query_charge is not supported by this channel".to_string(),
+        ))
+    }
+
+    /**
+     * 在渠道上关闭还没有支付的交易, 取消 order 的时候用, 关闭以后用户不能再用原来的 credential 付款
+     * 渠道上还没有这笔交易 (用户还没打开收银台) 或者已经关闭的当作关闭成功, 已经支付的返回错误
+     * 不支持关单的渠道默认返回错误
+     */
+    async fn close_charge(&self, _request: &ChannelQueryRequest) -> Result<(), ChargeError> {
+        Err(ChargeError::MalformedRequest(
+            "close_charge is not supported by this channel".to_string(),
+        ))
+    }
+
+    /**
+     * 撤销交易, 不管用户有没有付款, 没付款的关闭交易, 已经付款的渠道原路退款, POS 冲正用
+     * 渠道要求重试 (比如支付宝 retry_flag = Y) 的时候返回错误, 调用方可以再次撤销
+     * 不支持撤销的渠道默认返回错误
+     */
+    async fn reverse_charge(&self, _request: &ChannelQueryRequest) -> Result<(), ChargeError> {
+        Err(ChargeError::MalformedRequest(
+            "reverse_charge is not supported by this channel".to_string(),
+        ))
+    }
+
+    /**
+     * 支付成功的异步通知里需要合并到 charge extra 上的信息, 比如微信境外支付的结算币种和汇率
+     * 大部分渠道没有, 默认返回 None
+     */
+    fn charge_notify_extra(&self, _payload: &str) -> Option<serde_json::Value> {
+        None
+    }
+
+    /**
+     * 异步通知里的商户订单号和金额, 用来和数据库里的 charge 对比, 不一致的通知不处理
+     * 大部分渠道没有返回 (或者还没有实现), 默认返回 None 不检查
+     */
+    fn charge_notify_order(&self, _payload: &str) -> Option<ChannelNotifyOrder> {
+        None
+    }
+
+    /**
+     * 同上, 退款通知里的商户订单号, 退款单号和退款金额
+     */
+    fn refund_notify_order(&self, _payload: &str) -> Option<ChannelRefundNotifyOrder> {
+        None
+    }
+
+    /**
+     * 验证异步通知请求头上的签名, 比如微信 v3 的 Wechatpay-Signature
+     * 大部分渠道的签名在报文里, 在 process_charge_notify 里验证, 默认不做处理
+     */
+    async fn verify_charge_notify_headers(
+        &self,
+        _headers: &HashMap<String, String>,
+        _payload: &str,
+    ) -> Result<(), ChargeError> {
+        Ok(())
+    }
+
+    async fn verify_refund_notify_headers(
+        &self,
+        _headers: &HashMap<String, String>,
+        _payload: &str,
+    ) -> Result<(), RefundError> {
+        Ok(())
+    }
 }
 
 pub struct ChannelChargeRequest<'a> {
     pub charge_id: &'a str,
     pub charge_amount: i32,
+    pub currency: &'a str, // 三位 ISO 货币代码, 小写 cny | usd | ...
     pub merchant_order_no: &'a str,
     pub client_ip: &'a str,
     pub time_expire: i32, // 过期时间 timestamp 精确到秒
     pub subject: &'a str,
     pub body: &'a str,
     pub extra: &'a ChannelChargeExtra,
+    pub uid: Option<&'a str>, // 下单用户, balance 渠道用来扣余额
+}
+
+pub struct ChannelQueryRequest<'a> {
+    pub merchant_order_no: &'a str,
+    pub currency: &'a str, // 微信境外支付走跨境接口的域名
+}
+
+pub struct ChannelNotifyOrder {
+    pub merchant_order_no: String,
+    pub amount: i32,
+}
+
+pub struct ChannelRefundNotifyOrder {
+    pub charge_merchant_order_no: String,
+    pub refund_merchant_order_no: String,
+    pub refund_amount: i32,
 }
 
 /**
@@ -42,18 +140,52 @@ pub struct ChannelChargeExtra {
     pub cancel_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub open_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<String>, // wx_pub_qr 专用, 商户自定义的商品 ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_id: Option<String>, // alipay_lite 专用, 支付宝用户 ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_code: Option<String>, // alipay_scan 和 wx_pub_scan 专用, 用户付款码
 }
 
 #[derive(Debug, PartialEq)]
 pub enum ChargeStatus {
+    Pending, // 需要等渠道的异步通知
     Success,
     Fail,
 }
 
+/**
+ * 大部分渠道 create_credential 以后是 Pending, 需要等异步通知
+ * 付款码这类渠道在 create_credential 的时候就同步拿到了支付结果
+ */
+#[derive(Debug)]
+pub struct ChargeResult {
+    pub status: ChargeStatus,
+    pub credential: serde_json::Value,    // 前端调起支付所需的参数
+    pub extra: Option<serde_json::Value>, // 同步拿到支付结果的时候, 需要合并到 charge extra 上的信息
+    pub failure_code: Option<String>,
+    pub failure_msg: Option<String>,
+}
+
+impl Default for ChargeResult {
+    fn default() -> Self {
+        ChargeResult {
+            status: ChargeStatus::Pending,
+            credential: json!({}),
+            extra: None,
+            failure_code: None,
+            failure_msg: None,
+        }
+    }
+}
+
 pub struct ChannelRefundRequest<'a> {
     pub charge_id: &'a str,
     pub charge_amount: i32,
+    pub charge_currency: &'a str,
     pub charge_merchant_order_no: &'a str,
+    pub charge_credential: &'a serde_json::Value, // upacp 退款需要原交易 credential 里的 txnTime, balance 退款需要 uid
     pub refund_id: &'a str,
     pub refund_amount: i32,
     pub refund_merchant_order_no: &'a str,
@@ -142,7 +142,7 @@ pub mod charge {
         pub api_base: String,
         pub app: String,
         pub channel: String,
-        pub order_no: String,  // 兼容 basic 和 order 的 charge 接口, basic 接口上的商户订单号是 order_no
+        pub order_no: String, // 兼容 basic 和 order 的 charge 接口, basic 接口上的商户订单号是 order_no
         pub merchant_order_no: String,
         pub paid: bool,
         pub amount: i32,
@@ -156,6 +156,8 @@ pub mod charge {
         pub time_expire: i32,
         pub failure_code: Option<String>,
         pub failure_msg: Option<String>,
+        pub reversed: bool,
+        pub time_reversed: Option<i32>,
         pub refunds: ListResponse<RefundResponse>,
     }
 
@@ -196,6 +198,8 @@ pub mod charge {
                 time_expire: charge.time_expire,
                 failure_code: charge.failure_code,
                 failure_msg: charge.failure_msg,
+                reversed: charge.reversed,
+                time_reversed: charge.time_reversed,
                 refunds,
             }
         }
@@ -247,3 +251,69 @@ pub mod refund {
         }
     }
 }
+
+pub mod balance {
+    use super::*;
+    use crate::prisma::{
+        balance_transaction::Data as BalanceTransactionData, user_balance::Data as UserBalanceData,
+    };
+
+    #[derive(Serialize, Debug)]
+    pub struct UserResponse {
+        pub id: String, // uid
+        pub object: String,
+        pub app: String,
+        pub available_balance: i32,
+        pub created: Option<i32>,
+    }
+
+    // 用户还没有充值过的时候没有 UserBalance 记录, 余额是 0
+    type T<'a> = (&'a str, &'a str, Option<&'a UserBalanceData>);
+    impl From<T<'_>> for UserResponse {
+        fn from((app_id, uid, user_balance): T) -> Self {
+            Self {
+                id: uid.to_string(),
+                object: "user".to_string(),
+                app: app_id.to_string(),
+                available_balance: user_balance.map_or(0, |user_balance| user_balance.balance),
+                created: user_balance
+                    .map(|user_balance| user_balance.created_at.timestamp() as i32),
+            }
+        }
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct BalanceTransactionResponse {
+        pub id: String,
+        pub object: String,
+        pub app: String,
+        pub user: String,
+        #[serde(rename = "type")]
+        pub kind: String, // recharge | payment | refund
+        pub amount: i32,
+        pub available_balance: i32,
+        pub charge: Option<String>,
+        pub refund: Option<String>,
+        pub description: String,
+        pub created: i32,
+    }
+
+    impl From<&BalanceTransactionData> for BalanceTransactionResponse {
+        fn from(balance_transaction: &BalanceTransactionData) -> Self {
+            let balance_transaction = balance_transaction.clone();
+            Self {
+                id: balance_transaction.id,
+                object: "balance_transaction".to_string(),
+                app: balance_transaction.app_id,
+                user: balance_transaction.uid,
+                kind: balance_transaction.kind,
+                amount: balance_transaction.amount,
+                available_balance: balance_transaction.balance,
+                charge: balance_transaction.charge_id,
+                refund: balance_transaction.refund_id,
+                description: balance_transaction.description,
+                created: balance_transaction.created_at.timestamp() as i32,
+            }
+        }
+    }
+}
@@ -15,10 +15,13 @@ use tower_http::{
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod alipay;
+mod balance;
 mod core;
+mod paypal;
 #[allow(dead_code, unused_imports)]
 mod prisma;
 mod routes;
+mod upacp;
 mod utils;
 mod weixin;
 
@@ -36,6 +39,12 @@ async fn main() {
             }
         }))
         .init();
+    // 定时刷新微信支付平台证书
+    tokio::spawn(weixin::run_platform_cert_refresher());
+    // 定时向渠道查询没有收到异步通知的 charge
+    tokio::spawn(routes::run_charge_recovery());
+    // 定时取消过期的 order
+    tokio::spawn(routes::run_order_expiry_sweeper());
     // build our application with a route
     let charge_routes = routes::get_routes().await;
     let app = Router::new()
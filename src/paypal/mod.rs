@@ -0,0 +1,55 @@
+mod orders;
+mod paypal_checkout;
+
+mod config {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    pub struct PaypalConfig {
+        pub paypal_client_id: String,
+        pub paypal_client_secret: String,
+        pub paypal_webhook_id: String, // 在 PayPal 开发者后台创建 webhook 以后得到, 验证 webhook 签名需要
+    }
+}
+
+mod error {
+    use crate::core::{ChargeError, RefundError};
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    pub enum PaypalError {
+        #[error("[Malformed Paypal Request] {0}")]
+        MalformedRequest(String),
+        #[error("[Failed Communicating Paypal API] {0}")]
+        ApiError(String),
+        #[error("[Invalid Paypal Channel Params] {0}")]
+        InvalidConfig(String),
+    }
+
+    impl From<PaypalError> for ChargeError {
+        fn from(e: PaypalError) -> ChargeError {
+            tracing::error!("{:?}", e);
+            match e {
+                PaypalError::MalformedRequest(e) => ChargeError::MalformedRequest(e),
+                PaypalError::ApiError(e) => ChargeError::InternalError(e),
+                PaypalError::InvalidConfig(e) => ChargeError::InternalError(e),
+            }
+        }
+    }
+
+    impl From<PaypalError> for RefundError {
+        fn from(e: PaypalError) -> RefundError {
+            tracing::error!("{:?}", e);
+            match e {
+                PaypalError::MalformedRequest(e) => RefundError::BadRequest(e),
+                PaypalError::ApiError(e) => RefundError::Unexpected(e),
+                PaypalError::InvalidConfig(e) => RefundError::Unexpected(e),
+            }
+        }
+    }
+}
+
+pub use config::*;
+use error::*;
+pub use orders::PaypalNotifyPayload;
+pub use paypal_checkout::Paypal;
@@ -0,0 +1,268 @@
+use super::{PaypalConfig, PaypalError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+/**
+ * 默认请求 PayPal 生产环境, 沙箱或者本地测试的时候设置环境变量 PAYPAL_API_BASE
+ * 比如 https://api-m.sandbox.paypal.com 或者 http://127.0.0.1:8080
+ */
+fn paypal_api_base() -> String {
+    std::env::var("PAYPAL_API_BASE").unwrap_or_else(|_| String::from("https://api-m.paypal.com"))
+}
+
+/**
+ * PayPal 的小数位数, 一般和 utils::currency_decimals 一样
+ * HUF TWD 按 ISO 4217 有两位小数, 但是 PayPal 不支持小数, charge 上的 amount 按没有小数处理
+ * https://developer.paypal.com/api/rest/reference/currency-codes/
+ */
+fn paypal_currency_decimals(currency: &str) -> i32 {
+    match currency.to_uppercase().as_str() {
+        "HUF" | "TWD" => 0,
+        _ => crate::utils::currency_decimals(currency),
+    }
+}
+
+/**
+ * charge 上的 amount 是货币的最小单位, PayPal 的金额是字符串
+ */
+pub fn format_amount(amount: i32, currency: &str) -> String {
+    let decimals = paypal_currency_decimals(currency);
+    format!(
+        "{:.*}",
+        decimals as usize,
+        amount as f64 / 10f64.powi(decimals)
+    )
+}
+
+/**
+ * PayPal 的 webhook 地址是在开发者后台统一配置的, 不能像其他渠道一样每个 charge 单独设置 notify_url
+ * 所以 webhook 和用户付款以后跳转回来 (return) 这两种通知都包装成 PaypalNotifyPayload 再保存到 charge_notify_history
+ * 这样 retry_notify 也可以重新处理
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PaypalNotifyPayload {
+    Webhook {
+        headers: HashMap<String, String>, // 验证签名需要的 paypal-* 请求头
+        body: String,
+    },
+    Return {
+        charge_id: String,
+        token: String, // PayPal 跳转回来的时候带上的 token 就是 PayPal 订单 ID
+    },
+}
+
+pub struct PaypalClient {
+    api_base: String,
+    access_token: String,
+}
+
+impl PaypalClient {
+    /**
+     * https://developer.paypal.com/api/rest/authentication/
+     */
+    pub async fn new(config: &PaypalConfig) -> Result<Self, PaypalError> {
+        let api_base = paypal_api_base();
+        let res = reqwest::Client::new()
+            .post(format!("{}/v1/oauth2/token", api_base))
+            .basic_auth(&config.paypal_client_id, Some(&config.paypal_client_secret))
+            .form(&[("grant_type", "client_credentials")])
+            .send()
+            .await
+            .map_err(|e| PaypalError::ApiError(format!("error request paypal token: {}", e)))?;
+        let res_json: serde_json::Value = res.json().await.map_err(|e| {
+            PaypalError::ApiError(format!("error deserialize paypal token response: {}", e))
+        })?;
+        let access_token = res_json["access_token"].as_str().ok_or_else(|| {
+            PaypalError::InvalidConfig(format!("error getting paypal access_token: {}", res_json))
+        })?;
+        Ok(Self {
+            api_base,
+            access_token: access_token.to_string(),
+        })
+    }
+
+    async fn send_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+        request_id: Option<&str>, // PayPal-Request-Id, 保证重复请求的幂等
+    ) -> Result<serde_json::Value, PaypalError> {
+        let mut req = reqwest::Client::new()
+            .request(method, format!("{}{}", self.api_base, path))
+            .bearer_auth(&self.access_token);
+        if let Some(request_id) = request_id {
+            req = req.header("PayPal-Request-Id", request_id);
+        }
+        if let Some(body) = body {
+            req = req.json(&body);
+        }
+        let res = req
+            .send()
+            .await
+            .map_err(|e| PaypalError::ApiError(format!("error request paypal {}: {}", path, e)))?;
+        let status = res.status();
+        let res_text = res.text().await.map_err(|e| {
+            PaypalError::ApiError(format!("error read paypal {} response: {}", path, e))
+        })?;
+        tracing::debug!("paypal {} response: {:?}", path, res_text);
+        if !status.is_success() {
+            return Err(PaypalError::ApiError(format!(
+                "paypal {} status {}: {}",
+                path, status, res_text
+            )));
+        }
+        let res_json: serde_json::Value = serde_json::from_str(&res_text).map_err(|e| {
+            PaypalError::ApiError(format!("error deserialize paypal {} response: {}", path, e))
+        })?;
+        Ok(res_json)
+    }
+
+    /**
+     * https://developer.paypal.com/docs/api/orders/v2/#orders_create
+     * custom_id 保存 charge_id, webhook 里面用它找到对应的 charge
+     */
+    pub async fn create_order(
+        &self,
+        charge_id: &str,
+        merchant_order_no: &str,
+        charge_amount: i32,
+        currency: &str,
+        subject: &str,
+        return_url: &str,
+        cancel_url: &str,
+    ) -> Result<serde_json::Value, PaypalError> {
+        let body = json!({
+            "intent": "CAPTURE",
+            "purchase_units": [{
+                "reference_id": merchant_order_no,
+                "invoice_id": merchant_order_no,
+                "custom_id": charge_id,
+                "description": crate::utils::truncate_utf8(subject, 127),
+                "amount": {
+                    "currency_code": currency.to_uppercase(),
+                    "value": format_amount(charge_amount, currency),
+                },
+            }],
+            "application_context": {
+                "return_url": return_url,
+                "cancel_url": cancel_url,
+                "user_action": "PAY_NOW",
+                "shipping_preference": "NO_SHIPPING",
+            },
+        });
+        self.send_request(
+            reqwest::Method::POST,
+            "/v2/checkout/orders",
+            Some(body),
+            Some(charge_id),
+        )
+        .await
+    }
+
+    /**
+     * https://developer.paypal.com/docs/api/orders/v2/#orders_get
+     */
+    pub async fn get_order(&self, order_id: &str) -> Result<serde_json::Value, PaypalError> {
+        let path = format!("/v2/checkout/orders/{}", order_id);
+        self.send_request(reqwest::Method::GET, &path, None, None)
+            .await
+    }
+
+    /**
+     * https://developer.paypal.com/docs/api/orders/v2/#orders_capture
+     */
+    pub async fn capture_order(&self, order_id: &str) -> Result<serde_json::Value, PaypalError> {
+        let path = format!("/v2/checkout/orders/{}/capture", order_id);
+        let request_id = format!("capture-{}", order_id);
+        self.send_request(
+            reqwest::Method::POST,
+            &path,
+            Some(json!({})),
+            Some(&request_id),
+        )
+        .await
+    }
+
+    /**
+     * https://developer.paypal.com/docs/api/payments/v2/#captures_refund
+     */
+    pub async fn refund_capture(
+        &self,
+        capture_id: &str,
+        refund_id: &str,
+        refund_merchant_order_no: &str,
+        refund_amount: i32,
+        currency: &str,
+        description: &str,
+    ) -> Result<serde_json::Value, PaypalError> {
+        let path = format!("/v2/payments/captures/{}/refund", capture_id);
+        let body = json!({
+            "amount": {
+                "currency_code": currency.to_uppercase(),
+                "value": format_amount(refund_amount, currency),
+            },
+            "invoice_id": refund_merchant_order_no,
+            "note_to_payer": crate::utils::truncate_utf8(description, 255),
+        });
+        self.send_request(reqwest::Method::POST, &path, Some(body), Some(refund_id))
+            .await
+    }
+
+    /**
+     * https://developer.paypal.com/docs/api/webhooks/v1/#verify-webhook-signature_post
+     */
+    pub async fn verify_webhook_signature(
+        &self,
+        webhook_id: &str,
+        headers: &HashMap<String, String>,
+        body: &str,
+    ) -> Result<(), PaypalError> {
+        fn missing_header(name: &str) -> PaypalError {
+            PaypalError::ApiError(format!("missing webhook header {}", name))
+        }
+        let header = |name: &str| headers.get(name).ok_or_else(|| missing_header(name));
+        let webhook_event: serde_json::Value = serde_json::from_str(body).map_err(|e| {
+            PaypalError::ApiError(format!("error deserialize paypal webhook event: {}", e))
+        })?;
+        let verify_body = json!({
+            "auth_algo": header("paypal-auth-algo")?,
+            "cert_url": header("paypal-cert-url")?,
+            "transmission_id": header("paypal-transmission-id")?,
+            "transmission_sig": header("paypal-transmission-sig")?,
+            "transmission_time": header("paypal-transmission-time")?,
+            "webhook_id": webhook_id,
+            "webhook_event": webhook_event,
+        });
+        let res_json = self
+            .send_request(
+                reqwest::Method::POST,
+                "/v1/notifications/verify-webhook-signature",
+                Some(verify_body),
+                None,
+            )
+            .await?;
+        if res_json["verification_status"].as_str() != Some("SUCCESS") {
+            return Err(PaypalError::ApiError(
+                "wrong paypal webhook signature".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(12345, "usd"), "123.45");
+        assert_eq!(format_amount(100, "JPY"), "100");
+        // PayPal 的 HUF TWD 不支持小数
+        assert_eq!(format_amount(1000, "HUF"), "1000");
+        assert_eq!(format_amount(1000, "twd"), "1000");
+    }
+}
@@ -0,0 +1,295 @@
+use super::{
+    orders::{PaypalClient, PaypalNotifyPayload},
+    PaypalConfig, PaypalError,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelRefundRequest, ChargeError, ChargeResult,
+    ChargeStatus, PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::HashMap;
+
+pub struct Paypal {
+    config: PaypalConfig,
+}
+
+impl Paypal {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+    ) -> Result<Self, PaypalError> {
+        let channel_params = crate::utils::load_channel_params_from_db(
+            &prisma_client,
+            app_id,
+            sub_app_id,
+            &PaymentChannel::Paypal.to_string(),
+        )
+        .await
+        .map_err(|e| PaypalError::InvalidConfig(format!("{:?}", e)))?;
+        let config: PaypalConfig = serde_json::from_value(channel_params.params).map_err(|e| {
+            PaypalError::InvalidConfig(format!("error deserializing paypal config: {:?}", e))
+        })?;
+        Ok(Self { config })
+    }
+
+    /**
+     * 用户在 PayPal 确认付款以后订单状态是 APPROVED, 需要商户调用 capture 才会扣款
+     * return 和 webhook 都可能先到, 所以先查询订单状态, 已经 capture 过的不再重复 capture
+     */
+    async fn capture_if_approved(
+        &self,
+        client: &PaypalClient,
+        paypal_order_id: &str,
+        charge_id: &str,
+    ) -> Result<ChargeStatus, PaypalError> {
+        let paypal_order = client.get_order(paypal_order_id).await?;
+        if paypal_order["purchase_units"][0]["custom_id"].as_str() != Some(charge_id) {
+            return Err(PaypalError::MalformedRequest(format!(
+                "paypal order {} does not belong to charge {}",
+                paypal_order_id, charge_id
+            )));
+        }
+        match paypal_order["status"].as_str() {
+            Some("COMPLETED") => Ok(capture_status(&paypal_order)),
+            Some("APPROVED") => {
+                let captured_order = client.capture_order(paypal_order_id).await?;
+                Ok(capture_status(&captured_order))
+            }
+            _ => Ok(ChargeStatus::Pending), // CREATED, PAYER_ACTION_REQUIRED 等, 用户还没有付款
+        }
+    }
+}
+
+/**
+ * 退款通知只有 webhook 一种, 返回签名需要的请求头和事件内容
+ */
+fn refund_webhook_payload(payload: &str) -> Result<(HashMap<String, String>, String), PaypalError> {
+    let notify_payload: PaypalNotifyPayload = serde_json::from_str(payload).map_err(|e| {
+        PaypalError::MalformedRequest(format!("error deserializing paypal notify: {:?}", e))
+    })?;
+    match notify_payload {
+        PaypalNotifyPayload::Webhook { headers, body } => Ok((headers, body)),
+        PaypalNotifyPayload::Return { .. } => Err(PaypalError::MalformedRequest(
+            "paypal refund notify must be a webhook".into(),
+        )),
+    }
+}
+
+/**
+ * capture 的状态是 PENDING 的时候 (比如 eCheck) 要等 PAYMENT.CAPTURE.COMPLETED 的 webhook
+ */
+fn capture_status(paypal_order: &serde_json::Value) -> ChargeStatus {
+    match paypal_order["purchase_units"][0]["payments"]["captures"][0]["status"].as_str() {
+        Some("COMPLETED") => ChargeStatus::Success,
+        Some("DECLINED") | Some("FAILED") => ChargeStatus::Fail,
+        _ => ChargeStatus::Pending,
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for Paypal {
+    /**
+     * 创建 PayPal 订单, credential 里返回 approval_url, 前端跳转过去让用户登录 PayPal 付款
+     * 付款以后 PayPal 先跳转回 paypal_return 完成 capture, 再跳转到 success_url
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            currency,
+            merchant_order_no,
+            subject,
+            extra,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let success_url = match extra.success_url.as_ref() {
+            Some(url) => url.to_string(),
+            None => {
+                return Err(ChargeError::MalformedRequest(
+                    "missing success_url in charge extra".to_string(),
+                ))
+            }
+        };
+        let cancel_url = extra.cancel_url.clone().unwrap_or(success_url);
+        let return_url = format!(
+            "{}/notify/charges/{}/paypal_return",
+            crate::utils::api_base(),
+            charge_id
+        );
+        let client = PaypalClient::new(config).await?;
+        let paypal_order = client
+            .create_order(
+                charge_id,
+                merchant_order_no,
+                charge_amount,
+                currency,
+                subject,
+                &return_url,
+                &cancel_url,
+            )
+            .await?;
+        let paypal_order_id = paypal_order["id"].as_str().ok_or_else(|| {
+            PaypalError::ApiError("missing id in paypal create order response".into())
+        })?;
+        let approval_url = paypal_order["links"]
+            .as_array()
+            .and_then(|links| {
+                links.iter().find(|link| {
+                    let rel = link["rel"].as_str();
+                    rel == Some("approve") || rel == Some("payer-action")
+                })
+            })
+            .and_then(|link| link["href"].as_str())
+            .ok_or_else(|| {
+                PaypalError::ApiError("missing approve link in paypal create order response".into())
+            })?;
+        Ok(ChargeResult {
+            credential: json!({
+                "order_id": paypal_order_id,
+                "approval_url": approval_url,
+            }),
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        let config = &self.config;
+        let notify_payload: PaypalNotifyPayload = serde_json::from_str(payload).map_err(|e| {
+            PaypalError::MalformedRequest(format!("error deserializing paypal notify: {:?}", e))
+        })?;
+        let client = PaypalClient::new(config).await?;
+        match notify_payload {
+            PaypalNotifyPayload::Return { charge_id, token } => Ok(self
+                .capture_if_approved(&client, &token, &charge_id)
+                .await?),
+            PaypalNotifyPayload::Webhook { headers, body } => {
+                client
+                    .verify_webhook_signature(&config.paypal_webhook_id, &headers, &body)
+                    .await?;
+                let event: serde_json::Value = serde_json::from_str(&body).map_err(|e| {
+                    PaypalError::ApiError(format!("error deserializing paypal webhook: {:?}", e))
+                })?;
+                let resource = &event["resource"];
+                match event["event_type"].as_str() {
+                    Some("CHECKOUT.ORDER.APPROVED") => {
+                        let paypal_order_id = resource["id"].as_str().unwrap_or_default();
+                        let charge_id = resource["purchase_units"][0]["custom_id"]
+                            .as_str()
+                            .unwrap_or_default();
+                        Ok(self
+                            .capture_if_approved(&client, paypal_order_id, charge_id)
+                            .await?)
+                    }
+                    Some("PAYMENT.CAPTURE.COMPLETED") => Ok(ChargeStatus::Success),
+                    Some("PAYMENT.CAPTURE.DENIED") | Some("PAYMENT.CAPTURE.DECLINED") => {
+                        Ok(ChargeStatus::Fail)
+                    }
+                    _ => Ok(ChargeStatus::Pending),
+                }
+            }
+        }
+    }
+
+    /**
+     * 退款需要 capture id, 用 credential 里保存的 PayPal 订单 ID 查询得到
+     */
+    async fn create_refund(
+        &self,
+        &ChannelRefundRequest {
+            charge_credential,
+            refund_id,
+            refund_amount,
+            refund_merchant_order_no,
+            description,
+            ..
+        }: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        let config = &self.config;
+        let paypal_order_id = charge_credential[PaymentChannel::Paypal.to_string()]["order_id"]
+            .as_str()
+            .ok_or_else(|| {
+                PaypalError::MalformedRequest("missing order_id in charge credential".into())
+            })?;
+        let client = PaypalClient::new(config).await?;
+        let paypal_order = client.get_order(paypal_order_id).await?;
+        let capture = &paypal_order["purchase_units"][0]["payments"]["captures"][0];
+        let capture_id = capture["id"].as_str().ok_or_else(|| {
+            PaypalError::MalformedRequest(format!("paypal order {} not captured", paypal_order_id))
+        })?;
+        let currency = capture["amount"]["currency_code"]
+            .as_str()
+            .unwrap_or_default();
+        let refund_response = client
+            .refund_capture(
+                capture_id,
+                refund_id,
+                refund_merchant_order_no,
+                refund_amount,
+                currency,
+                description,
+            )
+            .await?;
+        let mut result = RefundResult {
+            amount: refund_amount,
+            description: description.to_string(),
+            extra: refund_response.clone(),
+            ..Default::default()
+        };
+        match refund_response["status"].as_str() {
+            Some("COMPLETED") => result.status = RefundStatus::Success,
+            Some("PENDING") => result.status = RefundStatus::Pending,
+            status => {
+                result.status = RefundStatus::Fail(format!("status = {:?}", status));
+                result.failure_msg = refund_response["status_details"]["reason"]
+                    .as_str()
+                    .map(|reason| reason.to_string());
+            }
+        }
+        Ok(result)
+    }
+
+    /**
+     * 退款 webhook 的签名要调用 PayPal 接口验证, process_refund_notify 不是 async 的, 所以在这里验证
+     * 签名需要的请求头保存在 PaypalNotifyPayload 里, 不用 headers 参数
+     */
+    async fn verify_refund_notify_headers(
+        &self,
+        _headers: &HashMap<String, String>,
+        payload: &str,
+    ) -> Result<(), RefundError> {
+        let config = &self.config;
+        let (headers, body) = refund_webhook_payload(payload)?;
+        let client = PaypalClient::new(config).await?;
+        client
+            .verify_webhook_signature(&config.paypal_webhook_id, &headers, &body)
+            .await?;
+        Ok(())
+    }
+
+    /**
+     * PAYMENT.CAPTURE.REFUNDED | PAYMENT.REFUND.PENDING | PAYMENT.REFUND.FAILED 的 resource 都是退款, 按退款的 status 处理
+     * 退款的时候 PayPal 返回 PENDING 的, 等这里的 webhook 确认最终结果
+     */
+    fn process_refund_notify(&self, payload: &str) -> Result<RefundStatus, RefundError> {
+        let (_headers, body) = refund_webhook_payload(payload)?;
+        let event: serde_json::Value = serde_json::from_str(&body).map_err(|e| {
+            PaypalError::ApiError(format!("error deserializing paypal webhook: {:?}", e))
+        })?;
+        let resource = &event["resource"];
+        match resource["status"].as_str() {
+            Some("COMPLETED") => Ok(RefundStatus::Success),
+            Some("PENDING") => Ok(RefundStatus::Pending),
+            status => Ok(RefundStatus::Fail(
+                resource["status_details"]["reason"]
+                    .as_str()
+                    .map(|reason| reason.to_string())
+                    .unwrap_or_else(|| format!("status = {:?}", status)),
+            )),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::balance::{credit_user_balance, load_user_balance, BalanceTransactionKind};
+use crate::core::{BalanceTransactionResponse, UserError, UserResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize, Debug)]
+pub struct ListBalanceTransactionsQuery {
+    pub limit: Option<i64>, // 默认 20, 最多 100
+    pub skip: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateRechargeRequestPayload {
+    pub amount: i32,
+    pub description: String,
+}
+
+async fn check_app_exists(
+    prisma_client: &crate::prisma::PrismaClient,
+    app_id: &str,
+) -> Result<(), UserError> {
+    prisma_client
+        .app()
+        .find_unique(crate::prisma::app::id::equals(app_id.to_string()))
+        .exec()
+        .await
+        .map_err(|e| UserError::Unexpected(format!("sql error: {:?}", e)))?
+        .ok_or_else(|| UserError::BadRequest(format!("app {} not found", app_id)))?;
+    Ok(())
+}
+
+pub async fn retrieve_user(
+    prisma_client: &crate::prisma::PrismaClient,
+    app_id: String,
+    uid: String,
+) -> Result<serde_json::Value, UserError> {
+    check_app_exists(prisma_client, &app_id).await?;
+    let user_balance = load_user_balance(prisma_client, &app_id, &uid).await?;
+    let user_response: UserResponse = (app_id.as_str(), uid.as_str(), user_balance.as_ref()).into();
+    let result = serde_json::to_value(user_response)
+        .map_err(|e| UserError::Unexpected(format!("error serializing user response: {:?}", e)))?;
+    Ok(result)
+}
+
+pub async fn list_balance_transactions(
+    prisma_client: &crate::prisma::PrismaClient,
+    app_id: String,
+    uid: String,
+    query: ListBalanceTransactionsQuery,
+) -> Result<serde_json::Value, UserError> {
+    check_app_exists(prisma_client, &app_id).await?;
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let mut balance_transactions = prisma_client
+        .balance_transaction()
+        .find_many(vec![
+            crate::prisma::balance_transaction::app_id::equals(app_id.clone()),
+            crate::prisma::balance_transaction::uid::equals(uid.clone()),
+        ])
+        .order_by(crate::prisma::balance_transaction::created_at::order(
+            prisma_client_rust::Direction::Desc,
+        ))
+        .skip(query.skip.unwrap_or(0).max(0))
+        .take(limit + 1) // 多取一条用来判断 has_more
+        .exec()
+        .await
+        .map_err(|e| UserError::Unexpected(format!("sql error: {:?}", e)))?;
+    let has_more = balance_transactions.len() as i64 > limit;
+    balance_transactions.truncate(limit as usize);
+    let data = balance_transactions
+        .iter()
+        .map(|balance_transaction| balance_transaction.into())
+        .collect::<Vec<BalanceTransactionResponse>>();
+    Ok(json!({
+        "object": "list",
+        "url": format!("/v1/apps/{}/users/{}/balance_transactions", app_id, uid),
+        "has_more": has_more,
+        "data": data,
+    }))
+}
+
+/**
+ * 给用户充值余额, 充值的钱由商户系统自己收, 这里只记账
+ */
+pub async fn create_recharge(
+    prisma_client: &crate::prisma::PrismaClient,
+    app_id: String,
+    uid: String,
+    payload: CreateRechargeRequestPayload,
+) -> Result<serde_json::Value, UserError> {
+    check_app_exists(prisma_client, &app_id).await?;
+    let balance_transaction = credit_user_balance(
+        prisma_client,
+        &app_id,
+        &uid,
+        BalanceTransactionKind::Recharge,
+        payload.amount,
+        &payload.description,
+        vec![],
+    )
+    .await?;
+    let balance_transaction_response: BalanceTransactionResponse = (&balance_transaction).into();
+    let result = serde_json::to_value(balance_transaction_response).map_err(|e| {
+        UserError::Unexpected(format!(
+            "error serializing balance transaction response: {:?}",
+            e
+        ))
+    })?;
+    Ok(result)
+}
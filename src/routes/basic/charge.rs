@@ -1,10 +1,11 @@
 use crate::core::{
-    ChannelChargeExtra, ChannelChargeRequest, ChannelHandler, ChargeError, ChargeResponse,
-    PaymentChannel,
+    ChannelChargeExtra, ChannelChargeRequest, ChannelQueryRequest, ChargeError, ChargeResponse,
+    ChargeStatus, PaymentChannel,
 };
-use crate::{alipay, weixin};
+use crate::routes::prelude::load_channel_handler;
 use serde::Deserialize;
 use serde_json::json;
+use std::str::FromStr;
 
 #[derive(Deserialize, Debug)]
 pub struct App {
@@ -28,7 +29,7 @@ pub struct CreateChargeRequestPayload {
 }
 
 pub async fn create_charge(
-    prisma_client: &crate::prisma::PrismaClient,
+    prisma_client: &std::sync::Arc<crate::prisma::PrismaClient>,
     charge_req_payload: CreateChargeRequestPayload,
 ) -> Result<serde_json::Value, ChargeError> {
     let charge_id = crate::utils::generate_id("ch_");
@@ -41,20 +42,15 @@ pub async fn create_charge(
         .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?
         .ok_or_else(|| ChargeError::MalformedRequest("app not found".to_string()))?;
 
-    let handler: Box<dyn ChannelHandler + Send> = match charge_req_payload.channel {
-        PaymentChannel::AlipayPcDirect => {
-            Box::new(alipay::AlipayPcDirect::new(&prisma_client, Some(&app.id), None).await?)
-        }
-        PaymentChannel::AlipayWap => {
-            Box::new(alipay::AlipayWap::new(&prisma_client, Some(&app.id), None).await?)
-        }
-        PaymentChannel::WxPub => {
-            Box::new(weixin::WxPub::new(&prisma_client, Some(&app.id), None).await?)
-        }
-        PaymentChannel::WxLite => {
-            Box::new(weixin::WxLite::new(&prisma_client, Some(&app.id), None).await?)
-        }
-    };
+    // 余额支付需要 order 上的 uid, 只能在 /v1/orders/:order_id/pay 上使用
+    if matches!(charge_req_payload.channel, PaymentChannel::Balance) {
+        return Err(ChargeError::MalformedRequest(
+            "balance channel is only supported by /v1/orders/:order_id/pay".to_string(),
+        ));
+    }
+
+    let handler =
+        load_channel_handler(prisma_client, &charge_req_payload.channel, &app.id, None).await?;
 
     let time_expire = match charge_req_payload.time_expire {
         Some(time_expire) => time_expire,
@@ -65,16 +61,18 @@ pub async fn create_charge(
         }
     };
 
-    let credential_object = handler
+    let charge_result = handler
         .create_credential(&ChannelChargeRequest {
             charge_id: &charge_id,
             charge_amount: charge_req_payload.charge_amount,
+            currency: &charge_req_payload.currency,
             merchant_order_no: &charge_req_payload.merchant_order_no,
             client_ip: &charge_req_payload.client_ip,
             time_expire,
             subject: &charge_req_payload.subject,
             body: &charge_req_payload.body,
             extra: &charge_req_payload.extra,
+            uid: None,
         })
         .await?;
 
@@ -88,7 +86,7 @@ pub async fn create_charge(
             .as_str()
             .unwrap()
             .to_owned();
-        credential[key] = credential_object;
+        credential[key] = charge_result.credential;
         credential
     };
 
@@ -112,13 +110,43 @@ pub async fn create_charge(
             extra,
             credential,
             time_expire,
-            vec![],
+            vec![
+                crate::prisma::charge::failure_code::set(charge_result.failure_code),
+                crate::prisma::charge::failure_msg::set(charge_result.failure_msg),
+            ],
         )
         .exec()
         .await
         .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
 
-    let refunds: Vec<crate::prisma::refund::Data> = vec![];
+    // 付款码这类渠道已经同步拿到了支付结果, 不会再有异步通知
+    if charge_result.status == ChargeStatus::Success {
+        super::super::notify::settle_paid_charge(
+            &prisma_client,
+            &charge,
+            None,
+            charge_result.extra,
+        )
+        .await?;
+    } else if charge_result.status == ChargeStatus::Pending
+        && matches!(
+            charge_req_payload.channel,
+            PaymentChannel::AlipayScan | PaymentChannel::WxPubScan
+        )
+    {
+        // 付款码支付用户需要输入密码, charge 已经以 pending 状态保存, 在后台等支付结果
+        super::super::notify::spawn_scan_pay_poller(
+            prisma_client.clone(),
+            charge.clone(),
+            app.id.clone(),
+            None,
+            None,
+        );
+    }
+
+    // 重新 load 一下 charge 数据，因为 charge.paid 可能已经更新
+    let (charge, _order, refunds, _app, _sub_app) =
+        crate::utils::load_charge_from_db(&prisma_client, &charge.id).await?;
     let charge_response: ChargeResponse = (&charge, &refunds, &app).into();
     let result = serde_json::to_value(charge_response).map_err(|e| {
         ChargeError::InternalError(format!("error serializing charge response: {:?}", e))
@@ -141,3 +169,47 @@ pub async fn retrieve_charge(
 
     Ok(result)
 }
+
+/**
+ * 异步通知丢了的时候主动向渠道查询支付结果, 返回最新的 charge
+ */
+pub async fn sync_charge(
+    prisma_client: &crate::prisma::PrismaClient,
+    charge_id: String,
+) -> Result<serde_json::Value, ChargeError> {
+    let charge_status = super::super::notify::sync_charge_status(prisma_client, &charge_id).await?;
+    tracing::info!(charge_id, "sync_charge: {:?}", charge_status);
+    retrieve_charge(prisma_client, charge_id).await
+}
+
+/**
+ * 撤销 charge, 不管用户有没有付款, 没付款的渠道关闭交易, 已经付款的渠道原路退款
+ * 撤销成功以后在 charge 上记录 reversed 和 time_reversed, 已经支付的同时更新 order 的退款金额, 返回最新的 charge, 已经撤销过的直接返回
+ */
+pub async fn reverse_charge(
+    prisma_client: &crate::prisma::PrismaClient,
+    charge_id: String,
+) -> Result<serde_json::Value, ChargeError> {
+    let (charge, _order, _refunds, app, sub_app) =
+        crate::utils::load_charge_from_db(prisma_client, &charge_id).await?;
+    if charge.reversed {
+        return retrieve_charge(prisma_client, charge_id).await;
+    }
+
+    let channel = PaymentChannel::from_str(&charge.channel).map_err(|e| {
+        ChargeError::InternalError(format!("error parsing charge channel: {:?}", e))
+    })?;
+    let sub_app_id = sub_app.as_ref().map(|sub_app| sub_app.id.as_str());
+    let handler = load_channel_handler(prisma_client, &channel, &app.id, sub_app_id).await?;
+    handler
+        .reverse_charge(&ChannelQueryRequest {
+            merchant_order_no: &charge.merchant_order_no,
+            currency: &charge.currency,
+        })
+        .await?;
+
+    super::super::notify::settle_reversed_charge(prisma_client, &charge_id, vec![]).await?;
+    tracing::info!(charge_id, channel = charge.channel, "charge reversed");
+
+    retrieve_charge(prisma_client, charge_id).await
+}
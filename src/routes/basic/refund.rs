@@ -1,8 +1,8 @@
 use crate::core::{
-    ChannelHandler, ChannelRefundExtra, ChannelRefundRequest, PaymentChannel, RefundError,
-    RefundResponse, RefundStatus,
+    ChannelRefundExtra, ChannelRefundRequest, PaymentChannel, RefundError, RefundResponse,
+    RefundStatus,
 };
-use crate::{alipay, weixin};
+use crate::routes::prelude::load_channel_handler;
 use serde::Deserialize;
 use std::str::FromStr;
 
@@ -24,32 +24,28 @@ pub async fn create_refund(
     let (charge, _order, _refunds, app, _sub_app) =
         crate::utils::load_charge_from_db(&prisma_client, &charge_id).await?;
 
+    if charge.reversed {
+        return Err(RefundError::BadRequest(format!(
+            "charge {} is reversed",
+            charge_id
+        )));
+    }
+
     let channel = PaymentChannel::from_str(&charge.channel).map_err(|e| {
         RefundError::Unexpected(format!(
             "channel {} on refunding charge {} is invalid: {:?}",
             charge.channel, charge.id, e
         ))
     })?;
-    let handler: Box<dyn ChannelHandler + Send> = match channel {
-        PaymentChannel::AlipayPcDirect => {
-            Box::new(alipay::AlipayPcDirect::new(&prisma_client, Some(&app.id), None).await?)
-        }
-        PaymentChannel::AlipayWap => {
-            Box::new(alipay::AlipayWap::new(&prisma_client, Some(&app.id), None).await?)
-        }
-        PaymentChannel::WxPub => {
-            Box::new(weixin::WxPub::new(&prisma_client, Some(&app.id), None).await?)
-        }
-        PaymentChannel::WxLite => {
-            Box::new(weixin::WxLite::new(&prisma_client, Some(&app.id), None).await?)
-        }
-    };
+    let handler = load_channel_handler(prisma_client, &channel, &app.id, None).await?;
 
     let refund_result = handler
         .create_refund(&ChannelRefundRequest {
             charge_id: &charge.id,
             charge_amount: charge.amount,
+            charge_currency: &charge.currency,
             charge_merchant_order_no: &charge.merchant_order_no,
+            charge_credential: &charge.credential,
             refund_id: &refund_id,
             refund_amount: refund_req_payload.amount,
             refund_merchant_order_no: &refund_merchant_order_no,
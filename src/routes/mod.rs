@@ -1,3 +1,4 @@
+mod balance;
 mod basic;
 mod notify;
 mod order;
@@ -7,11 +8,17 @@ use axum::{
     extract::{Path, Query, Request},
     http::{HeaderMap, StatusCode},
     middleware::{self, Next},
-    response::{IntoResponse, Json, Response},
+    response::{IntoResponse, Json, Redirect, Response},
     routing::{get, post, put},
     Router,
 };
-use notify::{create_charge_notify, create_refund_notify, retry_notify};
+use balance::{create_recharge, list_balance_transactions, retrieve_user};
+use notify::{
+    create_charge_notify, create_paypal_return_notify, create_paypal_webhook_notify,
+    create_refund_notify, retry_notify,
+};
+pub use notify::{run_charge_recovery, run_order_expiry_sweeper};
+use std::collections::HashMap;
 use sub_app::{create_or_update_sub_app_channel, retrieve_sub_app};
 
 async fn auth(req: Request, next: Next) -> Result<Response, StatusCode> {
@@ -32,6 +39,20 @@ async fn auth(req: Request, next: Next) -> Result<Response, StatusCode> {
     Err(StatusCode::UNAUTHORIZED)
 }
 
+/**
+ * 只保留验证微信 v3 通知签名需要的 wechatpay-* 请求头
+ */
+fn wechatpay_headers(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("wechatpay-"))
+        .map(|(name, value)| {
+            let value = value.to_str().unwrap_or_default().to_string();
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
 pub async fn get_routes() -> Router {
     let prisma_client = crate::prisma::new_client()
         .await
@@ -57,12 +78,26 @@ pub async fn get_routes() -> Router {
         })
         .route("/v1/orders/:order_id", {
             let prisma_client = prisma_client.clone();
+            let update_prisma_client = prisma_client.clone();
             get(|Path(order_id): Path<String>| async move {
                 match order::retrieve_order(&prisma_client, order_id).await {
                     Ok(result) => Ok(Json(result)),
                     Err(error) => Err(error.into_response()),
                 }
             })
+            .merge(put(|Path(order_id): Path<String>, body: String| async move {
+                tracing::info!(order_id, body, "update_order");
+                let payload: order::UpdateOrderRequestPayload = serde_json::from_str(&body)
+                    .map_err(|e| {
+                        let err_msg =
+                            format!("error parsing update_order request payload: {:?}", e);
+                        (StatusCode::BAD_REQUEST, err_msg).into_response()
+                    })?;
+                match order::update_order(&update_prisma_client, order_id, payload).await {
+                    Ok(result) => Ok(Json(result)),
+                    Err(error) => Err(error.into_response()),
+                }
+            }))
         })
         .route("/v1/orders/:order_id/pay", {
             let prisma_client = prisma_client.clone();
@@ -134,6 +169,26 @@ pub async fn get_routes() -> Router {
                 }
             })
         })
+        .route("/v1/charges/:charge_id/sync", {
+            let prisma_client = prisma_client.clone();
+            post(|Path(charge_id): Path<String>| async move {
+                tracing::info!(charge_id, "sync_charge");
+                match basic::sync_charge(&prisma_client, charge_id).await {
+                    Ok(result) => Ok(Json(result)),
+                    Err(error) => Err(error.into_response()),
+                }
+            })
+        })
+        .route("/v1/charges/:charge_id/reverse", {
+            let prisma_client = prisma_client.clone();
+            post(|Path(charge_id): Path<String>| async move {
+                tracing::info!(charge_id, "reverse_charge");
+                match basic::reverse_charge(&prisma_client, charge_id).await {
+                    Ok(result) => Ok(Json(result)),
+                    Err(error) => Err(error.into_response()),
+                }
+            })
+        })
         .route("/v1/charges/:charge_id/refunds", {
             let prisma_client = prisma_client.clone();
             post(|Path(charge_id): Path<String>, body: String| async move {
@@ -216,6 +271,57 @@ pub async fn get_routes() -> Router {
                 },
             )
         })
+        .route("/v1/apps/:app_id/users/:user_id", {
+            let prisma_client = prisma_client.clone();
+            get(
+                |Path((app_id, user_id)): Path<(String, String)>| async move {
+                    match retrieve_user(&prisma_client, app_id, user_id).await {
+                        Ok(result) => Ok(Json(result)),
+                        Err(error) => Err(error.into_response()),
+                    }
+                },
+            )
+        })
+        .route("/v1/apps/:app_id/users/:user_id/balance_transactions", {
+            let prisma_client = prisma_client.clone();
+            get({
+                let prisma_client = prisma_client.clone();
+                |Path((app_id, user_id)): Path<(String, String)>,
+                 Query(query): Query<balance::ListBalanceTransactionsQuery>| async move {
+                    match list_balance_transactions(&prisma_client, app_id, user_id, query).await {
+                        Ok(result) => Ok(Json(result)),
+                        Err(error) => Err(error.into_response()),
+                    }
+                }
+            })
+            .post(
+                |Path((app_id, user_id)): Path<(String, String)>, body: String| async move {
+                    tracing::info!(app_id, user_id, body, "create_recharge");
+                    let payload: balance::CreateRechargeRequestPayload =
+                        serde_json::from_str(&body).map_err(|e| {
+                            let err_msg =
+                                format!("error parsing create_recharge request payload: {:?}", e);
+                            (StatusCode::BAD_REQUEST, err_msg).into_response()
+                        })?;
+                    match create_recharge(&prisma_client, app_id, user_id, payload).await {
+                        Ok(result) => Ok(Json(result)),
+                        Err(error) => Err(error.into_response()),
+                    }
+                },
+            )
+        })
+        .route(
+            "/v1/metrics",
+            get(|| async {
+                (
+                    [(
+                        axum::http::header::CONTENT_TYPE,
+                        "text/plain; version=0.0.4",
+                    )],
+                    notify::charge_recovery_metrics(),
+                )
+            }),
+        )
         .layer(middleware::from_fn(auth))
         /*
          * 之后的 route 不需要 bearer auth, 会各自验证不同渠道的签名
@@ -235,7 +341,8 @@ pub async fn get_routes() -> Router {
                         headers = &headers_str,
                         "create_charge_notify"
                     );
-                    create_charge_notify(&prisma_client, charge_id, body).await
+                    let wechatpay_headers = wechatpay_headers(&headers);
+                    create_charge_notify(&prisma_client, charge_id, wechatpay_headers, body).await
                 },
             )
         })
@@ -255,7 +362,56 @@ pub async fn get_routes() -> Router {
                         headers = &headers_str,
                         "create_refund_notify"
                     );
-                    create_refund_notify(&prisma_client, charge_id, refund_id, body).await
+                    let wechatpay_headers = wechatpay_headers(&headers);
+                    create_refund_notify(
+                        &prisma_client,
+                        charge_id,
+                        refund_id,
+                        wechatpay_headers,
+                        body,
+                    )
+                    .await
+                },
+            )
+        })
+        .route("/notify/paypal/webhook", {
+            let prisma_client = prisma_client.clone();
+            post(|headers: HeaderMap, body: String| async move {
+                let headers_str = format!("{:?}", headers);
+                tracing::info!(
+                    payload = body.as_str(),
+                    headers = &headers_str,
+                    "create_paypal_webhook_notify"
+                );
+                // 只保留验证签名需要的 paypal-* 请求头
+                let paypal_headers = headers
+                    .iter()
+                    .filter(|(name, _)| name.as_str().starts_with("paypal-"))
+                    .map(|(name, value)| {
+                        let value = value.to_str().unwrap_or_default().to_string();
+                        (name.to_string(), value)
+                    })
+                    .collect();
+                create_paypal_webhook_notify(&prisma_client, paypal_headers, body).await
+            })
+        })
+        .route("/notify/charges/:charge_id/paypal_return", {
+            let prisma_client = prisma_client.clone();
+            get(
+                |Query(query): Query<serde_json::Value>, Path(charge_id): Path<String>| async move {
+                    tracing::info!(
+                        charge_id = charge_id,
+                        query = query.to_string(),
+                        "create_paypal_return_notify"
+                    );
+                    let token = query["token"].as_str().unwrap_or_default().to_string();
+                    create_paypal_return_notify(&prisma_client, charge_id, token)
+                        .await
+                        .map(|success_url| match success_url {
+                            Some(success_url) => Redirect::to(&success_url).into_response(),
+                            // 没有 success_url 的话不能跳转到空地址, 直接显示付款已提交
+                            None => "payment submitted, you can close this page".into_response(),
+                        })
                 },
             )
         })
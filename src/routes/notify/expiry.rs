@@ -0,0 +1,208 @@
+use super::notify::sync_charge_status;
+use super::recovery::{env_or, next_cursor, recoverable_channels};
+use super::webhook::send_order_canceled_webhook;
+use crate::core::{ChargeStatus, OrderError};
+
+const DEFAULT_INTERVAL_SECS: u64 = 300;
+const DEFAULT_BATCH_SIZE: i64 = 100;
+
+enum ExpiryOutcome {
+    Canceled,
+    Paid,            // 关单之前查到已经支付, 已经按异步通知的逻辑处理, order 不取消
+    Skipped(String), // 处理过程中 order 被别的请求改了状态 (收到支付通知, 用户取消等), 记录实际的状态
+}
+
+/**
+ * 过期 order 的每个没支付的 charge 先向渠道查询一次, 避免把丢了异步通知的已支付 charge 关掉
+ * 还在等待付款的在渠道上关单, 都处理完以后把 order.status 从 created 改成 canceled 并发送 order.canceled webhook
+ * 银联和 PayPal 不支持查询和关单, 依赖渠道自己的过期时间, 余额支付没有未完成的交易, 这些 charge 直接跳过
+ */
+async fn expire_order(
+    prisma_client: &crate::prisma::PrismaClient,
+    order_id: &str,
+) -> Result<ExpiryOutcome, OrderError> {
+    let (_order, charges, app, sub_app) =
+        crate::utils::load_order_from_db(prisma_client, order_id).await?;
+    let recoverable_channels = recoverable_channels();
+    for charge in charges.iter().filter(|charge| !charge.paid) {
+        if !recoverable_channels.contains(&charge.channel) {
+            continue;
+        }
+        match sync_charge_status(prisma_client, &charge.id).await? {
+            ChargeStatus::Success => return Ok(ExpiryOutcome::Paid),
+            ChargeStatus::Fail => {} // 渠道上已经关闭
+            ChargeStatus::Pending => {
+                super::super::order::close_charge(prisma_client, charge, &app.id, &sub_app.id)
+                    .await?;
+            }
+        }
+    }
+
+    // 只更新还是 created 的 order, 处理过程中收到支付成功的异步通知的话 order 已经是 paid
+    let updated = prisma_client
+        .order()
+        .update_many(
+            vec![
+                crate::prisma::order::id::equals(order_id.to_string()),
+                crate::prisma::order::status::equals("created".to_string()),
+            ],
+            vec![crate::prisma::order::status::set("canceled".to_string())],
+        )
+        .exec()
+        .await
+        .map_err(|e| OrderError::Unexpected(format!("sql error: {:?}", e)))?;
+    if updated == 0 {
+        let (order, _, _, _) = crate::utils::load_order_from_db(prisma_client, order_id).await?;
+        return Ok(ExpiryOutcome::Skipped(order.status));
+    }
+
+    if let Err(e) = send_order_canceled_webhook(prisma_client, order_id).await {
+        tracing::error!(order_id, "error sending order.canceled webhook: {:?}", e);
+    }
+    Ok(ExpiryOutcome::Canceled)
+}
+
+#[derive(Debug, Default)]
+struct ExpiryStats {
+    scanned: usize,
+    canceled: usize,
+    paid: usize,
+    skipped: usize,
+    errors: usize,
+}
+
+impl ExpiryStats {
+    fn record(&mut self, order_id: &str, result: Result<ExpiryOutcome, OrderError>) {
+        self.scanned += 1;
+        match result {
+            Ok(ExpiryOutcome::Canceled) => {
+                self.canceled += 1;
+                tracing::info!(order_id, "order expiry: order canceled");
+            }
+            Ok(ExpiryOutcome::Paid) => {
+                self.paid += 1;
+                tracing::info!(order_id, "order expiry: order is paid, skipped");
+            }
+            Ok(ExpiryOutcome::Skipped(status)) => {
+                self.skipped += 1;
+                tracing::info!(
+                    order_id,
+                    status,
+                    "order expiry: order is no longer created, skipped"
+                );
+            }
+            Err(e) => {
+                // 查询或者关单失败的 order 保持 created, 下一轮重试
+                self.errors += 1;
+                tracing::error!(order_id, "order expiry: error expiring order: {:?}", e);
+            }
+        }
+    }
+}
+
+/**
+ * 扫描一批已经过了 time_expire 还是 created 状态的 order, 逐个取消
+ * 一直取消失败的 order 每次都会被查到, 和补单一样按 id 分页, 从 cursor 之后继续扫描, 不满一批的时候从头开始
+ */
+async fn sweep_expired_orders(
+    prisma_client: &crate::prisma::PrismaClient,
+    batch_size: i64,
+    cursor: Option<String>,
+) -> Result<(ExpiryStats, Option<String>), prisma_client_rust::QueryError> {
+    let now = chrono::Utc::now().timestamp() as i32;
+    let mut filters = vec![
+        crate::prisma::order::status::equals("created".to_string()),
+        crate::prisma::order::paid::equals(false),
+        crate::prisma::order::time_expire::lt(now),
+    ];
+    if let Some(cursor) = cursor {
+        filters.push(crate::prisma::order::id::gt(cursor));
+    }
+    let orders = prisma_client
+        .order()
+        .find_many(filters)
+        .order_by(crate::prisma::order::id::order(
+            prisma_client_rust::Direction::Asc,
+        ))
+        .take(batch_size)
+        .exec()
+        .await?;
+    let next_cursor = next_cursor(
+        orders.last().map(|order| order.id.as_str()),
+        orders.len(),
+        batch_size,
+    );
+
+    let mut stats = ExpiryStats::default();
+    for order in orders {
+        let result = expire_order(prisma_client, &order.id).await;
+        stats.record(&order.id, result);
+    }
+    Ok((stats, next_cursor))
+}
+
+/**
+ * 定时取消过期的 order, 在 main 里面 spawn
+ * 间隔和每次扫描数量分别用环境变量 ORDER_EXPIRY_INTERVAL_SECS, ORDER_EXPIRY_BATCH_SIZE 配置
+ */
+pub async fn run_order_expiry_sweeper() {
+    let interval_secs = env_or("ORDER_EXPIRY_INTERVAL_SECS", DEFAULT_INTERVAL_SECS);
+    let batch_size = env_or("ORDER_EXPIRY_BATCH_SIZE", DEFAULT_BATCH_SIZE);
+    if interval_secs == 0 {
+        tracing::info!("order expiry sweeper disabled");
+        return;
+    }
+    let prisma_client = match crate::prisma::new_client().await {
+        Ok(prisma_client) => prisma_client,
+        Err(e) => {
+            tracing::error!("error getting prisma client for order expiry: {:?}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+    // 只保存在内存里, 重启以后从头开始扫描
+    let mut cursor: Option<String> = None;
+    loop {
+        interval.tick().await;
+        let started_at = std::time::Instant::now();
+        match sweep_expired_orders(&prisma_client, batch_size, cursor.clone()).await {
+            Ok((stats, next_cursor)) => {
+                cursor = next_cursor;
+                tracing::info!(
+                    scanned = stats.scanned,
+                    canceled = stats.canceled,
+                    paid = stats.paid,
+                    skipped = stats.skipped,
+                    errors = stats.errors,
+                    elapsed_ms = started_at.elapsed().as_millis() as u64,
+                    cursor = cursor.as_deref().unwrap_or_default(),
+                    "order expiry run finished"
+                );
+            }
+            Err(e) => {
+                tracing::error!("error loading expired orders: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry_stats_record() {
+        let mut stats = ExpiryStats::default();
+        stats.record("o_1", Ok(ExpiryOutcome::Canceled));
+        stats.record("o_2", Ok(ExpiryOutcome::Paid));
+        stats.record("o_3", Ok(ExpiryOutcome::Skipped("canceled".to_string())));
+        stats.record("o_4", Ok(ExpiryOutcome::Skipped("paid".to_string())));
+        stats.record("o_5", Err(OrderError::Unexpected("sql error".to_string())));
+        assert_eq!(stats.scanned, 5);
+        assert_eq!(stats.canceled, 1);
+        // 别的请求改成 paid 的 order 按实际状态记到 skipped, 不算成关单之前查到已支付
+        assert_eq!(stats.paid, 1);
+        assert_eq!(stats.skipped, 2);
+        assert_eq!(stats.errors, 1);
+    }
+}
@@ -1,3 +1,11 @@
 mod webhook;
+mod expiry;
 mod notify;
+mod paypal;
+mod recovery;
+mod scan;
+pub use expiry::*;
 pub use notify::*;
+pub use paypal::*;
+pub use recovery::*;
+pub use scan::*;
@@ -1,76 +1,288 @@
 use super::webhook::{send_charge_success_webhook, send_refund_success_webhook};
+use crate::balance;
 use crate::core::{
-    ChannelHandler, ChargeError, ChargeStatus, PaymentChannel, RefundError, RefundStatus,
+    ChannelNotifyOrder, ChannelQueryRequest, ChargeError, ChargeStatus, PaymentChannel,
+    RefundError, RefundStatus,
 };
-use crate::{alipay, weixin};
+use crate::routes::prelude::load_channel_handler;
+use std::collections::HashMap;
 use std::str::FromStr;
 
-async fn process_charge_notify(
+/**
+ * 渠道确认支付成功以后更新 charge 和 order, 然后发送 webhook
+ * 异步通知和付款码这类同步拿到支付结果的渠道都走这里
+ * 异步通知, 主动查询和补单可能同时确认同一个 charge, 只有把 paid 从 false 改成 true 的那一次更新 order 和发送 webhook
+ */
+pub async fn settle_paid_charge(
     prisma_client: &crate::prisma::PrismaClient,
-    charge_id: &str,
-    payload: &str,
-) -> Result<String, ChargeError> {
-    let (charge, order, _, app, sub_app) =
-        crate::utils::load_charge_from_db(&prisma_client, charge_id).await?;
-
-    let channel = PaymentChannel::from_str(&charge.channel).map_err(|e| {
-        ChargeError::InternalError(format!("error parsing charge channel: {:?}", e))
-    })?;
-
-    let sub_app_id = match &sub_app {
-        Some(sub_app) => Some(sub_app.id.as_str()),
-        None => None,
-    };
-    let handler: Box<dyn ChannelHandler + Send> = match channel {
-        PaymentChannel::AlipayPcDirect => {
-            Box::new(alipay::AlipayPcDirect::new(&prisma_client, Some(&app.id), sub_app_id).await?)
-        }
-        PaymentChannel::AlipayWap => {
-            Box::new(alipay::AlipayWap::new(&prisma_client, Some(&app.id), sub_app_id).await?)
-        }
-        PaymentChannel::WxPub => {
-            Box::new(weixin::WxPub::new(&prisma_client, Some(&app.id), sub_app_id).await?)
-        }
-        PaymentChannel::WxLite => {
-            Box::new(weixin::WxLite::new(&prisma_client, Some(&app.id), sub_app_id).await?)
-        }
-    };
-
+    charge: &crate::prisma::charge::Data,
+    order_id: Option<&str>,
+    channel_extra: Option<serde_json::Value>, // 渠道返回的需要合并到 charge.extra 的信息
+) -> Result<(), ChargeError> {
     let time_paid = chrono::Utc::now().timestamp() as i32;
-    let charge_status = handler.process_charge_notify(payload)?;
-    if charge_status == ChargeStatus::Success {
-        prisma_client
-            .charge()
-            .update(
-                crate::prisma::charge::id::equals(charge_id.to_string()),
+    let mut charge_params = vec![
+        crate::prisma::charge::paid::set(true),
+        crate::prisma::charge::time_paid::set(Some(time_paid)),
+    ];
+    if let Some(extra) = merge_channel_extra(&charge.extra, channel_extra) {
+        charge_params.push(crate::prisma::charge::extra::set(extra));
+    }
+    let count = prisma_client
+        .charge()
+        .update_many(
+            vec![
+                crate::prisma::charge::id::equals(charge.id.clone()),
+                crate::prisma::charge::paid::equals(false),
+                crate::prisma::charge::reversed::equals(false),
+            ],
+            charge_params,
+        )
+        .exec()
+        .await
+        .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
+    if count != 1 {
+        tracing::info!(
+            charge_id = charge.id,
+            "charge is already settled or reversed"
+        );
+        return Ok(());
+    }
+
+    if let Some(order_id) = order_id {
+        // update order.paid 并更新 order, 因为后面 send_webhook 需要最新的 order 数据
+        let count = prisma_client
+            .order()
+            .update_many(
                 vec![
-                    crate::prisma::charge::paid::set(true),
-                    crate::prisma::charge::time_paid::set(Some(time_paid)),
+                    crate::prisma::order::id::equals(order_id.to_string()),
+                    crate::prisma::order::status::not("canceled".to_string()),
+                ],
+                vec![
+                    crate::prisma::order::paid::set(true),
+                    crate::prisma::order::time_paid::set(Some(time_paid)),
+                    crate::prisma::order::amount_paid::set(charge.amount),
+                    crate::prisma::order::status::set("paid".to_string()),
                 ],
             )
             .exec()
             .await
             .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
-
-        if let Some(ref order) = order {
-            // update order.paid 并更新 order, 因为后面 send_webhook 需要最新的 order 数据
+        if count != 1 {
+            // order 已经取消 (关单和用户付款同时发生), 钱已经收到了, 记录到 order 上但是保持 canceled
+            // 不能当成正常支付处理, 需要人工或者业务系统收到 webhook 以后发起退款
             prisma_client
                 .order()
                 .update(
-                    crate::prisma::order::id::equals(order.id.clone()),
+                    crate::prisma::order::id::equals(order_id.to_string()),
                     vec![
                         crate::prisma::order::paid::set(true),
                         crate::prisma::order::time_paid::set(Some(time_paid)),
                         crate::prisma::order::amount_paid::set(charge.amount),
-                        crate::prisma::order::status::set("paid".to_string()),
                     ],
                 )
                 .exec()
                 .await
                 .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
+            tracing::error!(
+                order_id,
+                charge_id = charge.id,
+                amount = charge.amount,
+                "charge paid after order canceled, order kept canceled, refund required"
+            );
         }
+    }
+
+    let _ = send_charge_success_webhook(prisma_client, &charge.id).await;
+    Ok(())
+}
+
+/**
+ * 渠道返回的信息 (比如境外支付的币种和汇率) 合并到 charge.extra 上, 没有的话不更新 extra
+ */
+fn merge_channel_extra(
+    extra: &serde_json::Value,
+    channel_extra: Option<serde_json::Value>,
+) -> Option<serde_json::Value> {
+    let Some(serde_json::Value::Object(channel_extra)) = channel_extra else {
+        return None;
+    };
+    let mut extra = extra.clone();
+    if let Some(extra_map) = extra.as_object_mut() {
+        extra_map.extend(channel_extra);
+    }
+    Some(extra)
+}
+
+/**
+ * 渠道通知里的商户订单号和金额必须和 charge 一致, 防止用别的订单的通知把这个 charge 改成已支付
+ */
+fn check_charge_notify_order(
+    merchant_order_no: &str,
+    amount: i32,
+    notify_order: &ChannelNotifyOrder,
+) -> Result<(), ChargeError> {
+    if notify_order.merchant_order_no != merchant_order_no || notify_order.amount != amount {
+        return Err(ChargeError::MalformedRequest(format!(
+            "charge notify mismatch: merchant_order_no = {}, amount = {}",
+            notify_order.merchant_order_no, notify_order.amount
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * 渠道撤销成功以后把 charge 标记为 reversed, 撤销接口和付款码超时撤销都走这里, 只有把 reversed 从 false 改成 true 的那一次继续处理
+ * 标记以后 settle_paid_charge 不会再把 charge 改成已支付, 所以标记以后重新读取 charge
+ * 已经支付的 charge 渠道会原路退款, 和退款成功一样更新 order 上的 refunded, amount_refunded 和 status
+ */
+pub async fn settle_reversed_charge(
+    prisma_client: &crate::prisma::PrismaClient,
+    charge_id: &str,
+    mut charge_params: Vec<crate::prisma::charge::SetParam>, // 除了 reversed 以外还需要更新的字段
+) -> Result<(), ChargeError> {
+    let time_reversed = chrono::Utc::now().timestamp() as i32;
+    charge_params.push(crate::prisma::charge::reversed::set(true));
+    charge_params.push(crate::prisma::charge::time_reversed::set(Some(
+        time_reversed,
+    )));
+    let count = prisma_client
+        .charge()
+        .update_many(
+            vec![
+                crate::prisma::charge::id::equals(charge_id.to_string()),
+                crate::prisma::charge::reversed::equals(false),
+            ],
+            charge_params,
+        )
+        .exec()
+        .await
+        .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
+    if count != 1 {
+        tracing::info!(charge_id, "charge is already reversed");
+        return Ok(());
+    }
+
+    let (charge, order, refunds, _, _) =
+        crate::utils::load_charge_from_db(prisma_client, charge_id).await?;
+    if let (true, Some(order)) = (charge.paid, order) {
+        // 撤销之前已经部分退款的话, 之前的退款已经累加到 order 上了, 这里只加上还没退的部分
+        let amount = reversal_refund_amount(
+            charge.amount,
+            refunds
+                .iter()
+                .map(|refund| (refund.status.as_str(), refund.amount)),
+        );
+        prisma_client
+            .order()
+            .update(
+                crate::prisma::order::id::equals(order.id.clone()),
+                vec![
+                    crate::prisma::order::refunded::set(true),
+                    crate::prisma::order::amount_refunded::increment(amount),
+                    crate::prisma::order::status::set("refunded".to_string()),
+                ],
+            )
+            .exec()
+            .await
+            .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
+        tracing::info!(
+            order_id = order.id,
+            charge_id,
+            amount,
+            "paid charge reversed, order refunded"
+        );
+
+        // 撤销没有对应的 refund, 和退款成功一样发送 webhook
+        let _ = send_refund_success_webhook(prisma_client, charge_id, "").await;
+    }
+    Ok(())
+}
+
+/**
+ * 撤销已经支付的 charge 相当于退还 charge 上还没有退款的金额
+ */
+fn reversal_refund_amount<'a>(
+    charge_amount: i32,
+    refunds: impl IntoIterator<Item = (&'a str, i32)>, // (status, amount)
+) -> i32 {
+    let succeeded = RefundStatus::Success.to_string();
+    let refunded: i32 = refunds
+        .into_iter()
+        .filter(|(status, _)| *status == succeeded)
+        .map(|(_, amount)| amount)
+        .sum();
+    (charge_amount - refunded).max(0)
+}
 
-        let _ = send_charge_success_webhook(prisma_client, charge_id).await;
+/**
+ * 余额支付的 charge 保存以后再扣款, 扣款, 记账和更新 charge/order 在 balance::pay_charge 的同一个事务里
+ * 扣款失败的话在 charge 上记录失败原因, 然后返回错误
+ */
+pub async fn settle_balance_charge(
+    prisma_client: &crate::prisma::PrismaClient,
+    charge: &crate::prisma::charge::Data,
+    order_id: Option<&str>,
+    uid: &str,
+) -> Result<(), ChargeError> {
+    if let Err(e) = balance::pay_charge(prisma_client, charge, order_id, uid).await {
+        let failure_code = match &e {
+            balance::BalanceError::InsufficientBalance(_) => "insufficient_balance",
+            _ => "balance_error",
+        };
+        prisma_client
+            .charge()
+            .update(
+                crate::prisma::charge::id::equals(charge.id.clone()),
+                vec![
+                    crate::prisma::charge::failure_code::set(Some(failure_code.to_string())),
+                    crate::prisma::charge::failure_msg::set(Some(e.to_string())),
+                ],
+            )
+            .exec()
+            .await
+            .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
+        return Err(e.into());
+    }
+    let _ = send_charge_success_webhook(prisma_client, &charge.id).await;
+    Ok(())
+}
+
+async fn process_charge_notify(
+    prisma_client: &crate::prisma::PrismaClient,
+    charge_id: &str,
+    headers: Option<&HashMap<String, String>>, // 重发历史通知的时候没有请求头, 不验证请求头上的签名
+    payload: &str,
+) -> Result<String, ChargeError> {
+    let (charge, order, _, app, sub_app) =
+        crate::utils::load_charge_from_db(&prisma_client, charge_id).await?;
+
+    let channel = PaymentChannel::from_str(&charge.channel).map_err(|e| {
+        ChargeError::InternalError(format!("error parsing charge channel: {:?}", e))
+    })?;
+
+    let sub_app_id = match &sub_app {
+        Some(sub_app) => Some(sub_app.id.as_str()),
+        None => None,
+    };
+    let handler = load_channel_handler(prisma_client, &channel, &app.id, sub_app_id).await?;
+
+    if let Some(headers) = headers {
+        handler
+            .verify_charge_notify_headers(headers, payload)
+            .await?;
+    }
+    let charge_status = handler.process_charge_notify(payload).await?;
+    if let Some(notify_order) = handler.charge_notify_order(payload) {
+        check_charge_notify_order(&charge.merchant_order_no, charge.amount, &notify_order)?;
+    }
+    // 有的渠道会通知多次 (比如 paypal 的 return 和 webhook), 已经支付的 charge 不重复处理
+    // 已经撤销的 charge 渠道会原路退款, 收到支付成功的通知也不改成已支付, 照常应答避免渠道重复通知
+    if charge_status == ChargeStatus::Success && charge.reversed {
+        tracing::info!(charge_id, "ignoring success notify for reversed charge");
+    } else if charge_status == ChargeStatus::Success && !charge.paid {
+        let order_id = order.as_ref().map(|order| order.id.as_str());
+        let channel_extra = handler.charge_notify_extra(payload);
+        settle_paid_charge(prisma_client, &charge, order_id, channel_extra).await?;
     }
 
     match channel {
@@ -86,12 +298,90 @@ async fn process_charge_notify(
         PaymentChannel::WxLite => {
             Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
         }
+        PaymentChannel::AlipayQr => {
+            Ok("success".to_string())
+        }
+        PaymentChannel::WxPubQr => {
+            Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
+        }
+        PaymentChannel::WxWap => {
+            Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
+        }
+        PaymentChannel::Alipay => {
+            Ok("success".to_string())
+        }
+        PaymentChannel::Wx => {
+            Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
+        }
+        PaymentChannel::AlipayLite => {
+            Ok("success".to_string())
+        }
+        PaymentChannel::AlipayScan => {
+            Ok("success".to_string())
+        }
+        PaymentChannel::WxPubScan => {
+            Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
+        }
+        PaymentChannel::UpacpPc => {
+            Ok("ok".to_string())
+        }
+        PaymentChannel::UpacpWap => {
+            Ok("ok".to_string())
+        }
+        PaymentChannel::Paypal => {
+            Ok("ok".to_string())
+        }
+        PaymentChannel::Balance => {
+            Ok("success".to_string())
+        }
+    }
+}
+
+/**
+ * 主动向渠道查询支付结果, 用来补救丢失的异步通知
+ * 支付成功的话和异步通知一样更新 charge 和 order 并发送 webhook, 已经支付的 charge 不重复处理
+ * 已经撤销的 charge 交易已经关闭或者原路退款, 不再查询, 返回 Fail
+ */
+pub async fn sync_charge_status(
+    prisma_client: &crate::prisma::PrismaClient,
+    charge_id: &str,
+) -> Result<ChargeStatus, ChargeError> {
+    let (charge, order, _, app, sub_app) =
+        crate::utils::load_charge_from_db(prisma_client, charge_id).await?;
+    if charge.reversed {
+        return Ok(ChargeStatus::Fail);
+    }
+    if charge.paid {
+        return Ok(ChargeStatus::Success);
     }
+
+    let channel = PaymentChannel::from_str(&charge.channel).map_err(|e| {
+        ChargeError::InternalError(format!("error parsing charge channel: {:?}", e))
+    })?;
+
+    let sub_app_id = match &sub_app {
+        Some(sub_app) => Some(sub_app.id.as_str()),
+        None => None,
+    };
+    let handler = load_channel_handler(prisma_client, &channel, &app.id, sub_app_id).await?;
+
+    let charge_result = handler
+        .query_charge(&ChannelQueryRequest {
+            merchant_order_no: &charge.merchant_order_no,
+            currency: &charge.currency,
+        })
+        .await?;
+    if charge_result.status == ChargeStatus::Success {
+        let order_id = order.as_ref().map(|order| order.id.as_str());
+        settle_paid_charge(prisma_client, &charge, order_id, charge_result.extra).await?;
+    }
+    Ok(charge_result.status)
 }
 
 pub async fn create_charge_notify(
     prisma_client: &crate::prisma::PrismaClient,
     charge_id: String,
+    headers: HashMap<String, String>,
     notify_payload: String,
 ) -> Result<String, ChargeError> {
     prisma_client
@@ -100,7 +390,8 @@ pub async fn create_charge_notify(
         .exec()
         .await
         .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
-    let return_body = process_charge_notify(&prisma_client, &charge_id, &notify_payload).await?;
+    let return_body =
+        process_charge_notify(&prisma_client, &charge_id, Some(&headers), &notify_payload).await?;
     Ok(return_body)
 }
 
@@ -108,6 +399,7 @@ async fn process_refund_notify(
     prisma_client: &crate::prisma::PrismaClient,
     charge_id: &str,
     refund_id: &str,
+    headers: Option<&HashMap<String, String>>, // 重发历史通知的时候没有请求头, 不验证请求头上的签名
     payload: &str,
 ) -> Result<String, RefundError> {
     let (charge, order, _refunds, app, sub_app) =
@@ -128,29 +420,38 @@ async fn process_refund_notify(
         Some(sub_app) => Some(sub_app.id.as_str()),
         None => None,
     };
-    let handler: Box<dyn ChannelHandler + Send> = match channel {
-        PaymentChannel::AlipayPcDirect => {
-            Box::new(alipay::AlipayPcDirect::new(&prisma_client, Some(&app.id), sub_app_id).await?)
-        }
-        PaymentChannel::AlipayWap => {
-            Box::new(alipay::AlipayWap::new(&prisma_client, Some(&app.id), sub_app_id).await?)
-        }
-        PaymentChannel::WxPub => {
-            Box::new(weixin::WxPub::new(&prisma_client, Some(&app.id), sub_app_id).await?)
-        }
-        PaymentChannel::WxLite => {
-            Box::new(weixin::WxLite::new(&prisma_client, Some(&app.id), sub_app_id).await?)
-        }
-    };
+    let handler = load_channel_handler(prisma_client, &channel, &app.id, sub_app_id).await?;
 
     let time_refunded = chrono::Utc::now().timestamp() as i32;
+    if let Some(headers) = headers {
+        handler
+            .verify_refund_notify_headers(headers, payload)
+            .await?;
+    }
     let refund_status = handler.process_refund_notify(payload)?;
+    if let Some(notify_order) = handler.refund_notify_order(payload) {
+        if notify_order.charge_merchant_order_no != charge.merchant_order_no
+            || notify_order.refund_merchant_order_no != refund.merchant_order_no
+            || notify_order.refund_amount != refund.amount
+        {
+            return Err(RefundError::BadRequest(format!(
+                "refund notify mismatch: out_trade_no = {}, out_refund_no = {}, amount = {}",
+                notify_order.charge_merchant_order_no,
+                notify_order.refund_merchant_order_no,
+                notify_order.refund_amount
+            )));
+        }
+    }
     match refund_status {
         RefundStatus::Success => {
-            refund = prisma_client
+            // 退款接口同步返回成功以后还可能收到通知 (比如 PayPal 的 webhook), 已经成功的 refund 不重复累加退款金额
+            let count = prisma_client
                 .refund()
-                .update(
-                    crate::prisma::refund::id::equals(refund_id.to_string()),
+                .update_many(
+                    vec![
+                        crate::prisma::refund::id::equals(refund_id.to_string()),
+                        crate::prisma::refund::status::not(refund_status.to_string()),
+                    ],
                     vec![
                         crate::prisma::refund::status::set(refund_status.to_string()),
                         crate::prisma::refund::time_succeed::set(Some(time_refunded)),
@@ -159,24 +460,27 @@ async fn process_refund_notify(
                 .exec()
                 .await
                 .map_err(|e| RefundError::Unexpected(format!("sql error: {:?}", e)))?;
+            if count != 1 {
+                tracing::info!(refund_id, "refund is already succeeded");
+            } else {
+                if let Some(ref order) = order {
+                    prisma_client
+                        .order()
+                        .update(
+                            crate::prisma::order::id::equals(order.id.clone()),
+                            vec![
+                                crate::prisma::order::refunded::set(true),
+                                crate::prisma::order::amount_refunded::increment(refund.amount),
+                                crate::prisma::order::status::set("refunded".to_string()),
+                            ],
+                        )
+                        .exec()
+                        .await
+                        .map_err(|e| RefundError::Unexpected(format!("sql error: {:?}", e)))?;
+                }
 
-            if let Some(ref order) = order {
-                prisma_client
-                    .order()
-                    .update(
-                        crate::prisma::order::id::equals(order.id.clone()),
-                        vec![
-                            crate::prisma::order::refunded::set(true),
-                            crate::prisma::order::amount_refunded::increment(refund.amount),
-                            crate::prisma::order::status::set("refunded".to_string()),
-                        ],
-                    )
-                    .exec()
-                    .await
-                    .map_err(|e| RefundError::Unexpected(format!("sql error: {:?}", e)))?;
+                let _ = send_refund_success_webhook(prisma_client, charge_id, refund_id).await;
             }
-
-            let _ = send_refund_success_webhook(prisma_client, charge_id, refund_id).await;
         }
         RefundStatus::Fail(error) => {
             refund = prisma_client
@@ -208,6 +512,42 @@ async fn process_refund_notify(
         PaymentChannel::WxLite => {
             Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
         }
+        PaymentChannel::AlipayQr => {
+            Ok("success".to_string())
+        }
+        PaymentChannel::WxPubQr => {
+            Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
+        }
+        PaymentChannel::WxWap => {
+            Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
+        }
+        PaymentChannel::Alipay => {
+            Ok("success".to_string())
+        }
+        PaymentChannel::Wx => {
+            Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
+        }
+        PaymentChannel::AlipayLite => {
+            Ok("success".to_string())
+        }
+        PaymentChannel::AlipayScan => {
+            Ok("success".to_string())
+        }
+        PaymentChannel::WxPubScan => {
+            Ok("<xml><return_code><![CDATA[SUCCESS]]></return_code><return_msg><![CDATA[OK]]></return_msg></xml>".to_string())
+        }
+        PaymentChannel::UpacpPc => {
+            Ok("ok".to_string())
+        }
+        PaymentChannel::UpacpWap => {
+            Ok("ok".to_string())
+        }
+        PaymentChannel::Paypal => {
+            Ok("ok".to_string())
+        }
+        PaymentChannel::Balance => {
+            Ok("success".to_string())
+        }
     }
 }
 
@@ -215,6 +555,7 @@ pub async fn create_refund_notify(
     prisma_client: &crate::prisma::PrismaClient,
     charge_id: String,
     refund_id: String,
+    headers: HashMap<String, String>,
     notify_payload: String,
 ) -> Result<String, RefundError> {
     prisma_client
@@ -229,8 +570,14 @@ pub async fn create_refund_notify(
         .exec()
         .await
         .map_err(|e| RefundError::Unexpected(format!("sql error: {:?}", e)))?;
-    let return_body =
-        process_refund_notify(&prisma_client, &charge_id, &refund_id, &notify_payload).await?;
+    let return_body = process_refund_notify(
+        &prisma_client,
+        &charge_id,
+        &refund_id,
+        Some(&headers),
+        &notify_payload,
+    )
+    .await?;
     Ok(return_body)
 }
 
@@ -258,6 +605,7 @@ pub async fn retry_notify(
             &prisma_client,
             &charge_id,
             &refund_id,
+            None,
             &charge_notify_payload,
         )
         .await
@@ -266,11 +614,12 @@ pub async fn retry_notify(
         })?;
         return Ok(return_body);
     } else {
-        let return_body = process_charge_notify(&prisma_client, &charge_id, &charge_notify_payload)
-            .await
-            .map_err(|e| {
-                tracing::error!("process_charge_notify error {:?}", e);
-            })?;
+        let return_body =
+            process_charge_notify(&prisma_client, &charge_id, None, &charge_notify_payload)
+                .await
+                .map_err(|e| {
+                    tracing::error!("process_charge_notify error {:?}", e);
+                })?;
         Ok(return_body)
     }
 }
@@ -299,7 +648,7 @@ mod tests {
             .unwrap();
 
         let payload = history.data.clone();
-        process_charge_notify(&prisma_client, charge_id, &payload)
+        process_charge_notify(&prisma_client, charge_id, None, &payload)
             .await
             .unwrap();
     }
@@ -321,5 +670,54 @@ mod tests {
         // tracing::info!("verify result: {}", result);
     }
 
+    #[test]
+    fn test_merge_channel_extra() {
+        let extra = serde_json::json!({ "open_id": "o_123" });
+        assert_eq!(merge_channel_extra(&extra, None), None);
+        // 不是对象的渠道信息不合并
+        assert_eq!(
+            merge_channel_extra(&extra, Some(serde_json::json!("USD"))),
+            None
+        );
+        let channel_extra = serde_json::json!({ "forex_currency": "USD", "open_id": "o_456" });
+        assert_eq!(
+            merge_channel_extra(&extra, Some(channel_extra)),
+            Some(serde_json::json!({ "open_id": "o_456", "forex_currency": "USD" }))
+        );
+    }
+
+    #[test]
+    fn test_check_charge_notify_order() {
+        let notify_order = ChannelNotifyOrder {
+            merchant_order_no: "79320240623213641748".to_string(),
+            amount: 100,
+        };
+        assert!(check_charge_notify_order("79320240623213641748", 100, &notify_order).is_ok());
+        assert!(matches!(
+            check_charge_notify_order("79320240623213641749", 100, &notify_order),
+            Err(ChargeError::MalformedRequest(_))
+        ));
+        assert!(matches!(
+            check_charge_notify_order("79320240623213641748", 1, &notify_order),
+            Err(ChargeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_reversal_refund_amount() {
+        assert_eq!(reversal_refund_amount(100, vec![]), 100);
+        assert_eq!(
+            reversal_refund_amount(
+                100,
+                vec![("succeeded", 30), ("pending", 20), ("failed", 10)]
+            ),
+            70
+        );
+        assert_eq!(
+            reversal_refund_amount(100, vec![("succeeded", 30), ("succeeded", 70)]),
+            0
+        );
+    }
+
     // 由于没有 ping++ 的私钥，无法以 ping++ 的名义发送 webhook 到业务系统，业务系统需要单独验证从这里发出去的 webhook
 }
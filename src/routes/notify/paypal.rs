@@ -0,0 +1,125 @@
+use super::{create_charge_notify, create_refund_notify};
+use crate::core::{ChargeError, RefundError};
+use crate::paypal::PaypalNotifyPayload;
+use std::collections::HashMap;
+
+/**
+ * PayPal 的 webhook 地址是统一的 /notify/paypal/webhook, 从事件的 custom_id 里取出 charge_id
+ * 然后和其他渠道一样走 create_charge_notify, 签名在 Paypal::process_charge_notify 里验证
+ * 退款相关的事件交给 create_paypal_refund_webhook_notify
+ */
+pub async fn create_paypal_webhook_notify(
+    prisma_client: &crate::prisma::PrismaClient,
+    headers: HashMap<String, String>,
+    body: String,
+) -> Result<String, ChargeError> {
+    let event: serde_json::Value = serde_json::from_str(&body).map_err(|e| {
+        ChargeError::MalformedRequest(format!("error parsing paypal webhook: {:?}", e))
+    })?;
+    let event_type = event["event_type"].as_str().unwrap_or_default();
+    if event_type == "PAYMENT.CAPTURE.REFUNDED"
+        || event_type == "PAYMENT.CAPTURE.REVERSED"
+        || event_type.starts_with("PAYMENT.REFUND.")
+    {
+        return create_paypal_refund_webhook_notify(prisma_client, &event, headers, body)
+            .await
+            .map_err(|e| match e {
+                RefundError::BadRequest(e) => ChargeError::MalformedRequest(e),
+                RefundError::Unexpected(e) => ChargeError::InternalError(e),
+            });
+    }
+    let resource = &event["resource"];
+    // CHECKOUT.ORDER.* 的 resource 是订单, PAYMENT.CAPTURE.* 的 resource 是 capture
+    let charge_id = resource["custom_id"]
+        .as_str()
+        .or(resource["purchase_units"][0]["custom_id"].as_str());
+    let charge_id = match charge_id {
+        Some(charge_id) => charge_id.to_string(),
+        None => {
+            // 不是这里创建的订单, 或者是不需要处理的事件, 直接返回成功避免 PayPal 重试
+            tracing::info!("ignore paypal webhook {:?}", event["event_type"].as_str());
+            return Ok("ok".to_string());
+        }
+    };
+    let notify_payload = serde_json::to_string(&PaypalNotifyPayload::Webhook { headers, body })
+        .map_err(|e| ChargeError::InternalError(format!("error serializing notify: {:?}", e)))?;
+    // 签名需要的请求头已经放在 notify_payload 里了, 重发历史通知的时候也能验证
+    create_charge_notify(prisma_client, charge_id, HashMap::new(), notify_payload).await
+}
+
+/**
+ * 退款事件的 resource 是 PayPal 的退款, invoice_id 是退款的时候传的 refund.merchant_order_no
+ * 找到 refund 以后和其他渠道一样走 create_refund_notify, 签名在 Paypal::verify_refund_notify_headers 里验证
+ * PayPal 主动撤销 (PAYMENT.CAPTURE.REVERSED, 比如争议) 或者在 PayPal 后台发起的退款找不到 refund, 记录错误日志以后人工处理
+ */
+async fn create_paypal_refund_webhook_notify(
+    prisma_client: &crate::prisma::PrismaClient,
+    event: &serde_json::Value,
+    headers: HashMap<String, String>,
+    body: String,
+) -> Result<String, RefundError> {
+    let resource = &event["resource"];
+    let refund = match resource["invoice_id"].as_str() {
+        Some(invoice_id) => prisma_client
+            .refund()
+            .find_first(vec![crate::prisma::refund::merchant_order_no::equals(
+                invoice_id.to_string(),
+            )])
+            .exec()
+            .await
+            .map_err(|e| RefundError::Unexpected(format!("sql error: {:?}", e)))?,
+        None => None,
+    };
+    let refund = match refund {
+        Some(refund) => refund,
+        None => {
+            tracing::error!(
+                event_type = event["event_type"].as_str(),
+                paypal_refund_id = resource["id"].as_str(),
+                "paypal refund not created by this server, needs manual handling"
+            );
+            return Ok("ok".to_string());
+        }
+    };
+    let notify_payload = serde_json::to_string(&PaypalNotifyPayload::Webhook { headers, body })
+        .map_err(|e| RefundError::Unexpected(format!("error serializing notify: {:?}", e)))?;
+    create_refund_notify(
+        prisma_client,
+        refund.charge_id,
+        refund.id,
+        HashMap::new(),
+        notify_payload,
+    )
+    .await
+}
+
+/**
+ * 用户在 PayPal 付款以后跳转回来, 完成 capture 以后再跳转到 charge extra 里的 success_url
+ * 返回需要跳转的地址, charge extra 里没有 success_url 的话返回 None
+ */
+pub async fn create_paypal_return_notify(
+    prisma_client: &crate::prisma::PrismaClient,
+    charge_id: String,
+    token: String,
+) -> Result<Option<String>, ChargeError> {
+    let (charge, _, _, _, _) =
+        crate::utils::load_charge_from_db(&prisma_client, &charge_id).await?;
+    let success_url = charge.extra["success_url"]
+        .as_str()
+        .filter(|success_url| !success_url.is_empty())
+        .map(|success_url| success_url.to_string());
+    if !charge.paid {
+        let notify_payload = serde_json::to_string(&PaypalNotifyPayload::Return {
+            charge_id: charge_id.clone(),
+            token,
+        })
+        .map_err(|e| ChargeError::InternalError(format!("error serializing notify: {:?}", e)))?;
+        // capture 失败也跳转到 success_url, 商户系统自己查询 charge 状态, 之后还有 webhook 可以补救
+        if let Err(e) =
+            create_charge_notify(prisma_client, charge_id, HashMap::new(), notify_payload).await
+        {
+            tracing::error!("paypal return error: {:?}", e);
+        }
+    }
+    Ok(success_url)
+}
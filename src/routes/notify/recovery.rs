@@ -0,0 +1,299 @@
+use super::notify::sync_charge_status;
+use crate::core::{ChargeStatus, PaymentChannel};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const DEFAULT_INTERVAL_SECS: u64 = 300;
+const DEFAULT_MIN_AGE_SECS: i64 = 600;
+const DEFAULT_BATCH_SIZE: i64 = 100;
+
+/**
+ * 支持 query_charge 主动查询支付结果和 close_charge 关单的渠道, 其他渠道的 charge 不扫描也不关单
+ */
+pub(crate) fn recoverable_channels() -> Vec<String> {
+    vec![
+        PaymentChannel::Alipay,
+        PaymentChannel::AlipayPcDirect,
+        PaymentChannel::AlipayWap,
+        PaymentChannel::AlipayQr,
+        PaymentChannel::AlipayLite,
+        PaymentChannel::AlipayScan,
+        PaymentChannel::Wx,
+        PaymentChannel::WxPub,
+        PaymentChannel::WxPubQr,
+        PaymentChannel::WxPubScan,
+        PaymentChannel::WxLite,
+        PaymentChannel::WxWap,
+    ]
+    .into_iter()
+    .map(|channel| channel.to_string())
+    .collect()
+}
+
+pub(super) fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+#[derive(Debug, Default)]
+struct RecoveryStats {
+    scanned: usize,
+    paid: usize,
+    pending: usize,
+    failed: usize,
+    errors: usize,
+}
+
+/**
+ * 服务启动以后补单的累计计数, 只在内存里, 重启以后从 0 开始
+ * 通过 /v1/metrics 按 Prometheus 的文本格式输出, 监控系统按 counter 计算增量
+ */
+struct RecoveryCounters {
+    runs: AtomicU64,
+    scanned: AtomicU64,
+    paid: AtomicU64,
+    pending: AtomicU64,
+    failed: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl RecoveryCounters {
+    const fn new() -> Self {
+        Self {
+            runs: AtomicU64::new(0),
+            scanned: AtomicU64::new(0),
+            paid: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, stats: &RecoveryStats) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.scanned
+            .fetch_add(stats.scanned as u64, Ordering::Relaxed);
+        self.paid.fetch_add(stats.paid as u64, Ordering::Relaxed);
+        self.pending
+            .fetch_add(stats.pending as u64, Ordering::Relaxed);
+        self.failed
+            .fetch_add(stats.failed as u64, Ordering::Relaxed);
+        self.errors
+            .fetch_add(stats.errors as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        text.push_str("# HELP charge_recovery_runs_total Charge recovery runs finished.\n");
+        text.push_str("# TYPE charge_recovery_runs_total counter\n");
+        text.push_str(&format!(
+            "charge_recovery_runs_total {}\n",
+            self.runs.load(Ordering::Relaxed)
+        ));
+        text.push_str("# HELP charge_recovery_charges_total Charges queried by charge recovery.\n");
+        text.push_str("# TYPE charge_recovery_charges_total counter\n");
+        for (result, counter) in [
+            ("scanned", &self.scanned),
+            ("paid", &self.paid),
+            ("pending", &self.pending),
+            ("failed", &self.failed),
+            ("error", &self.errors),
+        ] {
+            text.push_str(&format!(
+                "charge_recovery_charges_total{{result=\"{}\"}} {}\n",
+                result,
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+        text
+    }
+}
+
+static RECOVERY_COUNTERS: RecoveryCounters = RecoveryCounters::new();
+
+/**
+ * 补单的累计计数, Prometheus 文本格式
+ */
+pub fn charge_recovery_metrics() -> String {
+    RECOVERY_COUNTERS.render()
+}
+
+/**
+ * 满一批的话用最后一个 id 作为下一次的 cursor, 不满一批说明已经扫到最后, 返回 None 从头开始
+ */
+pub(super) fn next_cursor(last_id: Option<&str>, count: usize, batch_size: i64) -> Option<String> {
+    match last_id {
+        Some(last_id) if count as i64 >= batch_size => Some(last_id.to_string()),
+        _ => None,
+    }
+}
+
+/**
+ * 扫描一批创建超过 min_age_secs 还没支付也没过期的 charge, 逐个向渠道查询支付结果
+ * 查到已支付的通过 sync_charge_status 和异步通知走同样的逻辑更新 charge 和 order 并发送 webhook
+ * 一直没人付款的 charge 在过期之前每次都会被查到, 只按创建时间取前 batch_size 个的话后面的 charge 永远轮不到
+ * 所以按 id (前缀 + 毫秒时间戳 + 定长随机数, 和创建顺序一致) 分页, 从 cursor 之后继续扫描
+ * 返回这一批最后一个 charge 的 id 作为下一次的 cursor, 不满一批说明已经扫到最后, 返回 None 从头开始
+ */
+async fn recover_unpaid_charges(
+    prisma_client: &crate::prisma::PrismaClient,
+    min_age_secs: i64,
+    batch_size: i64,
+    cursor: Option<String>,
+) -> Result<(RecoveryStats, Option<String>), prisma_client_rust::QueryError> {
+    let now = chrono::Utc::now();
+    let created_before = (now - chrono::Duration::seconds(min_age_secs)).fixed_offset();
+    let mut filters = vec![
+        crate::prisma::charge::paid::equals(false),
+        crate::prisma::charge::reversed::equals(false),
+        crate::prisma::charge::time_expire::gt(now.timestamp() as i32),
+        crate::prisma::charge::created_at::lt(created_before),
+        crate::prisma::charge::channel::in_vec(recoverable_channels()),
+    ];
+    if let Some(cursor) = cursor {
+        filters.push(crate::prisma::charge::id::gt(cursor));
+    }
+    let charges = prisma_client
+        .charge()
+        .find_many(filters)
+        .order_by(crate::prisma::charge::id::order(
+            prisma_client_rust::Direction::Asc,
+        ))
+        .take(batch_size)
+        .exec()
+        .await?;
+    let next_cursor = next_cursor(
+        charges.last().map(|charge| charge.id.as_str()),
+        charges.len(),
+        batch_size,
+    );
+
+    let mut stats = RecoveryStats::default();
+    for charge in charges {
+        stats.scanned += 1;
+        match sync_charge_status(prisma_client, &charge.id).await {
+            Ok(ChargeStatus::Success) => {
+                stats.paid += 1;
+                tracing::info!(
+                    charge_id = charge.id,
+                    channel = charge.channel,
+                    "charge recovery: charge is paid, settled"
+                );
+            }
+            Ok(ChargeStatus::Pending) => {
+                stats.pending += 1;
+            }
+            Ok(ChargeStatus::Fail) => {
+                stats.failed += 1;
+                tracing::info!(
+                    charge_id = charge.id,
+                    channel = charge.channel,
+                    "charge recovery: charge is failed or closed on channel"
+                );
+            }
+            Err(e) => {
+                stats.errors += 1;
+                tracing::error!(
+                    charge_id = charge.id,
+                    channel = charge.channel,
+                    "charge recovery: error querying charge: {:?}",
+                    e
+                );
+            }
+        }
+    }
+    Ok((stats, next_cursor))
+}
+
+/**
+ * 定时补救丢失异步通知的 charge, 在 main 里面 spawn
+ * 间隔, charge 最小创建时长和每次扫描数量分别用环境变量
+ * CHARGE_RECOVERY_INTERVAL_SECS, CHARGE_RECOVERY_MIN_AGE_SECS, CHARGE_RECOVERY_BATCH_SIZE 配置
+ */
+pub async fn run_charge_recovery() {
+    let interval_secs = env_or("CHARGE_RECOVERY_INTERVAL_SECS", DEFAULT_INTERVAL_SECS);
+    let min_age_secs = env_or("CHARGE_RECOVERY_MIN_AGE_SECS", DEFAULT_MIN_AGE_SECS);
+    let batch_size = env_or("CHARGE_RECOVERY_BATCH_SIZE", DEFAULT_BATCH_SIZE);
+    if interval_secs == 0 {
+        tracing::info!("charge recovery disabled");
+        return;
+    }
+    let prisma_client = match crate::prisma::new_client().await {
+        Ok(prisma_client) => prisma_client,
+        Err(e) => {
+            tracing::error!("error getting prisma client for charge recovery: {:?}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+    // 只保存在内存里, 重启以后从头开始扫描
+    let mut cursor: Option<String> = None;
+    loop {
+        interval.tick().await;
+        let started_at = std::time::Instant::now();
+        match recover_unpaid_charges(&prisma_client, min_age_secs, batch_size, cursor.clone()).await
+        {
+            Ok((stats, next_cursor)) => {
+                cursor = next_cursor;
+                RECOVERY_COUNTERS.record(&stats);
+                tracing::info!(
+                    scanned = stats.scanned,
+                    paid = stats.paid,
+                    pending = stats.pending,
+                    failed = stats.failed,
+                    errors = stats.errors,
+                    elapsed_ms = started_at.elapsed().as_millis() as u64,
+                    cursor = cursor.as_deref().unwrap_or_default(),
+                    "charge recovery run finished"
+                );
+            }
+            Err(e) => {
+                tracing::error!("error loading unpaid charges for recovery: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_cursor() {
+        assert_eq!(next_cursor(None, 0, 100), None);
+        // 不满一批从头开始
+        assert_eq!(
+            next_cursor(Some("ch_171795983600236120277728"), 99, 100),
+            None
+        );
+        assert_eq!(
+            next_cursor(Some("ch_171795983600236120277728"), 100, 100),
+            Some("ch_171795983600236120277728".to_string())
+        );
+    }
+
+    #[test]
+    fn test_recovery_counters() {
+        let counters = RecoveryCounters::new();
+        let stats = RecoveryStats {
+            scanned: 5,
+            paid: 1,
+            pending: 2,
+            failed: 1,
+            errors: 1,
+        };
+        counters.record(&stats);
+        counters.record(&RecoveryStats::default());
+        counters.record(&stats);
+
+        let text = counters.render();
+        assert!(text.contains("# TYPE charge_recovery_runs_total counter\n"));
+        assert!(text.contains("charge_recovery_runs_total 3\n"));
+        assert!(text.contains("charge_recovery_charges_total{result=\"scanned\"} 10\n"));
+        assert!(text.contains("charge_recovery_charges_total{result=\"paid\"} 2\n"));
+        assert!(text.contains("charge_recovery_charges_total{result=\"pending\"} 4\n"));
+        assert!(text.contains("charge_recovery_charges_total{result=\"failed\"} 2\n"));
+        assert!(text.contains("charge_recovery_charges_total{result=\"error\"} 2\n"));
+    }
+}
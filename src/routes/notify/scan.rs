@@ -0,0 +1,140 @@
+use super::notify::{settle_paid_charge, settle_reversed_charge};
+use crate::core::{ChannelHandler, ChannelQueryRequest, ChargeError, ChargeStatus, PaymentChannel};
+use crate::routes::prelude::load_channel_handler;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/**
+ * 用户付款中 (输入密码等) 的时候, 每隔 QUERY_INTERVAL_SECONDS 查询一次, 最多查询 QUERY_MAX_TIMES 次
+ */
+const QUERY_INTERVAL_SECONDS: u64 = 5;
+const QUERY_MAX_TIMES: u32 = 6;
+
+/**
+ * 付款码支付 (alipay_scan | wx_pub_scan) 需要用户输入密码的时候, create_credential 返回 Pending
+ * 最长要等 30 秒, 不能放在下单请求里等 (客户端或者代理会先超时), charge 以 pending 状态保存并返回以后在后台轮询
+ * 收银台通过 /v1/charges/:charge_id 或者 /sync 拿最终结果, 后台任务出错的时候由 run_charge_recovery 继续查询
+ */
+pub fn spawn_scan_pay_poller(
+    prisma_client: Arc<crate::prisma::PrismaClient>,
+    charge: crate::prisma::charge::Data,
+    app_id: String,
+    sub_app_id: Option<String>,
+    order_id: Option<String>,
+) {
+    tokio::spawn(async move {
+        let channel = match PaymentChannel::from_str(&charge.channel) {
+            Ok(channel) => channel,
+            Err(e) => {
+                tracing::error!(
+                    charge_id = charge.id,
+                    "error parsing charge channel: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        let handler =
+            match load_channel_handler(&prisma_client, &channel, &app_id, sub_app_id.as_deref())
+                .await
+            {
+                Ok(handler) => handler,
+                Err(e) => {
+                    tracing::error!(
+                        charge_id = charge.id,
+                        "error loading scan charge handler: {:?}",
+                        e
+                    );
+                    return;
+                }
+            };
+        if let Err(e) = wait_for_scan_pay(
+            &prisma_client,
+            handler.as_ref(),
+            &charge,
+            order_id.as_deref(),
+        )
+        .await
+        {
+            tracing::error!(charge_id = charge.id, "error waiting for scan pay: {:?}", e);
+        }
+    });
+}
+
+/**
+ * 轮询 query_charge, 支付成功的和异步通知一样 settle
+ * 超时以后调用 reverse_charge 撤销, 如果用户已经付款了渠道会原路退款, charge 标记为 reversed
+ * 查询和撤销出错都只记录日志, charge 保持 pending, 交给 run_charge_recovery 继续查询
+ */
+async fn wait_for_scan_pay(
+    prisma_client: &crate::prisma::PrismaClient,
+    handler: &dyn ChannelHandler,
+    charge: &crate::prisma::charge::Data,
+    order_id: Option<&str>,
+) -> Result<(), ChargeError> {
+    let query_request = ChannelQueryRequest {
+        merchant_order_no: &charge.merchant_order_no,
+        currency: &charge.currency,
+    };
+    for _ in 0..QUERY_MAX_TIMES {
+        tokio::time::sleep(Duration::from_secs(QUERY_INTERVAL_SECONDS)).await;
+        let charge_result = match handler.query_charge(&query_request).await {
+            Ok(charge_result) => charge_result,
+            Err(e) => {
+                // 查询失败不影响下一次查询
+                tracing::error!(charge_id = charge.id, "error querying scan charge: {:?}", e);
+                continue;
+            }
+        };
+        match charge_result.status {
+            ChargeStatus::Success => {
+                return settle_paid_charge(prisma_client, charge, order_id, charge_result.extra)
+                    .await;
+            }
+            ChargeStatus::Fail => {
+                prisma_client
+                    .charge()
+                    .update(
+                        crate::prisma::charge::id::equals(charge.id.clone()),
+                        vec![
+                            crate::prisma::charge::failure_code::set(charge_result.failure_code),
+                            crate::prisma::charge::failure_msg::set(charge_result.failure_msg),
+                        ],
+                    )
+                    .exec()
+                    .await
+                    .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
+                return Ok(());
+            }
+            ChargeStatus::Pending => {} // 用户还在付款或者交易还不存在, 继续等
+        }
+    }
+
+    // 超时了, 撤销交易
+    if let Err(e) = handler.reverse_charge(&query_request).await {
+        tracing::error!(
+            charge_id = charge.id,
+            "error reversing scan charge after paying timeout, left pending: {:?}",
+            e
+        );
+        return Ok(());
+    }
+    // 轮询期间刚好收到支付成功的通知的话, 渠道原路退款, settle_reversed_charge 会更新 order
+    settle_reversed_charge(
+        prisma_client,
+        &charge.id,
+        vec![
+            crate::prisma::charge::failure_code::set(Some("USER_PAYING_TIMEOUT".to_string())),
+            crate::prisma::charge::failure_msg::set(Some(
+                "等待用户付款超时, 交易已撤销".to_string(),
+            )),
+        ],
+    )
+    .await?;
+    tracing::info!(
+        charge_id = charge.id,
+        "scan charge reversed after paying timeout"
+    );
+    Ok(())
+}
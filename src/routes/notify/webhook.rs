@@ -91,6 +91,74 @@ async fn request_to_webhook_endpoint(
     ))
 }
 
+/**
+ * 记录一条 webhook 历史然后发送, 发送的结果 (状态码和应答) 更新到历史记录上
+ */
+async fn deliver_webhook(
+    prisma_client: &crate::prisma::PrismaClient,
+    app_id: &str,
+    endpoint: &str,
+    event_type: &str,
+    event_data: serde_json::Value,
+) -> Result<(), WebhookError> {
+    let record = prisma_client
+        .app_webhook_history()
+        .create(
+            crate::utils::generate_id("evt_"),
+            app_id.to_string(),
+            endpoint.to_string(),
+            event_type.to_string(),
+            event_data,
+            0,
+            "".to_string(),
+            vec![],
+        )
+        .exec()
+        .await
+        .map_err(|e| WebhookError::Unexpected(format!("sql error: {:?}", e)))?;
+
+    let webhook_result = request_to_webhook_endpoint(
+        &record.endpoint,
+        &record.id,
+        &record.event,
+        &record.payload,
+        record.created_at.timestamp(),
+    )
+    .await;
+
+    match webhook_result {
+        Ok((status_code, response_text)) => {
+            prisma_client
+                .app_webhook_history()
+                .update(
+                    crate::prisma::app_webhook_history::id::equals(record.id.clone()),
+                    vec![
+                        crate::prisma::app_webhook_history::status_code::set(status_code),
+                        crate::prisma::app_webhook_history::response::set(response_text),
+                    ],
+                )
+                .exec()
+                .await
+                .map_err(|e| WebhookError::Unexpected(format!("sql error: {:?}", e)))?;
+        }
+        Err(e) => {
+            prisma_client
+                .app_webhook_history()
+                .update(
+                    crate::prisma::app_webhook_history::id::equals(record.id.clone()),
+                    vec![
+                        crate::prisma::app_webhook_history::status_code::set(500),
+                        crate::prisma::app_webhook_history::response::set(e),
+                    ],
+                )
+                .exec()
+                .await
+                .map_err(|e| WebhookError::Unexpected(format!("sql error: {:?}", e)))?;
+        }
+    }
+    Ok(())
+}
+
 pub(super) async fn send_charge_success_webhook(
     prisma_client: &crate::prisma::PrismaClient,
     charge_id: &str,
@@ -135,61 +203,51 @@ pub(super) async fn send_charge_success_webhook(
             }
         };
 
-        let record = prisma_client
-            .app_webhook_history()
-            .create(
-                crate::utils::generate_id("evt_"),
-                app.id.clone(),
-                webhook_config.endpoint.clone(),
-                event_type.to_string(),
-                event_data,
-                0,
-                "".to_string(),
-                vec![],
-            )
-            .exec()
-            .await
-            .map_err(|e| WebhookError::Unexpected(format!("sql error: {:?}", e)))?;
-
-        let webhook_result = request_to_webhook_endpoint(
-            &record.endpoint,
-            &record.id,
-            &record.event,
-            &record.payload,
-            record.created_at.timestamp(),
+        deliver_webhook(
+            prisma_client,
+            &app.id,
+            &webhook_config.endpoint,
+            event_type,
+            event_data,
         )
-        .await;
-
-        match webhook_result {
-            Ok((status_code, response_text)) => {
-                prisma_client
-                    .app_webhook_history()
-                    .update(
-                        crate::prisma::app_webhook_history::id::equals(record.id.clone()),
-                        vec![
-                            crate::prisma::app_webhook_history::status_code::set(status_code),
-                            crate::prisma::app_webhook_history::response::set(response_text),
-                        ],
-                    )
-                    .exec()
-                    .await
-                    .map_err(|e| WebhookError::Unexpected(format!("sql error: {:?}", e)))?;
-            }
-            Err(e) => {
-                prisma_client
-                    .app_webhook_history()
-                    .update(
-                        crate::prisma::app_webhook_history::id::equals(record.id.clone()),
-                        vec![
-                            crate::prisma::app_webhook_history::status_code::set(500),
-                            crate::prisma::app_webhook_history::response::set(e),
-                        ],
-                    )
-                    .exec()
-                    .await
-                    .map_err(|e| WebhookError::Unexpected(format!("sql error: {:?}", e)))?;
-            }
-        }
+        .await?;
+    }
+
+    Ok(())
+}
+
+/**
+ * order 过期自动取消以后发送 order.canceled, 业务系统收到以后可以释放库存
+ */
+pub(super) async fn send_order_canceled_webhook(
+    prisma_client: &crate::prisma::PrismaClient,
+    order_id: &str,
+) -> Result<(), WebhookError> {
+    let (order, charges, app, sub_app) = crate::utils::load_order_from_db(prisma_client, order_id)
+        .await
+        .map_err(|e| WebhookError::Unexpected(e.to_string()))?;
+
+    let webhook_configs = prisma_client
+        .app_webhook_config()
+        .find_many(vec![crate::prisma::app_webhook_config::app_id::equals(
+            app.id.clone(),
+        )])
+        .exec()
+        .await
+        .map_err(|e| WebhookError::Unexpected(format!("sql error: {:?}", e)))?;
+
+    let order_response: OrderResponse = (&order, charges.first(), &charges, &app, &sub_app).into();
+    let event_data = serde_json::to_value(order_response)
+        .map_err(|e| WebhookError::Unexpected(format!("error serializing event data: {:?}", e)))?;
+    for webhook_config in webhook_configs {
+        deliver_webhook(
+            prisma_client,
+            &app.id,
+            &webhook_config.endpoint,
+            "order.canceled",
+            event_data.clone(),
+        )
+        .await?;
     }
 
     Ok(())
@@ -1,8 +1,8 @@
 use crate::core::{
-    ChannelChargeExtra, ChannelChargeRequest, ChannelHandler, ChargeError, OrderResponse,
+    ChannelChargeExtra, ChannelChargeRequest, ChargeError, ChargeStatus, OrderResponse,
     PaymentChannel,
 };
-use crate::{alipay, weixin};
+use crate::routes::prelude::load_channel_handler;
 use serde::Deserialize;
 use serde_json::json;
 
@@ -14,7 +14,7 @@ pub struct CreateChargeRequestPayload {
 }
 
 pub async fn create_charge(
-    prisma_client: &crate::prisma::PrismaClient,
+    prisma_client: &std::sync::Arc<crate::prisma::PrismaClient>,
     order_id: String,
     charge_req_payload: CreateChargeRequestPayload,
 ) -> Result<serde_json::Value, ChargeError> {
@@ -22,32 +22,33 @@ pub async fn create_charge(
 
     let (order, _charges, app, sub_app) =
         crate::utils::load_order_from_db(&prisma_client, &order_id).await?;
+    if order.status == "canceled" {
+        return Err(ChargeError::MalformedRequest(format!(
+            "order {} is canceled",
+            order_id
+        )));
+    }
 
-    let handler: Box<dyn ChannelHandler + Send> = match charge_req_payload.channel {
-        PaymentChannel::AlipayPcDirect => Box::new(
-            alipay::AlipayPcDirect::new(&prisma_client, Some(&app.id), Some(&sub_app.id)).await?,
-        ),
-        PaymentChannel::AlipayWap => Box::new(
-            alipay::AlipayWap::new(&prisma_client, Some(&app.id), Some(&sub_app.id)).await?,
-        ),
-        PaymentChannel::WxPub => {
-            Box::new(weixin::WxPub::new(&prisma_client, Some(&app.id), Some(&sub_app.id)).await?)
-        }
-        PaymentChannel::WxLite => {
-            Box::new(weixin::WxLite::new(&prisma_client, Some(&app.id), Some(&sub_app.id)).await?)
-        }
-    };
+    let handler = load_channel_handler(
+        prisma_client,
+        &charge_req_payload.channel,
+        &app.id,
+        Some(&sub_app.id),
+    )
+    .await?;
 
-    let credential_object = handler
+    let charge_result = handler
         .create_credential(&ChannelChargeRequest {
             charge_id: &charge_id,
             charge_amount: charge_req_payload.charge_amount,
+            currency: &order.currency,
             merchant_order_no: &order.merchant_order_no,
             client_ip: &order.client_ip,
             time_expire: order.time_expire,
             subject: &order.subject,
             body: &order.body,
             extra: &charge_req_payload.extra,
+            uid: Some(&order.uid),
         })
         .await?;
 
@@ -61,7 +62,7 @@ pub async fn create_charge(
             .as_str()
             .unwrap()
             .to_owned();
-        credential[key] = credential_object;
+        credential[key] = charge_result.credential;
         credential
     };
 
@@ -87,6 +88,8 @@ pub async fn create_charge(
             order.time_expire,
             vec![
                 // crate::prisma::charge::order_id::set(Some(order_id.clone()))
+                crate::prisma::charge::failure_code::set(charge_result.failure_code),
+                crate::prisma::charge::failure_msg::set(charge_result.failure_msg),
             ],
         )
         .exec()
@@ -103,6 +106,40 @@ pub async fn create_charge(
         .await
         .map_err(|e| ChargeError::InternalError(format!("sql error: {:?}", e)))?;
 
+    // 付款码这类渠道已经同步拿到了支付结果, 不会再有异步通知
+    if charge_result.status == ChargeStatus::Success {
+        super::super::notify::settle_paid_charge(
+            &prisma_client,
+            &charge,
+            Some(&order_id),
+            charge_result.extra,
+        )
+        .await?;
+    } else if matches!(charge_req_payload.channel, PaymentChannel::Balance) {
+        // 余额支付 charge 保存以后再扣款, 扣款失败的 charge 记录失败原因
+        super::super::notify::settle_balance_charge(
+            prisma_client,
+            &charge,
+            Some(&order_id),
+            &order.uid,
+        )
+        .await?;
+    } else if charge_result.status == ChargeStatus::Pending
+        && matches!(
+            charge_req_payload.channel,
+            PaymentChannel::AlipayScan | PaymentChannel::WxPubScan
+        )
+    {
+        // 付款码支付用户需要输入密码, charge 已经以 pending 状态保存, 在后台等支付结果
+        super::super::notify::spawn_scan_pay_poller(
+            prisma_client.clone(),
+            charge.clone(),
+            app.id.clone(),
+            Some(sub_app.id.clone()),
+            Some(order_id.clone()),
+        );
+    }
+
     // 重新 load 一下 order 数据，因为 order.charges 已经更新
     let (order, charges, _, _) =
         crate::utils::load_order_from_db(&prisma_client, &order_id).await?;
@@ -1,6 +1,9 @@
-use crate::core::{OrderError, OrderResponse};
+use crate::core::{ChannelQueryRequest, ChargeError, OrderError, OrderResponse, PaymentChannel};
+use crate::routes::notify::recoverable_channels;
+use crate::routes::prelude::load_channel_handler;
 use serde::Deserialize;
 use serde_json::json;
+use std::str::FromStr;
 
 #[derive(Deserialize, Debug)]
 pub struct CreateOrderRequestPayload {
@@ -85,3 +88,100 @@ pub async fn retrieve_order(
     })?;
     Ok(result)
 }
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateOrderRequestPayload {
+    pub status: String, // 目前只支持 canceled
+}
+
+/**
+ * 目前只支持取消 order, 先在渠道上关闭所有还没支付也没过期的 charge, 都关闭成功以后再把 order.status 改成 canceled
+ * 银联和 PayPal 不支持关单, 余额支付没有未完成的交易, 这些 charge 和过期自动取消一样直接跳过
+ * 已经支付的 order 不能取消, 关单失败 (比如用户刚好付款了) 的时候 order 保持原来的状态, 可以稍后重试
+ */
+pub async fn update_order(
+    prisma_client: &crate::prisma::PrismaClient,
+    order_id: String,
+    req_payload: UpdateOrderRequestPayload,
+) -> Result<serde_json::Value, OrderError> {
+    if req_payload.status != "canceled" {
+        return Err(OrderError::BadRequest(format!(
+            "unsupported order status {}, only canceled is allowed",
+            req_payload.status
+        )));
+    }
+
+    let (order, charges, app, sub_app) =
+        crate::utils::load_order_from_db(&prisma_client, &order_id).await?;
+    if order.status == "canceled" {
+        return retrieve_order(prisma_client, order_id).await;
+    }
+    if order.paid {
+        return Err(OrderError::BadRequest(format!(
+            "order {} is already paid",
+            order_id
+        )));
+    }
+
+    // 已经撤销的 charge 渠道上的交易已经关闭或者原路退款, 不需要再关单
+    let now = chrono::Utc::now().timestamp() as i32;
+    let recoverable_channels = recoverable_channels();
+    for charge in charges.iter().filter(|charge| {
+        !charge.paid
+            && !charge.reversed
+            && charge.time_expire > now
+            && recoverable_channels.contains(&charge.channel)
+    }) {
+        close_charge(prisma_client, charge, &app.id, &sub_app.id).await?;
+        tracing::info!(order_id, charge_id = charge.id, "charge closed on channel");
+    }
+
+    // 只更新还是 created 的 order, 关单过程中收到支付成功的异步通知的话 order 已经是 paid
+    let updated = prisma_client
+        .order()
+        .update_many(
+            vec![
+                crate::prisma::order::id::equals(order_id.clone()),
+                crate::prisma::order::status::equals("created".to_string()),
+            ],
+            vec![crate::prisma::order::status::set("canceled".to_string())],
+        )
+        .exec()
+        .await
+        .map_err(|e| OrderError::Unexpected(format!("sql error: {:?}", e)))?;
+    if updated == 0 {
+        let (order, _, _, _) = crate::utils::load_order_from_db(&prisma_client, &order_id).await?;
+        if order.status != "canceled" {
+            return Err(OrderError::BadRequest(format!(
+                "order {} is {}, can not be canceled",
+                order_id, order.status
+            )));
+        }
+    }
+
+    retrieve_order(prisma_client, order_id).await
+}
+
+/**
+ * 在渠道上关闭 charge, 取消 order 和过期自动取消都用这个
+ */
+pub async fn close_charge(
+    prisma_client: &crate::prisma::PrismaClient,
+    charge: &crate::prisma::charge::Data,
+    app_id: &str,
+    sub_app_id: &str,
+) -> Result<(), ChargeError> {
+    let channel = PaymentChannel::from_str(&charge.channel).map_err(|e| {
+        ChargeError::InternalError(format!(
+            "channel {} on charge {} is invalid: {:?}",
+            charge.channel, charge.id, e
+        ))
+    })?;
+    let handler = load_channel_handler(prisma_client, &channel, app_id, Some(sub_app_id)).await?;
+    handler
+        .close_charge(&ChannelQueryRequest {
+            merchant_order_no: &charge.merchant_order_no,
+            currency: &charge.currency,
+        })
+        .await
+}
@@ -1,8 +1,8 @@
 use crate::core::{
-    ChannelHandler, ChannelRefundExtra, ChannelRefundRequest, PaymentChannel, RefundError,
-    RefundResponse, RefundStatus,
+    ChannelRefundExtra, ChannelRefundRequest, PaymentChannel, RefundError, RefundResponse,
+    RefundStatus,
 };
-use crate::{alipay, weixin};
+use crate::routes::prelude::load_channel_handler;
 use serde::Deserialize;
 use serde_json::json;
 use std::str::FromStr;
@@ -29,6 +29,13 @@ pub async fn create_refund(
     let (charge, order, _refunds, app, sub_app) =
         crate::utils::load_charge_from_db(&prisma_client, &charge_id).await?;
 
+    if charge.reversed {
+        return Err(RefundError::BadRequest(format!(
+            "charge {} is reversed",
+            charge_id
+        )));
+    }
+
     let order = match order {
         Some(order) => order,
         None => {
@@ -62,26 +69,15 @@ pub async fn create_refund(
             charge.channel, charge.id, e
         ))
     })?;
-    let handler: Box<dyn ChannelHandler + Send> = match channel {
-        PaymentChannel::AlipayPcDirect => Box::new(
-            alipay::AlipayPcDirect::new(&prisma_client, Some(&app.id), Some(&sub_app.id)).await?,
-        ),
-        PaymentChannel::AlipayWap => Box::new(
-            alipay::AlipayWap::new(&prisma_client, Some(&app.id), Some(&sub_app.id)).await?,
-        ),
-        PaymentChannel::WxPub => {
-            Box::new(weixin::WxPub::new(&prisma_client, Some(&app.id), Some(&sub_app.id)).await?)
-        }
-        PaymentChannel::WxLite => {
-            Box::new(weixin::WxLite::new(&prisma_client, Some(&app.id), Some(&sub_app.id)).await?)
-        }
-    };
+    let handler = load_channel_handler(prisma_client, &channel, &app.id, Some(&sub_app.id)).await?;
 
     let refund_result = handler
         .create_refund(&ChannelRefundRequest {
             charge_id: &charge.id,
             charge_amount: charge.amount,
+            charge_currency: &charge.currency,
             charge_merchant_order_no: &charge.merchant_order_no,
+            charge_credential: &charge.credential,
             refund_id: &refund_id,
             refund_amount: refund_req_payload.refund_amount,
             refund_merchant_order_no: &refund_merchant_order_no,
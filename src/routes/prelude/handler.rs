@@ -0,0 +1,90 @@
+use crate::core::{ChannelHandler, ChargeError, PaymentChannel};
+use crate::{alipay, balance, paypal, upacp, weixin};
+
+/**
+ * 按渠道加载 ChannelHandler, 下单, 通知, 查询, 关单, 撤销和退款都用这个
+ * sub_app_id 是 None 的时候用 App 上的渠道参数, 否则用 sub_app 的 (服务商模式下再合并 parent App 的)
+ * 加载失败一般是渠道参数没配置或者配置错了, 统一转成 ChargeError, 退款的时候再转成 RefundError
+ */
+pub async fn load_channel_handler<'a>(
+    prisma_client: &'a crate::prisma::PrismaClient,
+    channel: &PaymentChannel,
+    app_id: &str,
+    sub_app_id: Option<&str>,
+) -> Result<Box<dyn ChannelHandler + Send + 'a>, ChargeError> {
+    let handler: Box<dyn ChannelHandler + Send + 'a> = match channel {
+        PaymentChannel::AlipayPcDirect => {
+            Box::new(alipay::AlipayPcDirect::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::AlipayWap => {
+            Box::new(alipay::AlipayWap::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::WxPub => Box::new(
+            weixin::WxJsapi::new(
+                prisma_client,
+                Some(app_id),
+                sub_app_id,
+                PaymentChannel::WxPub,
+            )
+            .await?,
+        ),
+        PaymentChannel::WxLite => Box::new(
+            weixin::WxJsapi::new(
+                prisma_client,
+                Some(app_id),
+                sub_app_id,
+                PaymentChannel::WxLite,
+            )
+            .await?,
+        ),
+        PaymentChannel::AlipayQr => {
+            Box::new(alipay::AlipayQr::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::WxPubQr => {
+            Box::new(weixin::WxPubQr::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::WxWap => {
+            Box::new(weixin::WxWap::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::Alipay => {
+            Box::new(alipay::AlipayApp::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::Wx => {
+            Box::new(weixin::WxApp::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::AlipayLite => {
+            Box::new(alipay::AlipayLite::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::AlipayScan => {
+            Box::new(alipay::AlipayScan::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::WxPubScan => {
+            Box::new(weixin::WxPubScan::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::UpacpPc => Box::new(
+            upacp::UpacpGateway::new(
+                prisma_client,
+                Some(app_id),
+                sub_app_id,
+                PaymentChannel::UpacpPc,
+                "07",
+            )
+            .await?,
+        ),
+        PaymentChannel::UpacpWap => Box::new(
+            upacp::UpacpGateway::new(
+                prisma_client,
+                Some(app_id),
+                sub_app_id,
+                PaymentChannel::UpacpWap,
+                "08",
+            )
+            .await?,
+        ),
+        PaymentChannel::Paypal => {
+            Box::new(paypal::Paypal::new(prisma_client, Some(app_id), sub_app_id).await?)
+        }
+        PaymentChannel::Balance => Box::new(balance::Balance::new(prisma_client, app_id)),
+    };
+    Ok(handler)
+}
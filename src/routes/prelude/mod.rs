@@ -1,6 +1,8 @@
+mod handler;
 mod serializers;
 use crate::core::{ChargeError, OrderError, RefundError};
 use crate::utils::DBError;
+pub use handler::load_channel_handler;
 
 impl From<DBError> for OrderError {
     fn from(e: DBError) -> Self {
@@ -11,6 +13,30 @@ impl From<DBError> for OrderError {
     }
 }
 
+/**
+ * 取消 order 的时候要在渠道上关单, 渠道的错误转成 OrderError
+ */
+impl From<ChargeError> for OrderError {
+    fn from(e: ChargeError) -> Self {
+        match e {
+            ChargeError::MalformedRequest(msg) => OrderError::BadRequest(msg),
+            ChargeError::InternalError(msg) => OrderError::Unexpected(msg),
+        }
+    }
+}
+
+/**
+ * 退款的时候加载渠道 handler 出错, ChargeError 转成 RefundError
+ */
+impl From<ChargeError> for RefundError {
+    fn from(e: ChargeError) -> Self {
+        match e {
+            ChargeError::MalformedRequest(msg) => RefundError::BadRequest(msg),
+            ChargeError::InternalError(msg) => RefundError::Unexpected(msg),
+        }
+    }
+}
+
 impl From<DBError> for ChargeError {
     fn from(e: DBError) -> Self {
         match e {
@@ -33,11 +33,43 @@ pub async fn create_or_update_sub_app_channel(
     app_id: String,
     sub_app_id: String,
     channel: String,
-    params: serde_json::Value,
+    mut params: serde_json::Value,
 ) -> Result<serde_json::Value, String> {
+    let payment_channel =
+        PaymentChannel::from_str(&channel).map_err(|e| format!("invalid channel: {:?}", e))?;
+    // 支付宝密钥统一转换成 PEM 保存, 解析不了的直接报错
+    if matches!(
+        payment_channel,
+        PaymentChannel::Alipay
+            | PaymentChannel::AlipayPcDirect
+            | PaymentChannel::AlipayWap
+            | PaymentChannel::AlipayQr
+            | PaymentChannel::AlipayLite
+            | PaymentChannel::AlipayScan
+    ) {
+        crate::alipay::normalize_alipay_keys(&mut params).map_err(|e| e.to_string())?;
+    }
     {
         let params = params.clone();
-        match PaymentChannel::from_str(&channel).map_err(|e| format!("invalid channel: {:?}", e))? {
+        match payment_channel {
+            // 支付宝服务商模式, sub_app 上只需要授权令牌, 其他参数在 parent App 上
+            PaymentChannel::Alipay
+            | PaymentChannel::AlipayPcDirect
+            | PaymentChannel::AlipayWap
+            | PaymentChannel::AlipayQr
+            | PaymentChannel::AlipayLite
+            | PaymentChannel::AlipayScan
+                if params.get("alipay_app_auth_token").is_some() =>
+            {
+                serde_json::from_value::<crate::alipay::AlipayIsvAuthConfig>(params)
+                    .map_err(|e| format!("invalid alipay isv params: {:?}", e))?;
+            }
+            PaymentChannel::Alipay => {
+                serde_json::from_value::<crate::alipay::AlipayOpenApiConfig>(params)
+                    .map_err(|e| format!("invalid alipay params: {:?}", e))?
+                    .check_public_key()
+                    .map_err(|e| format!("invalid alipay params: {:?}", e))?;
+            }
             PaymentChannel::AlipayPcDirect => {
                 serde_json::from_value::<crate::alipay::AlipayPcDirectConfig>(params)
                     .map_err(|e| format!("invalid alipay_pc_direct params: {:?}", e))?;
@@ -46,14 +78,79 @@ pub async fn create_or_update_sub_app_channel(
                 serde_json::from_value::<crate::alipay::AlipayWapConfig>(params)
                     .map_err(|e| format!("invalid alipay_wap params: {:?}", e))?;
             }
+            PaymentChannel::AlipayQr => {
+                serde_json::from_value::<crate::alipay::AlipayOpenApiConfig>(params)
+                    .map_err(|e| format!("invalid alipay_qr params: {:?}", e))?
+                    .check_public_key()
+                    .map_err(|e| format!("invalid alipay_qr params: {:?}", e))?;
+            }
+            PaymentChannel::AlipayLite => {
+                serde_json::from_value::<crate::alipay::AlipayOpenApiConfig>(params)
+                    .map_err(|e| format!("invalid alipay_lite params: {:?}", e))?
+                    .check_public_key()
+                    .map_err(|e| format!("invalid alipay_lite params: {:?}", e))?;
+            }
+            PaymentChannel::AlipayScan => {
+                serde_json::from_value::<crate::alipay::AlipayOpenApiConfig>(params)
+                    .map_err(|e| format!("invalid alipay_scan params: {:?}", e))?
+                    .check_public_key()
+                    .map_err(|e| format!("invalid alipay_scan params: {:?}", e))?;
+            }
+            PaymentChannel::Balance => {
+                // 余额支付不需要渠道参数
+            }
+            PaymentChannel::Paypal => {
+                serde_json::from_value::<crate::paypal::PaypalConfig>(params)
+                    .map_err(|e| format!("invalid paypal params: {:?}", e))?;
+            }
+            PaymentChannel::UpacpPc | PaymentChannel::UpacpWap => {
+                serde_json::from_value::<crate::upacp::UpacpConfig>(params)
+                    .map_err(|e| format!("invalid upacp params: {:?}", e))?;
+            }
+            // 微信服务商模式, sub_app 上只需要子商户号, 其他参数在 parent App 上
+            PaymentChannel::WxPub
+            | PaymentChannel::WxPubQr
+            | PaymentChannel::WxPubScan
+            | PaymentChannel::WxLite
+                if params.get("wx_sub_mch_id").is_some() =>
+            {
+                serde_json::from_value::<crate::weixin::WxSubMerchantConfig>(params)
+                    .map_err(|e| format!("invalid weixin sub merchant params: {:?}", e))?;
+            }
+            PaymentChannel::Wx => {
+                serde_json::from_value::<crate::weixin::WxAppConfig>(params)
+                    .map_err(|e| format!("invalid wx params: {:?}", e))?;
+            }
             PaymentChannel::WxPub => {
                 serde_json::from_value::<crate::weixin::WxPubConfig>(params)
+                    .map_err(|e| format!("invalid wx_pub params: {:?}", e))?
+                    .check_sign_key(false)
                     .map_err(|e| format!("invalid wx_pub params: {:?}", e))?;
             }
+            PaymentChannel::WxPubQr => {
+                // wx_pub_qr 和 wx_pub 使用相同的渠道参数
+                serde_json::from_value::<crate::weixin::WxPubConfig>(params)
+                    .map_err(|e| format!("invalid wx_pub_qr params: {:?}", e))?
+                    .check_sign_key(true)
+                    .map_err(|e| format!("invalid wx_pub_qr params: {:?}", e))?;
+            }
+            PaymentChannel::WxPubScan => {
+                // wx_pub_scan 和 wx_pub 使用相同的渠道参数
+                serde_json::from_value::<crate::weixin::WxPubConfig>(params)
+                    .map_err(|e| format!("invalid wx_pub_scan params: {:?}", e))?
+                    .check_sign_key(true)
+                    .map_err(|e| format!("invalid wx_pub_scan params: {:?}", e))?;
+            }
             PaymentChannel::WxLite => {
                 serde_json::from_value::<crate::weixin::WxLiteConfig>(params)
+                    .map_err(|e| format!("invalid wx_lite params: {:?}", e))?
+                    .check_sign_key()
                     .map_err(|e| format!("invalid wx_lite params: {:?}", e))?;
             }
+            PaymentChannel::WxWap => {
+                serde_json::from_value::<crate::weixin::WxWapConfig>(params)
+                    .map_err(|e| format!("invalid wx_wap params: {:?}", e))?;
+            }
         };
     }
 
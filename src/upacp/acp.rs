@@ -0,0 +1,450 @@
+use super::UpacpError;
+use serde::Serialize;
+use std::collections::HashMap;
+
+mod acp_rsa {
+    use super::*;
+    use openssl::{
+        hash::MessageDigest,
+        pkcs12::Pkcs12,
+        pkey::{PKey, Private},
+        sign::{Signer, Verifier},
+        x509::X509,
+    };
+
+    /**
+     * 5.1.0 版本 signMethod=01 的签名原文: 除 signature 以外的所有字段按 key 排序拼成 key1=value1&key2=value2
+     * 然后对原文做 sha256 得到 16 进制小写字符串, 再对这个字符串做 SHA256withRSA
+     */
+    fn sha256_hex_of_sorted_source(m: &HashMap<String, String>) -> String {
+        let mut query_list = Vec::<String>::new();
+        m.iter().for_each(|(k, v)| {
+            let query = format!("{}={}", k, v);
+            query_list.push(query);
+        });
+        query_list.sort();
+        let sign_sorted_source = query_list.join("&");
+        let digest = openssl::sha::sha256(sign_sorted_source.as_bytes());
+        data_encoding::HEXLOWER.encode(&digest)
+    }
+
+    /**
+     * 从 pfx 签名证书里取出私钥和 certId, certId 是证书序列号的十进制字符串
+     */
+    pub fn load_sign_cert(
+        sign_cert: &str,
+        sign_cert_password: &str,
+    ) -> Result<(PKey<Private>, String), UpacpError> {
+        let der = data_encoding::BASE64.decode(sign_cert.trim().as_bytes())?;
+        let parsed = Pkcs12::from_der(&der)?.parse2(sign_cert_password)?;
+        let private_key = parsed
+            .pkey
+            .ok_or_else(|| UpacpError::InvalidConfig("missing private key in sign cert".into()))?;
+        let cert = parsed
+            .cert
+            .ok_or_else(|| UpacpError::InvalidConfig("missing certificate in sign cert".into()))?;
+        let cert_id = cert.serial_number().to_bn()?.to_dec_str()?.to_string();
+        Ok((private_key, cert_id))
+    }
+
+    pub fn sign(
+        m: &HashMap<String, String>,
+        private_key: &PKey<Private>,
+    ) -> Result<String, UpacpError> {
+        let sign_source = sha256_hex_of_sorted_source(m);
+        let mut signer = Signer::new(MessageDigest::sha256(), private_key)?;
+        signer.update(sign_source.as_bytes())?;
+        let signature_bytes = signer.sign_to_vec()?;
+        let signature = data_encoding::BASE64.encode(&signature_bytes);
+        Ok(signature)
+    }
+
+    pub fn verify(
+        m: &HashMap<String, String>,
+        signature: &str,
+        verify_cert: &str,
+    ) -> Result<bool, UpacpError> {
+        let sign_source = sha256_hex_of_sorted_source(m);
+        let cert = X509::from_pem(verify_cert.as_bytes())?;
+        let public_key = cert.public_key()?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+        verifier.update(sign_source.as_bytes())?;
+        let signature_bytes = data_encoding::BASE64.decode(signature.as_bytes())?;
+        let result = verifier.verify(&signature_bytes)?;
+        Ok(result)
+    }
+}
+
+/**
+ * 银联的时间都是北京时间 yyyyMMddHHmmss
+ */
+fn format_beijing_time(timestamp: i64) -> Result<String, UpacpError> {
+    let time = chrono::DateTime::<chrono::Utc>::from_timestamp(timestamp, 0)
+        .ok_or_else(|| UpacpError::MalformedRequest("can't convert timestamp to datetime".into()))?
+        .with_timezone(&chrono::FixedOffset::east_opt(8 * 3600).unwrap())
+        .format("%Y%m%d%H%M%S")
+        .to_string();
+    Ok(time)
+}
+
+/**
+ * 把 payload 转成 HashMap 签名, signature 和 channel_url 不参与签名
+ */
+fn sign_payload<T: Serialize>(
+    payload: &T,
+    private_key: &openssl::pkey::PKey<openssl::pkey::Private>,
+) -> Result<String, UpacpError> {
+    // 这里 deserialize 不会出问题
+    let v = serde_json::to_value(payload).unwrap();
+    let mut m: HashMap<String, String> = serde_json::from_value(v).unwrap();
+    m.remove("signature");
+    m.remove("channel_url");
+    acp_rsa::sign(&m, private_key)
+}
+
+/**
+ * 银联后台接口同步返回的是 key1=value1&key2=value2, 没有 url encode
+ * signature 和 signPubKeyCert 里面会有 = 号, 所以只按第一个 = 号分割
+ */
+fn response_to_map(payload: &str) -> HashMap<String, String> {
+    let mut m: HashMap<String, String> = HashMap::new();
+    payload.split('&').for_each(|pair| {
+        if let Some((key, val)) = pair.split_once('=') {
+            m.insert(key.to_string(), val.to_string());
+        }
+    });
+    m
+}
+
+/**
+ * 后台接口 (查询, 退货) 使用 x-www-form-urlencoded 提交, 同步返回的结果需要验签
+ */
+async fn send_back_request(
+    url: &str,
+    m: &HashMap<String, String>,
+    verify_cert: &str,
+) -> Result<HashMap<String, String>, UpacpError> {
+    let res = reqwest::Client::new()
+        .post(url)
+        .form(m)
+        .send()
+        .await
+        .map_err(|e| UpacpError::ApiError(format!("error request upacp api: {}", e)))?;
+    let res_text = res
+        .text()
+        .await
+        .map_err(|e| UpacpError::ApiError(format!("error read upacp api response: {}", e)))?;
+    tracing::debug!("upacp api response: {:?}", res_text);
+
+    let mut m = response_to_map(&res_text);
+    let signature = m
+        .remove("signature")
+        .ok_or_else(|| UpacpError::ApiError(format!("missing signature: {}", res_text)))?;
+    if !acp_rsa::verify(&m, &signature, verify_cert)? {
+        return Err(UpacpError::ApiError(
+            "wrong upacp response signature".into(),
+        ));
+    }
+    Ok(m)
+}
+
+/**
+ * 前台交易 (frontTransReq), credential 里返回所有表单字段和 channel_url, 前端自动提交表单跳转到银联收银台
+ * https://open.unionpay.com/tjweb/acproduct/APIList?acpAPIId=754&apiservId=448&version=V2.2
+ */
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcpFrontPayload {
+    #[serde(rename = "channel_url")]
+    pub channel_url: String,
+    pub version: String,
+    pub encoding: String,
+    pub txn_type: String,
+    pub txn_sub_type: String,
+    pub biz_type: String,
+    pub channel_type: String,
+    pub access_type: String,
+    pub mer_id: String,
+    pub order_id: String,
+    pub txn_time: String,
+    pub txn_amt: String,
+    pub currency_code: String,
+    pub front_url: String,
+    pub back_url: String,
+    pub pay_timeout: String,
+    pub cert_id: String,
+    pub sign_method: String,
+    pub signature: String,
+}
+
+impl AcpFrontPayload {
+    pub fn new(
+        charge_id: &str,         //
+        channel_type: &str,      // 07: PC | 08: 手机
+        upacp_mer_id: &str,      // 银联商户号
+        front_url: &str,         // 支付成功跳转
+        merchant_order_no: &str, // 商户订单号
+        charge_amount: i32,      // 支付金额, 精确到分
+        time_expire: i32,        // 过期时间 timestamp 精确到秒
+    ) -> Result<Self, UpacpError> {
+        let txn_time = format_beijing_time(chrono::Utc::now().timestamp())?;
+        let pay_timeout = format_beijing_time(time_expire as i64)?;
+        Ok(Self {
+            channel_url: String::from("https://gateway.95516.com/gateway/api/frontTransReq.do"),
+            version: String::from("5.1.0"),
+            encoding: String::from("UTF-8"),
+            txn_type: String::from("01"),     // 消费
+            txn_sub_type: String::from("01"), // 自助消费
+            biz_type: String::from("000201"), // B2C 网关支付
+            channel_type: channel_type.to_string(),
+            access_type: String::from("0"), // 商户直连接入
+            mer_id: upacp_mer_id.to_string(),
+            order_id: merchant_order_no.to_string(),
+            txn_time,
+            txn_amt: charge_amount.to_string(),
+            currency_code: String::from("156"),
+            front_url: front_url.to_string(),
+            back_url: crate::utils::charge_notify_url(charge_id),
+            pay_timeout,
+            cert_id: String::from(""),
+            sign_method: String::from("01"), // RSA
+            signature: String::from(""),
+        })
+    }
+
+    pub fn sign_rsa(
+        &mut self,
+        sign_cert: &str,
+        sign_cert_password: &str,
+    ) -> Result<String, UpacpError> {
+        let (private_key, cert_id) = acp_rsa::load_sign_cert(sign_cert, sign_cert_password)?;
+        self.cert_id = cert_id;
+        let signature = sign_payload(&self, &private_key)?;
+        self.signature = signature.clone();
+        Ok(signature)
+    }
+}
+
+/**
+ * 交易状态查询, 退货需要原交易的 queryId, 用 orderId + txnTime 查询得到
+ */
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcpQueryPayload {
+    pub version: String,
+    pub encoding: String,
+    pub txn_type: String,
+    pub txn_sub_type: String,
+    pub biz_type: String,
+    pub access_type: String,
+    pub mer_id: String,
+    pub order_id: String,
+    pub txn_time: String,
+    pub cert_id: String,
+    pub sign_method: String,
+    pub signature: String,
+}
+
+impl AcpQueryPayload {
+    pub fn new(
+        upacp_mer_id: &str,      // 银联商户号
+        merchant_order_no: &str, // 原交易商户订单号
+        txn_time: &str,          // 原交易的 txnTime
+    ) -> Result<Self, UpacpError> {
+        Ok(Self {
+            version: String::from("5.1.0"),
+            encoding: String::from("UTF-8"),
+            txn_type: String::from("00"),
+            txn_sub_type: String::from("00"),
+            biz_type: String::from("000000"),
+            access_type: String::from("0"),
+            mer_id: upacp_mer_id.to_string(),
+            order_id: merchant_order_no.to_string(),
+            txn_time: txn_time.to_string(),
+            cert_id: String::from(""),
+            sign_method: String::from("01"),
+            signature: String::from(""),
+        })
+    }
+
+    pub fn sign_rsa(
+        &mut self,
+        sign_cert: &str,
+        sign_cert_password: &str,
+    ) -> Result<String, UpacpError> {
+        let (private_key, cert_id) = acp_rsa::load_sign_cert(sign_cert, sign_cert_password)?;
+        self.cert_id = cert_id;
+        let signature = sign_payload(&self, &private_key)?;
+        self.signature = signature.clone();
+        Ok(signature)
+    }
+
+    pub async fn send_request(
+        &self,
+        verify_cert: &str,
+    ) -> Result<HashMap<String, String>, UpacpError> {
+        // 这里 deserialize 不会出问题
+        let v = serde_json::to_value(&self).unwrap();
+        let m: HashMap<String, String> = serde_json::from_value(v).unwrap();
+        send_back_request(
+            "https://gateway.95516.com/gateway/api/queryTrans.do",
+            &m,
+            verify_cert,
+        )
+        .await
+    }
+}
+
+/**
+ * 退货 (backTransReq), 同步返回 respCode=00 只表示受理成功, 结果以退款异步通知为准
+ */
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcpRefundPayload {
+    pub version: String,
+    pub encoding: String,
+    pub txn_type: String,
+    pub txn_sub_type: String,
+    pub biz_type: String,
+    pub channel_type: String,
+    pub access_type: String,
+    pub mer_id: String,
+    pub order_id: String,
+    pub orig_qry_id: String,
+    pub txn_time: String,
+    pub txn_amt: String,
+    pub back_url: String,
+    pub cert_id: String,
+    pub sign_method: String,
+    pub signature: String,
+}
+
+impl AcpRefundPayload {
+    pub fn new(
+        refund_id: &str,
+        charge_id: &str,
+        channel_type: &str,
+        upacp_mer_id: &str,
+        orig_query_id: &str, // 原交易的 queryId
+        refund_merchant_order_no: &str,
+        refund_amount: i32,
+    ) -> Result<Self, UpacpError> {
+        let txn_time = format_beijing_time(chrono::Utc::now().timestamp())?;
+        Ok(Self {
+            version: String::from("5.1.0"),
+            encoding: String::from("UTF-8"),
+            txn_type: String::from("04"), // 退货
+            txn_sub_type: String::from("00"),
+            biz_type: String::from("000201"),
+            channel_type: channel_type.to_string(),
+            access_type: String::from("0"),
+            mer_id: upacp_mer_id.to_string(),
+            order_id: refund_merchant_order_no.to_string(),
+            orig_qry_id: orig_query_id.to_string(),
+            txn_time,
+            txn_amt: refund_amount.to_string(),
+            back_url: crate::utils::refund_notify_url(charge_id, refund_id),
+            cert_id: String::from(""),
+            sign_method: String::from("01"),
+            signature: String::from(""),
+        })
+    }
+
+    pub fn sign_rsa(
+        &mut self,
+        sign_cert: &str,
+        sign_cert_password: &str,
+    ) -> Result<String, UpacpError> {
+        let (private_key, cert_id) = acp_rsa::load_sign_cert(sign_cert, sign_cert_password)?;
+        self.cert_id = cert_id;
+        let signature = sign_payload(&self, &private_key)?;
+        self.signature = signature.clone();
+        Ok(signature)
+    }
+
+    pub async fn send_request(
+        &self,
+        verify_cert: &str,
+    ) -> Result<HashMap<String, String>, UpacpError> {
+        // 这里 deserialize 不会出问题
+        let v = serde_json::to_value(&self).unwrap();
+        let m: HashMap<String, String> = serde_json::from_value(v).unwrap();
+        send_back_request(
+            "https://gateway.95516.com/gateway/api/backTransReq.do",
+            &m,
+            verify_cert,
+        )
+        .await
+    }
+}
+
+/**
+ * 支付和退货的后台通知 (backUrl) 格式一样
+ */
+pub struct AcpNotifyPayload {
+    pub resp_code: String,
+    pub resp_msg: String,
+    pub merchant_order_no: String, // 商户订单号, 退货通知里是退款单号
+    pub amount: i32,               // 精确到分
+    signature: String,
+    m: HashMap<String, String>,
+}
+
+impl AcpNotifyPayload {
+    /**
+     * 银联的后台通知是 x-www-form-urlencoded, 和支付宝一样先把 + 还原为空格再 url decode
+     */
+    pub fn new(payload: &str) -> Result<Self, UpacpError> {
+        let payload = payload.replace("+", " ");
+        let mut m: HashMap<String, String> = HashMap::new();
+        payload.split('&').for_each(|pair| {
+            if let Some((key, val)) = pair.split_once('=') {
+                let val = percent_encoding::percent_decode_str(val)
+                    .decode_utf8()
+                    .unwrap_or_default()
+                    .to_string();
+                m.insert(key.to_string(), val);
+            }
+        });
+
+        fn missing_params() -> UpacpError {
+            UpacpError::ApiError("missing required params".into())
+        }
+
+        let signature = m.get("signature").ok_or_else(missing_params)?;
+        let resp_code = m.get("respCode").ok_or_else(missing_params)?;
+        let order_id = m.get("orderId").ok_or_else(missing_params)?;
+        let txn_amt = m.get("txnAmt").ok_or_else(missing_params)?;
+        let resp_msg = m.get("respMsg").cloned().unwrap_or_default();
+
+        let amount = txn_amt
+            .parse::<i32>()
+            .map_err(|_| UpacpError::ApiError("invalid txnAmt".into()))?;
+
+        Ok(Self {
+            resp_code: resp_code.to_owned(),
+            resp_msg,
+            merchant_order_no: order_id.to_owned(),
+            amount,
+            signature: signature.to_owned(),
+            m,
+        })
+    }
+
+    pub fn verify_rsa_sign(&self, verify_cert: &str) -> Result<(), UpacpError> {
+        let mut m = self.m.clone();
+        m.remove("signature");
+        let verified = acp_rsa::verify(&m, &self.signature, verify_cert)?;
+        if !verified {
+            return Err(UpacpError::ApiError("wrong rsa signature".into()));
+        }
+        Ok(())
+    }
+
+    /**
+     * 00 交易成功, A6 部分成功 (有缺陷的成功) 也按成功处理
+     */
+    pub fn is_success(&self) -> bool {
+        self.resp_code == "00" || self.resp_code == "A6"
+    }
+}
@@ -0,0 +1,199 @@
+use super::{
+    acp::{AcpFrontPayload, AcpNotifyPayload, AcpQueryPayload, AcpRefundPayload},
+    UpacpConfig, UpacpError,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelNotifyOrder, ChannelRefundRequest, ChargeError,
+    ChargeResult, ChargeStatus, PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+
+/**
+ * upacp_pc (网关支付) 和 upacp_wap (手机网页支付) 只有渠道名和 channelType 不一样, 共用这一个实现
+ * channelType 07 是互联网, 08 是移动
+ */
+pub struct UpacpGateway {
+    config: UpacpConfig,
+    channel: PaymentChannel,
+    channel_type: &'static str,
+}
+
+impl UpacpGateway {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+        channel: PaymentChannel,
+        channel_type: &'static str,
+    ) -> Result<Self, UpacpError> {
+        let channel_params = crate::utils::load_channel_params_from_db(
+            &prisma_client,
+            app_id,
+            sub_app_id,
+            &channel.to_string(),
+        )
+        .await
+        .map_err(|e| UpacpError::InvalidConfig(format!("{:?}", e)))?;
+        let config: UpacpConfig = serde_json::from_value(channel_params.params).map_err(|e| {
+            UpacpError::InvalidConfig(format!(
+                "error deserializing {} config: {:?}",
+                channel.to_string(),
+                e
+            ))
+        })?;
+        Ok(Self {
+            config,
+            channel,
+            channel_type,
+        })
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for UpacpGateway {
+    /**
+     * 银联网关支付和手机网页支付, credential 里返回签名以后的表单字段, 前端 POST 到 channel_url
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            merchant_order_no,
+            time_expire,
+            extra,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let front_url = match extra.success_url.as_ref() {
+            Some(url) => url.to_string(),
+            None => {
+                return Err(ChargeError::MalformedRequest(
+                    "missing success_url in charge extra".to_string(),
+                ))
+            }
+        };
+        let mut front_payload = AcpFrontPayload::new(
+            charge_id,
+            self.channel_type,
+            &config.upacp_mer_id,
+            &front_url,
+            merchant_order_no,
+            charge_amount,
+            time_expire,
+        )?;
+        front_payload.sign_rsa(&config.upacp_sign_cert, &config.upacp_sign_cert_password)?;
+        let res_json = serde_json::to_value(front_payload).map_err(|e| {
+            UpacpError::Unexpected(format!("error serializing AcpFrontPayload: {:?}", e))
+        })?;
+        Ok(ChargeResult {
+            credential: res_json,
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        let config = &self.config;
+        let notify_payload = AcpNotifyPayload::new(payload)?;
+        notify_payload.verify_rsa_sign(&config.upacp_verify_cert)?;
+        if notify_payload.is_success() {
+            Ok(ChargeStatus::Success)
+        } else {
+            Ok(ChargeStatus::Fail)
+        }
+    }
+
+    /**
+     * process_charge_notify 验签通过以后调用, 通知里的 orderId 和 txnAmt 要和 charge 一致
+     */
+    fn charge_notify_order(&self, payload: &str) -> Option<ChannelNotifyOrder> {
+        let notify_payload = AcpNotifyPayload::new(payload).ok()?;
+        Some(ChannelNotifyOrder {
+            merchant_order_no: notify_payload.merchant_order_no,
+            amount: notify_payload.amount,
+        })
+    }
+
+    /**
+     * 退货需要原交易的 queryId, 先用 credential 里的 txnTime 查询原交易
+     */
+    async fn create_refund(
+        &self,
+        &ChannelRefundRequest {
+            charge_id,
+            charge_merchant_order_no,
+            charge_credential,
+            refund_id,
+            refund_amount,
+            refund_merchant_order_no,
+            description,
+            ..
+        }: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        let config = &self.config;
+        let txn_time = charge_credential[self.channel.to_string()]["txnTime"]
+            .as_str()
+            .ok_or_else(|| {
+                UpacpError::MalformedRequest("missing txnTime in charge credential".into())
+            })?;
+        let mut query_payload =
+            AcpQueryPayload::new(&config.upacp_mer_id, charge_merchant_order_no, txn_time)?;
+        query_payload.sign_rsa(&config.upacp_sign_cert, &config.upacp_sign_cert_password)?;
+        let query_response = query_payload
+            .send_request(&config.upacp_verify_cert)
+            .await?;
+        let orig_query_id = match query_response.get("queryId") {
+            Some(query_id) if query_response.get("origRespCode") == Some(&"00".to_string()) => {
+                query_id
+            }
+            _ => {
+                return Err(UpacpError::ApiError(format!(
+                    "original transaction not succeeded: {:?}",
+                    query_response
+                        .get("origRespMsg")
+                        .or(query_response.get("respMsg"))
+                ))
+                .into())
+            }
+        };
+
+        let mut refund_payload = AcpRefundPayload::new(
+            refund_id,
+            charge_id,
+            self.channel_type,
+            &config.upacp_mer_id,
+            orig_query_id,
+            refund_merchant_order_no,
+            refund_amount,
+        )?;
+        refund_payload.sign_rsa(&config.upacp_sign_cert, &config.upacp_sign_cert_password)?;
+        let refund_response = refund_payload
+            .send_request(&config.upacp_verify_cert)
+            .await?;
+        let mut result = RefundResult {
+            amount: refund_amount,
+            description: description.to_string(),
+            extra: serde_json::to_value(&refund_response).unwrap_or_default(),
+            ..Default::default()
+        };
+        let resp_code = refund_response.get("respCode");
+        // 00 只表示受理成功, 保持 Pending 等退款通知
+        if resp_code != Some(&"00".to_string()) {
+            result.status = RefundStatus::Fail(format!("respCode = {:?}", resp_code));
+            result.failure_msg = refund_response.get("respMsg").cloned();
+        }
+        Ok(result)
+    }
+
+    fn process_refund_notify(&self, payload: &str) -> Result<RefundStatus, RefundError> {
+        let config = &self.config;
+        let notify_payload = AcpNotifyPayload::new(payload)?;
+        notify_payload.verify_rsa_sign(&config.upacp_verify_cert)?;
+        if notify_payload.is_success() {
+            Ok(RefundStatus::Success)
+        } else {
+            Ok(RefundStatus::Fail(notify_payload.resp_msg))
+        }
+    }
+}
@@ -0,0 +1,75 @@
+mod acp;
+mod gateway;
+
+mod config {
+    use serde::Deserialize;
+
+    /**
+     * upacp_pc 和 upacp_wap 是同一个银联商户号, 渠道参数一样
+     * 签名证书是商户在银联下载的 pfx 文件 (base64 编码以后保存), 验签证书是银联的公钥证书 (pem 格式)
+     */
+    #[derive(Debug, Deserialize)]
+    pub struct UpacpConfig {
+        pub upacp_mer_id: String,             // 银联商户号
+        pub upacp_sign_cert: String,          // 签名证书, base64 编码的 pfx 文件
+        pub upacp_sign_cert_password: String, // 签名证书密码
+        pub upacp_verify_cert: String,        // 验签证书, pem 格式
+    }
+}
+
+mod error {
+    use crate::core::{ChargeError, RefundError};
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    pub enum UpacpError {
+        #[error("[Malformed Upacp Request] {0}")]
+        MalformedRequest(String),
+        #[error("[Failed Communicating Upacp API] {0}")]
+        ApiError(String),
+        #[error("[Invalid Upacp Channel Params] {0}")]
+        InvalidConfig(String),
+        #[error("[Unexpected Upacp Error] {0}")]
+        Unexpected(String),
+    }
+
+    impl From<openssl::error::ErrorStack> for UpacpError {
+        fn from(e: openssl::error::ErrorStack) -> Self {
+            UpacpError::Unexpected(format!("[openssl] {:?}", e))
+        }
+    }
+
+    impl From<data_encoding::DecodeError> for UpacpError {
+        fn from(e: data_encoding::DecodeError) -> Self {
+            UpacpError::Unexpected(format!("[base64] {:?}", e))
+        }
+    }
+
+    impl From<UpacpError> for ChargeError {
+        fn from(e: UpacpError) -> ChargeError {
+            tracing::error!("{:?}", e);
+            match e {
+                UpacpError::MalformedRequest(e) => ChargeError::MalformedRequest(e),
+                UpacpError::ApiError(e) => ChargeError::InternalError(e),
+                UpacpError::InvalidConfig(e) => ChargeError::InternalError(e),
+                UpacpError::Unexpected(e) => ChargeError::InternalError(e),
+            }
+        }
+    }
+
+    impl From<UpacpError> for RefundError {
+        fn from(e: UpacpError) -> RefundError {
+            tracing::error!("{:?}", e);
+            match e {
+                UpacpError::MalformedRequest(e) => RefundError::BadRequest(e),
+                UpacpError::ApiError(e) => RefundError::Unexpected(e),
+                UpacpError::InvalidConfig(e) => RefundError::Unexpected(e),
+                UpacpError::Unexpected(e) => RefundError::Unexpected(e),
+            }
+        }
+    }
+}
+
+pub use config::*;
+use error::*;
+pub use gateway::UpacpGateway;
@@ -25,6 +25,20 @@ pub fn refund_notify_url(charge_id: &str, refund_id: &str) -> String {
     )
 }
 
+/**
+ * 货币的小数位数 (ISO 4217), charge 上的 amount 是货币的最小单位, 各个渠道换算金额的时候都用这里
+ * JPY KRW 这些没有小数, BHD KWD 这些三位小数, 其他币种两位小数
+ * 渠道自己有特殊限制的 (比如 PayPal 的 HUF TWD 不支持小数) 在渠道里处理
+ */
+pub fn currency_decimals(currency: &str) -> i32 {
+    match currency.to_uppercase().as_str() {
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX"
+        | "UYI" | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+        _ => 2,
+    }
+}
+
 pub fn truncate_utf8(s: &str, max_bytes: usize) -> &str {
     if s.len() <= max_bytes {
         return s;
@@ -179,6 +193,46 @@ mod db {
             })?;
         Ok(channel_params)
     }
+
+    /**
+     * App 自己的渠道参数 (sub_app_id 为空), 服务商模式下 sub_app 共用这一份密钥和证书
+     */
+    pub async fn load_app_channel_params_from_db(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: &str,
+        channel: &str,
+    ) -> Result<crate::prisma::channel_params::Data, DBError> {
+        let channel_params = prisma_client
+            .channel_params()
+            .find_first(vec![
+                crate::prisma::channel_params::app_id::equals(Some(app_id.to_string())),
+                crate::prisma::channel_params::sub_app_id::equals(None),
+                crate::prisma::channel_params::channel::equals(channel.to_string()),
+            ])
+            .exec()
+            .await?
+            .ok_or_else(|| {
+                DBError::DoesNotExist(format!("channel_params {:?} for app {:?}", channel, app_id))
+            })?;
+        Ok(channel_params)
+    }
 }
 
 pub use db::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_currency_decimals() {
+        assert_eq!(currency_decimals("cny"), 2);
+        assert_eq!(currency_decimals("USD"), 2);
+        assert_eq!(currency_decimals("jpy"), 0);
+        assert_eq!(currency_decimals("KRW"), 0);
+        assert_eq!(currency_decimals("KWD"), 3);
+        // HUF TWD 按 ISO 4217 是两位小数, PayPal 的限制不影响其他渠道
+        assert_eq!(currency_decimals("HUF"), 2);
+        assert_eq!(currency_decimals("twd"), 2);
+    }
+}
@@ -0,0 +1,456 @@
+use super::{
+    load_weixin_config,
+    v2api::{
+        self, V2ApiNotifyPayload, V2ApiOrderPayload, V2ApiRefundNotifyPayload, V2ApiRefundPayload,
+        V2ApiRequestPayload,
+    },
+    v3api::{
+        V3ApiClient, V3ApiJsapiPayload, V3ApiNotifyPayload, V3ApiOrderClosePayload,
+        V3ApiOrderQueryPayload, V3ApiRefundNotifyPayload, V3ApiRefundPayload,
+    },
+    v3cert::verify_platform_signature,
+    WeixinError, WxApiVersion, WxJsapiConfig, WxLiteConfig, WxPubConfig, WxSubMerchantConfig,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelNotifyOrder, ChannelQueryRequest,
+    ChannelRefundNotifyOrder, ChannelRefundRequest, ChargeError, ChargeResult, ChargeStatus,
+    PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::HashMap;
+
+/**
+ * wx_pub (公众号) 和 wx_lite (小程序) 都是 JSAPI 支付, 下单, 通知, 查询, 关单和退款都一样
+ * 只是渠道参数不一样, 按 channel 加载对应的参数
+ */
+pub struct WxJsapi<'a> {
+    prisma_client: &'a crate::prisma::PrismaClient, // v3 接口验证签名需要查平台证书
+    channel: PaymentChannel,
+    config: WxJsapiConfig,
+    sub_merchant: Option<WxSubMerchantConfig>, // 服务商模式下的子商户
+}
+
+impl<'a> WxJsapi<'a> {
+    pub async fn new(
+        prisma_client: &'a crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+        channel: PaymentChannel,
+    ) -> Result<Self, WeixinError> {
+        let (config, sub_merchant) = match channel {
+            PaymentChannel::WxPub => {
+                let (config, sub_merchant) = load_weixin_config::<WxPubConfig>(
+                    prisma_client,
+                    app_id,
+                    sub_app_id,
+                    PaymentChannel::WxPub,
+                )
+                .await?;
+                config.check_sign_key(false)?;
+                (WxJsapiConfig::from(config), sub_merchant)
+            }
+            PaymentChannel::WxLite => {
+                let (config, sub_merchant) = load_weixin_config::<WxLiteConfig>(
+                    prisma_client,
+                    app_id,
+                    sub_app_id,
+                    PaymentChannel::WxLite,
+                )
+                .await?;
+                config.check_sign_key()?;
+                (WxJsapiConfig::from(config), sub_merchant)
+            }
+            _ => {
+                return Err(WeixinError::Unexpected(format!(
+                    "{} is not a jsapi channel",
+                    channel.to_string()
+                )))
+            }
+        };
+        Ok(Self {
+            prisma_client,
+            channel,
+            config,
+            sub_merchant,
+        })
+    }
+
+    fn v3_apiv3_key(&self) -> Result<&str, WeixinError> {
+        self.config.apiv3_key.as_deref().ok_or_else(|| {
+            WeixinError::InvalidConfig(format!("missing {}_apiv3_key", self.channel.to_string()))
+        })
+    }
+
+    fn v3_client(&self) -> Result<V3ApiClient<'_>, WeixinError> {
+        let config = &self.config;
+        V3ApiClient::new(
+            self.prisma_client,
+            &config.mch_id,
+            &config.client_cert,
+            &config.client_key,
+            config.apiv3_key.as_deref(),
+        )
+    }
+
+    /**
+     * v3 的通知签名在请求头上, v2 的在报文里, 在 process_*_notify 里验证
+     */
+    async fn verify_notify_headers(
+        &self,
+        headers: &HashMap<String, String>,
+        payload: &str,
+    ) -> Result<(), WeixinError> {
+        if let WxApiVersion::V3 = self.config.api_version {
+            verify_platform_signature(&self.v3_client()?, headers, payload).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for WxJsapi<'_> {
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            currency,
+            merchant_order_no,
+            client_ip,
+            time_expire,
+            subject,
+            body,
+            extra,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let open_id = match extra.open_id.as_ref() {
+            Some(open_id) => open_id.to_string(),
+            None => {
+                return Err(ChargeError::MalformedRequest(
+                    "missing open_id in charge extra".to_string(),
+                ))
+            }
+        };
+        if let WxApiVersion::V3 = config.api_version {
+            let v3_api_payload = V3ApiJsapiPayload::new(
+                charge_id,
+                &config.app_id,
+                &config.mch_id,
+                self.sub_merchant.as_ref(),
+                &open_id,
+                merchant_order_no,
+                charge_amount,
+                currency,
+                time_expire,
+                body,
+            )?;
+            let v3_client = self.v3_client()?;
+            let prepay_id = v3_api_payload.create_prepay_order(&v3_client).await?;
+            let res_json = v3_api_payload.build_pay_params(&prepay_id, &v3_client)?;
+            return Ok(ChargeResult {
+                credential: res_json,
+                ..Default::default()
+            });
+        }
+
+        let mut v2_api_payload = V2ApiRequestPayload::new(
+            charge_id,
+            &config.app_id,
+            &config.mch_id,
+            self.sub_merchant.as_ref(),
+            "JSAPI",
+            Some(&open_id),
+            client_ip,
+            merchant_order_no,
+            charge_amount,
+            currency,
+            time_expire,
+            subject,
+            body,
+        )?;
+
+        v2_api_payload.sign(&config.key, config.sign_type)?;
+
+        let res_obj = v2_api_payload.create_prepay_order().await?;
+
+        /* paySign 不是用前面的 sign, 需要重新生成 */
+        // 服务商模式下有子商户 app id 的话, 要用子商户的 app id 调起支付
+        let app_id = res_obj.sub_appid.as_ref().or(res_obj.appid.as_ref());
+        let mut res_json = json!({
+            "appId": app_id,
+            "timeStamp": chrono::Utc::now().timestamp().to_string(),
+            "nonceStr": &v2_api_payload.nonce_str,
+            "package": format!("prepay_id={}", res_obj.prepay_id.as_ref().unwrap_or(&"".to_string())),
+            "signType": config.sign_type.as_str(),
+            // "paySign": "",
+        });
+        let m: HashMap<String, String> = serde_json::from_value(res_json.to_owned()).unwrap();
+        let signature = v2api::v2api_md5::sign(&m, &config.key, config.sign_type)?;
+        res_json["paySign"] = serde_json::Value::String(signature);
+
+        Ok(ChargeResult {
+            credential: res_json,
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        let config = &self.config;
+        if let WxApiVersion::V3 = config.api_version {
+            let notify_payload = V3ApiNotifyPayload::new(payload, self.v3_apiv3_key()?)?;
+            if notify_payload.trade_state == "SUCCESS" {
+                return Ok(ChargeStatus::Success);
+            } else {
+                return Ok(ChargeStatus::Fail);
+            }
+        }
+        let notify_payload = V2ApiNotifyPayload::new(payload)?;
+        notify_payload.verify_sign(&config.key, config.sign_type)?;
+        let result_code = notify_payload.result_code;
+        if result_code == "SUCCESS" {
+            Ok(ChargeStatus::Success)
+        } else {
+            Ok(ChargeStatus::Fail)
+        }
+    }
+
+    async fn create_refund(
+        &self,
+        &ChannelRefundRequest {
+            charge_id,
+            charge_amount,
+            charge_currency,
+            charge_merchant_order_no,
+            refund_id,
+            refund_amount,
+            refund_merchant_order_no,
+            description,
+            // extra,
+            ..
+        }: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        let config = &self.config;
+        if let WxApiVersion::V3 = config.api_version {
+            let refund_payload = V3ApiRefundPayload::new(
+                refund_id,
+                charge_id,
+                self.sub_merchant.as_ref(),
+                charge_merchant_order_no,
+                refund_merchant_order_no,
+                charge_amount,
+                refund_amount,
+                charge_currency,
+                description,
+            )?;
+            let refund_response = refund_payload.send_request(&self.v3_client()?).await?;
+            let status = match refund_response["status"].as_str() {
+                Some("SUCCESS") => RefundStatus::Success,
+                Some("PROCESSING") => RefundStatus::Pending,
+                status => RefundStatus::Fail(format!("status = {:?}", status)),
+            };
+            return Ok(RefundResult {
+                status,
+                amount: refund_amount,
+                description: description.to_string(),
+                extra: refund_response,
+                ..Default::default()
+            });
+        }
+        let mut refund_payload = V2ApiRefundPayload::new(
+            refund_id,
+            charge_id,
+            &config.app_id,
+            &config.mch_id,
+            self.sub_merchant.as_ref(),
+            charge_merchant_order_no,
+            refund_merchant_order_no,
+            charge_amount,
+            refund_amount,
+            charge_currency,
+            description,
+        )?;
+        refund_payload.sign(&config.key, config.sign_type)?;
+        let refund_response = refund_payload
+            .send_request(&config.client_cert, &config.client_key)
+            .await?;
+        let mut result = RefundResult {
+            amount: refund_amount,
+            description: description.to_string(),
+            extra: refund_response.clone(),
+            ..Default::default()
+        };
+        let code = refund_response["result_code"].as_str();
+        if code == Some("SUCCESS") {
+            result.status = RefundStatus::Pending;
+        } else {
+            result.status = RefundStatus::Fail(format!("code = {:?}", code));
+            result.failure_msg = match refund_response["err_code_des"].as_str() {
+                Some(msg) => Some(msg.to_string()),
+                None => None,
+            };
+        }
+        Ok(result)
+    }
+
+    fn process_refund_notify(&self, payload: &str) -> Result<RefundStatus, RefundError> {
+        let config = &self.config;
+        if let WxApiVersion::V3 = config.api_version {
+            let notify_payload = V3ApiRefundNotifyPayload::new(payload, self.v3_apiv3_key()?)?;
+            if notify_payload.refund_status == "SUCCESS" {
+                return Ok(RefundStatus::Success);
+            } else {
+                return Ok(RefundStatus::Fail(format!("refund_status != SUCCESS")));
+            }
+        }
+        let notify_payload = V2ApiRefundNotifyPayload::new(payload, &config.key)?;
+        let refund_status = notify_payload.refund_status;
+        if refund_status == "SUCCESS" {
+            Ok(RefundStatus::Success)
+        } else {
+            Ok(RefundStatus::Fail(format!("refund_status != SUCCESS")))
+        }
+    }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        if let WxApiVersion::V3 = config.api_version {
+            let query_payload = V3ApiOrderQueryPayload::new(
+                &config.mch_id,
+                self.sub_merchant.as_ref(),
+                merchant_order_no,
+            )?;
+            let query_response = query_payload.send_request(&self.v3_client()?).await?;
+            return Ok(v2api::order_query_result(query_response));
+        }
+        let mut query_payload = V2ApiOrderPayload::new(
+            &config.app_id,
+            &config.mch_id,
+            self.sub_merchant.as_ref(),
+            merchant_order_no,
+            currency,
+        )?;
+        query_payload.sign(&config.key, config.sign_type)?;
+        let query_response = query_payload
+            .query_order(&config.key, config.sign_type)
+            .await?;
+        Ok(v2api::order_query_result(query_response))
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let config = &self.config;
+        if let WxApiVersion::V3 = config.api_version {
+            let close_payload = V3ApiOrderClosePayload::new(
+                &config.mch_id,
+                self.sub_merchant.as_ref(),
+                merchant_order_no,
+            )?;
+            close_payload.send_request(&self.v3_client()?).await?;
+            return Ok(());
+        }
+        let mut close_payload = V2ApiOrderPayload::new(
+            &config.app_id,
+            &config.mch_id,
+            self.sub_merchant.as_ref(),
+            merchant_order_no,
+            currency,
+        )?;
+        close_payload.sign(&config.key, config.sign_type)?;
+        close_payload.close_order().await?;
+        Ok(())
+    }
+
+    /**
+     * 境外支付的通知里有用户支付币种和汇率, 记录到 charge extra 上
+     */
+    fn charge_notify_extra(&self, payload: &str) -> Option<serde_json::Value> {
+        let notify_obj = v2api::xml_to_json(payload).ok()?;
+        v2api::cross_border_extra(&notify_obj)
+    }
+
+    /**
+     * v3 的通知是加密的 json, 解密以后检查 out_trade_no 和 amount.total
+     * v2 的是 xml, 检查 out_trade_no 和 total_fee
+     */
+    fn charge_notify_order(&self, payload: &str) -> Option<ChannelNotifyOrder> {
+        if let WxApiVersion::V3 = self.config.api_version {
+            let notify_payload =
+                V3ApiNotifyPayload::new(payload, self.v3_apiv3_key().ok()?).ok()?;
+            return Some(ChannelNotifyOrder {
+                merchant_order_no: notify_payload.merchant_order_no,
+                amount: notify_payload.amount,
+            });
+        }
+        v2api::charge_notify_order(payload)
+    }
+
+    fn refund_notify_order(&self, payload: &str) -> Option<ChannelRefundNotifyOrder> {
+        if let WxApiVersion::V3 = self.config.api_version {
+            let notify_payload =
+                V3ApiRefundNotifyPayload::new(payload, self.v3_apiv3_key().ok()?).ok()?;
+            return Some(ChannelRefundNotifyOrder {
+                charge_merchant_order_no: notify_payload.merchant_order_no,
+                refund_merchant_order_no: notify_payload.refund_merchant_order_no,
+                refund_amount: notify_payload.amount,
+            });
+        }
+        v2api::refund_notify_order(payload, &self.config.key)
+    }
+
+    async fn verify_charge_notify_headers(
+        &self,
+        headers: &HashMap<String, String>,
+        payload: &str,
+    ) -> Result<(), ChargeError> {
+        Ok(self.verify_notify_headers(headers, payload).await?)
+    }
+
+    async fn verify_refund_notify_headers(
+        &self,
+        headers: &HashMap<String, String>,
+        payload: &str,
+    ) -> Result<(), RefundError> {
+        Ok(self.verify_notify_headers(headers, payload).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // 使用 v2 api
+    // use super::*;
+    // use wechat_pay_rust_sdk::model::JsapiParams;
+    // use wechat_pay_rust_sdk::pay::WechatPay;
+
+    // #[tokio::test]
+    // async fn test_wx_pub() {
+    //     let wechat_pay = WechatPay::new(
+    //         "app_id",
+    //         "mch_id",
+    //         "private_key",
+    //         "serial_no",
+    //         "v3_key",
+    //         "notifi_url",
+    //     );
+    //     let body = wechat_pay.jsapi_pay(JsapiParams::new(
+    //         "测试支付1分",
+    //         "1243243",
+    //         1.into(),
+    //         "open_id".into()
+    //         )).await.expect("jsapi_pay error");
+    //    println!("body: {:?}", body);
+    // }
+}
@@ -1,18 +1,80 @@
+mod partner;
 mod v2api;
-mod wx_pub;
-mod wx_lite;
+mod v3api;
+mod v3cert;
+mod jsapi;
+mod wx_app;
+mod wx_pub_qr;
+mod wx_pub_scan;
+mod wx_wap;
 
 mod config {
     use serde::Deserialize;
 
+    #[derive(Debug, Default)]
+    pub enum WxApiVersion {
+        #[default]
+        V2,
+        V3,
+    }
+
+    impl<'de> Deserialize<'de> for WxApiVersion {
+        fn deserialize<D>(deserializer: D) -> Result<WxApiVersion, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            let s = i32::deserialize(deserializer)?;
+            match s {
+                2 => Ok(WxApiVersion::V2),
+                3 => Ok(WxApiVersion::V3),
+                _ => Err(serde::de::Error::custom(format!(
+                    "unknown wx api version: {}",
+                    s
+                ))),
+            }
+        }
+    }
+
+    /**
+     * v2 接口的签名方式, 不填默认 MD5, 有风控要求的商户可以用 HMAC-SHA256
+     * 下单, 退款, JSAPI 调起支付的 paySign 和异步通知验签都用同一个签名方式
+     */
+    #[derive(Debug, Default, Clone, Copy, Deserialize)]
+    pub enum WxSignType {
+        #[default]
+        #[serde(rename = "MD5")]
+        MD5,
+        #[serde(rename = "HMAC-SHA256")]
+        HmacSha256,
+    }
+
+    impl WxSignType {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                WxSignType::MD5 => "MD5",
+                WxSignType::HmacSha256 => "HMAC-SHA256",
+            }
+        }
+    }
+
+    /**
+     * wx_pub_api_version 只对 wx_pub 生效, wx_pub_qr 和 wx_pub_scan 还是用 v2
+     * v3 用 wx_pub_client_key 签名, wx_pub_apiv3_key 解密通知, 不需要 wx_pub_key
+     */
     #[derive(Debug, Deserialize)]
     pub struct WxPubConfig {
         // pub finance_version: String,  // 微信新旧资金流，默认值为old，表示旧资金流；new表示新资金流
         pub wx_pub_app_id: String,
         pub wx_pub_mch_id: String,
+        #[serde(default)]
         pub wx_pub_key: String,
+        #[serde(default)]
+        pub wx_pub_sign_type: WxSignType, // MD5 (默认) | HMAC-SHA256
         pub wx_pub_client_cert: String,
         pub wx_pub_client_key: String,
+        #[serde(default)]
+        pub wx_pub_api_version: WxApiVersion, // 2:v2 (默认), 3:v3
+        pub wx_pub_apiv3_key: Option<String>, // APIv3 密钥, wx_pub_api_version == 3 时需要
     }
 
     #[derive(Debug, Deserialize)]
@@ -20,9 +82,121 @@ mod config {
         // pub finance_version: String,  // 微信新旧资金流，默认值为old，表示旧资金流；new表示新资金流
         pub wx_lite_app_id: String,
         pub wx_lite_mch_id: String,
+        #[serde(default)]
         pub wx_lite_key: String,
+        #[serde(default)]
+        pub wx_lite_sign_type: WxSignType, // MD5 (默认) | HMAC-SHA256
         pub wx_lite_client_cert: String,
         pub wx_lite_client_key: String,
+        #[serde(default)]
+        pub wx_lite_api_version: WxApiVersion, // 2:v2 (默认), 3:v3
+        pub wx_lite_apiv3_key: Option<String>, // APIv3 密钥, wx_lite_api_version == 3 时需要
+    }
+
+    impl WxPubConfig {
+        /**
+         * v3 不需要 wx_pub_key, 所以反序列化的时候是可选的, 用 v2 接口的时候必须有
+         * wx_pub_qr 和 wx_pub_scan 只有 v2 接口, always_v2 传 true
+         */
+        pub fn check_sign_key(&self, always_v2: bool) -> Result<(), super::WeixinError> {
+            let v2 = always_v2 || matches!(self.wx_pub_api_version, WxApiVersion::V2);
+            if v2 && self.wx_pub_key.is_empty() {
+                return Err(super::WeixinError::InvalidConfig(
+                    "missing wx_pub_key".to_string(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    impl WxLiteConfig {
+        pub fn check_sign_key(&self) -> Result<(), super::WeixinError> {
+            if matches!(self.wx_lite_api_version, WxApiVersion::V2) && self.wx_lite_key.is_empty() {
+                return Err(super::WeixinError::InvalidConfig(
+                    "missing wx_lite_key".to_string(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    /**
+     * wx_pub 和 wx_lite 都是 JSAPI 下单, 只是渠道参数的前缀不一样, 转成同一个结构给 WxJsapi 用
+     */
+    #[derive(Debug)]
+    pub struct WxJsapiConfig {
+        pub app_id: String,
+        pub mch_id: String,
+        pub key: String,
+        pub sign_type: WxSignType,
+        pub client_cert: String,
+        pub client_key: String,
+        pub api_version: WxApiVersion,
+        pub apiv3_key: Option<String>,
+    }
+
+    impl From<WxPubConfig> for WxJsapiConfig {
+        fn from(config: WxPubConfig) -> Self {
+            WxJsapiConfig {
+                app_id: config.wx_pub_app_id,
+                mch_id: config.wx_pub_mch_id,
+                key: config.wx_pub_key,
+                sign_type: config.wx_pub_sign_type,
+                client_cert: config.wx_pub_client_cert,
+                client_key: config.wx_pub_client_key,
+                api_version: config.wx_pub_api_version,
+                apiv3_key: config.wx_pub_apiv3_key,
+            }
+        }
+    }
+
+    impl From<WxLiteConfig> for WxJsapiConfig {
+        fn from(config: WxLiteConfig) -> Self {
+            WxJsapiConfig {
+                app_id: config.wx_lite_app_id,
+                mch_id: config.wx_lite_mch_id,
+                key: config.wx_lite_key,
+                sign_type: config.wx_lite_sign_type,
+                client_cert: config.wx_lite_client_cert,
+                client_key: config.wx_lite_client_key,
+                api_version: config.wx_lite_api_version,
+                apiv3_key: config.wx_lite_apiv3_key,
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct WxWapConfig {
+        pub wx_wap_app_id: String,
+        pub wx_wap_mch_id: String,
+        pub wx_wap_key: String,
+        #[serde(default)]
+        pub wx_wap_sign_type: WxSignType, // MD5 (默认) | HMAC-SHA256
+        pub wx_wap_client_cert: String,
+        pub wx_wap_client_key: String,
+        pub wx_wap_url: String,  // H5 支付的 scene_info 里需要的 WAP 网站 URL 地址
+        pub wx_wap_name: String, // H5 支付的 scene_info 里需要的 WAP 网站名
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct WxAppConfig {
+        pub wx_app_id: String, // 开放平台移动应用 app id
+        pub wx_mch_id: String,
+        pub wx_key: String,
+        #[serde(default)]
+        pub wx_sign_type: WxSignType, // MD5 (默认) | HMAC-SHA256
+        pub wx_client_cert: String,
+        pub wx_client_key: String,
+    }
+    /**
+     * 服务商模式, sub_app 是服务商下面的子商户, 渠道参数里只有子商户号和子商户的 app id
+     * app id, 商户号, 密钥和证书都用 parent App 上同一个渠道的服务商参数
+     * wx_pub, wx_pub_qr, wx_pub_scan, wx_lite 支持
+     */
+    #[derive(Debug, Deserialize)]
+    pub struct WxSubMerchantConfig {
+        pub wx_sub_mch_id: String,         // 子商户号
+        pub wx_sub_app_id: Option<String>, // 子商户的公众号/小程序 app id, 有的话 openid 按 sub_openid 传
     }
 }
 
@@ -42,6 +216,18 @@ mod error {
         Unexpected(String),
     }
 
+    impl From<openssl::error::ErrorStack> for WeixinError {
+        fn from(e: openssl::error::ErrorStack) -> Self {
+            WeixinError::Unexpected(format!("[openssl] {:?}", e))
+        }
+    }
+
+    impl From<data_encoding::DecodeError> for WeixinError {
+        fn from(e: data_encoding::DecodeError) -> Self {
+            WeixinError::Unexpected(format!("[base64] {:?}", e))
+        }
+    }
+
     impl From<WeixinError> for ChargeError {
         fn from(e: WeixinError) -> ChargeError {
             tracing::error!("{:?}", e);
@@ -67,7 +253,12 @@ mod error {
     }
 }
 
-pub use wx_pub::WxPub;
-pub use wx_lite::WxLite;
+pub use jsapi::WxJsapi;
+pub use wx_app::WxApp;
+pub use wx_pub_qr::WxPubQr;
+pub use wx_pub_scan::WxPubScan;
+pub use wx_wap::WxWap;
 pub use config::*;
+pub use v3cert::run_platform_cert_refresher;
 use error::*;
+use partner::load_weixin_config;
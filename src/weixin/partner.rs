@@ -0,0 +1,49 @@
+use super::{WeixinError, WxSubMerchantConfig};
+use crate::core::PaymentChannel;
+use serde::de::DeserializeOwned;
+
+/**
+ * 加载微信渠道参数, 返回 (服务商或普通商户的渠道参数, 服务商模式下的子商户参数)
+ * sub_app 上的渠道参数里有 wx_sub_mch_id 的就是服务商模式,
+ * 这时候 app id, 商户号, 密钥和证书从 parent App 上加载, 不是服务商模式就直接用 sub_app 上的
+ */
+pub async fn load_weixin_config<T: DeserializeOwned>(
+    prisma_client: &crate::prisma::PrismaClient,
+    app_id: Option<&str>,
+    sub_app_id: Option<&str>,
+    channel: PaymentChannel,
+) -> Result<(T, Option<WxSubMerchantConfig>), WeixinError> {
+    let channel = channel.to_string();
+    let channel_params =
+        crate::utils::load_channel_params_from_db(prisma_client, app_id, sub_app_id, &channel)
+            .await
+            .map_err(|e| WeixinError::InvalidConfig(format!("{:?}", e)))?;
+    if channel_params.params.get("wx_sub_mch_id").is_none() {
+        let config: T = serde_json::from_value(channel_params.params).map_err(|e| {
+            WeixinError::InvalidConfig(format!("error deserializing {} config: {:?}", channel, e))
+        })?;
+        return Ok((config, None));
+    }
+
+    let sub_merchant: WxSubMerchantConfig =
+        serde_json::from_value(channel_params.params).map_err(|e| {
+            WeixinError::InvalidConfig(format!(
+                "error deserializing {} sub merchant config: {:?}",
+                channel, e
+            ))
+        })?;
+    let app_id = app_id.ok_or_else(|| {
+        WeixinError::InvalidConfig(format!(
+            "app_id is required to load {} service provider config",
+            channel
+        ))
+    })?;
+    let app_channel_params =
+        crate::utils::load_app_channel_params_from_db(prisma_client, app_id, &channel)
+            .await
+            .map_err(|e| WeixinError::InvalidConfig(format!("{:?}", e)))?;
+    let config: T = serde_json::from_value(app_channel_params.params).map_err(|e| {
+        WeixinError::InvalidConfig(format!("error deserializing {} config: {:?}", channel, e))
+    })?;
+    Ok((config, Some(sub_merchant)))
+}
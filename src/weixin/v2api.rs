@@ -1,14 +1,22 @@
-use super::WeixinError;
+use super::{WeixinError, WxSignType, WxSubMerchantConfig};
+use crate::core::{ChannelNotifyOrder, ChannelRefundNotifyOrder, ChargeResult, ChargeStatus};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::HashMap;
 
 pub mod v2api_md5 {
     use super::*;
+    use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
     /**
      * https://pay.weixin.qq.com/wiki/doc/api/jsapi.php?chapter=4_3
      * 对 m 的所有字段进行签名, 所以 m 里面不能包含不需要签名的字段比如 sign, paySign, 或者他们需要为空
+     * MD5 和 HMAC-SHA256 的签名串一样, 只是摘要算法不同, HMAC-SHA256 用 sign_key 作为密钥
      */
-    pub fn sign(m: &HashMap<String, String>, sign_key: &str) -> String {
+    pub fn sign(
+        m: &HashMap<String, String>,
+        sign_key: &str,
+        sign_type: WxSignType,
+    ) -> Result<String, WeixinError> {
         let mut query_list = Vec::<String>::new();
         m.iter().for_each(|(k, v)| {
             if !v.is_empty() {
@@ -19,24 +27,29 @@ pub mod v2api_md5 {
         });
         query_list.sort();
         let sign_sorted_source = format!("{}&key={}", query_list.join("&"), sign_key);
-        let signature = md5::compute(sign_sorted_source.as_bytes());
-        let signature = format!("{:x}", signature).to_uppercase();
-        signature
+        let signature = match sign_type {
+            WxSignType::MD5 => {
+                let signature = md5::compute(sign_sorted_source.as_bytes());
+                format!("{:x}", signature)
+            }
+            WxSignType::HmacSha256 => {
+                let key = PKey::hmac(sign_key.as_bytes())?;
+                let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+                signer.update(sign_sorted_source.as_bytes())?;
+                data_encoding::HEXLOWER.encode(&signer.sign_to_vec()?)
+            }
+        };
+        Ok(signature.to_uppercase())
     }
 
-    pub fn verify(m: &HashMap<String, String>, signature: &str, sign_key: &str) -> bool {
-        let mut query_list = Vec::<String>::new();
-        m.iter().for_each(|(k, v)| {
-            if !v.is_empty() {
-                let query = format!("{}={}", k, v.trim());
-                query_list.push(query);
-            }
-        });
-        query_list.sort();
-        let sign_sorted_source = format!("{}&key={}", query_list.join("&"), sign_key);
-        let sign = md5::compute(sign_sorted_source.as_bytes());
-        let sign = format!("{:x}", sign).to_uppercase();
-        sign == *signature
+    pub fn verify(
+        m: &HashMap<String, String>,
+        signature: &str,
+        sign_key: &str,
+        sign_type: WxSignType,
+    ) -> Result<bool, WeixinError> {
+        let sign = sign(m, sign_key, sign_type)?;
+        Ok(sign == *signature)
     }
 
     pub fn decrypt_aes256_ecb(text: &str, sign_key: &str) -> Result<String, String> {
@@ -100,21 +113,192 @@ fn xml_to_map(payload: &str) -> Result<HashMap<String, String>, WeixinError> {
     Ok(m)
 }
 
+/**
+ * 微信支付的时间格式是北京时间 yyyyMMddHHmmss
+ */
+fn format_time_expire(time_expire: i32) -> Result<String, WeixinError> {
+    let time_expire = chrono::DateTime::<chrono::Utc>::from_timestamp(time_expire as i64, 0)
+        .ok_or_else(|| WeixinError::MalformedRequest("can't convert timestamp to datetime".into()))?
+        .with_timezone(&chrono::FixedOffset::east_opt(8 * 3600).unwrap())
+        .format("%Y%m%d%H%M%S")
+        .to_string();
+    Ok(time_expire)
+}
+
+fn client_with_identity(
+    client_cert: &str,
+    client_key: &str,
+) -> Result<reqwest::Client, WeixinError> {
+    // let cert = reqwest::Certificate::from_pem(client_cert.as_bytes())
+    //     .map_err(|e| WeixinError::InvalidConfig(format!("error parsing client_cert: {}", e)))?;
+    // let key = reqwest::Certificate::from_pem(client_key.as_bytes())
+    //     .map_err(|e| WeixinError::InvalidConfig(format!("error parsing client_key: {}", e)))?;
+    let identity = reqwest::Identity::from_pkcs8_pem(client_cert.as_bytes(), client_key.as_bytes())
+        .map_err(|e| WeixinError::InvalidConfig(format!("error creating identity: {}", e)))?;
+    reqwest::Client::builder()
+        .identity(identity)
+        .build()
+        .map_err(|e| WeixinError::Unexpected(format!("error building reqwest client: {}", e)))
+}
+
+/**
+ * fee_type 不是 CNY 的境外支付走跨境接口的域名, 默认是香港的 apihk, 可以用环境变量 WX_CROSS_BORDER_API_BASE 修改
+ * https://pay.weixin.qq.com/wiki/doc/api/wxpay/en/fusion_wallet/chapter1_5.shtml
+ */
+fn api_url(fee_type: &str, path: &str) -> String {
+    let api_base = if fee_type.eq_ignore_ascii_case("CNY") {
+        String::from("https://api.mch.weixin.qq.com")
+    } else {
+        std::env::var("WX_CROSS_BORDER_API_BASE")
+            .unwrap_or_else(|_| String::from("https://apihk.mch.weixin.qq.com"))
+    };
+    format!("{}{}", api_base, path)
+}
+
+/**
+ * 异步通知的 xml 转成 json, 和同步应答一样可以用 cross_border_extra 取境外支付的信息
+ */
+pub fn xml_to_json(payload: &str) -> Result<serde_json::Value, WeixinError> {
+    let m = xml_to_map(payload)?;
+    serde_json::to_value(&m)
+        .map_err(|e| WeixinError::ApiError(format!("error serializing xml payload: {:?}", e)))
+}
+
+/**
+ * 境外支付成功以后应答和异步通知里有用户实际支付的币种 cash_fee_type 和汇率 rate, 需要记录到 charge extra 上
+ * 境内支付没有 rate, 返回 None
+ */
+pub fn cross_border_extra(res_obj: &serde_json::Value) -> Option<serde_json::Value> {
+    let rate = res_obj["rate"].as_str()?;
+    Some(json!({
+        "fee_type": res_obj["fee_type"],
+        "cash_fee_type": res_obj["cash_fee_type"],
+        "cash_fee": res_obj["cash_fee"],
+        "rate": rate,
+    }))
+}
+
+/**
+ * 发送 xml 请求, 返回的 xml 转成 json, return_code 不是 SUCCESS 的时候返回错误
+ * result_code 由调用方自己处理
+ */
+async fn send_xml_request(
+    client: reqwest::Client,
+    url: &str,
+    xml_payload: String,
+    api_name: &str, // 只用于错误信息
+) -> Result<serde_json::Value, WeixinError> {
+    let res = client
+        .post(url)
+        .body(xml_payload)
+        .send()
+        .await
+        .map_err(|e| WeixinError::ApiError(format!("error request {}: {}", api_name, e)))?;
+    let res_text = res
+        .text()
+        .await
+        .map_err(|e| WeixinError::ApiError(format!("error read {} response: {}", api_name, e)))?;
+    tracing::debug!("{} response: {:?}", api_name, res_text);
+
+    let m = xml_to_map(&res_text)?;
+    let res_obj = serde_json::to_value(&m).map_err(|e| {
+        WeixinError::ApiError(format!("error serializing {} response: {:?}", api_name, e))
+    })?;
+
+    if res_obj["return_code"].as_str() != Some("SUCCESS") {
+        return Err(WeixinError::ApiError(format!(
+            "{} return_code != SUCCESS: {}",
+            api_name,
+            res_obj["return_msg"].as_str().unwrap_or_default()
+        )));
+    }
+
+    Ok(res_obj)
+}
+
+/**
+ * 验证同步应答的签名, 应答里除了 sign 以外的字段都参与签名
+ */
+fn verify_response_sign(
+    res_obj: &serde_json::Value,
+    sign_key: &str,
+    sign_type: WxSignType,
+    api_name: &str, // 只用于错误信息
+) -> Result<(), WeixinError> {
+    // 应答是 xml_to_map 转出来的, value 都是字符串, 这里 deserialize 不会出问题
+    let mut m: HashMap<String, String> = serde_json::from_value(res_obj.clone()).unwrap();
+    let signature = m
+        .remove("sign")
+        .ok_or_else(|| WeixinError::ApiError(format!("missing sign in {} response", api_name)))?;
+    if !v2api_md5::verify(&m, &signature, sign_key, sign_type)? {
+        return Err(WeixinError::ApiError(format!(
+            "wrong {} signature in {} response",
+            sign_type.as_str(),
+            api_name
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * orderquery 的结果转成 ChargeResult, v3 查询订单返回的 trade_state 也一样
+ * SUCCESS 和 REFUND (支付成功以后转入退款) 是支付成功, CLOSED | REVOKED | PAYERROR 是支付失败
+ * NOTPAY | USERPAYING 或者订单不存在都是 Pending
+ */
+pub fn order_query_result(query_response: serde_json::Value) -> ChargeResult {
+    match query_response["trade_state"].as_str() {
+        Some("SUCCESS") | Some("REFUND") => ChargeResult {
+            status: ChargeStatus::Success,
+            extra: cross_border_extra(&query_response),
+            credential: query_response,
+            ..Default::default()
+        },
+        Some("CLOSED") | Some("REVOKED") | Some("PAYERROR") => ChargeResult {
+            status: ChargeStatus::Fail,
+            failure_code: query_response["trade_state"]
+                .as_str()
+                .map(|s| s.to_string()),
+            failure_msg: query_response["trade_state_desc"]
+                .as_str()
+                .map(|s| s.to_string()),
+            credential: query_response,
+            ..Default::default()
+        },
+        _ => ChargeResult {
+            credential: query_response,
+            ..Default::default()
+        },
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct V2ApiRequestPayload {
     pub appid: String,
     pub mch_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_appid: Option<String>, // 服务商模式下子商户的 app id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_mch_id: Option<String>, // 服务商模式下的子商户号
     pub nonce_str: String,
     pub sign: String,
-    // pub sign_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sign_type: Option<String>, // 不传默认 MD5, 用 HMAC-SHA256 的时候需要传
     pub body: String,
     pub out_trade_no: String,
     pub total_fee: String,
+    pub fee_type: String, // 币种, 境外支付需要, 默认 CNY
     pub spbill_create_ip: String,
     pub time_expire: String,
     pub notify_url: String,
     pub trade_type: String,
-    pub openid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub openid: Option<String>, // trade_type=JSAPI 时必传
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_openid: Option<String>, // 服务商模式下用户在子商户 app id 下的 openid, 和 openid 二选一
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<String>, // trade_type=NATIVE 时必传
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scene_info: Option<String>, // trade_type=MWEB 时必传
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -124,6 +308,8 @@ pub struct WxJSAPIResponse {
 
     pub appid: Option<String>,
     pub mch_id: Option<String>,
+    pub sub_appid: Option<String>,
+    pub sub_mch_id: Option<String>,
     pub nonce_str: Option<String>,
     pub sign: Option<String>,
     pub result_code: Option<String>,
@@ -132,46 +318,60 @@ pub struct WxJSAPIResponse {
 
     pub trade_type: Option<String>,
     pub prepay_id: Option<String>,
+    pub code_url: Option<String>, // trade_type=NATIVE 时返回
+    pub mweb_url: Option<String>, // trade_type=MWEB 时返回
 }
 
 impl V2ApiRequestPayload {
     pub fn new(
-        charge_id: &str,         //
-        wx_pub_app_id: &str,     // 微信公众号 app id
-        wx_pub_mch_id: &str,     // 微信支付商户 id
-        open_id: &str,           // 支付成功跳转
+        charge_id: &str,     //
+        wx_pub_app_id: &str, // 微信公众号 app id
+        wx_pub_mch_id: &str, // 微信支付商户 id
+        // 服务商模式下的子商户, 普通商户是 None
+        sub_merchant: Option<&WxSubMerchantConfig>,
+        trade_type: &str,        // JSAPI | NATIVE | MWEB | APP
+        open_id: Option<&str>,   // 用户在公众号/小程序下的 openid, JSAPI 必传
         client_ip: &str,         // 客户端 IP
         merchant_order_no: &str, // 商户订单号
-        charge_amount: i32,      // 支付金额, 精确到分
+        charge_amount: i32,      // 支付金额, 货币的最小单位
+        currency: &str,          // 币种
         time_expire: i32,        // 过期时间 timestamp 精确到秒
         _subject: &str,          // 标题
         body: &str,              // 详情
     ) -> Result<Self, WeixinError> {
-        let time_expire = chrono::DateTime::<chrono::Utc>::from_timestamp(time_expire as i64, 0)
-            .ok_or_else(|| {
-                WeixinError::MalformedRequest("can't convert timestamp to datetime".into())
-            })?
-            .with_timezone(&chrono::FixedOffset::east_opt(8 * 3600).unwrap())
-            .format("%Y%m%d%H%M%S")
-            .to_string();
+        let time_expire = format_time_expire(time_expire)?;
         let total_fee = format!("{}", charge_amount);
         // create 32 charactors nonce string
         let nonce_str = v2api_md5::generate_nonce_str();
         let truncated_body = crate::utils::truncate_utf8(body, 127);
+        // 服务商模式下配置了子商户 app id 的话, open_id 是子商户 app id 下的, 要放在 sub_openid 里
+        let (openid, sub_openid) = match sub_merchant {
+            Some(WxSubMerchantConfig {
+                wx_sub_app_id: Some(_),
+                ..
+            }) => (None, open_id),
+            _ => (open_id, None),
+        };
         let payload = V2ApiRequestPayload {
             appid: wx_pub_app_id.to_string(),
             mch_id: wx_pub_mch_id.to_string(),
+            sub_appid: sub_merchant.and_then(|sub_merchant| sub_merchant.wx_sub_app_id.clone()),
+            sub_mch_id: sub_merchant.map(|sub_merchant| sub_merchant.wx_sub_mch_id.clone()),
             nonce_str,
             sign: String::from(""),
-            // sign_type: "MD5",
+            sign_type: None,
             body: truncated_body.to_string(),
             out_trade_no: merchant_order_no.to_string(),
             total_fee,
+            fee_type: currency.to_uppercase(),
             spbill_create_ip: client_ip.to_string(),
             time_expire,
             notify_url: crate::utils::charge_notify_url(charge_id),
-            trade_type: String::from("JSAPI"),
-            openid: open_id.to_string(),
+            trade_type: trade_type.to_string(),
+            openid: openid.map(|open_id| open_id.to_string()),
+            sub_openid: sub_openid.map(|open_id| open_id.to_string()),
+            product_id: None,
+            scene_info: None,
         };
         Ok(payload)
     }
@@ -179,12 +379,16 @@ impl V2ApiRequestPayload {
     /**
      * https://pay.weixin.qq.com/wiki/doc/api/jsapi.php?chapter=4_3
      */
-    pub fn sign_md5(&mut self, sign_key: &str) -> Result<String, WeixinError> {
+    pub fn sign(&mut self, sign_key: &str, sign_type: WxSignType) -> Result<String, WeixinError> {
+        // sign_type 本身也要参与签名, 所以要先设置
+        if let WxSignType::HmacSha256 = sign_type {
+            self.sign_type = Some(sign_type.as_str().to_string());
+        }
         // 这里 deserialize 不会出问题
         let v = serde_json::to_value(&self).unwrap();
         let mut m: HashMap<String, String> = serde_json::from_value(v.to_owned()).unwrap();
         m.remove("sign");
-        let signature = v2api_md5::sign(&m, sign_key);
+        let signature = v2api_md5::sign(&m, sign_key, sign_type)?;
         self.sign = signature.clone();
         Ok(signature)
     }
@@ -198,7 +402,7 @@ impl V2ApiRequestPayload {
             .map_err(|e| WeixinError::Unexpected(format!("malformed xml payload: {}", e)))?;
 
         let res = reqwest::Client::new()
-            .post("https://api.mch.weixin.qq.com/pay/unifiedorder")
+            .post(api_url(&self.fee_type, "/pay/unifiedorder"))
             .body(xml_payload)
             .send()
             .await
@@ -253,10 +457,10 @@ impl V2ApiNotifyPayload {
         let out_trade_no = m.get("out_trade_no").ok_or_else(missing_params)?;
         let total_fee = m.get("total_fee").ok_or_else(missing_params)?;
 
-        let amount = (total_fee
-            .parse::<f64>()
-            .map_err(|_| WeixinError::ApiError("invalid total_fee".into()))?
-            * 100.0) as i32;
+        // v2 的 total_fee 单位已经是分
+        let amount = total_fee
+            .parse::<i32>()
+            .map_err(|_| WeixinError::ApiError("invalid total_fee".into()))?;
 
         Ok(Self {
             result_code: result_code.to_owned(),
@@ -267,13 +471,16 @@ impl V2ApiNotifyPayload {
         })
     }
 
-    pub fn verify_md5_sign(&self, sign_key: &str) -> Result<(), WeixinError> {
+    pub fn verify_sign(&self, sign_key: &str, sign_type: WxSignType) -> Result<(), WeixinError> {
         let mut m = self.m.clone();
         // k != "sign";
         m.remove("sign");
-        let verified = v2api_md5::verify(&m, &self.signature, sign_key);
+        let verified = v2api_md5::verify(&m, &self.signature, sign_key, sign_type)?;
         if !verified {
-            return Err(WeixinError::ApiError("wrong md5 signature".into()));
+            return Err(WeixinError::ApiError(format!(
+                "wrong {} signature",
+                sign_type.as_str()
+            )));
         }
         Ok(())
     }
@@ -283,13 +490,19 @@ impl V2ApiNotifyPayload {
 pub struct V2ApiRefundPayload {
     pub appid: String,
     pub mch_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_appid: Option<String>, // 服务商模式下子商户的 app id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_mch_id: Option<String>, // 服务商模式下的子商户号
     pub nonce_str: String,
     pub sign: String,
-    // pub sign_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sign_type: Option<String>, // 不传默认 MD5, 用 HMAC-SHA256 的时候需要传
     pub out_trade_no: String,
     pub out_refund_no: String,
     pub total_fee: String,
     pub refund_fee: String,
+    pub refund_fee_type: String, // 和支付时的 fee_type 一致
     pub notify_url: String,
 }
 
@@ -299,33 +512,42 @@ impl V2ApiRefundPayload {
         charge_id: &str, //
         wx_pub_app_id: &str,
         wx_pub_mch_id: &str,
+        sub_merchant: Option<&WxSubMerchantConfig>,
         charge_merchant_order_no: &str,
         refund_merchant_order_no: &str,
         charge_amount: i32,
         refund_amount: i32,
+        currency: &str,
         _description: &str,
     ) -> Result<Self, WeixinError> {
         let nonce_str = v2api_md5::generate_nonce_str();
         Ok(Self {
             appid: wx_pub_app_id.to_string(),
             mch_id: wx_pub_mch_id.to_string(),
+            sub_appid: sub_merchant.and_then(|sub_merchant| sub_merchant.wx_sub_app_id.clone()),
+            sub_mch_id: sub_merchant.map(|sub_merchant| sub_merchant.wx_sub_mch_id.clone()),
             nonce_str,
             sign: String::from(""),
-            // sign_type: "MD5",
+            sign_type: None,
             out_trade_no: charge_merchant_order_no.to_string(),
             out_refund_no: refund_merchant_order_no.to_string(),
             total_fee: charge_amount.to_string(),
             refund_fee: refund_amount.to_string(),
+            refund_fee_type: currency.to_uppercase(),
             notify_url: crate::utils::refund_notify_url(charge_id, refund_id),
         })
     }
 
-    pub fn sign_md5(&mut self, sign_key: &str) -> Result<String, WeixinError> {
+    pub fn sign(&mut self, sign_key: &str, sign_type: WxSignType) -> Result<String, WeixinError> {
+        // sign_type 本身也要参与签名, 所以要先设置
+        if let WxSignType::HmacSha256 = sign_type {
+            self.sign_type = Some(sign_type.as_str().to_string());
+        }
         // 这里 deserialize 不会出问题
         let v = serde_json::to_value(&self).unwrap();
         let mut m: HashMap<String, String> = serde_json::from_value(v.to_owned()).unwrap();
         m.remove("sign");
-        let signature = v2api_md5::sign(&m, sign_key);
+        let signature = v2api_md5::sign(&m, sign_key, sign_type)?;
         self.sign = signature.clone();
         Ok(signature)
     }
@@ -341,56 +563,259 @@ impl V2ApiRefundPayload {
         let xml_payload = quick_xml::se::to_string_with_root("xml", &self)
             .map_err(|e| WeixinError::Unexpected(format!("malformed xml payload: {}", e)))?;
 
-        let client = {
-            // let cert = reqwest::Certificate::from_pem(client_cert.as_bytes())
-            //     .map_err(|e| WeixinError::InvalidConfig(format!("error parsing client_cert: {}", e)))?;
-            // let key = reqwest::Certificate::from_pem(client_key.as_bytes())
-            //     .map_err(|e| WeixinError::InvalidConfig(format!("error parsing client_key: {}", e)))?;
-            let identity =
-                reqwest::Identity::from_pkcs8_pem(client_cert.as_bytes(), client_key.as_bytes())
-                    .map_err(|e| {
-                        WeixinError::InvalidConfig(format!("error creating identity: {}", e))
-                    })?;
-            reqwest::Client::builder()
-                .identity(identity)
-                .build()
-                .map_err(|e| {
-                    WeixinError::Unexpected(format!("error building reqwest client: {}", e))
-                })?
-        };
+        let client = client_with_identity(client_cert, client_key)?;
+        send_xml_request(
+            client,
+            &api_url(&self.refund_fee_type, "/secapi/pay/refund"),
+            xml_payload,
+            "wx refund api",
+        )
+        .await
+    }
+}
 
-        let res = client // reqwest::Client::new()
-            .post("https://api.mch.weixin.qq.com/secapi/pay/refund")
-            .body(xml_payload)
-            .send()
-            .await
-            .map_err(|e| WeixinError::ApiError(format!("error request wx refund api: {}", e)))?;
-        let res_text = res.text().await.map_err(|e| {
-            WeixinError::ApiError(format!("error read wx refund api response: {}", e))
-        })?;
-        tracing::debug!("wx refund api response: {:?}", res_text);
+/**
+ * 付款码支付, 没有 notify_url 和 trade_type, 支付结果同步返回
+ */
+#[derive(Debug, Serialize)]
+pub struct V2ApiMicropayPayload {
+    pub appid: String,
+    pub mch_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_appid: Option<String>, // 服务商模式下子商户的 app id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_mch_id: Option<String>, // 服务商模式下的子商户号
+    pub nonce_str: String,
+    pub sign: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sign_type: Option<String>, // 不传默认 MD5, 用 HMAC-SHA256 的时候需要传
+    pub body: String,
+    pub out_trade_no: String,
+    pub total_fee: String,
+    pub fee_type: String,
+    pub spbill_create_ip: String,
+    pub time_expire: String,
+    pub auth_code: String,
+}
 
-        let m = xml_to_map(&res_text)?;
-        let res_obj = serde_json::to_value(&m).map_err(|e| {
-            WeixinError::ApiError(format!("error serializing wx refund api response: {:?}", e))
-        })?;
+impl V2ApiMicropayPayload {
+    pub fn new(
+        wx_pub_app_id: &str, // 微信公众号 app id
+        wx_pub_mch_id: &str, // 微信支付商户 id
+        // 服务商模式下的子商户, 普通商户是 None
+        sub_merchant: Option<&WxSubMerchantConfig>,
+        auth_code: &str,         // 用户付款码
+        client_ip: &str,         // 客户端 IP
+        merchant_order_no: &str, // 商户订单号
+        charge_amount: i32,      // 支付金额, 货币的最小单位
+        currency: &str,          // 币种
+        time_expire: i32,        // 过期时间 timestamp 精确到秒
+        body: &str,              // 详情
+    ) -> Result<Self, WeixinError> {
+        let time_expire = format_time_expire(time_expire)?;
+        let nonce_str = v2api_md5::generate_nonce_str();
+        let truncated_body = crate::utils::truncate_utf8(body, 127);
+        Ok(Self {
+            appid: wx_pub_app_id.to_string(),
+            mch_id: wx_pub_mch_id.to_string(),
+            sub_appid: sub_merchant.and_then(|sub_merchant| sub_merchant.wx_sub_app_id.clone()),
+            sub_mch_id: sub_merchant.map(|sub_merchant| sub_merchant.wx_sub_mch_id.clone()),
+            nonce_str,
+            sign: String::from(""),
+            sign_type: None,
+            body: truncated_body.to_string(),
+            out_trade_no: merchant_order_no.to_string(),
+            total_fee: charge_amount.to_string(),
+            fee_type: currency.to_uppercase(),
+            spbill_create_ip: client_ip.to_string(),
+            time_expire,
+            auth_code: auth_code.to_string(),
+        })
+    }
 
-        if res_obj["return_code"].as_str() != Some("SUCCESS") {
-            return Err(WeixinError::ApiError(format!(
-                "wx refund api return_code != SUCCESS: {}",
-                res_obj["return_msg"].as_str().unwrap_or_default()
-            )));
+    pub fn sign(&mut self, sign_key: &str, sign_type: WxSignType) -> Result<String, WeixinError> {
+        // sign_type 本身也要参与签名, 所以要先设置
+        if let WxSignType::HmacSha256 = sign_type {
+            self.sign_type = Some(sign_type.as_str().to_string());
         }
+        // 这里 deserialize 不会出问题
+        let v = serde_json::to_value(&self).unwrap();
+        let mut m: HashMap<String, String> = serde_json::from_value(v.to_owned()).unwrap();
+        m.remove("sign");
+        let signature = v2api_md5::sign(&m, sign_key, sign_type)?;
+        self.sign = signature.clone();
+        Ok(signature)
+    }
+
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/api/micropay.php?chapter=9_10&index=1
+     */
+    pub async fn send_request(&self) -> Result<serde_json::Value, WeixinError> {
+        let xml_payload = quick_xml::se::to_string_with_root("xml", &self)
+            .map_err(|e| WeixinError::Unexpected(format!("malformed xml payload: {}", e)))?;
+        send_xml_request(
+            reqwest::Client::new(),
+            &api_url(&self.fee_type, "/pay/micropay"),
+            xml_payload,
+            "wx micropay api",
+        )
+        .await
+    }
+}
+
+/**
+ * orderquery | reverse | ... 这些接口只需要 out_trade_no
+ */
+#[derive(Debug, Serialize)]
+pub struct V2ApiOrderPayload {
+    pub appid: String,
+    pub mch_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_appid: Option<String>, // 服务商模式下子商户的 app id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_mch_id: Option<String>, // 服务商模式下的子商户号
+    pub nonce_str: String,
+    pub sign: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sign_type: Option<String>, // 不传默认 MD5, 用 HMAC-SHA256 的时候需要传
+    pub out_trade_no: String,
+    #[serde(skip)]
+    fee_type: String, // 只用来选择接口域名, 不需要签名
+}
+
+impl V2ApiOrderPayload {
+    pub fn new(
+        wx_pub_app_id: &str,
+        wx_pub_mch_id: &str,
+        sub_merchant: Option<&WxSubMerchantConfig>,
+        merchant_order_no: &str,
+        currency: &str,
+    ) -> Result<Self, WeixinError> {
+        let nonce_str = v2api_md5::generate_nonce_str();
+        Ok(Self {
+            appid: wx_pub_app_id.to_string(),
+            mch_id: wx_pub_mch_id.to_string(),
+            sub_appid: sub_merchant.and_then(|sub_merchant| sub_merchant.wx_sub_app_id.clone()),
+            sub_mch_id: sub_merchant.map(|sub_merchant| sub_merchant.wx_sub_mch_id.clone()),
+            nonce_str,
+            sign: String::from(""),
+            sign_type: None,
+            out_trade_no: merchant_order_no.to_string(),
+            fee_type: currency.to_uppercase(),
+        })
+    }
+
+    pub fn sign(&mut self, sign_key: &str, sign_type: WxSignType) -> Result<String, WeixinError> {
+        // sign_type 本身也要参与签名, 所以要先设置
+        if let WxSignType::HmacSha256 = sign_type {
+            self.sign_type = Some(sign_type.as_str().to_string());
+        }
+        // 这里 deserialize 不会出问题
+        let v = serde_json::to_value(&self).unwrap();
+        let mut m: HashMap<String, String> = serde_json::from_value(v.to_owned()).unwrap();
+        m.remove("sign");
+        let signature = v2api_md5::sign(&m, sign_key, sign_type)?;
+        self.sign = signature.clone();
+        Ok(signature)
+    }
 
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/api/jsapi.php?chapter=9_2
+     * 查询结果会用来确认支付成功, 所以要验证应答的签名
+     */
+    pub async fn query_order(
+        &self,
+        sign_key: &str,
+        sign_type: WxSignType,
+    ) -> Result<serde_json::Value, WeixinError> {
+        let xml_payload = quick_xml::se::to_string_with_root("xml", &self)
+            .map_err(|e| WeixinError::Unexpected(format!("malformed xml payload: {}", e)))?;
+        let res_obj = send_xml_request(
+            reqwest::Client::new(),
+            &api_url(&self.fee_type, "/pay/orderquery"),
+            xml_payload,
+            "wx orderquery api",
+        )
+        .await?;
+        verify_response_sign(&res_obj, sign_key, sign_type, "wx orderquery api")?;
         Ok(res_obj)
     }
+
+    /**
+     * 关闭订单, 订单已经关闭或者不存在的当作关闭成功, 已经支付的返回 ORDERPAID 错误
+     * https://pay.weixin.qq.com/wiki/doc/api/jsapi.php?chapter=9_3
+     */
+    pub async fn close_order(&self) -> Result<(), WeixinError> {
+        let xml_payload = quick_xml::se::to_string_with_root("xml", &self)
+            .map_err(|e| WeixinError::Unexpected(format!("malformed xml payload: {}", e)))?;
+        let res_obj = send_xml_request(
+            reqwest::Client::new(),
+            &api_url(&self.fee_type, "/pay/closeorder"),
+            xml_payload,
+            "wx closeorder api",
+        )
+        .await?;
+        match (
+            res_obj["result_code"].as_str(),
+            res_obj["err_code"].as_str(),
+        ) {
+            (Some("SUCCESS"), _) | (_, Some("ORDERCLOSED")) | (_, Some("ORDERNOTEXIST")) => Ok(()),
+            (_, err_code) => Err(WeixinError::ApiError(format!(
+                "wx closeorder api result_code != SUCCESS: {} {}",
+                err_code.unwrap_or_default(),
+                res_obj["err_code_des"].as_str().unwrap_or_default()
+            ))),
+        }
+    }
+
+    /**
+     * 撤销订单, 只有付款码支付可以用, 需要证书
+     * https://pay.weixin.qq.com/wiki/doc/api/micropay.php?chapter=9_11&index=3
+     */
+    pub async fn reverse_order(
+        &self,
+        client_cert: &str,
+        client_key: &str,
+    ) -> Result<serde_json::Value, WeixinError> {
+        let xml_payload = quick_xml::se::to_string_with_root("xml", &self)
+            .map_err(|e| WeixinError::Unexpected(format!("malformed xml payload: {}", e)))?;
+        let client = client_with_identity(client_cert, client_key)?;
+        send_xml_request(
+            client,
+            &api_url(&self.fee_type, "/secapi/pay/reverse"),
+            xml_payload,
+            "wx reverse api",
+        )
+        .await
+    }
+}
+
+/**
+ * reverse 的结果, recall = Y 表示撤销没有完成需要重试, 和 result_code 不是 SUCCESS 一样返回错误
+ */
+pub fn reverse_result(reverse_response: &serde_json::Value) -> Result<(), WeixinError> {
+    if reverse_response["result_code"].as_str() != Some("SUCCESS") {
+        return Err(WeixinError::ApiError(format!(
+            "wx reverse api result_code != SUCCESS: {} {}",
+            reverse_response["err_code"].as_str().unwrap_or_default(),
+            reverse_response["err_code_des"]
+                .as_str()
+                .unwrap_or_default()
+        )));
+    }
+    if reverse_response["recall"].as_str() == Some("Y") {
+        return Err(WeixinError::ApiError(
+            "wx reverse api recall = Y, need to retry".to_string(),
+        ));
+    }
+    Ok(())
 }
 
 pub struct V2ApiRefundNotifyPayload {
     pub refund_status: String,
-    pub merchant_order_no: String, // 商户订单号
-    pub refund_id: String,         // pingxx-proxy-server 系统里 refund 的 id
-    pub amount: i32,               // 退款金额
+    pub merchant_order_no: String,        // 商户订单号
+    pub refund_merchant_order_no: String, // 商户退款单号 out_refund_no
+    pub amount: i32,                      // 退款金额
 }
 
 impl V2ApiRefundNotifyPayload {
@@ -419,16 +844,55 @@ impl V2ApiRefundNotifyPayload {
         // let total_fee = m.get("total_fee").ok_or_else(missing_params)?;
         let refund_fee = m.get("refund_fee").ok_or_else(missing_params)?;
 
-        let amount = (refund_fee
-            .parse::<f64>()
-            .map_err(|_| WeixinError::ApiError("invalid refund_fee".into()))?
-            * 100.0) as i32;
+        let amount = refund_fee
+            .parse::<i32>()
+            .map_err(|_| WeixinError::ApiError("invalid refund_fee".into()))?;
 
         Ok(Self {
             refund_status: refund_status.to_owned(),
             merchant_order_no: out_trade_no.to_owned(),
-            refund_id: out_refund_no.to_owned(),
+            refund_merchant_order_no: out_refund_no.to_owned(),
             amount,
         })
     }
 }
+
+/**
+ * v2 的通知是 xml, 签名在 process_charge_notify 里验证, 这里只取 out_trade_no 和 total_fee 检查订单
+ */
+pub fn charge_notify_order(payload: &str) -> Option<ChannelNotifyOrder> {
+    let notify_payload = V2ApiNotifyPayload::new(payload).ok()?;
+    Some(ChannelNotifyOrder {
+        merchant_order_no: notify_payload.merchant_order_no,
+        amount: notify_payload.amount,
+    })
+}
+
+/**
+ * v2 的退款通知 req_info 是用 key 加密的, 能解密就说明是微信发的
+ */
+pub fn refund_notify_order(payload: &str, sign_key: &str) -> Option<ChannelRefundNotifyOrder> {
+    let notify_payload = V2ApiRefundNotifyPayload::new(payload, sign_key).ok()?;
+    Some(ChannelRefundNotifyOrder {
+        charge_merchant_order_no: notify_payload.merchant_order_no,
+        refund_merchant_order_no: notify_payload.refund_merchant_order_no,
+        refund_amount: notify_payload.amount,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charge_notify_order() {
+        // v2 的 total_fee 单位是分, 不需要再换算
+        let payload = "<xml><return_code><![CDATA[SUCCESS]]></return_code><result_code><![CDATA[SUCCESS]]></result_code><out_trade_no><![CDATA[79320240623213641748]]></out_trade_no><total_fee>10</total_fee><sign><![CDATA[ABC]]></sign></xml>";
+        let order = charge_notify_order(payload).unwrap();
+        assert_eq!(order.merchant_order_no, "79320240623213641748");
+        assert_eq!(order.amount, 10);
+
+        let payload = "<xml><return_code><![CDATA[FAIL]]></return_code></xml>";
+        assert!(charge_notify_order(payload).is_none());
+    }
+}
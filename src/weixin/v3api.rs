@@ -0,0 +1,734 @@
+use super::{
+    v2api::v2api_md5::generate_nonce_str, v3cert::verify_platform_signature, WeixinError,
+    WxSubMerchantConfig,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+
+/**
+ * 微信支付 APIv3, JSON 格式, 请求用商户 API 证书的私钥做 SHA256-RSA2048 签名
+ * https://pay.weixin.qq.com/wiki/doc/apiv3/wechatpay/wechatpay4_0.shtml
+ */
+pub mod v3api_rsa {
+    use super::*;
+    use openssl::{
+        hash::MessageDigest,
+        pkey::PKey,
+        sign::{Signer, Verifier},
+        x509::X509,
+    };
+
+    pub fn sign(message: &str, private_key: &str) -> Result<String, WeixinError> {
+        // apiclient_key.pem 是 PKCS#8 格式, 用 PKey 读
+        let keypair = PKey::private_key_from_pem(private_key.as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &keypair)?;
+        signer.update(message.as_bytes())?;
+        let signature_bytes = signer.sign_to_vec()?;
+        Ok(data_encoding::BASE64.encode(&signature_bytes))
+    }
+
+    /**
+     * 应答和通知的签名用微信支付平台证书的公钥验证
+     */
+    pub fn verify(
+        message: &str,
+        signature: &str,
+        platform_cert: &str,
+    ) -> Result<bool, WeixinError> {
+        let cert = X509::from_pem(platform_cert.as_bytes())?;
+        let public_key = cert.public_key()?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+        verifier.update(message.as_bytes())?;
+        let signature_bytes = data_encoding::BASE64.decode(signature.as_bytes())?;
+        Ok(verifier.verify(&signature_bytes)?)
+    }
+
+    /**
+     * Authorization 里需要商户 API 证书的序列号, 直接从 apiclient_cert.pem 里读, 不需要另外配置
+     */
+    pub fn cert_serial_no(client_cert: &str) -> Result<String, WeixinError> {
+        let cert = X509::from_pem(client_cert.as_bytes())?;
+        let serial_no = cert.serial_number().to_bn()?.to_hex_str()?.to_string();
+        Ok(serial_no.to_uppercase())
+    }
+
+    /**
+     * 通知里的 resource 用 APIv3 密钥 AEAD_AES_256_GCM 加密, ciphertext 最后 16 字节是 tag
+     */
+    pub fn decrypt_aes256_gcm(
+        apiv3_key: &str,
+        nonce: &str,
+        associated_data: &str,
+        ciphertext: &str,
+    ) -> Result<String, WeixinError> {
+        let ciphertext = data_encoding::BASE64.decode(ciphertext.as_bytes())?;
+        if ciphertext.len() < 16 {
+            return Err(WeixinError::ApiError("ciphertext too short".into()));
+        }
+        let (data, tag) = ciphertext.split_at(ciphertext.len() - 16);
+        let decrypted = openssl::symm::decrypt_aead(
+            openssl::symm::Cipher::aes_256_gcm(),
+            apiv3_key.as_bytes(),
+            Some(nonce.as_bytes()),
+            associated_data.as_bytes(),
+            data,
+            tag,
+        )?;
+        String::from_utf8(decrypted).map_err(|e| {
+            WeixinError::ApiError(format!(
+                "error converting decrypted resource to utf8: {}",
+                e
+            ))
+        })
+    }
+}
+
+/**
+ * WECHATPAY2-SHA256-RSA2048 mchid="",nonce_str="",signature="",timestamp="",serial_no=""
+ * 签名串是 请求方法\n URL 路径\n 时间戳\n 随机串\n 请求报文主体\n
+ */
+fn build_authorization(
+    mch_id: &str,
+    method: &str,
+    path: &str,
+    body: &str,
+    client_cert: &str,
+    client_key: &str,
+) -> Result<String, WeixinError> {
+    let timestamp = chrono::Utc::now().timestamp().to_string();
+    let nonce_str = generate_nonce_str();
+    let message = format!(
+        "{}\n{}\n{}\n{}\n{}\n",
+        method, path, timestamp, nonce_str, body
+    );
+    let signature = v3api_rsa::sign(&message, client_key)?;
+    let serial_no = v3api_rsa::cert_serial_no(client_cert)?;
+    Ok(format!(
+        "WECHATPAY2-SHA256-RSA2048 mchid=\"{}\",nonce_str=\"{}\",signature=\"{}\",timestamp=\"{}\",serial_no=\"{}\"",
+        mch_id, nonce_str, signature, timestamp, serial_no
+    ))
+}
+
+/**
+ * 调用 v3 接口需要的商户信息, 应答的签名用平台证书验证, 平台证书缓存在数据库里
+ */
+pub struct V3ApiClient<'a> {
+    pub prisma_client: &'a crate::prisma::PrismaClient,
+    pub mch_id: &'a str,
+    pub client_cert: &'a str,
+    pub client_key: &'a str,
+    pub apiv3_key: &'a str,
+}
+
+impl<'a> V3ApiClient<'a> {
+    pub fn new(
+        prisma_client: &'a crate::prisma::PrismaClient,
+        mch_id: &'a str,
+        client_cert: &'a str,
+        client_key: &'a str,
+        apiv3_key: Option<&'a str>, // APIv3 密钥, 解密通知和平台证书需要
+    ) -> Result<Self, WeixinError> {
+        let apiv3_key = apiv3_key
+            .ok_or_else(|| WeixinError::InvalidConfig("missing apiv3_key for v3 api".into()))?;
+        Ok(Self {
+            prisma_client,
+            mch_id,
+            client_cert,
+            client_key,
+            apiv3_key,
+        })
+    }
+
+    /**
+     * 发送 v3 请求, 返回 (应答头, 应答报文), 不验证应答签名
+     * HTTP 状态码不是 2xx 的时候返回 code 和 message 组成的错误
+     * 应答头只保留 wechatpay-* 开头的, 验证签名用
+     */
+    pub(super) async fn send_raw_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+        api_name: &str, // 只用于错误信息
+    ) -> Result<(HashMap<String, String>, String), WeixinError> {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let authorization = build_authorization(
+            self.mch_id,
+            method.as_str(),
+            path,
+            &body,
+            self.client_cert,
+            self.client_key,
+        )?;
+        let mut req = reqwest::Client::new()
+            .request(method, format!("https://api.mch.weixin.qq.com{}", path))
+            .header("Authorization", authorization)
+            .header("Accept", "application/json")
+            // v3 接口要求带 User-Agent
+            .header("User-Agent", "pingxx-proxy-server");
+        if !body.is_empty() {
+            req = req.header("Content-Type", "application/json").body(body);
+        }
+        let res = req
+            .send()
+            .await
+            .map_err(|e| WeixinError::ApiError(format!("error request {}: {}", api_name, e)))?;
+        let status = res.status();
+        let headers: HashMap<String, String> = res
+            .headers()
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("wechatpay-"))
+            .map(|(name, value)| {
+                let value = value.to_str().unwrap_or_default().to_string();
+                (name.to_string(), value)
+            })
+            .collect();
+        let res_text = res.text().await.map_err(|e| {
+            WeixinError::ApiError(format!("error read {} response: {}", api_name, e))
+        })?;
+        tracing::debug!("{} response: {} {:?}", api_name, status, res_text);
+
+        if !status.is_success() {
+            let res_obj: serde_json::Value =
+                serde_json::from_str(&res_text).unwrap_or(serde_json::Value::Null);
+            return Err(WeixinError::ApiError(format!(
+                "{} {} {}: {}",
+                api_name,
+                status,
+                res_obj["code"].as_str().unwrap_or_default(),
+                res_obj["message"].as_str().unwrap_or_default()
+            )));
+        }
+        Ok((headers, res_text))
+    }
+
+    /**
+     * 发送 v3 请求并用平台证书验证应答签名, 返回 JSON
+     */
+    pub async fn send_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+        api_name: &str, // 只用于错误信息
+    ) -> Result<serde_json::Value, WeixinError> {
+        let (headers, res_text) = self.send_raw_request(method, path, body, api_name).await?;
+        verify_platform_signature(self, &headers, &res_text).await?;
+        if res_text.is_empty() {
+            return Ok(serde_json::Value::Null);
+        }
+        serde_json::from_str(&res_text).map_err(|e| {
+            WeixinError::ApiError(format!("error deserialize {} response: {}", api_name, e))
+        })
+    }
+}
+
+/**
+ * v3 的时间格式是 rfc3339, 比如 2018-06-08T10:34:56+08:00
+ */
+fn format_time_expire(time_expire: i32) -> Result<String, WeixinError> {
+    let time_expire = chrono::DateTime::<chrono::Utc>::from_timestamp(time_expire as i64, 0)
+        .ok_or_else(|| WeixinError::MalformedRequest("can't convert timestamp to datetime".into()))?
+        .with_timezone(&chrono::FixedOffset::east_opt(8 * 3600).unwrap())
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, false);
+    Ok(time_expire)
+}
+
+/**
+ * v3 境内接口只支持人民币, 境外支付需要用 v2
+ */
+fn check_currency(currency: &str) -> Result<(), WeixinError> {
+    if !currency.eq_ignore_ascii_case("CNY") {
+        return Err(WeixinError::MalformedRequest(format!(
+            "currency {} is not supported by weixin v3 api",
+            currency
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * JSAPI 下单, 公众号和小程序都用这个接口
+ * 服务商模式用 partner 接口, 参数名不一样
+ */
+pub struct V3ApiJsapiPayload {
+    pay_app_id: String, // 调起支付用的 app id, 服务商模式下有子商户 app id 的话是子商户的
+    path: String,
+    body: serde_json::Value,
+}
+
+impl V3ApiJsapiPayload {
+    pub fn new(
+        charge_id: &str, //
+        wx_app_id: &str, // 微信公众号/小程序 app id
+        wx_mch_id: &str, // 微信支付商户 id
+        // 服务商模式下的子商户, 普通商户是 None
+        sub_merchant: Option<&WxSubMerchantConfig>,
+        open_id: &str,           // 用户在公众号/小程序下的 openid
+        merchant_order_no: &str, // 商户订单号
+        charge_amount: i32,      // 支付金额, 精确到分
+        currency: &str,          // 币种, 只支持 CNY
+        time_expire: i32,        // 过期时间 timestamp 精确到秒
+        body: &str,              // 详情
+    ) -> Result<Self, WeixinError> {
+        check_currency(currency)?;
+        let time_expire = format_time_expire(time_expire)?;
+        let description = crate::utils::truncate_utf8(body, 127);
+        let amount = json!({ "total": charge_amount, "currency": "CNY" });
+        let notify_url = crate::utils::charge_notify_url(charge_id);
+        let payload = match sub_merchant {
+            Some(sub_merchant) => {
+                let mut body = json!({
+                    "sp_appid": wx_app_id,
+                    "sp_mchid": wx_mch_id,
+                    "sub_mchid": sub_merchant.wx_sub_mch_id,
+                    "description": description,
+                    "out_trade_no": merchant_order_no,
+                    "time_expire": time_expire,
+                    "notify_url": notify_url,
+                    "amount": amount,
+                });
+                // 有子商户 app id 的话, open_id 是子商户 app id 下的
+                match sub_merchant.wx_sub_app_id.as_ref() {
+                    Some(sub_app_id) => {
+                        body["sub_appid"] = json!(sub_app_id);
+                        body["payer"] = json!({ "sub_openid": open_id });
+                    }
+                    None => {
+                        body["payer"] = json!({ "sp_openid": open_id });
+                    }
+                }
+                Self {
+                    pay_app_id: sub_merchant
+                        .wx_sub_app_id
+                        .clone()
+                        .unwrap_or_else(|| wx_app_id.to_string()),
+                    path: String::from("/v3/pay/partner/transactions/jsapi"),
+                    body,
+                }
+            }
+            None => Self {
+                pay_app_id: wx_app_id.to_string(),
+                path: String::from("/v3/pay/transactions/jsapi"),
+                body: json!({
+                    "appid": wx_app_id,
+                    "mchid": wx_mch_id,
+                    "description": description,
+                    "out_trade_no": merchant_order_no,
+                    "time_expire": time_expire,
+                    "notify_url": notify_url,
+                    "amount": amount,
+                    "payer": { "openid": open_id },
+                }),
+            },
+        };
+        Ok(payload)
+    }
+
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/apiv3/apis/chapter3_1_1.shtml
+     */
+    pub async fn create_prepay_order(
+        &self,
+        client: &V3ApiClient<'_>,
+    ) -> Result<String, WeixinError> {
+        let res_obj = client
+            .send_request(
+                reqwest::Method::POST,
+                &self.path,
+                Some(&self.body),
+                "wx v3 jsapi api",
+            )
+            .await?;
+        let prepay_id = res_obj["prepay_id"]
+            .as_str()
+            .ok_or_else(|| WeixinError::ApiError("missing prepay_id in jsapi response".into()))?;
+        Ok(prepay_id.to_string())
+    }
+
+    /**
+     * 调起支付的参数, paySign 是对 appId\n timeStamp\n nonceStr\n package\n 的 RSA 签名
+     * https://pay.weixin.qq.com/wiki/doc/apiv3/apis/chapter3_1_4.shtml
+     */
+    pub fn build_pay_params(
+        &self,
+        prepay_id: &str,
+        client: &V3ApiClient<'_>,
+    ) -> Result<serde_json::Value, WeixinError> {
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let nonce_str = generate_nonce_str();
+        let package = format!("prepay_id={}", prepay_id);
+        let message = format!(
+            "{}\n{}\n{}\n{}\n",
+            self.pay_app_id, timestamp, nonce_str, package
+        );
+        let signature = v3api_rsa::sign(&message, client.client_key)?;
+        Ok(json!({
+            "appId": self.pay_app_id,
+            "timeStamp": timestamp,
+            "nonceStr": nonce_str,
+            "package": package,
+            "signType": "RSA",
+            "paySign": signature,
+        }))
+    }
+}
+
+/**
+ * 商户订单号查询订单, 服务商模式用 partner 接口
+ * https://pay.weixin.qq.com/wiki/doc/apiv3/apis/chapter3_1_2.shtml
+ */
+pub struct V3ApiOrderQueryPayload {
+    path: String,
+}
+
+impl V3ApiOrderQueryPayload {
+    pub fn new(
+        wx_mch_id: &str,
+        sub_merchant: Option<&WxSubMerchantConfig>,
+        merchant_order_no: &str,
+    ) -> Result<Self, WeixinError> {
+        let out_trade_no = percent_encoding::utf8_percent_encode(
+            merchant_order_no,
+            percent_encoding::NON_ALPHANUMERIC,
+        );
+        let path = match sub_merchant {
+            Some(sub_merchant) => format!(
+                "/v3/pay/partner/transactions/out-trade-no/{}?sp_mchid={}&sub_mchid={}",
+                out_trade_no, wx_mch_id, sub_merchant.wx_sub_mch_id
+            ),
+            None => format!(
+                "/v3/pay/transactions/out-trade-no/{}?mchid={}",
+                out_trade_no, wx_mch_id
+            ),
+        };
+        Ok(Self { path })
+    }
+
+    /**
+     * 返回的 trade_state: SUCCESS | REFUND | NOTPAY | CLOSED | REVOKED | USERPAYING | PAYERROR
+     * 订单不存在的时候微信返回 404 ORDER_NOT_EXIST, 这里返回 Null, 当作还没有付款
+     */
+    pub async fn send_request(
+        &self,
+        client: &V3ApiClient<'_>,
+    ) -> Result<serde_json::Value, WeixinError> {
+        let result = client
+            .send_request(
+                reqwest::Method::GET,
+                &self.path,
+                None,
+                "wx v3 order query api",
+            )
+            .await;
+        match result {
+            Err(WeixinError::ApiError(msg)) if msg.contains("ORDER_NOT_EXIST") => {
+                tracing::info!("{}", msg);
+                Ok(serde_json::Value::Null)
+            }
+            result => result,
+        }
+    }
+}
+
+/**
+ * 商户订单号关闭订单, 服务商模式用 partner 接口, 成功返回 204 没有报文
+ * https://pay.weixin.qq.com/wiki/doc/apiv3/apis/chapter3_1_3.shtml
+ */
+pub struct V3ApiOrderClosePayload {
+    path: String,
+    body: serde_json::Value,
+}
+
+impl V3ApiOrderClosePayload {
+    pub fn new(
+        wx_mch_id: &str,
+        sub_merchant: Option<&WxSubMerchantConfig>,
+        merchant_order_no: &str,
+    ) -> Result<Self, WeixinError> {
+        let out_trade_no = percent_encoding::utf8_percent_encode(
+            merchant_order_no,
+            percent_encoding::NON_ALPHANUMERIC,
+        );
+        let (path, body) = match sub_merchant {
+            Some(sub_merchant) => (
+                format!(
+                    "/v3/pay/partner/transactions/out-trade-no/{}/close",
+                    out_trade_no
+                ),
+                json!({ "sp_mchid": wx_mch_id, "sub_mchid": sub_merchant.wx_sub_mch_id }),
+            ),
+            None => (
+                format!("/v3/pay/transactions/out-trade-no/{}/close", out_trade_no),
+                json!({ "mchid": wx_mch_id }),
+            ),
+        };
+        Ok(Self { path, body })
+    }
+
+    /**
+     * 订单不存在的时候微信返回 404 ORDER_NOT_EXIST, 当作关闭成功
+     * 已经支付的订单返回 ORDER_PAID 之类的错误
+     */
+    pub async fn send_request(&self, client: &V3ApiClient<'_>) -> Result<(), WeixinError> {
+        let result = client
+            .send_request(
+                reqwest::Method::POST,
+                &self.path,
+                Some(&self.body),
+                "wx v3 order close api",
+            )
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(WeixinError::ApiError(msg)) if msg.contains("ORDER_NOT_EXIST") => {
+                tracing::info!("{}", msg);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct V3ApiNotifyResource {
+    // algorithm: String, // 固定 AEAD_AES_256_GCM
+    ciphertext: String,
+    associated_data: Option<String>,
+    nonce: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct V3ApiNotifyEnvelope {
+    // id: String,
+    event_type: String, // TRANSACTION.SUCCESS | REFUND.SUCCESS | REFUND.ABNORMAL | REFUND.CLOSED
+    resource: V3ApiNotifyResource,
+}
+
+/**
+ * 解密通知里的 resource, 返回 (event_type, 解密后的 JSON)
+ */
+fn decrypt_notify(
+    payload: &str,
+    apiv3_key: &str,
+) -> Result<(String, serde_json::Value), WeixinError> {
+    let envelope: V3ApiNotifyEnvelope = serde_json::from_str(payload)
+        .map_err(|e| WeixinError::ApiError(format!("error deserialize v3 notify: {}", e)))?;
+    let resource = &envelope.resource;
+    let plaintext = v3api_rsa::decrypt_aes256_gcm(
+        apiv3_key,
+        &resource.nonce,
+        resource.associated_data.as_deref().unwrap_or_default(),
+        &resource.ciphertext,
+    )?;
+    tracing::debug!("v3 notify resource: {:?}", plaintext);
+    let resource: serde_json::Value = serde_json::from_str(&plaintext).map_err(|e| {
+        WeixinError::ApiError(format!("error deserialize v3 notify resource: {}", e))
+    })?;
+    Ok((envelope.event_type, resource))
+}
+
+pub struct V3ApiNotifyPayload {
+    pub trade_state: String,
+    pub merchant_order_no: String,
+    pub amount: i32,
+}
+
+impl V3ApiNotifyPayload {
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/apiv3/apis/chapter3_1_5.shtml
+     * 能用 APIv3 密钥解密就说明通知来自微信
+     */
+    pub fn new(payload: &str, apiv3_key: &str) -> Result<Self, WeixinError> {
+        let (_, resource) = decrypt_notify(payload, apiv3_key)?;
+
+        fn missing_params() -> WeixinError {
+            WeixinError::ApiError("missing required params".into())
+        }
+
+        let trade_state = resource["trade_state"]
+            .as_str()
+            .ok_or_else(missing_params)?;
+        let out_trade_no = resource["out_trade_no"]
+            .as_str()
+            .ok_or_else(missing_params)?;
+        let total = resource["amount"]["total"]
+            .as_i64()
+            .ok_or_else(missing_params)?;
+
+        Ok(Self {
+            trade_state: trade_state.to_string(),
+            merchant_order_no: out_trade_no.to_string(),
+            amount: total as i32,
+        })
+    }
+}
+
+/**
+ * 退款接口服务商和普通商户是同一个, 服务商模式多一个 sub_mchid
+ */
+pub struct V3ApiRefundPayload {
+    body: serde_json::Value,
+}
+
+impl V3ApiRefundPayload {
+    pub fn new(
+        refund_id: &str,
+        charge_id: &str,
+        sub_merchant: Option<&WxSubMerchantConfig>,
+        charge_merchant_order_no: &str,
+        refund_merchant_order_no: &str,
+        charge_amount: i32,
+        refund_amount: i32,
+        currency: &str,
+        description: &str,
+    ) -> Result<Self, WeixinError> {
+        check_currency(currency)?;
+        let mut body = json!({
+            "out_trade_no": charge_merchant_order_no,
+            "out_refund_no": refund_merchant_order_no,
+            "reason": crate::utils::truncate_utf8(description, 80),
+            "notify_url": crate::utils::refund_notify_url(charge_id, refund_id),
+            "amount": {
+                "refund": refund_amount,
+                "total": charge_amount,
+                "currency": "CNY",
+            },
+        });
+        if let Some(sub_merchant) = sub_merchant {
+            body["sub_mchid"] = json!(sub_merchant.wx_sub_mch_id);
+        }
+        Ok(Self { body })
+    }
+
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/apiv3/apis/chapter3_1_9.shtml
+     * 返回的 status: SUCCESS | CLOSED | PROCESSING | ABNORMAL
+     */
+    pub async fn send_request(
+        &self,
+        client: &V3ApiClient<'_>,
+    ) -> Result<serde_json::Value, WeixinError> {
+        client
+            .send_request(
+                reqwest::Method::POST,
+                "/v3/refund/domestic/refunds",
+                Some(&self.body),
+                "wx v3 refund api",
+            )
+            .await
+    }
+}
+
+pub struct V3ApiRefundNotifyPayload {
+    pub refund_status: String,
+    pub merchant_order_no: String,        // 商户订单号
+    pub refund_merchant_order_no: String, // 商户退款单号
+    pub amount: i32,                      // 退款金额
+}
+
+impl V3ApiRefundNotifyPayload {
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/apiv3/apis/chapter3_1_11.shtml
+     */
+    pub fn new(payload: &str, apiv3_key: &str) -> Result<Self, WeixinError> {
+        let (_, resource) = decrypt_notify(payload, apiv3_key)?;
+
+        fn missing_params() -> WeixinError {
+            WeixinError::ApiError("missing required params".into())
+        }
+
+        let refund_status = resource["refund_status"]
+            .as_str()
+            .ok_or_else(missing_params)?;
+        let out_trade_no = resource["out_trade_no"]
+            .as_str()
+            .ok_or_else(missing_params)?;
+        let out_refund_no = resource["out_refund_no"]
+            .as_str()
+            .ok_or_else(missing_params)?;
+        let refund = resource["amount"]["refund"]
+            .as_i64()
+            .ok_or_else(missing_params)?;
+
+        Ok(Self {
+            refund_status: refund_status.to_string(),
+            merchant_order_no: out_trade_no.to_string(),
+            refund_merchant_order_no: out_refund_no.to_string(),
+            amount: refund as i32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::symm::{encrypt_aead, Cipher};
+
+    const APIV3_KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn encrypt_resource(plaintext: &str, nonce: &str, associated_data: &str) -> String {
+        let mut tag = [0u8; 16];
+        let mut ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            APIV3_KEY.as_bytes(),
+            Some(nonce.as_bytes()),
+            associated_data.as_bytes(),
+            plaintext.as_bytes(),
+            &mut tag,
+        )
+        .unwrap();
+        ciphertext.extend_from_slice(&tag);
+        data_encoding::BASE64.encode(&ciphertext)
+    }
+
+    #[test]
+    fn test_decrypt_aes256_gcm() {
+        let plaintext = r#"{"out_trade_no":"123","trade_state":"SUCCESS","amount":{"total":100}}"#;
+        let ciphertext = encrypt_resource(plaintext, "fdasflkja484", "transaction");
+        let decrypted =
+            v3api_rsa::decrypt_aes256_gcm(APIV3_KEY, "fdasflkja484", "transaction", &ciphertext)
+                .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        // nonce, associated_data 和密钥不对的都解不出来
+        assert!(v3api_rsa::decrypt_aes256_gcm(
+            APIV3_KEY,
+            "fdasflkja485",
+            "transaction",
+            &ciphertext
+        )
+        .is_err());
+        assert!(v3api_rsa::decrypt_aes256_gcm(
+            APIV3_KEY,
+            "fdasflkja484",
+            "certificate",
+            &ciphertext
+        )
+        .is_err());
+        assert!(v3api_rsa::decrypt_aes256_gcm(
+            "fedcba9876543210fedcba9876543210",
+            "fdasflkja484",
+            "transaction",
+            &ciphertext
+        )
+        .is_err());
+
+        // 改了 tag 的不接受
+        let mut tampered = data_encoding::BASE64.decode(ciphertext.as_bytes()).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = data_encoding::BASE64.encode(&tampered);
+        assert!(
+            v3api_rsa::decrypt_aes256_gcm(APIV3_KEY, "fdasflkja484", "transaction", &tampered)
+                .is_err()
+        );
+
+        // 不够 16 字节 tag 的直接报错
+        let short = data_encoding::BASE64.encode(b"short");
+        assert!(matches!(
+            v3api_rsa::decrypt_aes256_gcm(APIV3_KEY, "fdasflkja484", "transaction", &short),
+            Err(WeixinError::ApiError(_))
+        ));
+    }
+}
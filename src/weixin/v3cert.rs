@@ -0,0 +1,378 @@
+use super::{
+    v3api::{v3api_rsa, V3ApiClient},
+    WeixinError, WxApiVersion, WxJsapiConfig, WxLiteConfig, WxPubConfig,
+};
+use crate::prisma::{channel_params, wx_platform_cert};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, OnceLock},
+};
+
+/**
+ * 微信支付平台证书, 用来验证 v3 接口应答和通知的签名
+ * 按商户号从 /v3/certificates 下载, 用 APIv3 密钥解密以后按序列号缓存在数据库里
+ * 微信会在旧证书过期之前启用新证书, 所以除了定时刷新, 遇到没见过的 Wechatpay-Serial 也会马上重新下载
+ * https://pay.weixin.qq.com/wiki/doc/apiv3/apis/wechatpay5_1.shtml
+ */
+
+#[derive(Debug, Deserialize)]
+struct V3ApiEncryptCertificate {
+    // algorithm: String, // 固定 AEAD_AES_256_GCM
+    nonce: String,
+    associated_data: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct V3ApiPlatformCertificate {
+    serial_no: String,
+    effective_time: String, // rfc3339, 比如 2018-06-08T10:34:56+08:00
+    expire_time: String,
+    encrypt_certificate: V3ApiEncryptCertificate,
+}
+
+#[derive(Debug, Deserialize)]
+struct V3ApiCertificatesResponse {
+    data: Vec<V3ApiPlatformCertificate>,
+}
+
+struct DecryptedCertificate {
+    certificate: String, // PEM
+    effective_at: i32,
+    expire_at: i32,
+}
+
+// 平台证书每 12 小时刷新一次, 微信建议的间隔是 12 小时以内
+const REFRESH_INTERVAL_SECS: u64 = 12 * 3600;
+// 遇到本地没有的序列号时, 同一个商户号 5 分钟内最多下载一次, 防止伪造的序列号不停触发下载
+const MIN_DOWNLOAD_INTERVAL_SECS: i64 = 5 * 60;
+// 应答和通知的时间戳和当前时间相差超过 5 分钟的不接受, 防止重放
+const MAX_TIMESTAMP_SKEW_SECS: i64 = 5 * 60;
+
+fn parse_rfc3339(time: &str) -> Result<i32, WeixinError> {
+    let datetime = chrono::DateTime::parse_from_rfc3339(time).map_err(|e| {
+        WeixinError::ApiError(format!("error parsing certificate time {}: {}", time, e))
+    })?;
+    Ok(datetime.timestamp() as i32)
+}
+
+/**
+ * 验证签名, 签名串是 时间戳\n 随机串\n 报文主体\n
+ */
+fn verify_signature(
+    headers: &HashMap<String, String>, // 只需要 wechatpay-* 开头的请求头, 名字是小写
+    body: &str,
+    platform_cert: &str,
+) -> Result<(), WeixinError> {
+    fn missing_header(name: &str) -> WeixinError {
+        WeixinError::ApiError(format!("missing {} header", name))
+    }
+
+    let timestamp = headers
+        .get("wechatpay-timestamp")
+        .ok_or_else(|| missing_header("Wechatpay-Timestamp"))?;
+    let nonce = headers
+        .get("wechatpay-nonce")
+        .ok_or_else(|| missing_header("Wechatpay-Nonce"))?;
+    let signature = headers
+        .get("wechatpay-signature")
+        .ok_or_else(|| missing_header("Wechatpay-Signature"))?;
+
+    let timestamp_secs = timestamp.parse::<i64>().map_err(|e| {
+        WeixinError::ApiError(format!("invalid Wechatpay-Timestamp {}: {}", timestamp, e))
+    })?;
+    if (chrono::Utc::now().timestamp() - timestamp_secs).abs() > MAX_TIMESTAMP_SKEW_SECS {
+        return Err(WeixinError::ApiError(format!(
+            "Wechatpay-Timestamp {} is expired",
+            timestamp
+        )));
+    }
+
+    let message = format!("{}\n{}\n{}\n", timestamp, nonce, body);
+    if !v3api_rsa::verify(&message, signature, platform_cert)? {
+        return Err(WeixinError::ApiError("invalid wechatpay signature".into()));
+    }
+    Ok(())
+}
+
+/**
+ * 下载商户的平台证书并保存到数据库, 返回下载到的证书序列号
+ * 下载证书的应答也有签名, 本地已经有这个商户的平台证书的话必须用本地的证书验证,
+ * 只有第一次下载 (本地还没有未过期的平台证书) 的时候才用下载到的证书来验证
+ * 微信会提前发布新证书, 定时刷新的间隔比这个短, 所以换证书的时候本地已经有签名用的证书了
+ */
+pub async fn download_platform_certs(client: &V3ApiClient<'_>) -> Result<Vec<String>, WeixinError> {
+    let (headers, res_text) = client
+        .send_raw_request(
+            reqwest::Method::GET,
+            "/v3/certificates",
+            None,
+            "wx v3 certificates api",
+        )
+        .await?;
+    let res_obj: V3ApiCertificatesResponse = serde_json::from_str(&res_text).map_err(|e| {
+        WeixinError::ApiError(format!("error deserialize certificates response: {}", e))
+    })?;
+
+    let mut certificates = HashMap::new();
+    for platform_cert in res_obj.data {
+        let encrypted = &platform_cert.encrypt_certificate;
+        let certificate = v3api_rsa::decrypt_aes256_gcm(
+            client.apiv3_key,
+            &encrypted.nonce,
+            &encrypted.associated_data,
+            &encrypted.ciphertext,
+        )?;
+        certificates.insert(
+            platform_cert.serial_no,
+            DecryptedCertificate {
+                certificate,
+                effective_at: parse_rfc3339(&platform_cert.effective_time)?,
+                expire_at: parse_rfc3339(&platform_cert.expire_time)?,
+            },
+        );
+    }
+
+    let serial_no = headers
+        .get("wechatpay-serial")
+        .ok_or_else(|| WeixinError::ApiError("missing Wechatpay-Serial header".into()))?;
+    let signing_cert = match find_platform_cert(client, serial_no).await? {
+        Some(platform_cert) => platform_cert.certificate,
+        None if has_platform_certs(client).await? => {
+            return Err(WeixinError::ApiError(format!(
+                "certificates response is signed by untrusted serial {}",
+                serial_no
+            )));
+        }
+        None => {
+            tracing::warn!(
+                "no wx platform cert for mch {}, trusting downloaded cert {}",
+                client.mch_id,
+                serial_no
+            );
+            certificates
+                .get(serial_no)
+                .map(|decrypted| decrypted.certificate.clone())
+                .ok_or_else(|| {
+                    WeixinError::ApiError(format!(
+                        "certificates response is signed by unknown serial {}",
+                        serial_no
+                    ))
+                })?
+        }
+    };
+    verify_signature(&headers, &res_text, &signing_cert)?;
+
+    for (serial_no, decrypted) in certificates.iter() {
+        client
+            .prisma_client
+            .wx_platform_cert()
+            .upsert(
+                wx_platform_cert::mch_id_serial_no(client.mch_id.to_string(), serial_no.clone()),
+                wx_platform_cert::create(
+                    client.mch_id.to_string(),
+                    serial_no.clone(),
+                    decrypted.certificate.clone(),
+                    decrypted.effective_at,
+                    decrypted.expire_at,
+                    vec![],
+                ),
+                vec![
+                    wx_platform_cert::certificate::set(decrypted.certificate.clone()),
+                    wx_platform_cert::effective_at::set(decrypted.effective_at),
+                    wx_platform_cert::expire_at::set(decrypted.expire_at),
+                ],
+            )
+            .exec()
+            .await
+            .map_err(|e| WeixinError::Unexpected(format!("sql error: {:?}", e)))?;
+    }
+    tracing::info!(
+        "downloaded wx platform certs {:?} for mch {}",
+        certificates.keys(),
+        client.mch_id
+    );
+    Ok(certificates.into_keys().collect())
+}
+
+async fn find_platform_cert(
+    client: &V3ApiClient<'_>,
+    serial_no: &str,
+) -> Result<Option<wx_platform_cert::Data>, WeixinError> {
+    let now = chrono::Utc::now().timestamp() as i32;
+    let platform_cert = client
+        .prisma_client
+        .wx_platform_cert()
+        .find_unique(wx_platform_cert::mch_id_serial_no(
+            client.mch_id.to_string(),
+            serial_no.to_string(),
+        ))
+        .exec()
+        .await
+        .map_err(|e| WeixinError::Unexpected(format!("sql error: {:?}", e)))?;
+    Ok(platform_cert.filter(|platform_cert| platform_cert.expire_at > now))
+}
+
+/**
+ * 商户在本地有没有未过期的平台证书, 没有的话下载证书的时候只能相信下载到的证书
+ */
+async fn has_platform_certs(client: &V3ApiClient<'_>) -> Result<bool, WeixinError> {
+    let now = chrono::Utc::now().timestamp() as i32;
+    let count = client
+        .prisma_client
+        .wx_platform_cert()
+        .count(vec![
+            wx_platform_cert::mch_id::equals(client.mch_id.to_string()),
+            wx_platform_cert::expire_at::gt(now),
+        ])
+        .exec()
+        .await
+        .map_err(|e| WeixinError::Unexpected(format!("sql error: {:?}", e)))?;
+    Ok(count > 0)
+}
+
+/**
+ * 记录这次按需下载的时间, 距离上次下载不到 MIN_DOWNLOAD_INTERVAL_SECS 的返回 false
+ * 下载失败也算, 只在内存里记录, 多个实例各自限制
+ */
+fn try_start_download(mch_id: &str) -> bool {
+    static LAST_DOWNLOADS: OnceLock<Mutex<HashMap<String, i64>>> = OnceLock::new();
+    let now = chrono::Utc::now().timestamp();
+    let mut last_downloads = LAST_DOWNLOADS.get_or_init(Default::default).lock().unwrap();
+    match last_downloads.get(mch_id) {
+        Some(last_download) if now - last_download < MIN_DOWNLOAD_INTERVAL_SECS => false,
+        _ => {
+            last_downloads.insert(mch_id.to_string(), now);
+            true
+        }
+    }
+}
+
+/**
+ * 按序列号加载平台证书, 本地没有或者已经过期的话重新下载一次
+ */
+async fn load_platform_cert(
+    client: &V3ApiClient<'_>,
+    serial_no: &str,
+) -> Result<String, WeixinError> {
+    if let Some(platform_cert) = find_platform_cert(client, serial_no).await? {
+        return Ok(platform_cert.certificate);
+    }
+    if !try_start_download(client.mch_id) {
+        return Err(WeixinError::ApiError(format!(
+            "unknown wx platform cert serial {}, downloaded recently",
+            serial_no
+        )));
+    }
+    tracing::info!(
+        "wx platform cert {} for mch {} not found, downloading",
+        serial_no,
+        client.mch_id
+    );
+    download_platform_certs(client).await?;
+    let platform_cert = find_platform_cert(client, serial_no)
+        .await?
+        .ok_or_else(|| {
+            WeixinError::ApiError(format!("unknown wx platform cert serial {}", serial_no))
+        })?;
+    Ok(platform_cert.certificate)
+}
+
+/**
+ * 用 Wechatpay-Serial 对应的平台证书验证 v3 接口应答或者异步通知的签名
+ */
+pub async fn verify_platform_signature(
+    client: &V3ApiClient<'_>,
+    headers: &HashMap<String, String>,
+    body: &str,
+) -> Result<(), WeixinError> {
+    let serial_no = headers
+        .get("wechatpay-serial")
+        .ok_or_else(|| WeixinError::ApiError("missing Wechatpay-Serial header".into()))?;
+    let platform_cert = load_platform_cert(client, serial_no).await?;
+    verify_signature(headers, body, &platform_cert)
+}
+
+/**
+ * 刷新所有使用 v3 接口的商户的平台证书, 同一个商户号只下载一次
+ * 单个商户失败不影响其他商户
+ */
+pub async fn refresh_all_platform_certs(
+    prisma_client: &crate::prisma::PrismaClient,
+) -> Result<(), WeixinError> {
+    let channel_params_list = prisma_client
+        .channel_params()
+        .find_many(vec![channel_params::channel::in_vec(vec![
+            "wx_pub".to_string(),
+            "wx_lite".to_string(),
+        ])])
+        .exec()
+        .await
+        .map_err(|e| WeixinError::Unexpected(format!("sql error: {:?}", e)))?;
+
+    let mut refreshed_mch_ids = HashSet::new();
+    for channel_params in channel_params_list {
+        // 服务商模式下 sub_app 上只有子商户参数, 反序列化失败直接跳过, 证书跟着 parent App 的参数刷新
+        let config = match channel_params.channel.as_str() {
+            "wx_pub" => serde_json::from_value::<WxPubConfig>(channel_params.params)
+                .ok()
+                .map(WxJsapiConfig::from),
+            _ => serde_json::from_value::<WxLiteConfig>(channel_params.params)
+                .ok()
+                .map(WxJsapiConfig::from),
+        };
+        let Some(WxJsapiConfig {
+            api_version: WxApiVersion::V3,
+            mch_id,
+            client_cert,
+            client_key,
+            apiv3_key,
+            ..
+        }) = config
+        else {
+            continue;
+        };
+        if !refreshed_mch_ids.insert(mch_id.clone()) {
+            continue;
+        }
+        let result = match V3ApiClient::new(
+            prisma_client,
+            &mch_id,
+            &client_cert,
+            &client_key,
+            apiv3_key.as_deref(),
+        ) {
+            Ok(client) => download_platform_certs(&client).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::error!(
+                "error refreshing wx platform certs for mch {}: {:?}",
+                mch_id,
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/**
+ * 定时刷新平台证书, 在 main 里 tokio::spawn
+ */
+pub async fn run_platform_cert_refresher() {
+    let prisma_client = match crate::prisma::new_client().await {
+        Ok(prisma_client) => prisma_client,
+        Err(e) => {
+            tracing::error!("error getting prisma client for wx platform certs: {:?}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(REFRESH_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        if let Err(e) = refresh_all_platform_certs(&prisma_client).await {
+            tracing::error!("error refreshing wx platform certs: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use super::{
+    v2api::{
+        self, V2ApiNotifyPayload, V2ApiOrderPayload, V2ApiRefundNotifyPayload, V2ApiRefundPayload,
+        V2ApiRequestPayload,
+    },
+    WeixinError, WxAppConfig, WxSignType,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelNotifyOrder, ChannelQueryRequest,
+    ChannelRefundNotifyOrder, ChannelRefundRequest, ChargeError, ChargeResult, ChargeStatus,
+    PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::HashMap;
+
+pub struct WxApp {
+    config: WxAppConfig,
+}
+
+impl WxApp {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+    ) -> Result<Self, WeixinError> {
+        let channel_params = crate::utils::load_channel_params_from_db(
+            &prisma_client,
+            app_id,
+            sub_app_id,
+            &PaymentChannel::Wx.to_string(),
+        )
+        .await
+        .map_err(|e| WeixinError::InvalidConfig(format!("{:?}", e)))?;
+        let config: WxAppConfig = serde_json::from_value(channel_params.params).map_err(|e| {
+            WeixinError::InvalidConfig(format!("error deserializing wx config: {:?}", e))
+        })?;
+        Ok(Self { config })
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for WxApp {
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/api/app/app.php?chapter=9_1
+     * https://pay.weixin.qq.com/wiki/doc/api/app/app.php?chapter=9_12
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            currency,
+            merchant_order_no,
+            client_ip,
+            time_expire,
+            subject,
+            body,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let mut v2_api_payload = V2ApiRequestPayload::new(
+            charge_id,
+            &config.wx_app_id,
+            &config.wx_mch_id,
+            None,
+            "APP",
+            None,
+            client_ip,
+            merchant_order_no,
+            charge_amount,
+            currency,
+            time_expire,
+            subject,
+            body,
+        )?;
+
+        v2_api_payload.sign(&config.wx_key, config.wx_sign_type)?;
+
+        let res_obj = v2_api_payload.create_prepay_order().await?;
+        let prepay_id = res_obj.prepay_id.ok_or_else(|| {
+            WeixinError::ApiError("missing prepay_id in unifiedorder response".into())
+        })?;
+
+        /* 调起支付的 sign 不是用前面的 sign, 需要用小写的参数名重新生成 */
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let nonce_str = v2api::v2api_md5::generate_nonce_str();
+        let sign_json = json!({
+            "appid": &config.wx_app_id,
+            "partnerid": &config.wx_mch_id,
+            "prepayid": &prepay_id,
+            "package": "Sign=WXPay",
+            "noncestr": &nonce_str,
+            "timestamp": &timestamp,
+        });
+        let m: HashMap<String, String> = serde_json::from_value(sign_json).unwrap();
+        // APP 调起支付的参数里没有 signType, 不管下单用哪种签名方式, 这里都用 MD5
+        let signature = v2api::v2api_md5::sign(&m, &config.wx_key, WxSignType::MD5)?;
+
+        /* 返回 Ping++ SDK 需要的字段名 */
+        let res_json = json!({
+            "appId": &config.wx_app_id,
+            "partnerId": &config.wx_mch_id,
+            "prepayId": &prepay_id,
+            "nonceStr": &nonce_str,
+            "timeStamp": &timestamp,
+            "packageValue": "Sign=WXPay",
+            "sign": signature,
+        });
+
+        Ok(ChargeResult {
+            credential: res_json,
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        let config = &self.config;
+        let notify_payload = V2ApiNotifyPayload::new(payload)?;
+        notify_payload.verify_sign(&config.wx_key, config.wx_sign_type)?;
+        let result_code = notify_payload.result_code;
+        if result_code == "SUCCESS" {
+            Ok(ChargeStatus::Success)
+        } else {
+            Ok(ChargeStatus::Fail)
+        }
+    }
+
+    async fn create_refund(
+        &self,
+        &ChannelRefundRequest {
+            charge_id,
+            charge_amount,
+            charge_currency,
+            charge_merchant_order_no,
+            refund_id,
+            refund_amount,
+            refund_merchant_order_no,
+            description,
+            ..
+        }: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        let config = &self.config;
+        let mut refund_payload = V2ApiRefundPayload::new(
+            refund_id,
+            charge_id,
+            &config.wx_app_id,
+            &config.wx_mch_id,
+            None,
+            charge_merchant_order_no,
+            refund_merchant_order_no,
+            charge_amount,
+            refund_amount,
+            charge_currency,
+            description,
+        )?;
+        refund_payload.sign(&config.wx_key, config.wx_sign_type)?;
+        let refund_response = refund_payload
+            .send_request(&config.wx_client_cert, &config.wx_client_key)
+            .await?;
+        let mut result = RefundResult {
+            amount: refund_amount,
+            description: description.to_string(),
+            extra: refund_response.clone(),
+            ..Default::default()
+        };
+        let code = refund_response["result_code"].as_str();
+        if code == Some("SUCCESS") {
+            result.status = RefundStatus::Pending;
+        } else {
+            result.status = RefundStatus::Fail(format!("code = {:?}", code));
+            result.failure_msg = match refund_response["err_code_des"].as_str() {
+                Some(msg) => Some(msg.to_string()),
+                None => None,
+            };
+        }
+        Ok(result)
+    }
+
+    fn process_refund_notify(&self, payload: &str) -> Result<RefundStatus, RefundError> {
+        let config = &self.config;
+        let notify_payload = V2ApiRefundNotifyPayload::new(payload, &config.wx_key)?;
+        let refund_status = notify_payload.refund_status;
+        if refund_status == "SUCCESS" {
+            Ok(RefundStatus::Success)
+        } else {
+            Ok(RefundStatus::Fail(format!("refund_status != SUCCESS")))
+        }
+    }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let mut query_payload = V2ApiOrderPayload::new(
+            &config.wx_app_id,
+            &config.wx_mch_id,
+            None,
+            merchant_order_no,
+            currency,
+        )?;
+        query_payload.sign(&config.wx_key, config.wx_sign_type)?;
+        let query_response = query_payload
+            .query_order(&config.wx_key, config.wx_sign_type)
+            .await?;
+        Ok(v2api::order_query_result(query_response))
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let config = &self.config;
+        let mut close_payload = V2ApiOrderPayload::new(
+            &config.wx_app_id,
+            &config.wx_mch_id,
+            None,
+            merchant_order_no,
+            currency,
+        )?;
+        close_payload.sign(&config.wx_key, config.wx_sign_type)?;
+        close_payload.close_order().await?;
+        Ok(())
+    }
+
+    /**
+     * 境外支付的通知里有用户支付币种和汇率, 记录到 charge extra 上
+     */
+    fn charge_notify_extra(&self, payload: &str) -> Option<serde_json::Value> {
+        let notify_obj = v2api::xml_to_json(payload).ok()?;
+        v2api::cross_border_extra(&notify_obj)
+    }
+
+    fn charge_notify_order(&self, payload: &str) -> Option<ChannelNotifyOrder> {
+        v2api::charge_notify_order(payload)
+    }
+
+    fn refund_notify_order(&self, payload: &str) -> Option<ChannelRefundNotifyOrder> {
+        v2api::refund_notify_order(payload, &self.config.wx_key)
+    }
+}
@@ -0,0 +1,273 @@
+use super::{
+    load_weixin_config,
+    v2api::{
+        self, V2ApiMicropayPayload, V2ApiNotifyPayload, V2ApiOrderPayload,
+        V2ApiRefundNotifyPayload, V2ApiRefundPayload,
+    },
+    WeixinError, WxPubConfig, WxSubMerchantConfig,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelNotifyOrder, ChannelQueryRequest,
+    ChannelRefundNotifyOrder, ChannelRefundRequest, ChargeError, ChargeResult, ChargeStatus,
+    PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+
+/**
+ * 微信付款码支付, 和 wx_pub 用的是同一个公众号和商户号, 所以渠道参数和 wx_pub 一样
+ */
+pub struct WxPubScan {
+    config: WxPubConfig,
+    sub_merchant: Option<WxSubMerchantConfig>, // 服务商模式下的子商户
+}
+
+impl WxPubScan {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+    ) -> Result<Self, WeixinError> {
+        let (config, sub_merchant) = load_weixin_config::<WxPubConfig>(
+            prisma_client,
+            app_id,
+            sub_app_id,
+            PaymentChannel::WxPubScan,
+        )
+        .await?;
+        config.check_sign_key(true)?;
+        Ok(Self {
+            config,
+            sub_merchant,
+        })
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for WxPubScan {
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/api/micropay.php?chapter=9_10&index=1
+     * 付款码支付同步返回支付结果, 用户需要输入密码的时候返回 Pending, charge 保存以后再轮询 query_charge, 超时以后 reverse_charge 撤销
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_amount,
+            currency,
+            merchant_order_no,
+            client_ip,
+            time_expire,
+            body,
+            extra,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let auth_code = match extra.auth_code.as_ref() {
+            Some(auth_code) => auth_code.to_string(),
+            None => {
+                return Err(ChargeError::MalformedRequest(
+                    "missing auth_code in charge extra".to_string(),
+                ))
+            }
+        };
+        let mut micropay_payload = V2ApiMicropayPayload::new(
+            &config.wx_pub_app_id,
+            &config.wx_pub_mch_id,
+            self.sub_merchant.as_ref(),
+            &auth_code,
+            client_ip,
+            merchant_order_no,
+            charge_amount,
+            currency,
+            time_expire,
+            body,
+        )?;
+        micropay_payload.sign(&config.wx_pub_key, config.wx_pub_sign_type)?;
+        let micropay_response = micropay_payload.send_request().await?;
+
+        if micropay_response["result_code"].as_str() == Some("SUCCESS") {
+            return Ok(ChargeResult {
+                status: ChargeStatus::Success,
+                extra: v2api::cross_border_extra(&micropay_response),
+                credential: micropay_response,
+                ..Default::default()
+            });
+        }
+        match micropay_response["err_code"].as_str() {
+            // USERPAYING 需要用户输入密码, SYSTEMERROR 和 BANKERROR 结果未知, 都需要查询订单确认结果
+            Some("USERPAYING") | Some("SYSTEMERROR") | Some("BANKERROR") => Ok(ChargeResult {
+                status: ChargeStatus::Pending,
+                credential: micropay_response,
+                ..Default::default()
+            }),
+            _ => Ok(ChargeResult {
+                status: ChargeStatus::Fail,
+                failure_code: micropay_response["err_code"]
+                    .as_str()
+                    .map(|s| s.to_string()),
+                failure_msg: micropay_response["err_code_des"]
+                    .as_str()
+                    .map(|s| s.to_string()),
+                credential: micropay_response,
+                ..Default::default()
+            }),
+        }
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        let config = &self.config;
+        let notify_payload = V2ApiNotifyPayload::new(payload)?;
+        notify_payload.verify_sign(&config.wx_pub_key, config.wx_pub_sign_type)?;
+        let result_code = notify_payload.result_code;
+        if result_code == "SUCCESS" {
+            Ok(ChargeStatus::Success)
+        } else {
+            Ok(ChargeStatus::Fail)
+        }
+    }
+
+    async fn create_refund(
+        &self,
+        &ChannelRefundRequest {
+            charge_id,
+            charge_amount,
+            charge_currency,
+            charge_merchant_order_no,
+            refund_id,
+            refund_amount,
+            refund_merchant_order_no,
+            description,
+            ..
+        }: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        let config = &self.config;
+        let mut refund_payload = V2ApiRefundPayload::new(
+            refund_id,
+            charge_id,
+            &config.wx_pub_app_id,
+            &config.wx_pub_mch_id,
+            self.sub_merchant.as_ref(),
+            charge_merchant_order_no,
+            refund_merchant_order_no,
+            charge_amount,
+            refund_amount,
+            charge_currency,
+            description,
+        )?;
+        refund_payload.sign(&config.wx_pub_key, config.wx_pub_sign_type)?;
+        let refund_response = refund_payload
+            .send_request(&config.wx_pub_client_cert, &config.wx_pub_client_key)
+            .await?;
+        let mut result = RefundResult {
+            amount: refund_amount,
+            description: description.to_string(),
+            extra: refund_response.clone(),
+            ..Default::default()
+        };
+        let code = refund_response["result_code"].as_str();
+        if code == Some("SUCCESS") {
+            result.status = RefundStatus::Pending;
+        } else {
+            result.status = RefundStatus::Fail(format!("code = {:?}", code));
+            result.failure_msg = match refund_response["err_code_des"].as_str() {
+                Some(msg) => Some(msg.to_string()),
+                None => None,
+            };
+        }
+        Ok(result)
+    }
+
+    fn process_refund_notify(&self, payload: &str) -> Result<RefundStatus, RefundError> {
+        let config = &self.config;
+        let notify_payload = V2ApiRefundNotifyPayload::new(payload, &config.wx_pub_key)?;
+        let refund_status = notify_payload.refund_status;
+        if refund_status == "SUCCESS" {
+            Ok(RefundStatus::Success)
+        } else {
+            Ok(RefundStatus::Fail(format!("refund_status != SUCCESS")))
+        }
+    }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let mut query_payload = V2ApiOrderPayload::new(
+            &config.wx_pub_app_id,
+            &config.wx_pub_mch_id,
+            self.sub_merchant.as_ref(),
+            merchant_order_no,
+            currency,
+        )?;
+        query_payload.sign(&config.wx_pub_key, config.wx_pub_sign_type)?;
+        let query_response = query_payload
+            .query_order(&config.wx_pub_key, config.wx_pub_sign_type)
+            .await?;
+        Ok(v2api::order_query_result(query_response))
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let config = &self.config;
+        let mut close_payload = V2ApiOrderPayload::new(
+            &config.wx_pub_app_id,
+            &config.wx_pub_mch_id,
+            self.sub_merchant.as_ref(),
+            merchant_order_no,
+            currency,
+        )?;
+        close_payload.sign(&config.wx_pub_key, config.wx_pub_sign_type)?;
+        close_payload.close_order().await?;
+        Ok(())
+    }
+
+    /**
+     * 微信只有付款码支付可以撤销, 需要证书
+     */
+    async fn reverse_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let config = &self.config;
+        let mut reverse_payload = V2ApiOrderPayload::new(
+            &config.wx_pub_app_id,
+            &config.wx_pub_mch_id,
+            self.sub_merchant.as_ref(),
+            merchant_order_no,
+            currency,
+        )?;
+        reverse_payload.sign(&config.wx_pub_key, config.wx_pub_sign_type)?;
+        let reverse_response = reverse_payload
+            .reverse_order(&config.wx_pub_client_cert, &config.wx_pub_client_key)
+            .await?;
+        Ok(v2api::reverse_result(&reverse_response)?)
+    }
+
+    /**
+     * 境外支付的通知里有用户支付币种和汇率, 记录到 charge extra 上
+     */
+    fn charge_notify_extra(&self, payload: &str) -> Option<serde_json::Value> {
+        let notify_obj = v2api::xml_to_json(payload).ok()?;
+        v2api::cross_border_extra(&notify_obj)
+    }
+
+    fn charge_notify_order(&self, payload: &str) -> Option<ChannelNotifyOrder> {
+        v2api::charge_notify_order(payload)
+    }
+
+    fn refund_notify_order(&self, payload: &str) -> Option<ChannelRefundNotifyOrder> {
+        v2api::refund_notify_order(payload, &self.config.wx_pub_key)
+    }
+}
@@ -0,0 +1,242 @@
+use super::{
+    v2api::{
+        self, V2ApiNotifyPayload, V2ApiOrderPayload, V2ApiRefundNotifyPayload, V2ApiRefundPayload,
+        V2ApiRequestPayload,
+    },
+    WeixinError, WxWapConfig,
+};
+use crate::core::{
+    ChannelChargeRequest, ChannelHandler, ChannelNotifyOrder, ChannelQueryRequest,
+    ChannelRefundNotifyOrder, ChannelRefundRequest, ChargeError, ChargeResult, ChargeStatus,
+    PaymentChannel, RefundError, RefundResult, RefundStatus,
+};
+use async_trait::async_trait;
+use serde_json::json;
+
+pub struct WxWap {
+    config: WxWapConfig,
+}
+
+impl WxWap {
+    pub async fn new(
+        prisma_client: &crate::prisma::PrismaClient,
+        app_id: Option<&str>,
+        sub_app_id: Option<&str>,
+    ) -> Result<Self, WeixinError> {
+        let channel_params = crate::utils::load_channel_params_from_db(
+            &prisma_client,
+            app_id,
+            sub_app_id,
+            &PaymentChannel::WxWap.to_string(),
+        )
+        .await
+        .map_err(|e| WeixinError::InvalidConfig(format!("{:?}", e)))?;
+        let config: WxWapConfig = serde_json::from_value(channel_params.params).map_err(|e| {
+            WeixinError::InvalidConfig(format!("error deserializing wx_wap config: {:?}", e))
+        })?;
+        Ok(Self { config })
+    }
+}
+
+#[async_trait]
+impl ChannelHandler for WxWap {
+    /**
+     * https://pay.weixin.qq.com/wiki/doc/api/H5.php?chapter=9_20&index=1
+     * credential 里直接返回 mweb_url, 后面拼上 redirect_url, 支付完成后微信会跳转到 success_url
+     */
+    async fn create_credential(
+        &self,
+        &ChannelChargeRequest {
+            charge_id,
+            charge_amount,
+            currency,
+            merchant_order_no,
+            client_ip,
+            time_expire,
+            subject,
+            body,
+            extra,
+            ..
+        }: &ChannelChargeRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let success_url = match extra.success_url.as_ref() {
+            Some(url) => url.to_string(),
+            None => {
+                return Err(ChargeError::MalformedRequest(
+                    "missing success_url in charge extra".to_string(),
+                ))
+            }
+        };
+        let mut v2_api_payload = V2ApiRequestPayload::new(
+            charge_id,
+            &config.wx_wap_app_id,
+            &config.wx_wap_mch_id,
+            None,
+            "MWEB",
+            None,
+            client_ip, // H5 支付要求是用户的真实 IP, 微信会校验和发起支付的 IP 是否一致
+            merchant_order_no,
+            charge_amount,
+            currency,
+            time_expire,
+            subject,
+            body,
+        )?;
+        let scene_info = json!({
+            "h5_info": {
+                "type": "Wap",
+                "wap_url": &config.wx_wap_url,
+                "wap_name": &config.wx_wap_name,
+            }
+        });
+        v2_api_payload.scene_info = Some(scene_info.to_string());
+
+        v2_api_payload.sign(&config.wx_wap_key, config.wx_wap_sign_type)?;
+
+        let res_obj = v2_api_payload.create_prepay_order().await?;
+        let mweb_url = res_obj.mweb_url.ok_or_else(|| {
+            WeixinError::ApiError("missing mweb_url in unifiedorder response".into())
+        })?;
+        let redirect_url =
+            percent_encoding::utf8_percent_encode(&success_url, percent_encoding::NON_ALPHANUMERIC);
+        let mweb_url = format!("{}&redirect_url={}", mweb_url, redirect_url);
+
+        Ok(ChargeResult {
+            credential: serde_json::Value::String(mweb_url),
+            ..Default::default()
+        })
+    }
+
+    async fn process_charge_notify(&self, payload: &str) -> Result<ChargeStatus, ChargeError> {
+        let config = &self.config;
+        let notify_payload = V2ApiNotifyPayload::new(payload)?;
+        notify_payload.verify_sign(&config.wx_wap_key, config.wx_wap_sign_type)?;
+        let result_code = notify_payload.result_code;
+        if result_code == "SUCCESS" {
+            Ok(ChargeStatus::Success)
+        } else {
+            Ok(ChargeStatus::Fail)
+        }
+    }
+
+    async fn create_refund(
+        &self,
+        &ChannelRefundRequest {
+            charge_id,
+            charge_amount,
+            charge_currency,
+            charge_merchant_order_no,
+            refund_id,
+            refund_amount,
+            refund_merchant_order_no,
+            description,
+            ..
+        }: &ChannelRefundRequest,
+    ) -> Result<RefundResult, RefundError> {
+        let config = &self.config;
+        let mut refund_payload = V2ApiRefundPayload::new(
+            refund_id,
+            charge_id,
+            &config.wx_wap_app_id,
+            &config.wx_wap_mch_id,
+            None,
+            charge_merchant_order_no,
+            refund_merchant_order_no,
+            charge_amount,
+            refund_amount,
+            charge_currency,
+            description,
+        )?;
+        refund_payload.sign(&config.wx_wap_key, config.wx_wap_sign_type)?;
+        let refund_response = refund_payload
+            .send_request(&config.wx_wap_client_cert, &config.wx_wap_client_key)
+            .await?;
+        let mut result = RefundResult {
+            amount: refund_amount,
+            description: description.to_string(),
+            extra: refund_response.clone(),
+            ..Default::default()
+        };
+        let code = refund_response["result_code"].as_str();
+        if code == Some("SUCCESS") {
+            result.status = RefundStatus::Pending;
+        } else {
+            result.status = RefundStatus::Fail(format!("code = {:?}", code));
+            result.failure_msg = match refund_response["err_code_des"].as_str() {
+                Some(msg) => Some(msg.to_string()),
+                None => None,
+            };
+        }
+        Ok(result)
+    }
+
+    fn process_refund_notify(&self, payload: &str) -> Result<RefundStatus, RefundError> {
+        let config = &self.config;
+        let notify_payload = V2ApiRefundNotifyPayload::new(payload, &config.wx_wap_key)?;
+        let refund_status = notify_payload.refund_status;
+        if refund_status == "SUCCESS" {
+            Ok(RefundStatus::Success)
+        } else {
+            Ok(RefundStatus::Fail(format!("refund_status != SUCCESS")))
+        }
+    }
+
+    async fn query_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<ChargeResult, ChargeError> {
+        let config = &self.config;
+        let mut query_payload = V2ApiOrderPayload::new(
+            &config.wx_wap_app_id,
+            &config.wx_wap_mch_id,
+            None,
+            merchant_order_no,
+            currency,
+        )?;
+        query_payload.sign(&config.wx_wap_key, config.wx_wap_sign_type)?;
+        let query_response = query_payload
+            .query_order(&config.wx_wap_key, config.wx_wap_sign_type)
+            .await?;
+        Ok(v2api::order_query_result(query_response))
+    }
+
+    async fn close_charge(
+        &self,
+        &ChannelQueryRequest {
+            merchant_order_no,
+            currency,
+        }: &ChannelQueryRequest,
+    ) -> Result<(), ChargeError> {
+        let config = &self.config;
+        let mut close_payload = V2ApiOrderPayload::new(
+            &config.wx_wap_app_id,
+            &config.wx_wap_mch_id,
+            None,
+            merchant_order_no,
+            currency,
+        )?;
+        close_payload.sign(&config.wx_wap_key, config.wx_wap_sign_type)?;
+        close_payload.close_order().await?;
+        Ok(())
+    }
+
+    /**
+     * 境外支付的通知里有用户支付币种和汇率, 记录到 charge extra 上
+     */
+    fn charge_notify_extra(&self, payload: &str) -> Option<serde_json::Value> {
+        let notify_obj = v2api::xml_to_json(payload).ok()?;
+        v2api::cross_border_extra(&notify_obj)
+    }
+
+    fn charge_notify_order(&self, payload: &str) -> Option<ChannelNotifyOrder> {
+        v2api::charge_notify_order(payload)
+    }
+
+    fn refund_notify_order(&self, payload: &str) -> Option<ChannelRefundNotifyOrder> {
+        v2api::refund_notify_order(payload, &self.config.wx_wap_key)
+    }
+}